@@ -0,0 +1,153 @@
+use crate::config::{Config, PackageEntry, Section};
+use crate::errors::AppError;
+
+/// Parses a `brew bundle` Brewfile into the `[brew]`, `[brew_cask]` and
+/// `[brew_tap]` sections. Only the `tap`, `brew` and `cask` directives are
+/// imported; options after the name (`brew "x", args: [...]`) are dropped and
+/// other directives (`mas`, `vscode`, ...) are skipped with a warning.
+pub fn parse(content: &str) -> Result<Config, AppError> {
+    let mut brew = Vec::new();
+    let mut casks = Vec::new();
+    let mut taps = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line_number = index + 1;
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (directive, rest) = line
+            .split_once(|c: char| c.is_whitespace() || c == '(')
+            .unwrap_or((line, ""));
+        let target = match directive {
+            "brew" => &mut brew,
+            "cask" => &mut casks,
+            "tap" => &mut taps,
+            _ => {
+                eprintln!(
+                    "Warning: Brewfile line {}: skipping unsupported directive '{}'.",
+                    line_number, directive
+                );
+                continue;
+            }
+        };
+        let name = first_string_literal(rest).ok_or_else(|| {
+            AppError::Other(
+                format!(
+                    "Brewfile line {}: expected a quoted name after '{}'.",
+                    line_number, directive
+                )
+                .into(),
+            )
+        })?;
+        target.push(PackageEntry::Name(name));
+    }
+
+    let section = |list: Vec<PackageEntry>| (!list.is_empty()).then_some(Section { list });
+    Ok(Config {
+        brew: section(brew),
+        brew_cask: section(casks),
+        brew_tap: section(taps),
+        ..Default::default()
+    })
+}
+
+/// Renders the brew sections of `config` as a Brewfile, taps first as
+/// `brew bundle` expects.
+pub fn render(config: &Config) -> String {
+    let mut out = String::new();
+    for (directive, section) in [
+        ("tap", &config.brew_tap),
+        ("brew", &config.brew),
+        ("cask", &config.brew_cask),
+    ] {
+        for entry in section.iter().flat_map(|s| &s.list) {
+            let name = entry.name().replace('\\', "\\\\").replace('"', "\\\"");
+            out.push_str(&format!("{} \"{}\"\n", directive, name));
+        }
+    }
+    out
+}
+
+/// Removes a trailing `#` comment, ignoring `#` inside quoted strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Returns the contents of the first single- or double-quoted string in `s`.
+fn first_string_literal(s: &str) -> Option<String> {
+    let s = s.trim_start();
+    let quote = s.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let body = &s[1..];
+    let mut value = String::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => value.push(chars.next()?),
+            c if c == quote => return Some(value),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_brewfile() {
+        let config = parse(
+            r#"
+# Taps
+tap "homebrew/bundle"
+tap 'hashicorp/tap', "https://github.com/hashicorp/homebrew-tap"
+brew "git" # version control
+brew "hashicorp/tap/terraform", args: ["with-foo"]
+brew("jq")
+cask "firefox#nightly"
+mas "Xcode", id: 497799835
+"#,
+        )
+        .unwrap();
+
+        let names = |section: &Option<Section>| -> Vec<String> {
+            section
+                .iter()
+                .flat_map(|s| &s.list)
+                .map(|e| e.name().to_string())
+                .collect()
+        };
+        assert_eq!(
+            names(&config.brew_tap),
+            vec!["homebrew/bundle", "hashicorp/tap"]
+        );
+        assert_eq!(
+            names(&config.brew),
+            vec!["git", "hashicorp/tap/terraform", "jq"]
+        );
+        assert_eq!(names(&config.brew_cask), vec!["firefox#nightly"]);
+    }
+
+    #[test]
+    fn test_parse_brewfile_rejects_unquoted_name() {
+        let err = parse("brew git\n").unwrap_err().to_string();
+        assert!(err.contains("line 1"));
+    }
+
+    #[test]
+    fn test_render_round_trip() {
+        let brewfile = "tap \"homebrew/bundle\"\nbrew \"git\"\ncask \"firefox\"\n";
+        assert_eq!(render(&parse(brewfile).unwrap()), brewfile);
+    }
+}
@@ -0,0 +1,25 @@
+use crate::utils::format_utc_timestamp;
+use serde::Serialize;
+
+/// What was built, from the metadata `build.rs` embeds.
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    pub build_date: String,
+    pub rustc: &'static str,
+    pub features: Vec<&'static str>,
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("GIT_HASH"),
+        build_date: format_utc_timestamp(env!("BUILD_TIMESTAMP").parse().unwrap_or_default()),
+        rustc: env!("RUSTC_VERSION"),
+        features: env!("BUILD_FEATURES")
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .collect(),
+    }
+}
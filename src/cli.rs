@@ -1,4 +1,16 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use clap_complete::engine::ArgValueCandidates;
+
+use crate::commands::CARGO_RETRIES;
+use crate::completion::{CompletionKind, complete_script_names, complete_section_names};
+use crate::config::ColorChoice;
+use crate::package::{AssumeInstalled, VersionMatch};
+use crate::report::{ApplyFormat, DoctorFormat};
+use crate::template::parse_template_var;
+use crate::utils::{parse_duration, parse_size};
+use std::path::PathBuf;
+use std::time::Duration;
 
 /// Railtube: Declarative OS Package Management
 #[derive(Parser, Debug)]
@@ -6,43 +18,364 @@ use clap::{Parser, Subcommand};
 pub struct Args {
     #[command(subcommand)]
     pub command: Commands,
+    /// Host whose manifest to use when --source is a directory or URL base
+    /// (selects hosts/<host>.toml merged over base.toml). Defaults to the hostname.
+    #[arg(long, global = true)]
+    pub host: Option<String>,
+    /// File to write the log to
+    /// (default: $XDG_STATE_HOME/railtube/railtube.log).
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+    /// Rotate the log file once it exceeds this size (e.g. 512K, 10MB). 0 disables rotation.
+    #[arg(long, global = true, default_value = "10MB", value_parser = parse_size)]
+    pub log_max_size: u64,
+    /// Number of rotated log files to keep.
+    #[arg(long, global = true, default_value_t = 3)]
+    pub log_keep: usize,
+    /// Directory for temporary files such as downloaded .deb packages
+    /// (default: the system temp directory).
+    #[arg(long, global = true, env = "RAILTUBE_TMPDIR")]
+    pub temp_dir: Option<PathBuf>,
+    /// Print railtube's own messages without colors. Colors are also off when
+    /// NO_COLOR is set or the output is not a terminal.
+    #[arg(long, global = true)]
+    pub no_color: bool,
+    /// Program that runs privileged commands, e.g. `doas` or `run0`. Pass an
+    /// empty value (`--sudo-command ''`) to run them directly, e.g. as root.
+    #[arg(long, global = true, default_value = "sudo", value_name = "BIN")]
+    pub sudo_command: String,
+    /// Do not read or write the cache of manifests fetched from URLs
+    /// ($XDG_CACHE_HOME/railtube/manifests).
+    #[arg(long, global = true)]
+    pub no_cache: bool,
+    /// Download URL manifests again instead of revalidating the cached copy,
+    /// and replace it.
+    #[arg(long, global = true, conflicts_with = "no_cache")]
+    pub refresh: bool,
+    /// Set a variable for manifest templates (repeatable). Manifests ending in
+    /// .toml.j2 are always templates; with this flag, every manifest is.
+    #[arg(long, global = true, value_name = "KEY=VALUE", value_parser = parse_template_var)]
+    pub template_vars: Vec<(String, String)>,
+    /// Print more: -v also shows every command railtube runs, -vv also its
+    /// output. The log file always records everything.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+    /// Only print warnings and errors.
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Apply configurations from a TOML manifest
     Apply {
-        /// The source of the TOML configuration file (local path or URL).
+        /// The source of the TOML configuration file (local path or URL), or a
+        /// directory/URL base containing base.toml and hosts/<host>.toml.
         #[arg(short, long)]
         source: String,
+        /// Only apply the manifest if this detached OpenPGP signature of it
+        /// (local path or URL) was made by a key in `railtube keyring`.
+        #[arg(long, value_name = "PATH_OR_URL")]
+        signature: Option<String>,
         /// Perform a dry run, showing what would be installed without actually installing anything.
-        #[arg(long, default_value = "false")]
-        dry_run: bool,
+        /// Overrides `[settings] dry_run`; pass `--dry-run=false` to turn it off.
+        #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        dry_run: Option<bool>,
         /// Skip confirmation prompts for installations.
-        #[arg(long, default_value = "false")]
-        yes: bool,
+        /// Overrides `[settings] yes`; pass `--yes=false` to turn it off.
+        #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        yes: Option<bool>,
+        /// Apply independent sections, and install Snap and Flatpak packages, in
+        /// parallel (overrides `[settings] parallel`).
+        #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+        parallel: Option<bool>,
+        /// Number of parallel install workers (overrides `[settings] jobs`).
+        #[arg(long, short = 'j')]
+        jobs: Option<usize>,
+        /// Colored output of the package managers railtube runs (overrides `[settings] color`).
+        #[arg(long, value_enum)]
+        color: Option<ColorChoice>,
+        /// Confirm each section's pending installs at once instead of package by package.
+        #[arg(long, conflicts_with = "yes")]
+        confirm_sections: bool,
+        /// Print everything the apply would install or remove, grouped by section,
+        /// and confirm once before doing it. With --yes the plan is only printed.
+        #[arg(long, conflicts_with = "confirm_sections")]
+        plan: bool,
+        /// Skip the `[system] update` step even if the manifest enables it.
+        #[arg(long)]
+        no_update: bool,
+        /// Do not use the network: URL manifests are rejected, `[system] update`,
+        /// APT repository setup, [deb] and [appimage] are skipped, and
+        /// `cargo install` runs with --offline.
+        #[arg(long)]
+        offline: bool,
+        /// Install exactly the versions recorded in railtube.lock, failing if a
+        /// package is missing from it or ends up at a different version.
+        #[arg(long)]
+        frozen: bool,
+        /// Kill any command that runs longer than this many seconds (0: no limit).
+        #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+        timeout: u64,
+        /// Install the versions recorded in railtube.lock, if it exists, for the
+        /// packages it lists; other packages are installed as usual.
+        #[arg(long, conflicts_with = "frozen")]
+        locked: bool,
         /// Apply configurations to specific sections only (e.g., cargo, apt).
-        #[arg(long, value_delimiter = ',')] // Allow multiple comma-separated values
+        #[arg(long, value_delimiter = ',', add = ArgValueCandidates::new(complete_section_names))]
+        // Allow multiple comma-separated values
         only: Option<Vec<String>>,
+        /// Leave these sections out (e.g., deb,appimage), even if --only selects them.
+        #[arg(long, value_delimiter = ',', add = ArgValueCandidates::new(complete_section_names))]
+        exclude: Vec<String>,
+        /// Only install packages tagged with this group (`groups = [...]`), and
+        /// packages without groups.
+        #[arg(long)]
+        group: Option<String>,
+        /// Only install [deb] URLs matching this glob (`*`, `?`) or, without wildcards, substring.
+        #[arg(long = "match", value_name = "PATTERN")]
+        deb_match: Option<String>,
+        /// How pinned APT versions are compared with installed ones.
+        #[arg(long, value_enum, default_value_t = VersionMatch::Exact)]
+        version_match: VersionMatch,
+        /// Write the run report, resolved config, command log and timings to a timestamped subdirectory.
+        #[arg(long)]
+        output_dir: Option<String>,
+        /// Seconds to wait for a dpkg/apt lock held by another process before failing.
+        #[arg(long, default_value_t = 0)]
+        wait_for_lock: u64,
+        /// Write the commands a dry run would execute to this file as a shell script
+        /// instead of running them. Implies --dry-run.
+        #[arg(long)]
+        emit_script: Option<String>,
+        /// After installing, remove packages of the manifest's sections that it does not list.
+        /// Each removal is confirmed unless --yes is given.
+        #[arg(long)]
+        prune: bool,
+        /// After installing, check that every package the run installed is
+        /// actually installed, and fail if any is not.
+        #[arg(long)]
+        verify: bool,
+        /// Don't check whether packages are installed: `none` installs every
+        /// package, `all` skips every package.
+        #[arg(long, value_enum)]
+        assume_installed: Option<AssumeInstalled>,
+        /// How often to retry a `cargo install` that fails on a network or
+        /// registry error (not on a compile error).
+        #[arg(long, default_value_t = CARGO_RETRIES)]
+        retries: u32,
+        /// Stream per-package events as NDJSON to this file, or to an inherited
+        /// file descriptor given as a number (e.g. 3).
+        #[arg(long, value_name = "PATH|FD")]
+        events: Option<String>,
+        /// Output format. `json` prints the per-package results as a JSON array
+        /// at the end and implies --keep-going.
+        #[arg(long, value_enum, default_value_t = ApplyFormat::Text)]
+        format: ApplyFormat,
+        /// Continue with the remaining packages when an install fails, list the
+        /// failures at the end and exit with status 2.
+        #[arg(long)]
+        keep_going: bool,
+        /// Benchmark `cargo install` against `cargo binstall` for the [cargo] section
+        /// in throwaway directories instead of applying.
+        #[arg(long, hide = true)]
+        benchmark: bool,
     },
     /// Run scripts defined in the TOML manifest
     Run {
-        /// The source of the TOML configuration file (local path or URL).
+        /// The source of the TOML configuration file (local path or URL), or a
+        /// directory/URL base containing base.toml and hosts/<host>.toml.
+        #[arg(short, long)]
+        source: String,
+        /// The name of the script to run from the [scripts] section, after the
+        /// scripts it declares in `after`. Without it (and without --all), the
+        /// script is picked from a list.
+        #[arg(add = ArgValueCandidates::new(complete_script_names))]
+        script_name: Option<String>,
+        /// Run every script, in dependency order.
+        #[arg(long, conflicts_with = "script_name")]
+        all: bool,
+        /// Warn about programs the script invokes that are not in PATH.
+        #[arg(long)]
+        check: bool,
+        /// Print the script instead of running it.
+        #[arg(long)]
+        dry_run: bool,
+        /// Kill the script if it runs longer than this many seconds (0: no limit).
+        #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+        timeout: u64,
+        /// Run the script's teardown (`down`) command instead, without its dependencies.
+        #[arg(long, conflicts_with = "all")]
+        down: bool,
+        /// Run only the named script, without the scripts it declares in `after`.
+        #[arg(long, requires = "script_name", conflicts_with = "all")]
+        no_deps: bool,
+    },
+    /// List the scripts of the [scripts] section with their descriptions
+    ListScripts {
+        /// The source of the TOML configuration file (local path or URL), or a
+        /// directory/URL base containing base.toml and hosts/<host>.toml.
         #[arg(short, long)]
         source: String,
-        /// The name of the script to run from the [scripts] section.
-        script_name: String,
     },
     /// Run the doctor command to check installed packages against the TOML manifest.
     Doctor {
-        /// The source of the TOML configuration file (local path or URL).
+        /// The source of the TOML configuration file (local path or URL), or a
+        /// directory/URL base containing base.toml and hosts/<host>.toml.
+        #[arg(short, long, required_unless_present = "trend")]
+        source: Option<String>,
+        /// Append the result as a JSON line to this file (read back by --trend).
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Output format of the report.
+        #[arg(long, value_enum, default_value_t = DoctorFormat::Text, conflicts_with = "trend")]
+        format: DoctorFormat,
+        /// Install the packages reported as missing. Extra packages are never removed.
+        #[arg(long, conflicts_with_all = ["trend", "format"])]
+        fix_missing: bool,
+        /// With --fix-missing, show what would be installed without installing anything.
+        #[arg(long, requires = "fix_missing")]
+        dry_run: bool,
+        /// With --fix-missing, skip confirmation prompts.
+        #[arg(long, requires = "fix_missing")]
+        yes: bool,
+        /// Exit with status 1 if any discrepancy was found, like `git diff --exit-code`.
+        #[arg(long, conflicts_with = "trend")]
+        exit_code: bool,
+        /// Summarize drift from the records in --output instead of checking the system.
+        #[arg(long, requires = "output")]
+        trend: bool,
+        /// Only include records newer than this (e.g. 24h, 7d) in --trend.
+        #[arg(long, requires = "trend", value_parser = parse_duration)]
+        since: Option<Duration>,
+    },
+    /// Print the manifest as railtube parsed it, with includes, per-host manifests
+    /// and templates resolved, as TOML
+    #[command(alias = "dump-config")]
+    Config {
+        /// The source of the TOML configuration file (local path or URL), or a
+        /// directory/URL base containing base.toml and hosts/<host>.toml.
+        #[arg(short, long)]
+        source: String,
+    },
+    /// Check a TOML manifest for mistakes without installing anything
+    Validate {
+        /// The source of the TOML configuration file (local path or URL), or a
+        /// directory/URL base containing base.toml and hosts/<host>.toml.
         #[arg(short, long)]
         source: String,
     },
     /// Export the current environment to a TOML manifest
     Export {
+        /// The output file path (default: exported-env.toml, or Brewfile for --format brewfile).
+        #[arg(short, long)]
+        output: Option<String>,
+        /// The output format. `brewfile` exports Homebrew taps, formulae and casks only.
+        #[arg(long, value_enum, default_value_t = ExportFormat::Toml)]
+        format: ExportFormat,
+    },
+    /// Convert a manifest in another format into a railtube TOML manifest
+    Import {
+        /// The format of the input file.
+        #[arg(long, value_enum)]
+        from: ImportFormat,
+        /// The file to import (local path or URL).
+        input: String,
         /// The output file path for the generated TOML manifest.
-        #[arg(short, long, default_value = "exported-env.toml")]
+        #[arg(short, long, default_value = "railtube.toml")]
         output: String,
     },
+    /// Print version and build information
+    Version {
+        /// Print the build information as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Create a commented starter manifest showing the main sections
+    Init {
+        /// Where to write the manifest.
+        #[arg(short, long, default_value = "railtube.toml")]
+        output: PathBuf,
+        /// Overwrite the file if it already exists.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Show which package managers provide a package, and at which version,
+    /// before adding it to the manifest
+    Which {
+        /// The package to look for, e.g. `ripgrep`. Flatpak apps also match
+        /// the last part of their ID, so `gimp` finds `org.gimp.GIMP`.
+        name: String,
+    },
+    /// Show the most recent apply runs recorded in history.jsonl, next to the log file
+    Log {
+        /// How many runs to show, most recent last.
+        #[arg(long, default_value_t = 10)]
+        last: usize,
+    },
+    /// Remove the packages an apply run newly installed, as recorded in its history
+    Rollback {
+        /// The run to undo, numbered as `railtube log` shows it (default: the last run).
+        #[arg(long, value_name = "ID")]
+        run: Option<usize>,
+        /// Show what would be removed without removing anything.
+        #[arg(long)]
+        dry_run: bool,
+        /// Skip confirmation prompts.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Manage the trusted public keys in $XDG_CONFIG_HOME/railtube/keyring/
+    Keyring {
+        #[command(subcommand)]
+        action: KeyringAction,
+    },
+    /// Print a shell completion script, e.g.
+    /// `railtube completions bash > /etc/bash_completion.d/railtube`
+    Completions {
+        /// The shell to generate the script for.
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Print manifest-aware completion candidates (used by shell completions).
+    #[command(name = "__complete", hide = true)]
+    Complete {
+        /// The kind of candidates to print.
+        kind: CompletionKind,
+        /// Only print candidates starting with this prefix.
+        #[arg(default_value = "")]
+        prefix: String,
+        /// The manifest to read (defaults to $RAILTUBE_MANIFEST or ./railtube.toml).
+        #[arg(short, long)]
+        source: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    Toml,
+    /// A `brew bundle` Brewfile.
+    Brewfile,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum KeyringAction {
+    /// Trust the public key in an armored or binary key file.
+    Import {
+        /// The public key file.
+        file: PathBuf,
+    },
+    /// List the trusted keys.
+    List,
+    /// Stop trusting a key.
+    Rm {
+        /// The key's fingerprint, or its long key ID.
+        fingerprint: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ImportFormat {
+    /// A `brew bundle` Brewfile.
+    Brewfile,
 }
@@ -1,429 +1,4677 @@
-use crate::config::{Config, Section, SystemSection};
-use crate::errors::AppError;
+use crate::config::{
+    AppImageEntry, AptFrontend, AptRepo, AptRepoSource, AptSection, Config, DebEntry,
+    MANIFEST_KEYS, PackageEntry, RefreshHook, RustupSection, SECTION_NAMES, ScriptsSection,
+    Section, SystemSection,
+};
+use crate::console::{Level, Verbosity, info, paint, skip, step, success, verbosity, warning};
+use crate::errors::{AppError, CommandError};
+use crate::history::{ApplyRun, history_file, read_history};
+use crate::lock::{LOCK_FILE, LockFile, lock_file_path};
 use crate::package::*;
-use crate::utils::{confirm_installation, run_command};
+use crate::report::{
+    ApplyReport, DoctorFormat, DoctorReport, PackageAction, PackageResult, SectionDiff,
+    VersionDrift, summarize_drift,
+};
+use crate::sarif::doctor_sarif;
+use crate::utils::{
+    RETRY_BASE_DELAY, SudoKeepalive, command_exists, confirm_installation, confirm_while_locked,
+    create_temp_dir, download_file, format_utc_timestamp, glob_match, is_url, lock_prompts,
+    preview_command, preview_download, run_command, run_command_streamed, run_or_preview, sudo,
+    verify_sha256,
+};
 use rayon::prelude::*;
 use reqwest::blocking::Client;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
-use std::io;
-use tempfile::tempdir;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub fn apply_config(
-    config: &Config,
-    dry_run: bool,
-    yes: bool,
-    only: Option<Vec<String>>,
-) -> Result<(), AppError> {
-    let should_process = |section_name: &str| -> bool {
-        match &only {
-            Some(sections) => sections
-                .iter()
-                .any(|s| s.eq_ignore_ascii_case(section_name)),
-            None => true,
+/// Options controlling how `apply_config` installs packages.
+#[derive(Debug, Default, Clone)]
+pub struct ApplyOptions {
+    pub dry_run: bool,
+    pub yes: bool,
+    /// Ask once per section instead of once per package.
+    pub confirm_sections: bool,
+    /// Skip `[system] update` regardless of the manifest.
+    pub no_update: bool,
+    /// Install exactly the versions of this lock file and verify them afterwards.
+    pub frozen: Option<LockFile>,
+    /// Install the versions of this lock file for the packages it lists.
+    pub locked: Option<LockFile>,
+    /// The directory relative `[deb]` paths are resolved against; `None` for a
+    /// manifest fetched from a URL, which cannot name local files.
+    pub manifest_dir: Option<PathBuf>,
+    /// Where a run that applies the whole manifest without failures records
+    /// the installed versions; `None` records none.
+    pub lock_file: Option<PathBuf>,
+    /// Number of parallel install workers; `None` uses one per CPU.
+    pub jobs: Option<usize>,
+    /// Only install `[deb]` URLs matching this glob or substring.
+    pub deb_match: Option<String>,
+    pub only: Option<Vec<String>>,
+    /// Sections to leave out, even if `only` selects them.
+    pub exclude: Vec<String>,
+    /// Only install packages tagged with this group, and untagged ones.
+    pub group: Option<String>,
+    pub version_match: VersionMatch,
+    /// Seconds to wait for a dpkg/apt lock held by another process (0 fails immediately).
+    pub wait_for_lock: u64,
+    /// Remove installed packages the manifest does not list.
+    pub prune: bool,
+    /// Check afterwards that every package the run installed is installed.
+    pub verify: bool,
+    /// Skip everything that needs the network: `[system] update`, APT repository
+    /// setup, `[deb]` and AppImage downloads. Cargo installs get `--offline`.
+    pub offline: bool,
+    /// Answer the per-package presence checks without running them.
+    pub assume_installed: Option<AssumeInstalled>,
+    /// How often a `cargo install` failing on a network error is retried.
+    pub retries: u32,
+}
+
+impl ApplyOptions {
+    /// Whether each package install should be confirmed individually.
+    fn prompts_per_package(&self) -> bool {
+        !self.yes && !self.confirm_sections
+    }
+
+    /// Whether a package is installed, by `check` unless `--assume-installed`
+    /// already answers it.
+    fn is_installed(&self, check: impl FnOnce() -> bool) -> bool {
+        match self.assume_installed {
+            Some(AssumeInstalled::None) => false,
+            Some(AssumeInstalled::All) => true,
+            None => check(),
         }
-    };
+    }
+}
 
-    if should_process("system")
-        && let Some(sys) = &config.system
-        && sys.update
-    {
-        if dry_run {
-            println!("Would run: sudo apt update");
-        } else {
-            run_command("sudo", ["apt", "update"])?;
+/// Parsed `--only` values. Bare values select whole sections (`cargo`), while
+/// manager-qualified values (`cargo:ripgrep`) select single packages.
+#[derive(Debug, Default)]
+pub struct OnlyFilter {
+    /// `None` when `--only` was not given. A section mapped to `None` is selected
+    /// entirely, otherwise only the listed package names are.
+    sections: Option<HashMap<String, Option<HashSet<String>>>>,
+    /// `--group`: entries tagged with other groups only are left out.
+    group: Option<String>,
+    /// `--exclude`: sections left out even if `--only` selects them.
+    excluded: HashSet<String>,
+}
+
+/// Fails if one of the section `names` given to `flag` is not a known
+/// section: a typo would otherwise silently select or leave out nothing.
+fn check_section_names<'a>(
+    flag: &str,
+    names: impl IntoIterator<Item = &'a str>,
+) -> Result<(), AppError> {
+    let mut unknown: Vec<String> = Vec::new();
+    for name in names {
+        let name = name.to_ascii_lowercase();
+        if !SECTION_NAMES.contains(&name.as_str()) && !unknown.contains(&name) {
+            unknown.push(name);
         }
     }
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    Err(AppError::Other(
+        format!(
+            "{}: unknown section{} {}. Known sections: {}.",
+            flag,
+            if unknown.len() == 1 { "" } else { "s" },
+            unknown
+                .iter()
+                .map(|name| format!("'{}'", name))
+                .collect::<Vec<_>>()
+                .join(", "),
+            SECTION_NAMES.join(", ")
+        )
+        .into(),
+    ))
+}
 
-    if should_process("apt")
-        && let Some(apt) = &config.apt
-    {
-        let apt_map = match crate::package::get_installed_apt_packages_map() {
-            Ok(m) => m,
-            Err(e) => {
-                eprintln!(
-                    "Warning: Error fetching APT packages map: {}. Proceeding with installation for all APT packages.",
-                    e
-                );
-                std::collections::HashMap::new()
-            }
+/// Sections whose entries can be targeted individually with `--only <section>:<name>`.
+const PACKAGE_FILTER_SECTIONS: &[&str] = &[
+    "apt",
+    "snap",
+    "flatpak",
+    "rustup",
+    "cargo",
+    "pipx",
+    "npm",
+    "go",
+    "gem",
+    "mise",
+    "xbps",
+    "dnf",
+    "nix",
+    "brew_tap",
+    "brew",
+    "brew_cask",
+];
+
+impl OnlyFilter {
+    pub fn parse(only: &Option<Vec<String>>, config: &Config) -> Result<Self, AppError> {
+        let Some(values) = only else {
+            return Ok(OnlyFilter::default());
         };
-        for pkg_spec in &apt.list {
-            let (pkg_name, desired_version) =
-                if let Some((name, version)) = pkg_spec.split_once('=') {
-                    (name, Some(version.to_string()))
-                } else {
-                    (pkg_spec.as_str(), None)
-                };
 
-            let should_install = crate::package::determine_package_installation(
-                pkg_name,
-                &desired_version,
-                apt_map.get(pkg_name),
-                "APT",
-            );
+        check_section_names(
+            "--only",
+            values
+                .iter()
+                .map(|value| value.split_once(':').map_or(value.as_str(), |(s, _)| s)),
+        )?;
 
-            if !should_install {
-                continue;
+        let mut sections: HashMap<String, Option<HashSet<String>>> = HashMap::new();
+        for value in values {
+            match value.split_once(':') {
+                None => {
+                    sections.insert(value.to_ascii_lowercase(), None);
+                }
+                Some((section, package)) => {
+                    let section = section.to_ascii_lowercase();
+                    if !PACKAGE_FILTER_SECTIONS.contains(&section.as_str()) {
+                        return Err(AppError::Other(
+                            format!(
+                                "--only '{}': the [{}] section does not support package filters.",
+                                value, section
+                            )
+                            .into(),
+                        ));
+                    }
+                    let listed = section_entries(config, &section)
+                        .iter()
+                        .any(|entry| base_package_name(&section, entry.name()) == package);
+                    if !listed {
+                        return Err(AppError::Other(
+                            format!(
+                                "--only '{}': package '{}' is not listed in the [{}] section.",
+                                value, package, section
+                            )
+                            .into(),
+                        ));
+                    }
+                    // A bare section value selects the whole section and takes precedence.
+                    if let Some(packages) = sections
+                        .entry(section)
+                        .or_insert_with(|| Some(HashSet::new()))
+                    {
+                        packages.insert(package.to_string());
+                    }
+                }
             }
+        }
+        Ok(OnlyFilter {
+            sections: Some(sections),
+            ..Default::default()
+        })
+    }
 
-            let action_desc = format!("Installing APT package '{}'", pkg_spec);
-            crate::utils::log_or_eprint(&action_desc, "Failed to log message");
-            println!("{}", action_desc);
+    /// Leaves out the `exclude`d sections (`--exclude`), including those
+    /// `--only` selects.
+    pub fn with_exclude(mut self, exclude: &[String]) -> Result<Self, AppError> {
+        check_section_names("--exclude", exclude.iter().map(String::as_str))?;
+        self.excluded = exclude.iter().map(|s| s.to_ascii_lowercase()).collect();
+        Ok(self)
+    }
 
-            if dry_run {
-                println!("Would run: sudo apt install -y {}", pkg_spec);
-            } else {
-                if !yes
-                    && !confirm_installation(&format!("Do you want to install '{}'?", pkg_spec))?
-                {
-                    println!("Installation aborted by user.");
-                    continue;
-                }
-                run_command("sudo", ["apt", "install", "-y", pkg_spec])?;
+    /// Restricts the filter to the packages of `group`. Fails if no package of
+    /// the manifest is tagged with it, which is most likely a typo.
+    pub fn with_group(mut self, group: Option<&str>, config: &Config) -> Result<Self, AppError> {
+        if let Some(group) = group {
+            let tagged = PACKAGE_FILTER_SECTIONS.iter().any(|section| {
+                section_entries(config, section)
+                    .iter()
+                    .any(|entry| entry.groups().iter().any(|g| g == group))
+            });
+            if !tagged {
+                return Err(AppError::Other(
+                    format!("--group '{}': no package is tagged with this group.", group).into(),
+                ));
             }
+            self.group = Some(group.to_string());
         }
+        Ok(self)
     }
 
-    if should_process("snap")
-        && let Some(snap) = &config.snap
-    {
-        install_generic_packages(
-            &snap.list,
-            "Snap",
-            &["sudo", "snap", "install"],
-            is_snap_package_installed,
-            |pkg| pkg.split_whitespace().next().unwrap_or(pkg),
-            dry_run,
-            yes,
-        )?;
+    pub fn includes_section(&self, section: &str) -> bool {
+        let section = section.to_ascii_lowercase();
+        if self.excluded.contains(&section) {
+            return false;
+        }
+        match &self.sections {
+            Some(sections) => sections.contains_key(&section),
+            None => true,
+        }
     }
 
-    if should_process("flatpak")
-        && let Some(flatpak) = &config.flatpak
-    {
-        install_generic_packages(
-            &flatpak.list,
-            "Flatpak",
-            &["flatpak", "install", "-y"],
-            is_flatpak_package_installed,
-            |pkg| pkg,
-            dry_run,
-            yes,
-        )?;
+    /// Whether the filter selects every package of the manifest.
+    pub fn is_everything(&self) -> bool {
+        self.sections.is_none() && self.group.is_none() && self.excluded.is_empty()
     }
 
-    if should_process("cargo")
-        && let Some(cargo) = &config.cargo
-    {
-        let cargo_map = match crate::package::get_installed_cargo_packages_map() {
-            Ok(m) => m,
-            Err(e) => {
-                eprintln!(
-                    "Warning: Error fetching Cargo packages map: {}. Proceeding with installation for all Cargo packages.",
-                    e
-                );
-                std::collections::HashMap::new()
-            }
-        };
-        for pkg_spec in &cargo.list {
-            let (pkg_name, desired_version) =
-                if let Some((name, version)) = pkg_spec.split_once('=') {
-                    (name, Some(version.to_string()))
-                } else {
-                    (pkg_spec.as_str(), None)
-                };
-
-            let should_install = crate::package::determine_package_installation(
-                pkg_name,
-                &desired_version,
-                cargo_map.get(pkg_name),
-                "Cargo",
-            );
+    /// Whether `section` is selected as a whole rather than package by package.
+    pub fn includes_whole_section(&self, section: &str) -> bool {
+        if self.excluded.contains(section) {
+            return false;
+        }
+        match &self.sections {
+            Some(sections) => matches!(sections.get(section), Some(None)),
+            None => true,
+        }
+    }
 
-            if should_install {
-                if dry_run {
-                    println!("Would run: cargo install --locked --force {}", pkg_spec);
-                } else {
-                    run_command("cargo", ["install", "--locked", "--force", pkg_spec])?;
-                }
-            }
+    pub fn includes_package(&self, section: &str, spec: &str) -> bool {
+        if self.excluded.contains(section) {
+            return false;
+        }
+        match &self.sections {
+            Some(sections) => match sections.get(section) {
+                Some(Some(packages)) => packages.contains(base_package_name(section, spec)),
+                Some(None) => true,
+                None => false,
+            },
+            None => true,
         }
     }
 
-    if should_process("deb")
-        && let Some(deb) = &config.deb
-    {
-        let temp_dir = tempdir()?;
-        let client = Client::new();
-        for url in &deb.urls {
-            let filename = url
-                .split('/')
-                .next_back()
-                .filter(|s| !s.is_empty())
-                .unwrap_or("package.deb");
-            let temp_path = temp_dir.path().join(filename);
-
-            println!("Downloading {} to {}", url, temp_path.display());
-            let mut response = client.get(url).send()?;
-            if !response.status().is_success() {
-                return Err(AppError::Other(
-                    format!("Failed to download {}: {}", url, response.status()).into(),
-                ));
-            }
-            let mut file = std::fs::File::create(&temp_path)?;
-            response.copy_to(&mut file)?;
+    /// Returns the entries of `list` selected by this filter.
+    pub fn select(&self, section: &str, list: &[PackageEntry]) -> Vec<PackageEntry> {
+        list.iter()
+            .filter(|entry| self.includes_package(section, entry.name()))
+            .filter(|entry| {
+                self.group
+                    .as_ref()
+                    .is_none_or(|group| entry.in_group(group))
+            })
+            .cloned()
+            .collect()
+    }
+}
 
-            println!("Installing {}...", temp_path.display());
-            if dry_run {
-                println!("Would run: sudo dpkg -i {}", temp_path.display());
-                println!("Would run: sudo apt --fix-broken install -y");
-            } else {
-                if !yes
-                    && !confirm_installation(&format!(
-                        "Do you want to install deb package '{}'?",
-                        url
-                    ))?
-                {
-                    println!("Installation aborted by user.");
-                    continue;
-                }
-                let dpkg_args = vec![OsStr::new("dpkg"), OsStr::new("-i"), temp_path.as_os_str()];
-                run_command("sudo", dpkg_args)?;
-                run_command("sudo", ["apt", "--fix-broken", "install", "-y"])?;
-            }
+fn section_entries(config: &Config, section: &str) -> Vec<PackageEntry> {
+    let section = match section {
+        "apt" => {
+            return config
+                .apt
+                .as_ref()
+                .map(AptSection::entries)
+                .unwrap_or_default();
         }
-    }
+        "rustup" => {
+            return config
+                .rustup
+                .as_ref()
+                .map(RustupSection::entries)
+                .unwrap_or_default();
+        }
+        "cargo" => return config.cargo.as_ref().map(cargo_entries).unwrap_or_default(),
+        "snap" => &config.snap,
+        "flatpak" => &config.flatpak,
+        "pipx" => &config.pipx,
+        "npm" => &config.npm,
+        "go" => &config.go,
+        "gem" => &config.gem,
+        "mise" => &config.mise,
+        "xbps" => &config.xbps,
+        "dnf" => &config.dnf,
+        "nix" => &config.nix,
+        "brew_tap" => &config.brew_tap,
+        "brew" => &config.brew,
+        "brew_cask" => &config.brew_cask,
+        _ => &None,
+    };
+    section.as_ref().map_or_else(Vec::new, |s| s.list.clone())
+}
 
-    Ok(())
+/// Sections that must be applied one after another, in order. Different
+/// chains use different package managers and are applied in parallel.
+/// `[system] update` refreshes the index APT, XBPS and dnf install from, and
+/// [deb] installs go through dpkg like APT's; rustup provides the toolchain
+/// cargo builds with, and taps provide formulae and casks.
+const SECTION_CHAINS: &[&[&str]] = &[
+    &["system", "apt", "xbps", "dnf", "deb"],
+    &["snap"],
+    &["flatpak"],
+    &["rustup", "cargo"],
+    &["pipx"],
+    &["npm"],
+    &["go"],
+    &["gem"],
+    &["mise"],
+    &["nix"],
+    &["brew_tap", "brew", "brew_cask"],
+    &["appimage"],
+];
+
+/// What `apply_section` needs besides the section name.
+struct SectionContext<'a> {
+    config: &'a Config,
+    options: &'a ApplyOptions,
+    report: &'a ApplyReport,
+    filter: &'a OnlyFilter,
+    apt_frontend: &'a str,
 }
 
-fn install_generic_packages(
-    list: &[String],
-    manager_name: &str,
-    base_cmd: &[&str],
-    check_installed: impl Fn(&str) -> bool + Sync + Send,
-    extract_pkg_name: impl Fn(&str) -> &str + Sync + Send,
-    dry_run: bool,
-    yes: bool,
+/// Applies the sections of `config` that `options` select and, unless it is a
+/// dry run, records the run and its outcome in the history file.
+pub fn apply_config(
+    config: &Config,
+    options: &ApplyOptions,
+    report: &ApplyReport,
 ) -> Result<(), AppError> {
-    let packages_to_install: Vec<&str> = list
+    let filter = OnlyFilter::parse(&options.only, config)?
+        .with_exclude(&options.exclude)?
+        .with_group(options.group.as_deref(), config)?;
+    let present = config.section_names();
+    let sections: Vec<&str> = SECTION_NAMES
         .iter()
-        .map(|pkg| pkg.as_str())
-        .filter(|pkg| {
-            let pkg_name = extract_pkg_name(pkg);
-            if !check_installed(pkg_name) {
-                true
-            } else {
-                println!(
-                    "{} package '{}' already installed, skipping.",
-                    manager_name, pkg_name
-                );
-                false
-            }
-        })
+        .copied()
+        .filter(|section| *section != "scripts")
+        .filter(|section| present.contains(section) && filter.includes_section(section))
         .collect();
 
-    if packages_to_install.is_empty() {
-        return Ok(());
+    for manager in [&Apt as &dyn PackageManager, &Cargo, &Dnf] {
+        if sections.contains(&manager.section()) {
+            dedup_specs(manager, section_entries(config, manager.section()))?;
+        }
     }
 
-    println!(
-        "Will attempt to install the following {} packages: {:?}",
-        manager_name, packages_to_install
-    );
-
-    if dry_run {
-        for pkg in &packages_to_install {
-            println!("Would run: {} {}", base_cmd.join(" "), pkg);
-        }
-    } else if !yes {
-        for pkg in &packages_to_install {
-            if confirm_installation(&format!(
-                "Do you want to install {} package '{}'?",
-                manager_name, pkg
-            ))? {
-                let args = base_cmd[1..].iter().copied().chain(std::iter::once(*pkg));
-                run_command(base_cmd[0], args)?;
-            } else {
-                println!("Installation aborted by user.");
-            }
-        }
-    } else {
-        packages_to_install.par_iter().try_for_each(|pkg| {
-            let args = base_cmd
-                .iter()
-                .skip(1)
-                .copied()
-                .chain(std::iter::once(*pkg));
-            run_command(base_cmd[0], args).map_err(AppError::Command)
-        })?;
+    // `[system]` installs no packages, so it has nothing to lock.
+    let unlocked: Vec<String> = sections
+        .iter()
+        .filter(|&&section| {
+            section != "system" && !LOCKED_MANAGERS.iter().any(|m| m.section() == section)
+        })
+        .map(|section| format!("[{}]", section))
+        .collect();
+    if options.frozen.is_some() && !unlocked.is_empty() {
+        return Err(AppError::Other(
+            format!(
+                "--frozen cannot pin {}: {} only records versions for {}. Leave them out with --exclude or apply without --frozen.",
+                unlocked.join(", "),
+                LOCK_FILE,
+                LOCKED_MANAGERS
+                    .map(|manager| format!("[{}]", manager.section()))
+                    .join(", ")
+            )
+            .into(),
+        ));
     }
 
-    Ok(())
+    if options.dry_run {
+        return apply_sections(config, options, report, &filter, &sections);
+    }
+    let before = installed_snapshot(&sections);
+    let outcome = match apply_sections(config, options, report, &filter, &sections) {
+        Ok(()) if options.verify => verify_installs(report),
+        outcome => outcome,
+    };
+    let new_installs = new_installs(report, &before, &installed_snapshot(&sections));
+    let history = history_file();
+    if let Err(e) = ApplyRun::new(&sections, report, new_installs, &outcome).append(&history) {
+        warning!(
+            "Warning: Failed to record the run in {}: {}",
+            history.display(),
+            e
+        );
+    }
+    outcome
 }
 
-pub fn run_scripts(
+/// Applies `sections`, in parallel where `SECTION_CHAINS` allow it, then
+/// prunes, runs the refresh hooks and updates or checks the lock file.
+fn apply_sections(
     config: &Config,
-    script_name: &str,
-    is_remote_source: bool,
+    options: &ApplyOptions,
+    report: &ApplyReport,
+    filter: &OnlyFilter,
+    sections: &[&str],
 ) -> Result<(), AppError> {
-    if let Some(scripts) = &config.scripts {
-        if let Some(command_to_run) = scripts.commands.get(script_name) {
-            println!("Running script '{}': {}", script_name, command_to_run);
-
-            if is_remote_source {
-                println!("WARNING: Executing script from a remote source.");
-                if !confirm_installation("Do you want to proceed?")? {
-                    println!("Script execution aborted by user.");
-                    return Ok(());
-                }
-            }
+    let dry_run = options.dry_run;
+    let should_process = |section_name: &str| -> bool { filter.includes_section(section_name) };
 
-            run_command("sh", ["-c", command_to_run])?;
-        } else {
-            eprintln!("Script '{}' not found in [scripts] section.", script_name);
-            return Err(AppError::Other(
-                format!("Script '{}' not found.", script_name).into(),
-            ));
-        }
-    } else {
-        eprintln!("No [scripts] section found in the TOML configuration.");
-        return Err(AppError::Other("No [scripts] section found.".into()));
+    if config.section_names().iter().all(|&name| name == "scripts") {
+        info!("Nothing to apply: the manifest does not declare any sections.");
     }
-    Ok(())
-}
 
-pub fn export_current_environment() -> Result<Config, AppError> {
-    let config = Config {
-        system: Some(SystemSection { update: false }),
-        apt: Some(Section {
-            list: get_installed_apt_packages()?,
-        }),
-        snap: Some(Section {
-            list: get_installed_snap_packages()?,
-        }),
-        flatpak: Some(Section {
-            list: get_installed_flatpak_packages()?,
-        }),
-        cargo: Some(Section {
-            list: get_installed_cargo_packages()?,
-        }),
-        deb: None,
-        scripts: None,
+    let apt_frontend = if should_process("apt") || should_process("system") {
+        resolve_apt_frontend(config)
+    } else {
+        AptFrontend::Apt.program()
     };
 
-    Ok(config)
-}
+    // Only sudo caches credentials in a way `sudo -v` can refresh.
+    let _sudo = (!dry_run && sudo() == "sudo" && needs_sudo(config, filter))
+        .then(SudoKeepalive::start)
+        .flatten();
 
-pub fn check_package_discrepancies<W: std::io::Write>(
-    writer: &mut W,
-    package_manager_name: &str,
-    toml_packages: &HashSet<&str>,
-    installed_packages: &HashSet<&str>,
-) -> std::io::Result<()> {
-    let missing: Vec<_> = toml_packages
-        .difference(installed_packages)
-        .copied()
-        .collect();
-    if !missing.is_empty() {
-        writeln!(
-            writer,
-            "\n{} packages listed in TOML but not installed:",
-            package_manager_name
-        )?;
-        for pkg in missing {
-            writeln!(writer, "- {}", pkg)?;
+    let context = SectionContext {
+        config,
+        options,
+        report,
+        filter,
+        apt_frontend,
+    };
+    // Dry runs stay sequential so previews, and `--emit-script`, follow the
+    // manifest order; `parallel = false` asks for one thing at a time.
+    if dry_run || options.jobs == Some(1) {
+        for &section in sections {
+            apply_section(section, &context)?;
+        }
+    } else {
+        let mut errors: Vec<AppError> = std::thread::scope(|scope| {
+            let chains: Vec<_> = SECTION_CHAINS
+                .iter()
+                .map(|chain| {
+                    chain
+                        .iter()
+                        .copied()
+                        .filter(|section| sections.contains(section))
+                        .collect::<Vec<_>>()
+                })
+                .filter(|chain| !chain.is_empty())
+                .map(|chain| {
+                    let context = &context;
+                    scope.spawn(move || {
+                        chain
+                            .into_iter()
+                            .try_for_each(|section| apply_section(section, context))
+                    })
+                })
+                .collect();
+            chains
+                .into_iter()
+                .filter_map(|chain| match chain.join() {
+                    Ok(result) => result.err(),
+                    Err(panic) => std::panic::resume_unwind(panic),
+                })
+                .collect()
+        });
+        if errors.len() > 1 {
+            let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+            return Err(AppError::Other(
+                format!("{} sections failed:\n{}", errors.len(), messages.join("\n")).into(),
+            ));
+        }
+        if let Some(error) = errors.pop() {
+            return Err(error);
         }
     }
 
-    let extra: Vec<_> = installed_packages
-        .difference(toml_packages)
-        .copied()
-        .collect();
-    if !extra.is_empty() {
-        writeln!(
-            writer,
-            "\n{} packages installed but not listed in TOML:",
-            package_manager_name
-        )?;
-        for pkg in extra {
-            writeln!(writer, "- {}", pkg)?;
+    if options.prune {
+        prune_extra_packages(config, filter, options, report)?;
+    }
+
+    if let Some(post) = &config.post {
+        run_refresh_hooks(&post.refresh, report, dry_run)?;
+    }
+
+    if !dry_run {
+        match (&options.frozen, &options.lock_file) {
+            (Some(lock), _) => verify_frozen_installs(config, filter, lock)?,
+            // A partial lock would drop the versions of what the run left out.
+            (None, Some(path))
+                if filter.is_everything()
+                    && options.deb_match.is_none()
+                    && !report.has_failures() =>
+            {
+                if let Err(e) = resolve_lock_file(config).save(path) {
+                    warning!("Warning: Failed to write {}: {}", path.display(), e);
+                }
+            }
+            (None, _) => {}
         }
     }
 
     Ok(())
 }
 
-fn check_section_discrepancies<F, P>(
-    section: &Option<Section>,
-    manager_name: &str,
-    get_installed: F,
-    parse_pkg: P,
-) where
-    F: FnOnce() -> Result<Vec<String>, AppError>,
-    P: Fn(&String) -> &str,
-{
-    if let Some(section) = section {
-        let toml_packages = section.list.iter().map(parse_pkg).collect::<HashSet<_>>();
-        match get_installed() {
-            Ok(installed_packages) => {
-                let installed_packages_set = installed_packages
-                    .iter()
-                    .map(String::as_str)
-                    .collect::<HashSet<_>>();
-                let mut stdout = io::stdout().lock();
-                if let Err(e) = check_package_discrepancies(
-                    &mut stdout,
-                    manager_name,
-                    &toml_packages,
-                    &installed_packages_set,
-                ) {
-                    eprintln!("Warning: Failed to write to stdout: {}", e);
+/// Installs one section of the manifest between its pre and post hooks.
+/// `apply_config` may apply several sections at once, from different threads.
+fn apply_section(section: &str, context: &SectionContext) -> Result<(), AppError> {
+    let SectionContext {
+        config,
+        options,
+        report,
+        filter,
+        apt_frontend,
+    } = *context;
+    let ApplyOptions {
+        dry_run,
+        version_match,
+        wait_for_lock,
+        no_update,
+        ..
+    } = *options;
+
+    let hooks = config.hooks.as_ref();
+    let pre_hook = |section| run_hook("pre", section, hooks.and_then(|h| h.pre(section)), dry_run);
+    let post_hook = |section| {
+        run_hook(
+            "post",
+            section,
+            hooks.and_then(|h| h.post(section)),
+            dry_run,
+        )
+    };
+
+    match section {
+        "system" => {
+            let Some(sys) = &config.system else {
+                return Ok(());
+            };
+            let update = SystemUpdate::detect();
+            if sys.update {
+                if no_update {
+                    skip!("Skipping {} (--no-update).", update.args().join(" "));
+                } else if options.offline {
+                    skip!("Skipping {} (--offline).", update.args().join(" "));
+                } else {
+                    if !dry_run && update == SystemUpdate::Apt {
+                        wait_for_dpkg_lock(wait_for_lock)?;
+                    }
+                    match update {
+                        SystemUpdate::Apt => {
+                            run_or_preview(dry_run, sudo(), [apt_frontend, "update"])?
+                        }
+                        SystemUpdate::Xbps | SystemUpdate::Dnf => {
+                            run_or_preview(dry_run, sudo(), update.args())?
+                        }
+                    }
                 }
             }
-            Err(e) => {
-                eprintln!(
-                    "Warning: Failed to list installed {} packages: {}",
-                    manager_name, e
+            let apt_commands = sys.apt_commands();
+            if !apt_commands.is_empty() && update != SystemUpdate::Apt {
+                warning!(
+                    "Warning: [system] upgrade, dist_upgrade and autoremove need APT; skipping them."
                 );
+            } else {
+                // Always plain apt: the other frontends name these commands differently.
+                for command in apt_commands {
+                    if options.offline && command != "autoremove" {
+                        skip!("Skipping apt {} (--offline).", command);
+                        continue;
+                    }
+                    if !dry_run {
+                        wait_for_dpkg_lock(wait_for_lock)?;
+                    }
+                    run_or_preview(dry_run, sudo(), ["apt", command, "-y"])?;
+                }
             }
         }
-    }
-}
 
-pub fn doctor_command(config: &Config, source: &str) -> Result<(), AppError> {
-    println!("Running railtube doctor for: {}", source);
+        "apt" => {
+            if let Some(apt) = &config.apt {
+                pre_hook("apt")?;
+                let list = dedup_specs(&Apt, filter.select("apt", &apt.entries()))?;
+                let list = frozen_list(&Apt, list, options)?;
+                if list.is_empty() {
+                    skip!("Nothing to do in [apt].");
+                } else if !command_exists(apt_frontend) {
+                    warning!(
+                        "Warning: apt is not installed or not on PATH; skipping the [apt] section."
+                    );
+                } else {
+                    let repos: Vec<&AptRepo> = apt
+                        .repos
+                        .iter()
+                        .filter(|repo| {
+                            repo.packages.is_empty()
+                                || repo
+                                    .packages
+                                    .iter()
+                                    .any(|pkg| filter.includes_package("apt", pkg))
+                        })
+                        .collect();
+                    if options.offline && !repos.is_empty() {
+                        warning!("Warning: Skipping the setup of [[apt.repos]] (--offline).");
+                    } else {
+                        setup_apt_repos(&repos, apt_frontend, dry_run, wait_for_lock)?;
+                    }
 
-    check_section_discrepancies(&config.apt, "APT", get_installed_apt_packages, |pkg_spec| {
-        pkg_spec.split('=').next().unwrap_or(pkg_spec.as_str())
-    });
+                    let pending = pending_installs(&Apt, &list, version_match, options, report);
+                    // Per-package `release` wins over `[apt] default_release`.
+                    let release_of = |pkg_spec: &str| {
+                        list.iter()
+                            .find(|entry| entry.name() == pkg_spec)
+                            .and_then(PackageEntry::release)
+                            .or(apt.default_release.as_deref())
+                    };
+                    if dry_run {
+                        let releases: Vec<&str> =
+                            pending.iter().filter_map(|pkg| release_of(pkg)).collect();
+                        check_apt_releases(&releases);
+                    }
 
-    check_section_discrepancies(&config.snap, "Snap", get_installed_snap_packages, |pkg| {
-        pkg.split_whitespace().next().unwrap_or(pkg.as_str())
-    });
+                    // APT resolves dependencies once per invocation, so everything is
+                    // installed in a single `apt install`, or one per target release.
+                    // Without --yes the whole batch is confirmed at once.
+                    let pending =
+                        if dry_run || options.yes || confirm_batch("apt", &pending, report)? {
+                            pending
+                        } else {
+                            Vec::new()
+                        };
+                    if !dry_run {
+                        report.expect_installs(pending.len());
+                    }
+                    let mut batches: Vec<(Option<&str>, Vec<&str>)> = Vec::new();
+                    for pkg_spec in pending {
+                        let release = release_of(pkg_spec);
+                        match batches.iter_mut().find(|(r, _)| *r == release) {
+                            Some((_, batch)) => batch.push(pkg_spec),
+                            None => batches.push((release, vec![pkg_spec])),
+                        }
+                    }
+                    for (release, batch) in batches {
+                        for pkg_spec in &batch {
+                            let action_desc = format!("Installing APT package '{}'", pkg_spec);
+                            crate::utils::log_or_eprint(&action_desc, "Failed to log message");
+                            step!("{}", action_desc);
+                        }
 
-    check_section_discrepancies(
-        &config.flatpak,
-        "Flatpak",
-        get_installed_flatpak_packages,
-        |pkg| pkg.as_str(),
-    );
+                        let mut apt_args = vec![apt_frontend, "install", "-y"];
+                        if let Some(release) = release {
+                            apt_args.extend(["-t", release]);
+                        }
+                        apt_args.extend(&batch);
+                        if dry_run {
+                            preview_command(sudo(), &apt_args);
+                            for pkg_spec in batch {
+                                report.record("apt", pkg_spec, PackageAction::WouldInstall);
+                            }
+                        } else {
+                            wait_for_dpkg_lock(wait_for_lock)?;
+                            report.time_batch_install("apt", &batch, || {
+                                run_command(sudo(), &apt_args)
+                            })?;
+                        }
+                    }
+                }
+                post_hook("apt")?;
+            }
+        }
 
-    check_section_discrepancies(
-        &config.cargo,
-        "Cargo",
-        get_installed_cargo_packages,
-        |pkg| pkg.split('=').next().unwrap_or(pkg.as_str()),
-    );
+        "snap" => {
+            if let Some(snap) = &config.snap {
+                pre_hook("snap")?;
+                install_generic_packages(
+                    &filter.select("snap", &snap.list),
+                    "Snap",
+                    &[sudo(), "snap", "install"],
+                    is_snap_package_installed,
+                    snap_install_args,
+                    options,
+                    report,
+                )?;
+                post_hook("snap")?;
+            }
+        }
 
-    Ok(())
-}
+        "flatpak" => {
+            if let Some(flatpak) = &config.flatpak {
+                pre_hook("flatpak")?;
+                let list = filter.select("flatpak", &flatpak.list);
+                install_generic_packages(
+                    &list,
+                    "Flatpak",
+                    &["flatpak", "install", "-y"],
+                    is_flatpak_package_installed,
+                    |pkg| vec![parse_flatpak_spec(pkg).install_ref()],
+                    options,
+                    report,
+                )?;
+                apply_flatpak_overrides(&list, dry_run)?;
+                post_hook("flatpak")?;
+            }
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::collections::HashSet;
+        "rustup" => {
+            if let Some(rustup) = &config.rustup {
+                pre_hook("rustup")?;
+                install_rustup_section(rustup, filter, options, report)?;
+                post_hook("rustup")?;
+            }
+        }
+
+        "cargo" => {
+            if let Some(cargo) = &config.cargo {
+                pre_hook("cargo")?;
+                let list = dedup_specs(&Cargo, filter.select("cargo", &cargo_entries(cargo)))?;
+                let list = frozen_list(&Cargo, list, options)?;
+                if list.is_empty() {
+                    skip!("Nothing to do in [cargo].");
+                } else {
+                    let pending =
+                        pending_installs(&Cargo, &list, VersionMatch::Exact, options, report);
+                    let cargo_args =
+                        |pkg_spec: &str| match list.iter().find(|e| e.name() == pkg_spec) {
+                            Some(entry) => cargo_install_args(entry, options.offline),
+                            None => cargo_install_args(
+                                &PackageEntry::from(pkg_spec.to_string()),
+                                options.offline,
+                            ),
+                        };
+
+                    if dry_run {
+                        for pkg_spec in pending {
+                            preview_command("cargo", cargo_args(pkg_spec));
+                            report.record("cargo", pkg_spec, PackageAction::WouldInstall);
+                        }
+                    } else if confirm_section("cargo", &pending, options, report)? {
+                        report.expect_installs(pending.len());
+                        for pkg_spec in pending {
+                            report.time_install("cargo", pkg_spec, || {
+                                cargo_install_with_retry(&cargo_args(pkg_spec), options.retries)
+                            })?;
+                        }
+                    }
+                }
+                post_hook("cargo")?;
+            }
+        }
+
+        "pipx" => {
+            if let Some(pipx) = &config.pipx {
+                pre_hook("pipx")?;
+                let list = frozen_list(&Pipx, filter.select("pipx", &pipx.list), options)?;
+                if list.is_empty() {
+                    skip!("Nothing to do in [pipx].");
+                } else if !command_exists("pipx") {
+                    warning!(
+                        "Warning: pipx is not installed or not on PATH; skipping the [pipx] section."
+                    );
+                } else {
+                    let pending =
+                        pending_installs(&Pipx, &list, VersionMatch::Exact, options, report);
+                    // `--force` reinstalls a package whose pinned version differs.
+                    let pipx_args = |pkg_spec| ["install", "--force", pkg_spec];
+
+                    if dry_run {
+                        for pkg_spec in pending {
+                            preview_command("pipx", pipx_args(pkg_spec));
+                            report.record("pipx", pkg_spec, PackageAction::WouldInstall);
+                        }
+                    } else if confirm_section("pipx", &pending, options, report)? {
+                        report.expect_installs(pending.len());
+                        for pkg_spec in pending {
+                            report.time_install("pipx", pkg_spec, || {
+                                run_command("pipx", pipx_args(pkg_spec))
+                            })?;
+                        }
+                    }
+                }
+                post_hook("pipx")?;
+            }
+        }
+
+        "npm" => {
+            if let Some(npm) = &config.npm {
+                pre_hook("npm")?;
+                let list = frozen_list(&Npm, filter.select("npm", &npm.list), options)?;
+                if list.is_empty() {
+                    skip!("Nothing to do in [npm].");
+                } else if !command_exists("npm") {
+                    warning!(
+                        "Warning: npm is not installed or not on PATH; skipping the [npm] section."
+                    );
+                } else {
+                    let pending =
+                        pending_installs(&Npm, &list, VersionMatch::Exact, options, report);
+                    let npm_args = |pkg_spec| ["install", "-g", pkg_spec];
+
+                    if dry_run {
+                        for pkg_spec in pending {
+                            preview_command("npm", npm_args(pkg_spec));
+                            report.record("npm", pkg_spec, PackageAction::WouldInstall);
+                        }
+                    } else if confirm_section("npm", &pending, options, report)? {
+                        report.expect_installs(pending.len());
+                        for pkg_spec in pending {
+                            report.time_install("npm", pkg_spec, || {
+                                run_command("npm", npm_args(pkg_spec))
+                            })?;
+                        }
+                    }
+                }
+                post_hook("npm")?;
+            }
+        }
+
+        "go" => {
+            if let Some(go) = &config.go {
+                pre_hook("go")?;
+                let list = frozen_list(&Go, filter.select("go", &go.list), options)?;
+                if list.is_empty() {
+                    skip!("Nothing to do in [go].");
+                } else if !command_exists("go") {
+                    warning!(
+                        "Warning: go is not installed or not on PATH; skipping the [go] section."
+                    );
+                } else {
+                    let pending =
+                        pending_installs(&Go, &list, VersionMatch::Exact, options, report);
+
+                    if dry_run {
+                        for pkg_spec in pending {
+                            preview_command("go", ["install", &go_install_target(pkg_spec)]);
+                            report.record("go", pkg_spec, PackageAction::WouldInstall);
+                        }
+                    } else if confirm_section("go", &pending, options, report)? {
+                        report.expect_installs(pending.len());
+                        for pkg_spec in pending {
+                            report.time_install("go", pkg_spec, || {
+                                run_command("go", ["install", &go_install_target(pkg_spec)])
+                            })?;
+                        }
+                    }
+                }
+                post_hook("go")?;
+            }
+        }
+
+        "gem" => {
+            if let Some(gem) = &config.gem {
+                pre_hook("gem")?;
+                let list = filter.select("gem", &gem.list);
+                if !list.is_empty() && !command_exists("gem") {
+                    warning!(
+                        "Warning: gem is not installed or not on PATH; skipping the [gem] section."
+                    );
+                } else {
+                    let installed =
+                        list_installed("Gem", &list, options, get_installed_gem_packages_map);
+                    install_generic_packages(
+                        &list,
+                        "Gem",
+                        &["gem"],
+                        |pkg| is_gem_package_installed(&installed, pkg),
+                        |pkg| {
+                            gem_install_args(pkg)
+                                .into_iter()
+                                .map(String::from)
+                                .collect()
+                        },
+                        options,
+                        report,
+                    )?;
+                }
+                post_hook("gem")?;
+            }
+        }
+
+        "mise" => {
+            if let Some(mise) = &config.mise {
+                pre_hook("mise")?;
+                let list = filter.select("mise", &mise.list);
+                if !list.is_empty() && !command_exists("mise") {
+                    warning!(
+                        "Warning: mise is not installed or not on PATH; skipping the [mise] section."
+                    );
+                } else {
+                    let installed =
+                        list_installed("mise", &list, options, get_installed_mise_tools_map);
+                    // `mise use -g` installs the tool and makes it the global default.
+                    install_generic_packages(
+                        &list,
+                        "mise",
+                        &["mise", "use", "-g"],
+                        |pkg| is_mise_tool_installed(&installed, pkg),
+                        |pkg| vec![pkg.to_string()],
+                        options,
+                        report,
+                    )?;
+                }
+                post_hook("mise")?;
+            }
+        }
+
+        "xbps" => {
+            if let Some(xbps) = &config.xbps {
+                pre_hook("xbps")?;
+                let list = frozen_list(&Xbps, filter.select("xbps", &xbps.list), options)?;
+                if list.is_empty() {
+                    skip!("Nothing to do in [xbps].");
+                } else {
+                    let pending =
+                        pending_installs(&Xbps, &list, VersionMatch::Exact, options, report);
+                    let xbps_args = |pkg_spec| ["xbps-install", "-y", pkg_spec];
+
+                    if dry_run {
+                        for pkg_spec in pending {
+                            preview_command(sudo(), xbps_args(pkg_spec));
+                            report.record("xbps", pkg_spec, PackageAction::WouldInstall);
+                        }
+                    } else if confirm_section("xbps", &pending, options, report)? {
+                        report.expect_installs(pending.len());
+                        for pkg_spec in pending {
+                            report.time_install("xbps", pkg_spec, || {
+                                run_command(sudo(), xbps_args(pkg_spec))
+                            })?;
+                        }
+                    }
+                }
+                post_hook("xbps")?;
+            }
+        }
+
+        "dnf" => {
+            if let Some(dnf) = &config.dnf {
+                pre_hook("dnf")?;
+                let list = dedup_specs(&Dnf, filter.select("dnf", &dnf.list))?;
+                let list = frozen_list(&Dnf, list, options)?;
+                if list.is_empty() {
+                    skip!("Nothing to do in [dnf].");
+                } else if !command_exists("dnf") {
+                    warning!(
+                        "Warning: dnf is not installed or not on PATH; skipping the [dnf] section."
+                    );
+                } else {
+                    // Pins name the version without the release, which `rpm`
+                    // reports after it.
+                    let pending =
+                        pending_installs(&Dnf, &list, VersionMatch::Upstream, options, report);
+
+                    // Like APT, dnf resolves dependencies once per invocation, so the
+                    // section is installed with a single `dnf install`.
+                    let pending =
+                        if dry_run || options.yes || confirm_batch("dnf", &pending, report)? {
+                            pending
+                        } else {
+                            Vec::new()
+                        };
+                    if !pending.is_empty() {
+                        let mut dnf_args: Vec<String> =
+                            ["dnf", "install", "-y"].map(String::from).to_vec();
+                        dnf_args.extend(pending.iter().map(|pkg| dnf_install_arg(pkg)));
+                        if dry_run {
+                            preview_command(sudo(), &dnf_args);
+                            for pkg in pending {
+                                report.record("dnf", pkg, PackageAction::WouldInstall);
+                            }
+                        } else {
+                            report.expect_installs(pending.len());
+                            report.time_batch_install("dnf", &pending, || {
+                                run_command(sudo(), &dnf_args)
+                            })?;
+                        }
+                    }
+                }
+                post_hook("dnf")?;
+            }
+        }
+
+        "nix" => {
+            if let Some(nix) = &config.nix {
+                pre_hook("nix")?;
+                let list = filter.select("nix", &nix.list);
+                if !list.is_empty() && !command_exists("nix") {
+                    warning!(
+                        "Warning: nix is not installed or not on PATH; skipping the [nix] section."
+                    );
+                } else {
+                    let installed =
+                        list_installed("nix", &list, options, get_installed_nix_packages);
+                    install_generic_packages(
+                        &list,
+                        "nix",
+                        &["nix", "profile", "install"],
+                        |pkg| installed.iter().any(|name| name == nix_package_name(pkg)),
+                        |pkg| vec![nix_installable(pkg)],
+                        options,
+                        report,
+                    )?;
+                }
+                post_hook("nix")?;
+            }
+        }
+
+        "brew_tap" | "brew" | "brew_cask" => {
+            let brew = match section {
+                "brew_tap" => &config.brew_tap,
+                "brew" => &config.brew,
+                _ => &config.brew_cask,
+            };
+            if let Some(brew) = brew {
+                pre_hook(section)?;
+                install_brew_section(
+                    section,
+                    &filter.select(section, &brew.list),
+                    options,
+                    report,
+                )?;
+                post_hook(section)?;
+            }
+        }
+
+        "deb" => {
+            if let Some(deb) = &config.deb {
+                pre_hook("deb")?;
+                let entries: Vec<&DebEntry> = deb
+                    .urls
+                    .iter()
+                    .filter(|entry| match &options.deb_match {
+                        Some(pattern) if !glob_match(pattern, entry.url()) => {
+                            skip!(
+                                "Skipping {}: does not match --match '{}'.",
+                                entry.url(),
+                                pattern
+                            );
+                            false
+                        }
+                        _ => true,
+                    })
+                    .collect();
+                // Local files are checked before anything is installed.
+                let mut entries: Vec<(&DebEntry, Option<PathBuf>)> = entries
+                    .into_iter()
+                    .map(|entry| {
+                        let local = (!is_url(entry.url()))
+                            .then(|| local_deb_path(entry.url(), options.manifest_dir.as_deref()))
+                            .transpose()?;
+                        Ok((entry, local))
+                    })
+                    .collect::<Result<_, AppError>>()?;
+                if options.offline && entries.iter().any(|(_, local)| local.is_none()) {
+                    warning!(
+                        "Warning: Skipping the [deb] section's URLs, which download their packages (--offline)."
+                    );
+                    entries.retain(|(_, local)| local.is_some());
+                }
+                let pending: Vec<&str> = entries.iter().map(|(entry, _)| entry.url()).collect();
+                if entries.is_empty() {
+                    skip!("Nothing to do in [deb].");
+                } else if dry_run || confirm_section("deb", &pending, options, report)? {
+                    let temp_dir = create_temp_dir()?;
+                    let client = Client::new();
+                    if !dry_run && !options.prompts_per_package() {
+                        report.expect_installs(entries.len());
+                    }
+                    for (entry, local) in entries {
+                        let url = entry.url();
+                        let filename = url
+                            .split('/')
+                            .next_back()
+                            .filter(|s| !s.is_empty())
+                            .unwrap_or("package.deb");
+                        let download = local.is_none();
+                        let deb_path = local.unwrap_or_else(|| temp_dir.path().join(filename));
+                        let dpkg_args =
+                            [OsStr::new("dpkg"), OsStr::new("-i"), deb_path.as_os_str()];
+                        let fix_broken_args = ["apt", "--fix-broken", "install", "-y"];
+
+                        if dry_run {
+                            if download {
+                                preview_download(url, &deb_path.to_string_lossy(), false);
+                            }
+                            if let Some(sha256) = entry.sha256() {
+                                info!("Expected SHA-256 of {}: {}", filename, sha256);
+                            }
+                            preview_command(sudo(), dpkg_args);
+                            preview_command(sudo(), fix_broken_args);
+                            report.record("deb", url, PackageAction::WouldInstall);
+                        } else {
+                            if options.prompts_per_package()
+                                && !confirm_installation(&format!(
+                                    "Do you want to install deb package '{}'?",
+                                    url
+                                ))?
+                            {
+                                info!("Installation aborted by user.");
+                                report.record("deb", url, PackageAction::Declined);
+                                continue;
+                            }
+                            if options.prompts_per_package() {
+                                report.expect_installs(1);
+                            }
+                            if download {
+                                step!("Downloading {} to {}", url, deb_path.display());
+                                download_file(&client, url, &deb_path)?;
+                            }
+                            if let Some(sha256) = entry.sha256() {
+                                verify_sha256(&deb_path, sha256)?;
+                                success!("Verified SHA-256 of {}.", filename);
+                            }
+
+                            step!("Installing {}...", deb_path.display());
+                            wait_for_dpkg_lock(wait_for_lock)?;
+                            report.time_install("deb", url, || {
+                                run_command(sudo(), dpkg_args)?;
+                                run_command(sudo(), fix_broken_args)
+                            })?;
+                        }
+                    }
+                }
+                post_hook("deb")?;
+            }
+        }
+
+        "appimage" => {
+            if let Some(appimages) = &config.appimage {
+                pre_hook("appimage")?;
+                if options.offline {
+                    warning!(
+                        "Warning: Skipping the [appimage] section, which downloads its AppImages (--offline)."
+                    );
+                } else {
+                    install_appimages(appimages, options, report)?;
+                }
+                post_hook("appimage")?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// The absolute path of a local `[deb]` entry, which must name an existing
+/// `.deb` file. Relative paths are resolved against `manifest_dir`.
+fn local_deb_path(path: &str, manifest_dir: Option<&Path>) -> Result<PathBuf, AppError> {
+    if !path.ends_with(".deb") {
+        return Err(AppError::Other(
+            format!(
+                "[deb] entry '{}' is neither an http(s) URL nor a path to a .deb file.",
+                path
+            )
+            .into(),
+        ));
+    }
+    let Some(manifest_dir) = manifest_dir else {
+        return Err(AppError::Other(
+            format!(
+                "[deb] entry '{}' is a local path, but the manifest was fetched from a URL.",
+                path
+            )
+            .into(),
+        ));
+    };
+    std::fs::canonicalize(manifest_dir.join(path))
+        .ok()
+        .filter(|path| path.is_file())
+        .ok_or_else(|| AppError::Other(format!("[deb] file '{}' does not exist.", path).into()))
+}
+
+/// The `[cargo]` entries with a table's `version` folded into the spec
+/// (`name=version`), so that both forms are pinned, locked and checked alike.
+fn cargo_entries(cargo: &Section) -> Vec<PackageEntry> {
+    cargo
+        .list
+        .iter()
+        .cloned()
+        .map(|mut entry| {
+            let pinned = entry
+                .version()
+                .filter(|_| Cargo.split_spec(entry.name()).1.is_none())
+                .map(|version| Cargo.pin_spec(entry.name(), version));
+            if let Some(spec) = pinned {
+                entry.set_name(spec);
+            }
+            entry
+        })
+        .collect()
+}
+
+/// `cargo install` arguments for a `[cargo]` entry: the crate name, with
+/// `--version` for a pinned spec, its features and its git source.
+fn cargo_install_args(entry: &PackageEntry, offline: bool) -> Vec<String> {
+    let (name, version) = Cargo.split_spec(entry.name());
+    let mut args: Vec<String> = ["install", "--locked", "--force"]
+        .map(String::from)
+        .to_vec();
+    if offline {
+        args.push("--offline".to_string());
+    }
+    if let Some(version) = version {
+        args.extend(["--version".to_string(), version.to_string()]);
+    }
+    if !entry.features().is_empty() {
+        args.extend(["--features".to_string(), entry.features().join(",")]);
+    }
+    if let Some((git, branch)) = entry.git() {
+        args.extend(["--git".to_string(), git.to_string()]);
+        if let Some(branch) = branch {
+            args.extend(["--branch".to_string(), branch.to_string()]);
+        }
+    }
+    args.push(name.to_string());
+    args
+}
+
+/// Default of `apply --retries`.
+pub const CARGO_RETRIES: u32 = 2;
+
+/// Runs `cargo install` with `args`, retrying up to `retries` times with
+/// exponential backoff when it fails on a network or registry error. Other
+/// failures, such as compile errors, are returned right away.
+fn cargo_install_with_retry(args: &[String], retries: u32) -> Result<(), CommandError> {
+    let mut attempt = 0;
+    loop {
+        match run_command("cargo", args) {
+            Err(e) if attempt < retries && is_cargo_network_error(&e.stderr) => {
+                let delay = RETRY_BASE_DELAY * 2u32.pow(attempt);
+                attempt += 1;
+                let message = format!(
+                    "cargo {} failed with a network error; retrying in {}s (attempt {}/{})",
+                    args.join(" "),
+                    delay.as_secs(),
+                    attempt,
+                    retries
+                );
+                crate::utils::log_or_eprint(&message, "Failed to log retry");
+                warning!("Warning: {}", message);
+                std::thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether `cargo` stderr reports a failure to reach the network or a
+/// registry, rather than a build failure.
+fn is_cargo_network_error(stderr: &str) -> bool {
+    const NETWORK_ERRORS: [&str; 10] = [
+        "spurious network error",
+        "failed to download",
+        "failed to fetch",
+        "failed to update registry",
+        "unable to update registry",
+        "failed to query replaced source registry",
+        "could not resolve host",
+        "couldn't connect to server",
+        "connection reset",
+        "timeout was reached",
+    ];
+    let stderr = stderr.to_lowercase();
+    !stderr.contains("could not compile")
+        && NETWORK_ERRORS.iter().any(|error| stderr.contains(error))
+}
+
+/// Works out what `apply_config` would install and remove with a dry run whose
+/// progress output is hidden, prints it grouped by section and asks once
+/// whether to go ahead. With `yes` the plan is only printed.
+pub fn confirm_plan(config: &Config, options: &ApplyOptions) -> Result<bool, AppError> {
+    let plan = ApplyReport::new();
+    let plan_options = ApplyOptions {
+        dry_run: true,
+        yes: true,
+        confirm_sections: false,
+        ..options.clone()
+    };
+    crate::console::set_quiet(true);
+    let outcome = apply_config(config, &plan_options, &plan);
+    crate::console::set_quiet(false);
+    outcome?;
+
+    let results = plan.results();
+    let mut sections: Vec<(&str, Vec<String>)> = Vec::new();
+    let (mut installs, mut removals) = (0, 0);
+    for result in &results {
+        let line = match result.action {
+            PackageAction::WouldInstall => {
+                installs += 1;
+                format!("install {}", result.name)
+            }
+            PackageAction::WouldRemove => {
+                removals += 1;
+                format!("remove {}", result.name)
+            }
+            _ => continue,
+        };
+        match sections.iter_mut().find(|(s, _)| *s == result.section) {
+            Some((_, lines)) => lines.push(line),
+            None => sections.push((&result.section, vec![line])),
+        }
+    }
+    if sections.is_empty() {
+        info!("Plan: nothing to install or remove.");
+        return Ok(true);
+    }
+
+    info!("Plan: {} install(s), {} removal(s).", installs, removals);
+    for (section, lines) in sections {
+        info!("  [{}]", section);
+        for line in lines {
+            info!("    {}", line);
+        }
+    }
+    Ok(options.yes || confirm_installation("Proceed?")?)
+}
+
+/// Installs the missing toolchains of `[rustup]`, then adds its missing
+/// components to the default toolchain.
+fn install_rustup_section(
+    rustup: &RustupSection,
+    filter: &OnlyFilter,
+    options: &ApplyOptions,
+    report: &ApplyReport,
+) -> Result<(), AppError> {
+    let selected = |names: &[String]| -> Vec<String> {
+        names
+            .iter()
+            .filter(|name| filter.includes_package("rustup", name))
+            .cloned()
+            .collect()
+    };
+    let toolchains = selected(&rustup.toolchains);
+    let components = selected(&rustup.components);
+    if toolchains.is_empty() && components.is_empty() {
+        skip!("Nothing to do in [rustup].");
+        return Ok(());
+    }
+    if !command_exists("rustup") {
+        warning!("Warning: rustup is not installed or not on PATH; skipping the [rustup] section.");
+        return Ok(());
+    }
+
+    let (installed_toolchains, installed_components) = match options.assume_installed {
+        Some(_) => (Vec::new(), Vec::new()),
+        None => (
+            get_installed_rustup_toolchains()?,
+            get_installed_rustup_components()?,
+        ),
+    };
+    let mut pending: Vec<(&str, [&str; 3])> = Vec::new();
+    let lists = [
+        ("toolchain", &toolchains, &installed_toolchains, "install"),
+        ("component", &components, &installed_components, "add"),
+    ];
+    for (kind, names, installed, verb) in lists {
+        for name in names {
+            if options.is_installed(|| installed.contains(name)) {
+                skip!("rustup {} '{}' already installed, skipping.", kind, name);
+                report.record("rustup", name, PackageAction::Skipped);
+            } else {
+                pending.push((name, [kind, verb, name]));
+            }
+        }
+    }
+
+    if options.dry_run {
+        for (name, args) in pending {
+            preview_command("rustup", args);
+            report.record("rustup", name, PackageAction::WouldInstall);
+        }
+        return Ok(());
+    }
+    let names: Vec<&str> = pending.iter().map(|(name, _)| *name).collect();
+    if confirm_section("rustup", &names, options, report)? {
+        report.expect_installs(pending.len());
+        for (name, args) in pending {
+            report.time_install("rustup", name, || run_command("rustup", args))?;
+        }
+    }
+    Ok(())
+}
+
+/// Installs a Homebrew section: taps with `brew tap`, formulae with
+/// `brew install` and casks with `brew install --cask`, one at a time since
+/// brew holds a lock while installing.
+fn install_brew_section(
+    section: &str,
+    list: &[PackageEntry],
+    options: &ApplyOptions,
+    report: &ApplyReport,
+) -> Result<(), AppError> {
+    if list.is_empty() {
+        skip!("Nothing to do in [{}].", section);
+        return Ok(());
+    }
+    if !command_exists("brew") {
+        warning!(
+            "Warning: brew is not installed or not on PATH; skipping the [{}] section.",
+            section
+        );
+        return Ok(());
+    }
+
+    let taps = if section == "brew_tap" && options.assume_installed.is_none() {
+        get_installed_brew_taps().unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    let is_installed = |name: &str| match section {
+        "brew_tap" => taps.iter().any(|tap| tap.eq_ignore_ascii_case(name)),
+        "brew_cask" => is_brew_cask_installed(name),
+        _ => is_brew_package_installed(name),
+    };
+    let brew_args = |name| match section {
+        "brew_tap" => vec!["tap", name],
+        "brew_cask" => vec!["install", "--cask", name],
+        _ => vec!["install", name],
+    };
+
+    let pending: Vec<&str> = list
+        .iter()
+        .map(PackageEntry::name)
+        .filter(|name| {
+            if !options.is_installed(|| is_installed(name)) {
+                return true;
+            }
+            skip!("[{}] '{}' already installed, skipping.", section, name);
+            report.record(section, name, PackageAction::Skipped);
+            false
+        })
+        .collect();
+
+    if options.dry_run {
+        for name in pending {
+            preview_command("brew", brew_args(name));
+            report.record(section, name, PackageAction::WouldInstall);
+        }
+    } else if confirm_section(section, &pending, options, report)? {
+        report.expect_installs(pending.len());
+        for name in pending {
+            report.time_install(section, name, || run_command("brew", brew_args(name)))?;
+        }
+    }
+    Ok(())
+}
+
+type ListInstalled = fn() -> Result<Vec<String>, AppError>;
+
+/// How the installed packages of a section are listed, by the names
+/// `base_package_name` gives, and how one is removed. `apply --verify`,
+/// `apply --prune` and `rollback` all go through it.
+struct SectionPackages {
+    list_installed: ListInstalled,
+    /// `None` for sections railtube installs into but cannot uninstall from.
+    remove_cmd: Option<Vec<&'static str>>,
+}
+
+/// `None` for sections whose installs cannot be listed by name: `[deb]`,
+/// whose entries are URLs, and `[appimage]`.
+fn section_packages(section: &str) -> Option<SectionPackages> {
+    let (list_installed, remove_cmd): (ListInstalled, _) = match section {
+        "apt" => (
+            get_installed_apt_packages,
+            Some(vec![sudo(), "apt", "remove", "-y"]),
+        ),
+        "snap" => (
+            get_installed_snap_packages,
+            Some(vec![sudo(), "snap", "remove"]),
+        ),
+        "flatpak" => (
+            get_installed_flatpak_packages,
+            Some(vec!["flatpak", "uninstall", "-y"]),
+        ),
+        // Toolchains and components are removed with different commands.
+        "rustup" => (get_installed_rustup_packages, None),
+        "cargo" => (
+            get_installed_cargo_packages,
+            Some(vec!["cargo", "uninstall"]),
+        ),
+        "pipx" => (get_installed_pipx_packages, Some(vec!["pipx", "uninstall"])),
+        "npm" => (
+            get_installed_npm_packages,
+            Some(vec!["npm", "uninstall", "-g"]),
+        ),
+        // `go install` has no uninstall; its binaries are deleted by hand.
+        "go" => (get_installed_go_packages, None),
+        "gem" => (
+            get_installed_gem_packages,
+            Some(vec!["gem", "uninstall", "-a", "-x"]),
+        ),
+        "mise" => (
+            get_installed_mise_tools,
+            Some(vec!["mise", "uninstall", "--all"]),
+        ),
+        "xbps" => (
+            get_installed_xbps_packages,
+            Some(vec![sudo(), "xbps-remove", "-y"]),
+        ),
+        "dnf" => (
+            get_installed_dnf_packages,
+            Some(vec![sudo(), "dnf", "remove", "-y"]),
+        ),
+        "nix" => (
+            get_installed_nix_packages,
+            Some(vec!["nix", "profile", "remove"]),
+        ),
+        "brew_tap" => (get_installed_brew_taps, Some(vec!["brew", "untap"])),
+        "brew" => (get_installed_brew_packages, Some(vec!["brew", "uninstall"])),
+        "brew_cask" => (
+            get_installed_brew_casks,
+            Some(vec!["brew", "uninstall", "--cask"]),
+        ),
+        _ => return None,
+    };
+    Some(SectionPackages {
+        list_installed,
+        remove_cmd,
+    })
+}
+
+/// The installed packages of each of `sections` that can be listed. Sections
+/// whose packages fail to list, e.g. because the package manager is missing,
+/// are left out.
+fn installed_snapshot(sections: &[&str]) -> HashMap<String, HashSet<String>> {
+    sections
+        .iter()
+        .filter_map(|&section| {
+            let installed = (section_packages(section)?.list_installed)().ok()?;
+            Some((section.to_string(), installed.into_iter().collect()))
+        })
+        .collect()
+}
+
+/// The packages `report` installed that were missing from the `before`
+/// snapshot and are in the `after` one, per section. Packages that were
+/// only upgraded or pinned to another version were there before. Sections
+/// that cannot be listed at all, `[deb]` and `[appimage]`, record every
+/// install.
+fn new_installs(
+    report: &ApplyReport,
+    before: &HashMap<String, HashSet<String>>,
+    after: &HashMap<String, HashSet<String>>,
+) -> BTreeMap<String, Vec<String>> {
+    let mut new_installs: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for result in report.results() {
+        if result.action != PackageAction::Installed {
+            continue;
+        }
+        let name = base_package_name(&result.section, &result.name);
+        let is_new = match (before.get(&result.section), after.get(&result.section)) {
+            (Some(before), Some(after)) => !before.contains(name) && after.contains(name),
+            _ => section_packages(&result.section).is_none(),
+        };
+        if is_new {
+            new_installs
+                .entry(result.section.clone())
+                .or_default()
+                .push(name.to_string());
+        }
+    }
+    new_installs
+}
+
+type InstalledCheck = Box<dyn Fn(&str) -> bool>;
+
+/// How `apply --verify` checks that a spec of `section` is installed: against
+/// a fresh listing of the section. `None` for sections whose installs cannot
+/// be listed.
+fn installed_check(section: &str) -> Option<InstalledCheck> {
+    // A [cargo] entry may name one of the crate's binaries.
+    if section == "cargo" {
+        let crates = get_installed_cargo_crates().ok()?;
+        return Some(Box::new(move |spec| {
+            find_cargo_crate(&crates, Cargo.split_spec(spec).0).is_some()
+        }));
+    }
+    let installed: HashSet<String> = (section_packages(section)?.list_installed)()
+        .ok()?
+        .into_iter()
+        .collect();
+    let section = section.to_string();
+    Some(Box::new(move |spec| {
+        installed.contains(base_package_name(&section, spec))
+    }))
+}
+
+/// Checks that every package `report` recorded as installed is installed now,
+/// catching installs that exited successfully without installing anything.
+fn verify_installs(report: &ApplyReport) -> Result<(), AppError> {
+    let mut installed: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for result in report.results() {
+        if result.action == PackageAction::Installed {
+            installed
+                .entry(result.section)
+                .or_default()
+                .push(result.name);
+        }
+    }
+
+    let mut checked = 0;
+    let mut missing = Vec::new();
+    for (section, specs) in &installed {
+        let Some(is_installed) = installed_check(section) else {
+            warning!(
+                "Warning: Cannot verify the {} package(s) installed in [{}].",
+                specs.len(),
+                section
+            );
+            continue;
+        };
+        checked += specs.len();
+        missing.extend(
+            specs
+                .iter()
+                .filter(|spec| !is_installed(spec))
+                .map(|spec| format!("[{}] {}", section, spec)),
+        );
+    }
+
+    if missing.is_empty() {
+        success!("Verified {} installed package(s).", checked);
+        return Ok(());
+    }
+    Err(AppError::Other(
+        format!(
+            "Verification failed: {} of {} installed package(s) are not installed:\n- {}",
+            missing.len(),
+            checked,
+            missing.join("\n- ")
+        )
+        .into(),
+    ))
+}
+
+/// Removes the packages run `run_id` of the history (the last run if `None`)
+/// newly installed, newest section first. Packages removed since are skipped.
+pub fn rollback_command(run_id: Option<usize>, dry_run: bool, yes: bool) -> Result<(), AppError> {
+    let history = history_file();
+    let runs = read_history(&history)?;
+    if runs.is_empty() {
+        return Err(AppError::Other(
+            format!("No apply runs recorded in {}", history.display()).into(),
+        ));
+    }
+    let run_id = run_id.unwrap_or(runs.len());
+    let Some(run) = run_id.checked_sub(1).and_then(|index| runs.get(index)) else {
+        return Err(AppError::Other(
+            format!(
+                "No run #{} in {}: runs are numbered 1 to {} (see `railtube log`)",
+                run_id,
+                history.display(),
+                runs.len()
+            )
+            .into(),
+        ));
+    };
+    if run.new_installs.is_empty() {
+        info!(
+            "Run #{} did not newly install any package; nothing to roll back.",
+            run_id
+        );
+        return Ok(());
+    }
+
+    info!(
+        "Rolling back run #{} of {}",
+        run_id,
+        format_utc_timestamp(run.timestamp)
+    );
+    for &section in SECTION_NAMES.iter().rev() {
+        let Some(packages) = run.new_installs.get(section) else {
+            continue;
+        };
+        let Some(SectionPackages {
+            list_installed,
+            remove_cmd: Some(remove_cmd),
+        }) = section_packages(section)
+        else {
+            warning!(
+                "Warning: Cannot roll back [{}]; remove {} by hand.",
+                section,
+                packages.join(", ")
+            );
+            continue;
+        };
+        let installed = match list_installed() {
+            Ok(installed) => installed,
+            Err(e) => {
+                warning!(
+                    "Warning: Not rolling back [{}]: failed to list installed packages: {}",
+                    section,
+                    e
+                );
+                continue;
+            }
+        };
+        for pkg in packages {
+            if !installed.contains(pkg) {
+                skip!("[{}] '{}' is no longer installed, skipping.", section, pkg);
+                continue;
+            }
+            let args = remove_cmd[1..].iter().copied().chain([pkg.as_str()]);
+            if dry_run {
+                preview_command(remove_cmd[0], args);
+            } else if yes
+                || confirm_installation(&format!("Remove {} package '{}'?", section, pkg))?
+            {
+                run_command(remove_cmd[0], args)?;
+                success!("Removed [{}] {}", section, pkg);
+            } else {
+                info!("Keeping {} package '{}'.", section, pkg);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Crates `--prune` never removes: railtube itself and the installer it uses.
+const PROTECTED_CARGO_CRATES: &[&str] = &["railtube", "cargo-binstall"];
+
+/// Removes, per section, the installed packages that the manifest does not
+/// list. Sections absent from the manifest, or narrowed to single packages
+/// with `--only`, are left alone.
+fn prune_extra_packages(
+    config: &Config,
+    filter: &OnlyFilter,
+    options: &ApplyOptions,
+    report: &ApplyReport,
+) -> Result<(), AppError> {
+    // apt and snap prune only what the user installed, not the base system.
+    let sections: [(&str, Option<ListInstalled>); 5] = [
+        ("apt", Some(get_prunable_apt_packages)),
+        ("snap", Some(get_prunable_snap_packages)),
+        ("flatpak", None),
+        ("cargo", None),
+        ("pipx", None),
+    ];
+
+    let present = config.section_names();
+    for (section, list_prunable) in sections {
+        if !present.contains(&section) || !filter.includes_whole_section(section) {
+            continue;
+        }
+        let Some(SectionPackages {
+            list_installed,
+            remove_cmd: Some(remove_cmd),
+        }) = section_packages(section)
+        else {
+            continue;
+        };
+        let list = match &config.cargo {
+            Some(cargo) if section == "cargo" => resolve_cargo_binaries(&cargo.list),
+            _ => section_entries(config, section),
+        };
+        let installed = match list_prunable.unwrap_or(list_installed)() {
+            Ok(installed) => installed,
+            Err(e) => {
+                warning!(
+                    "Warning: Not pruning [{}]: failed to list installed packages: {}",
+                    section,
+                    e
+                );
+                continue;
+            }
+        };
+        let listed: HashSet<&str> = list
+            .iter()
+            .map(|entry| base_package_name(section, entry.name()))
+            .collect();
+        let installed: HashSet<&str> = installed
+            .iter()
+            .map(String::as_str)
+            .filter(|pkg| section != "cargo" || !PROTECTED_CARGO_CRATES.contains(pkg))
+            .collect();
+        let extra = SectionDiff::new(&listed, &installed).extra;
+        if extra.is_empty() {
+            continue;
+        }
+
+        if options.dry_run {
+            info!("\nWould remove from [{}] (not in the manifest):", section);
+        } else {
+            info!("\nRemoving from [{}] (not in the manifest):", section);
+        }
+        for pkg in &extra {
+            let args = remove_cmd[1..].iter().copied().chain([pkg.as_str()]);
+            if options.dry_run {
+                preview_command(remove_cmd[0], args);
+                report.record(section, pkg, PackageAction::WouldRemove);
+            } else if options.yes
+                || confirm_installation(&format!("Remove {} package '{}'?", section, pkg))?
+            {
+                if section == "apt" {
+                    wait_for_dpkg_lock(options.wait_for_lock)?;
+                }
+                run_command(remove_cmd[0], args)?;
+                report.record(section, pkg, PackageAction::Removed);
+            } else {
+                info!("Keeping {} package '{}'.", section, pkg);
+                report.record(section, pkg, PackageAction::Declined);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Downloads each AppImage that is not installed yet to `~/.local/bin/<name>`,
+/// marks it executable and, if requested, adds a desktop entry for it.
+fn install_appimages(
+    appimages: &[AppImageEntry],
+    options: &ApplyOptions,
+    report: &ApplyReport,
+) -> Result<(), AppError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (Some(bin_dir), Some(applications_dir)) = (
+        dirs::executable_dir()
+            .or_else(|| dirs::home_dir().map(|home| home.join(".local").join("bin"))),
+        dirs::data_dir().map(|dir| dir.join("applications")),
+    ) else {
+        return Err(AppError::Other(
+            "Could not determine the home directory to install AppImages to.".into(),
+        ));
+    };
+
+    let mut pending = Vec::new();
+    for appimage in appimages {
+        let target = bin_dir.join(&appimage.name);
+        if options.is_installed(|| target.exists()) {
+            skip!(
+                "AppImage '{}' is already installed at {}, skipping.",
+                appimage.name,
+                target.display()
+            );
+            report.record("appimage", &appimage.name, PackageAction::Skipped);
+        } else {
+            pending.push((appimage, target));
+        }
+    }
+    if pending.is_empty() {
+        skip!("Nothing to do in [appimage].");
+        return Ok(());
+    }
+
+    let names: Vec<&str> = pending.iter().map(|(a, _)| a.name.as_str()).collect();
+    if !options.dry_run && !confirm_section("appimage", &names, options, report)? {
+        return Ok(());
+    }
+    let client = Client::new();
+    for (appimage, target) in pending {
+        let desktop_file = applications_dir.join(format!("{}.desktop", appimage.name));
+        if options.dry_run {
+            preview_download(&appimage.url, &target.to_string_lossy(), false);
+            if appimage.wants_desktop_entry() {
+                step!("Would create desktop entry {}", desktop_file.display());
+            }
+            report.record("appimage", &appimage.name, PackageAction::WouldInstall);
+            continue;
+        }
+        if options.prompts_per_package()
+            && !confirm_installation(&format!(
+                "Do you want to install AppImage '{}'?",
+                appimage.name
+            ))?
+        {
+            info!("Installation aborted by user.");
+            report.record("appimage", &appimage.name, PackageAction::Declined);
+            continue;
+        }
+
+        step!("Downloading {} to {}", appimage.url, target.display());
+        std::fs::create_dir_all(&bin_dir)?;
+        // Download next to the target so a failed download never leaves a
+        // truncated AppImage that would later count as installed.
+        let partial = target.with_file_name(format!(".{}.part", appimage.name));
+        download_file(&client, &appimage.url, &partial)?;
+        std::fs::set_permissions(&partial, std::fs::Permissions::from_mode(0o755))?;
+        std::fs::rename(&partial, &target)?;
+        if appimage.wants_desktop_entry() {
+            std::fs::create_dir_all(&applications_dir)?;
+            std::fs::write(
+                &desktop_file,
+                appimage_desktop_entry(&appimage.name, &target),
+            )?;
+            success!("Created desktop entry {}", desktop_file.display());
+        }
+        report.record("appimage", &appimage.name, PackageAction::Installed);
+    }
+    Ok(())
+}
+
+fn appimage_desktop_entry(name: &str, target: &Path) -> String {
+    format!(
+        "[Desktop Entry]\nType=Application\nName={}\nExec=\"{}\" %U\nTerminal=false\nCategories=Utility;\n",
+        name,
+        target.display()
+    )
+}
+
+/// Sections whose installs run through `sudo`.
+const PRIVILEGED_SECTIONS: &[&str] = &["system", "apt", "snap", "xbps", "dnf", "deb"];
+
+/// Whether the selected sections of `config` will run anything through `sudo`.
+fn needs_sudo(config: &Config, filter: &OnlyFilter) -> bool {
+    config
+        .section_names()
+        .into_iter()
+        .filter(|&name| {
+            name != "system" || config.system.as_ref().is_some_and(SystemSection::is_active)
+        })
+        .any(|name| PRIVILEGED_SECTIONS.contains(&name) && filter.includes_section(name))
+}
+
+/// Warns about target releases that no configured APT repository provides.
+fn check_apt_releases(releases: &[&str]) {
+    if releases.is_empty() {
+        return;
+    }
+    let known = match get_apt_release_names() {
+        Ok(known) => known,
+        Err(e) => {
+            warning!("Warning: Could not check APT target releases: {}", e);
+            return;
+        }
+    };
+    let mut unknown: Vec<&str> = releases
+        .iter()
+        .copied()
+        .filter(|release| !known.iter().any(|name| name == release))
+        .collect();
+    unknown.sort();
+    unknown.dedup();
+    for release in unknown {
+        warning!(
+            "Warning: APT release '{}' is not provided by any configured repository (known: {}).",
+            release,
+            known.join(", ")
+        );
+    }
+}
+
+/// The package managers whose sections `railtube.lock` records versions for.
+const LOCKED_MANAGERS: [&dyn PackageManager; 7] = [&Apt, &Cargo, &Pipx, &Npm, &Go, &Dnf, &Xbps];
+
+/// Records the installed versions of the manifest's version-capable packages.
+/// Packages that are not installed are left out.
+fn resolve_lock_file(config: &Config) -> LockFile {
+    let mut lock = LockFile::default();
+    for manager in LOCKED_MANAGERS {
+        let section = manager.section();
+        let list = section_entries(config, section);
+        if list.is_empty() {
+            continue;
+        }
+        let installed = match manager.installed_versions() {
+            Ok(installed) => installed,
+            Err(e) => {
+                warning!(
+                    "Warning: Failed to list installed {} package versions for {}: {}",
+                    manager.display_name(),
+                    LOCK_FILE,
+                    e
+                );
+                continue;
+            }
+        };
+        let versions = list
+            .iter()
+            .filter_map(|entry| {
+                let (name, _) = manager.split_spec(entry.name());
+                Some((name.to_string(), installed.get(name)?.clone()))
+            })
+            .collect();
+        lock.sections.insert(section.to_string(), versions);
+    }
+    lock
+}
+
+/// Drops repeated identical specs from `list`, keeping the first, and fails if
+/// a package is listed with different version pins (`nodejs`, `nodejs=18.0.0`).
+fn dedup_specs(
+    manager: &dyn PackageManager,
+    list: Vec<PackageEntry>,
+) -> Result<Vec<PackageEntry>, AppError> {
+    let mut specs: Vec<(&str, Vec<&str>)> = Vec::new();
+    for entry in &list {
+        let name = manager.split_spec(entry.name()).0;
+        match specs.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, pins)) if pins.contains(&entry.name()) => {}
+            Some((_, pins)) => pins.push(entry.name()),
+            None => specs.push((name, vec![entry.name()])),
+        }
+    }
+    let conflicts: Vec<String> = specs
+        .iter()
+        .filter(|(_, pins)| pins.len() > 1)
+        .map(|(name, pins)| {
+            format!(
+                "[{}] lists '{}' with conflicting versions: '{}'",
+                manager.section(),
+                name,
+                pins.join("', '")
+            )
+        })
+        .collect();
+    if !conflicts.is_empty() {
+        return Err(AppError::Other(conflicts.join("\n").into()));
+    }
+
+    let mut seen = HashSet::new();
+    Ok(list
+        .into_iter()
+        .filter(|entry| seen.insert(entry.name().to_string()))
+        .collect())
+}
+
+/// With `--frozen`, replaces `list` by the versions recorded in the lock file;
+/// with `--locked`, pins the packages the lock file lists.
+fn frozen_list(
+    manager: &dyn PackageManager,
+    list: Vec<PackageEntry>,
+    options: &ApplyOptions,
+) -> Result<Vec<PackageEntry>, AppError> {
+    match (&options.frozen, &options.locked) {
+        (Some(lock), _) => lock.pin(manager, &list),
+        (None, Some(lock)) => Ok(lock.pin_locked(manager, &list)),
+        (None, None) => Ok(list),
+    }
+}
+
+/// Checks after a frozen apply that every selected package ended up at its
+/// locked version.
+fn verify_frozen_installs(
+    config: &Config,
+    filter: &OnlyFilter,
+    lock: &LockFile,
+) -> Result<(), AppError> {
+    let mut mismatches = Vec::new();
+    for manager in LOCKED_MANAGERS {
+        let section = manager.section();
+        let list = filter.select(section, &section_entries(config, section));
+        if !filter.includes_section(section) || list.is_empty() {
+            continue;
+        }
+        mismatches.extend(lock.verify(manager, &list, &manager.installed_versions()?));
+    }
+
+    if mismatches.is_empty() {
+        success!("All packages match {}.", LOCK_FILE);
+        Ok(())
+    } else {
+        Err(AppError::Other(
+            format!(
+                "Installed versions do not match {}:\n- {}",
+                LOCK_FILE,
+                mismatches.join("\n- ")
+            )
+            .into(),
+        ))
+    }
+}
+
+/// Decides which entries of `list` need installing or reinstalling from a
+/// single installed-versions probe of `manager`; only the installs themselves
+/// spawn further processes. Entries already satisfied are recorded as skipped.
+fn pending_installs<'a>(
+    manager: &dyn PackageManager,
+    list: &'a [PackageEntry],
+    version_match: VersionMatch,
+    options: &ApplyOptions,
+    report: &ApplyReport,
+) -> Vec<&'a str> {
+    // `--assume-installed` makes the installed-versions probe unnecessary.
+    if let Some(assume) = options.assume_installed {
+        return list
+            .iter()
+            .map(PackageEntry::name)
+            .filter(|pkg_spec| {
+                if assume == AssumeInstalled::None {
+                    return true;
+                }
+                skip!(
+                    "{} package '{}' assumed installed, skipping.",
+                    manager.display_name(),
+                    pkg_spec
+                );
+                report.record(manager.section(), pkg_spec, PackageAction::Skipped);
+                false
+            })
+            .collect();
+    }
+    let installed = manager.installed_versions().unwrap_or_else(|e| {
+        warning!(
+            "Warning: Error fetching {name} packages map: {e}. Proceeding with installation for all {name} packages.",
+            name = manager.display_name(),
+        );
+        HashMap::new()
+    });
+    list.iter()
+        .map(PackageEntry::name)
+        .filter(|pkg_spec| {
+            let (pkg_name, desired_version) = manager.split_spec(pkg_spec);
+            let should_install = crate::package::determine_package_installation(
+                pkg_name,
+                &desired_version.map(str::to_string),
+                installed.get(pkg_name),
+                manager.display_name(),
+                version_match,
+            );
+            if !should_install {
+                report.record(manager.section(), pkg_spec, PackageAction::Skipped);
+            }
+            should_install
+        })
+        .collect()
+}
+
+/// Lists the installed packages of a section once, for the presence checks of
+/// all of `list`. An empty `list` or `--assume-installed` skips the listing; a
+/// failed one is warned about and treated as empty, so everything is installed.
+fn list_installed<T: Default>(
+    manager_name: &str,
+    list: &[PackageEntry],
+    options: &ApplyOptions,
+    get_installed: impl FnOnce() -> Result<T, AppError>,
+) -> T {
+    if list.is_empty() || options.assume_installed.is_some() {
+        return T::default();
+    }
+    get_installed().unwrap_or_else(|e| {
+        warning!(
+            "Warning: Error listing installed {name} packages: {e}. Proceeding with installation for all {name} packages.",
+            name = manager_name,
+        );
+        T::default()
+    })
+}
+
+/// Runs a `[hooks]` command with `sh -c`, or previews it in a dry run.
+fn run_hook(stage: &str, section: &str, hook: Option<&str>, dry_run: bool) -> Result<(), AppError> {
+    let Some(hook) = hook else {
+        return Ok(());
+    };
+    let result = if dry_run {
+        preview_command("sh", ["-c", hook]);
+        Ok(())
+    } else {
+        step!("Running {}_{} hook.", stage, section);
+        run_command_streamed("sh", ["-c", hook])
+    };
+    result
+        .map_err(|e| AppError::Other(format!("[hooks] {}_{} failed: {}", stage, section, e).into()))
+}
+
+/// With `--confirm-sections`, lists the pending installs of `section` and asks
+/// once for all of them, recording every package as declined on refusal.
+/// Returns whether the section's installs should go ahead.
+fn confirm_section(
+    section: &str,
+    pending: &[&str],
+    options: &ApplyOptions,
+    report: &ApplyReport,
+) -> Result<bool, AppError> {
+    if !options.confirm_sections || options.yes {
+        return Ok(true);
+    }
+    confirm_batch(section, pending, report)
+}
+
+/// Lists a section's pending installs and asks once whether to install them
+/// all, recording them as declined otherwise.
+fn confirm_batch(section: &str, pending: &[&str], report: &ApplyReport) -> Result<bool, AppError> {
+    if pending.is_empty() {
+        return Ok(true);
+    }
+    // Held across the list and the question, which other sections must not split.
+    let prompts = lock_prompts();
+    info!("Pending [{}] installs:", section);
+    for pkg in pending {
+        info!("  {}", pkg);
+    }
+    let confirmed = confirm_while_locked(
+        &format!("Install these {} {} packages?", pending.len(), section),
+        &prompts,
+    )?;
+    drop(prompts);
+    if confirmed {
+        return Ok(true);
+    }
+    info!("Installation of [{}] aborted by user.", section);
+    for pkg in pending {
+        report.record(section, pkg, PackageAction::Declined);
+    }
+    Ok(false)
+}
+
+const APT_SOURCES_DIR: &str = "/etc/apt/sources.list.d";
+const APT_KEYRINGS_DIR: &str = "/etc/apt/keyrings";
+
+/// Default location for a repository key: named after the sources file so it
+/// matches the `signed-by=/etc/apt/keyrings/<name>.asc` convention.
+fn default_apt_key_path(sources_file_name: &str, key_url: &str) -> String {
+    let stem = sources_file_name
+        .trim_end_matches(".sources")
+        .trim_end_matches(".list");
+    let extension = if key_url.ends_with(".gpg") {
+        "gpg"
+    } else {
+        "asc"
+    };
+    format!("{}/{}.{}", APT_KEYRINGS_DIR, stem, extension)
+}
+
+/// The program of `[apt] frontend`, or `apt` if it is not set or not installed.
+fn resolve_apt_frontend(config: &Config) -> &'static str {
+    let frontend = config
+        .apt
+        .as_ref()
+        .and_then(|apt| apt.frontend)
+        .unwrap_or_default();
+    if frontend != AptFrontend::Apt && !command_exists(frontend.program()) {
+        warning!(
+            "Warning: APT frontend '{}' is not installed or not on PATH; using apt instead.",
+            frontend.program()
+        );
+        return AptFrontend::Apt.program();
+    }
+    frontend.program()
+}
+
+/// Sets up `repos`, then runs `<frontend> update` if anything changed.
+/// Repositories that are already configured are skipped.
+fn setup_apt_repos(
+    repos: &[&AptRepo],
+    frontend: &str,
+    dry_run: bool,
+    wait_for_lock: u64,
+) -> Result<(), AppError> {
+    if repos.is_empty() {
+        return Ok(());
+    }
+
+    let client = Client::new();
+    let temp_dir = create_temp_dir()?;
+    let sources_dir = Path::new(APT_SOURCES_DIR);
+    let mut changed = false;
+    for repo in repos {
+        changed |= match repo.source() {
+            Some(AptRepoSource::SourcesFile(url)) => {
+                install_apt_sources_file(repo, url, &client, temp_dir.path(), dry_run)?
+            }
+            Some(AptRepoSource::Ppa(ppa)) => add_apt_ppa(ppa, sources_dir, dry_run)?,
+            Some(AptRepoSource::Line(line)) => {
+                install_apt_sources_line(repo, line, &client, temp_dir.path(), dry_run)?
+            }
+            None => {
+                return Err(AppError::Other(
+                    "Each [[apt.repos]] entry needs exactly one of sources_file, ppa and line."
+                        .into(),
+                ));
+            }
+        };
+    }
+
+    if changed {
+        if !dry_run {
+            wait_for_dpkg_lock(wait_for_lock)?;
+        }
+        run_or_preview(dry_run, sudo(), [frontend, "update"])?;
+    }
+    Ok(())
+}
+
+/// Downloads the key at `url` and installs it at `dest`, or previews that.
+fn install_apt_key(
+    client: &Client,
+    url: &str,
+    dest: &str,
+    temp_dir: &Path,
+    dry_run: bool,
+) -> Result<(), AppError> {
+    if dry_run {
+        preview_download(url, dest, true);
+        return Ok(());
+    }
+    let key_tmp = temp_dir.join(format!(
+        "{}.key",
+        Path::new(dest)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+    ));
+    download_file(client, url, &key_tmp)?;
+    run_command(
+        sudo(),
+        [
+            OsStr::new("install"),
+            OsStr::new("-D"),
+            OsStr::new("-m"),
+            OsStr::new("0644"),
+            key_tmp.as_os_str(),
+            OsStr::new(dest),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Installs a `sources_file` repository and its key unless the snippet is
+/// already installed with identical content. Returns whether anything changed.
+fn install_apt_sources_file(
+    repo: &AptRepo,
+    url: &str,
+    client: &Client,
+    temp_dir: &Path,
+    dry_run: bool,
+) -> Result<bool, AppError> {
+    let file_name = url
+        .split('/')
+        .next_back()
+        .filter(|name| name.ends_with(".list") || name.ends_with(".sources"))
+        .ok_or_else(|| {
+            AppError::Other(
+                format!(
+                    "APT repository sources_file '{}' must point to a .list or .sources file.",
+                    url
+                )
+                .into(),
+            )
+        })?;
+    let sources_dest = format!("{}/{}", APT_SOURCES_DIR, file_name);
+    let key_dest = repo.key.as_ref().map(|key| {
+        repo.key_path
+            .clone()
+            .unwrap_or_else(|| default_apt_key_path(file_name, key))
+    });
+
+    if dry_run {
+        if let (Some(key), Some(key_dest)) = (&repo.key, &key_dest) {
+            install_apt_key(client, key, key_dest, temp_dir, dry_run)?;
+        }
+        preview_download(url, &sources_dest, true);
+        return Ok(true);
+    }
+
+    let sources_tmp = temp_dir.join(file_name);
+    download_file(client, url, &sources_tmp)?;
+    let up_to_date = std::fs::read(&sources_dest).ok() == Some(std::fs::read(&sources_tmp)?)
+        && key_dest
+            .as_ref()
+            .is_none_or(|path| Path::new(path).exists());
+    if up_to_date {
+        skip!(
+            "APT repository '{}' already configured, skipping.",
+            sources_dest
+        );
+        return Ok(false);
+    }
+
+    if let (Some(key), Some(key_dest)) = (&repo.key, &key_dest) {
+        install_apt_key(client, key, key_dest, temp_dir, dry_run)?;
+    }
+    run_command(
+        sudo(),
+        [
+            OsStr::new("install"),
+            OsStr::new("-D"),
+            OsStr::new("-m"),
+            OsStr::new("0644"),
+            sources_tmp.as_os_str(),
+            OsStr::new(&sources_dest),
+        ],
+    )?;
+    Ok(true)
+}
+
+/// Adds `ppa` (`ppa:owner/name`, or just `owner/name`) with
+/// `add-apt-repository` unless a file in `sources_dir` already points to it.
+/// Returns whether it was added.
+fn add_apt_ppa(ppa: &str, sources_dir: &Path, dry_run: bool) -> Result<bool, AppError> {
+    let path = ppa.strip_prefix("ppa:").unwrap_or(ppa);
+    if !path.contains('/') {
+        return Err(AppError::Other(
+            format!("APT repository ppa '{}' must be ppa:owner/name.", ppa).into(),
+        ));
+    }
+    // Launchpad serves PPAs from ppa.launchpadcontent.net (ppa.launchpad.net
+    // on older releases) under /<owner>/<name>/ubuntu.
+    let configured = apt_sources_contain(sources_dir, |line| {
+        line.contains(&format!("launchpadcontent.net/{}/ubuntu", path))
+            || line.contains(&format!("launchpad.net/{}/ubuntu", path))
+    });
+    if configured {
+        skip!(
+            "APT repository 'ppa:{}' already configured, skipping.",
+            path
+        );
+        return Ok(false);
+    }
+    if !dry_run && !command_exists("add-apt-repository") {
+        return Err(AppError::Other(
+            format!(
+                "Cannot add ppa:{}: add-apt-repository is not installed (it is part of software-properties-common).",
+                path
+            )
+            .into(),
+        ));
+    }
+    run_or_preview(
+        dry_run,
+        sudo(),
+        ["add-apt-repository", "-y", &format!("ppa:{}", path)],
+    )?;
+    Ok(true)
+}
+
+/// Writes a one-line sources entry, and installs its key, unless a file in
+/// `/etc/apt/sources.list.d` already has the line. Returns whether it was written.
+fn install_apt_sources_line(
+    repo: &AptRepo,
+    line: &str,
+    client: &Client,
+    temp_dir: &Path,
+    dry_run: bool,
+) -> Result<bool, AppError> {
+    let file_name = apt_line_file_name(line).ok_or_else(|| {
+        AppError::Other(
+            format!(
+                "APT repository line '{}' must be a `deb` or `deb-src` line with a URL.",
+                line
+            )
+            .into(),
+        )
+    })?;
+    let key_dest = repo.key.as_ref().map(|key| {
+        repo.key_path
+            .clone()
+            .unwrap_or_else(|| default_apt_key_path(&file_name, key))
+    });
+    let line = match &key_dest {
+        Some(key_dest) => apt_line_signed_by(line, key_dest),
+        None => line.split_whitespace().collect::<Vec<_>>().join(" "),
+    };
+
+    let configured = apt_sources_contain(Path::new(APT_SOURCES_DIR), |existing| {
+        existing.split_whitespace().eq(line.split_whitespace())
+    });
+    if configured
+        && key_dest
+            .as_ref()
+            .is_none_or(|path| Path::new(path).exists())
+    {
+        skip!("APT repository '{}' already configured, skipping.", line);
+        return Ok(false);
+    }
+
+    if let (Some(key), Some(key_dest)) = (&repo.key, &key_dest) {
+        install_apt_key(client, key, key_dest, temp_dir, dry_run)?;
+    }
+    let sources_dest = format!("{}/{}", APT_SOURCES_DIR, file_name);
+    run_or_preview(
+        dry_run,
+        sudo(),
+        [
+            "sh",
+            "-c",
+            "printf '%s\\n' \"$1\" > \"$2\"",
+            "sh",
+            &line,
+            &sources_dest,
+        ],
+    )?;
+    Ok(true)
+}
+
+/// Whether a line of a file in `sources_dir` that is not commented out
+/// matches. A missing directory holds no sources.
+fn apt_sources_contain(sources_dir: &Path, matches: impl Fn(&str) -> bool) -> bool {
+    let Ok(entries) = std::fs::read_dir(sources_dir) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        std::fs::read_to_string(entry.path()).is_ok_and(|content| {
+            content
+                .lines()
+                .map(str::trim)
+                .any(|line| !line.starts_with('#') && matches(line))
+        })
+    })
+}
+
+/// The file a one-line sources entry is written to, named after the host and
+/// path of its URL: `download-docker-com-linux-ubuntu.list`.
+fn apt_line_file_name(line: &str) -> Option<String> {
+    let mut words = line.split_whitespace();
+    if !matches!(words.next(), Some("deb" | "deb-src")) {
+        return None;
+    }
+    let url = words.find_map(|word| word.split_once("://").map(|(_, rest)| rest))?;
+    let name: String = url
+        .trim_end_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    (!name.is_empty()).then(|| format!("{}.list", name))
+}
+
+/// `line` with a `signed-by` option pointing to `key_path`, added to its
+/// `[...]` options if it has them. A line that already names a key is kept.
+fn apt_line_signed_by(line: &str, key_path: &str) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let signed = match words.as_slice() {
+        _ if line.contains("signed-by=") => line.to_string(),
+        [kind, options, rest @ ..] if options.starts_with('[') => format!(
+            "{} [signed-by={} {} {}",
+            kind,
+            key_path,
+            &options[1..],
+            rest.join(" ")
+        ),
+        [kind, rest @ ..] => format!("{} [signed-by={}] {}", kind, key_path, rest.join(" ")),
+        [] => String::new(),
+    };
+    signed.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Waits up to `timeout_secs` for other processes to release the dpkg/apt
+/// locks, failing with the holding PID if they are still held afterwards.
+fn wait_for_dpkg_lock(timeout_secs: u64) -> Result<(), AppError> {
+    const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+    let Some(mut holder) = find_dpkg_lock_holder() else {
+        return Ok(());
+    };
+    let deadline = Instant::now() + Duration::from_secs(timeout_secs);
+    let mut tick = 0;
+    while Instant::now() < deadline {
+        eprint!(
+            "\r{} Waiting for {} (held by PID {})...",
+            SPINNER[tick % SPINNER.len()],
+            holder.path,
+            holder.pid
+        );
+        io::Write::flush(&mut io::stderr())?;
+        std::thread::sleep(Duration::from_millis(250));
+        tick += 1;
+        match find_dpkg_lock_holder() {
+            Some(current) => holder = current,
+            None => {
+                eprintln!("\rLock on {} released.", holder.path);
+                return Ok(());
+            }
+        }
+    }
+    if timeout_secs > 0 {
+        eprintln!();
+    }
+
+    Err(AppError::Other(
+        format!(
+            "apt is locked by PID {}{} ({}). Wait for it to finish or pass --wait-for-lock <seconds>.",
+            holder.pid,
+            holder
+                .name
+                .map(|name| format!(" [{}]", name))
+                .unwrap_or_default(),
+            holder.path
+        )
+        .into(),
+    ))
+}
+
+/// Selects the `[post] refresh` hooks relevant to what was installed, in
+/// declaration order and without duplicates.
+fn relevant_refresh_hooks(hooks: &[RefreshHook], changed: &[PackageResult]) -> Vec<RefreshHook> {
+    let mut selected = Vec::new();
+    for &hook in hooks {
+        if selected.contains(&hook) {
+            continue;
+        }
+        let relevant = changed.iter().any(|result| match hook {
+            RefreshHook::Ldconfig => matches!(result.section.as_str(), "apt" | "deb"),
+            RefreshHook::Desktop => matches!(result.section.as_str(), "apt" | "deb" | "snap"),
+            RefreshHook::Fonts => is_font_package(&result.name),
+        });
+        if relevant {
+            selected.push(hook);
+        }
+    }
+    selected
+}
+
+fn run_refresh_hooks(
+    hooks: &[RefreshHook],
+    report: &ApplyReport,
+    dry_run: bool,
+) -> Result<(), AppError> {
+    for hook in relevant_refresh_hooks(hooks, &report.changed()) {
+        let (cmd, args): (&str, &[&str]) = match hook {
+            RefreshHook::Ldconfig => (sudo(), &["ldconfig"]),
+            RefreshHook::Desktop => (sudo(), &["update-desktop-database"]),
+            RefreshHook::Fonts => ("fc-cache", &["-f"]),
+        };
+        run_or_preview(dry_run, cmd, args)?;
+    }
+    Ok(())
+}
+
+fn apply_flatpak_overrides(list: &[PackageEntry], dry_run: bool) -> Result<(), AppError> {
+    for entry in list.iter().filter(|entry| !entry.overrides().is_empty()) {
+        let app_id = parse_flatpak_spec(entry.name()).id;
+        let overrides = entry.overrides();
+        let args = ["override", "--user", app_id]
+            .into_iter()
+            .chain(overrides.iter().map(String::as_str));
+        if dry_run {
+            preview_command("flatpak", args);
+            continue;
+        }
+        if !is_flatpak_package_installed(entry.name()) {
+            warning!(
+                "Warning: Flatpak package '{}' is not installed, skipping permission overrides.",
+                app_id
+            );
+            continue;
+        }
+        run_command("flatpak", args)?;
+    }
+    Ok(())
+}
+
+fn install_generic_packages(
+    list: &[PackageEntry],
+    manager_name: &str,
+    base_cmd: &[&str],
+    check_installed: impl Fn(&str) -> bool + Sync + Send,
+    install_args: impl Fn(&str) -> Vec<String> + Sync + Send,
+    options: &ApplyOptions,
+    report: &ApplyReport,
+) -> Result<(), AppError> {
+    let section = manager_name.to_lowercase();
+    if list.is_empty() {
+        skip!("Nothing to do in [{}].", section);
+        return Ok(());
+    }
+
+    let packages_to_install: Vec<&str> = list
+        .iter()
+        .map(PackageEntry::name)
+        .filter(|pkg| {
+            if !options.is_installed(|| check_installed(pkg)) {
+                true
+            } else {
+                skip!(
+                    "{} package '{}' already installed, skipping.",
+                    manager_name,
+                    base_package_name(&section, pkg)
+                );
+                report.record(&section, pkg, PackageAction::Skipped);
+                false
+            }
+        })
+        .collect();
+
+    if packages_to_install.is_empty() {
+        return Ok(());
+    }
+
+    info!(
+        "Will attempt to install the following {} packages: {:?}",
+        manager_name, packages_to_install
+    );
+
+    if options.dry_run {
+        for pkg in &packages_to_install {
+            let args = base_cmd[1..].iter().copied().map(String::from);
+            preview_command(base_cmd[0], args.chain(install_args(pkg)));
+            report.record(&section, pkg, PackageAction::WouldInstall);
+        }
+    } else if options.prompts_per_package() {
+        for pkg in &packages_to_install {
+            if confirm_installation(&format!(
+                "Do you want to install {} package '{}'?",
+                manager_name, pkg
+            ))? {
+                report.expect_installs(1);
+                let args = base_cmd[1..].iter().copied().map(String::from);
+                let args = args.chain(install_args(pkg));
+                report.time_install(&section, pkg, || run_command(base_cmd[0], args))?;
+            } else {
+                info!("Installation aborted by user.");
+                report.record(&section, pkg, PackageAction::Declined);
+            }
+        }
+    } else if confirm_section(&section, &packages_to_install, options, report)? {
+        report.expect_installs(packages_to_install.len());
+        // Every `nix profile install` rewrites the one profile, which nix
+        // locks, so installing several at once gains nothing; concurrent
+        // `mise use -g` calls would race to rewrite the global mise config, and
+        // concurrent `gem install`s to install a shared dependency.
+        let jobs = if matches!(section.as_str(), "nix" | "mise" | "gem") {
+            Some(1)
+        } else {
+            options.jobs
+        };
+        let install = |pkg: &&str| {
+            let args = base_cmd
+                .iter()
+                .skip(1)
+                .copied()
+                .map(String::from)
+                .chain(install_args(pkg));
+            report
+                .time_install(&section, pkg, || run_command(base_cmd[0], args))
+                .map_err(AppError::Command)
+        };
+        match jobs {
+            Some(1) => packages_to_install.iter().try_for_each(install)?,
+            Some(jobs) => rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs)
+                .build()
+                .map_err(|e| AppError::Other(Box::new(e)))?
+                .install(|| packages_to_install.par_iter().try_for_each(install))?,
+            None => packages_to_install.par_iter().try_for_each(install)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Installs every crate of the `[cargo]` section with both `cargo install` and
+/// `cargo binstall` into throwaway `--root` directories and prints how long
+/// each took. Nothing is installed into the real environment.
+pub fn benchmark_cargo_installs(
+    config: &Config,
+    only: &Option<Vec<String>>,
+) -> Result<(), AppError> {
+    let filter = OnlyFilter::parse(only, config)?;
+    let Some(cargo) = &config.cargo else {
+        println!("No [cargo] section to benchmark.");
+        return Ok(());
+    };
+    if !is_cargo_binstall_available() {
+        return Err(AppError::Other(
+            "cargo-binstall is not installed. Install it with `cargo install cargo-binstall` to run the benchmark.".into(),
+        ));
+    }
+
+    let time_install = |args: Vec<String>| -> Option<Duration> {
+        let start = Instant::now();
+        match run_command("cargo", &args) {
+            Ok(()) => Some(start.elapsed()),
+            Err(e) => {
+                eprintln!("Warning: {}", e);
+                None
+            }
+        }
+    };
+
+    let mut rows = Vec::new();
+    for entry in filter.select("cargo", &cargo.list) {
+        let (name, version) = match entry.name().split_once('=') {
+            Some((name, version)) => (name, Some(version)),
+            None => (entry.name(), None),
+        };
+
+        let install_root = create_temp_dir()?;
+        let mut install_args = vec![
+            "install".to_string(),
+            "--locked".to_string(),
+            "--root".to_string(),
+            install_root.path().to_string_lossy().into_owned(),
+            name.to_string(),
+        ];
+        if let Some(version) = version {
+            install_args.extend(["--version".to_string(), version.to_string()]);
+        }
+
+        let binstall_root = create_temp_dir()?;
+        let binstall_args = vec![
+            "binstall".to_string(),
+            "--no-confirm".to_string(),
+            "--root".to_string(),
+            binstall_root.path().to_string_lossy().into_owned(),
+            version.map_or_else(|| name.to_string(), |v| format!("{}@{}", name, v)),
+        ];
+
+        println!("Benchmarking '{}'...", entry.name());
+        rows.push((
+            entry.name().to_string(),
+            time_install(install_args),
+            time_install(binstall_args),
+        ));
+    }
+
+    let format_duration = |duration: Option<Duration>| {
+        duration.map_or_else(
+            || "failed".to_string(),
+            |d| format!("{:.1}s", d.as_secs_f64()),
+        )
+    };
+    let width = rows
+        .iter()
+        .map(|(name, _, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max("Crate".len());
+    println!(
+        "\n{:<width$}  {:>14}  {:>14}  {:>8}",
+        "Crate", "cargo install", "cargo binstall", "Speedup"
+    );
+    for (name, install, binstall) in rows {
+        let speedup = match (install, binstall) {
+            (Some(install), Some(binstall)) if binstall.as_secs_f64() > 0.0 => {
+                format!("{:.1}x", install.as_secs_f64() / binstall.as_secs_f64())
+            }
+            _ => "-".to_string(),
+        };
+        println!(
+            "{:<width$}  {:>14}  {:>14}  {:>8}",
+            name,
+            format_duration(install),
+            format_duration(binstall),
+            speedup
+        );
+    }
+
+    Ok(())
+}
+
+/// Manifest written by `init`. Example lines, commented out with a bare `#`
+/// unlike the explanations, form a valid manifest once uncommented.
+const STARTER_MANIFEST: &str = r#"# railtube manifest, see https://github.com/p14c31355/railtube
+#
+# Preview what it would do with `railtube apply --source railtube.toml --dry-run`,
+# apply it without --dry-run, and compare the system against it with
+# `railtube doctor --source railtube.toml`. Lines starting with `#[`, `#list`
+# or `#urls` are examples: remove the `#` to enable them.
+
+# Refresh the package index (`sudo apt update`) before installing anything.
+[system]
+update = true
+
+# APT packages, installed with a single `sudo apt install -y`. Pin a version
+# with "name=version", e.g. "git=1:2.43.0-1ubuntu7".
+[apt]
+list = ["git", "curl"]
+
+# Snap packages. Install flags follow the name.
+#[snap]
+#list = ["code --classic"]
+
+# Flatpak applications, by application ID.
+#[flatpak]
+#list = ["org.mozilla.firefox"]
+
+# Rust crates installed with `cargo install`. Pin a version with "name=version".
+#[cargo]
+#list = ["ripgrep", "bat=0.24.0"]
+
+# .deb packages downloaded from these URLs and installed with dpkg.
+#[deb]
+#urls = ["https://example.com/tool_1.0.0_amd64.deb"]
+
+# Shell commands run with `railtube run --source railtube.toml <name>`.
+[scripts]
+hello = "echo 'Hello from railtube'"
+
+# More sections: [rustup], [pipx], [npm], [go], [gem], [mise], [xbps], [dnf],
+# [nix], [brew], [[appimage]], [hooks] and [settings]. See the README for all of
+# them.
+"#;
+
+/// Writes the starter manifest to `manifest`. An existing manifest is an
+/// error unless `force` is set.
+pub fn init_command(manifest: &Path, force: bool) -> Result<(), AppError> {
+    if manifest.exists() && !force {
+        return Err(AppError::Other(
+            format!(
+                "{} already exists. Pass --force to overwrite it.",
+                manifest.display()
+            )
+            .into(),
+        ));
+    }
+    std::fs::write(manifest, STARTER_MANIFEST)?;
+    println!("Created {}", manifest.display());
+    println!(
+        "`railtube apply` records the installed versions in {} next to it; commit that file too, so `apply --frozen` can install the same versions elsewhere.",
+        LOCK_FILE
+    );
+    Ok(())
+}
+
+/// Looks up a package by name in one package manager.
+type FindCandidate = fn(&str) -> Result<Option<Candidate>, AppError>;
+
+/// Where `which` looks for a package: the section, the program the section
+/// installs with and the search to run.
+const WHICH_SOURCES: &[(&str, &str, FindCandidate)] = &[
+    ("apt", "apt-cache", find_apt_candidate),
+    ("snap", "snap", find_snap_candidate),
+    ("flatpak", "flatpak", find_flatpak_candidate),
+    ("cargo", "cargo", find_crates_io_candidate),
+];
+
+/// Searches for `name` with every package manager installed here, in
+/// parallel, and prints a table of where it is available. Returns whether
+/// any manager provides it.
+pub fn which_command(name: &str) -> Result<bool, AppError> {
+    let rows: Vec<(&str, Result<Option<Candidate>, String>)> = WHICH_SOURCES
+        .par_iter()
+        .map(|&(section, program, find)| {
+            let found = if command_exists(program) {
+                find(name).map_err(|e| format!("search failed: {}", e))
+            } else {
+                Err(format!("{} is not installed", program))
+            };
+            (section, found)
+        })
+        .collect();
+
+    let cells: Vec<(Level, [String; 3])> = rows
+        .into_iter()
+        .map(|(section, found)| match found {
+            Ok(Some(candidate)) => (
+                Level::Success,
+                [section.to_string(), candidate.name, candidate.version],
+            ),
+            Ok(None) => (
+                Level::Skip,
+                [
+                    section.to_string(),
+                    "-".to_string(),
+                    "not found".to_string(),
+                ],
+            ),
+            Err(e) => (Level::Warn, [section.to_string(), "-".to_string(), e]),
+        })
+        .collect();
+    let header = ["SECTION", "PACKAGE", "VERSION"].map(String::from);
+    let width = |column: usize| {
+        cells
+            .iter()
+            .map(|(_, row)| row[column].len())
+            .chain([header[column].len()])
+            .max()
+            .unwrap_or_default()
+    };
+    let (section_width, name_width) = (width(0), width(1));
+    let line = |row: &[String; 3]| {
+        format!(
+            "{:<section_width$}  {:<name_width$}  {}",
+            row[0], row[1], row[2]
+        )
+    };
+    println!("{}", line(&header));
+    for (level, row) in &cells {
+        println!("{}", paint(*level, line(row)));
+    }
+    Ok(cells.iter().any(|(level, _)| *level == Level::Success))
+}
+
+/// Shell builtins and keywords, which never resolve through `PATH`.
+const SHELL_BUILTINS: &[&str] = &[
+    ".", ":", "[", "alias", "break", "case", "cd", "command", "continue", "do", "done", "echo",
+    "elif", "else", "esac", "eval", "exec", "exit", "export", "false", "fi", "for", "if", "local",
+    "printf", "pwd", "read", "return", "set", "shift", "source", "test", "then", "trap", "true",
+    "type", "ulimit", "umask", "unset", "until", "wait", "while",
+];
+
+/// Heuristically extracts the programs a script invokes: the first word of
+/// every line and of every command after `;`, `&&`, `||` or `|`, skipping
+/// variable assignments and looking through `sudo`. Quoting, subshells and
+/// functions are not understood.
+fn script_commands(script: &str) -> Vec<&str> {
+    let mut commands = Vec::new();
+    for line in script.lines() {
+        let line = line.split_once('#').map_or(line, |(code, _)| code);
+        for part in line.split([';', '|', '&']) {
+            let mut words = part
+                .split_whitespace()
+                .skip_while(|word| word.contains('=') && !word.starts_with('='));
+            let command = match words.next() {
+                Some("sudo") => words.find(|word| !word.starts_with('-')),
+                command => command,
+            };
+            // `2>&1` splits into a part starting with the fd number.
+            if let Some(command) = command
+                && !command.starts_with(|c: char| c.is_ascii_digit() || c == '>')
+                && !SHELL_BUILTINS.contains(&command)
+                && !commands.contains(&command)
+            {
+                commands.push(command);
+            }
+        }
+    }
+    commands
+}
+
+/// Warns about programs invoked by `script` that cannot be found. Returns the
+/// missing names.
+fn check_script_commands(script_name: &str, script: &str) -> Vec<String> {
+    let missing: Vec<String> = script_commands(script)
+        .into_iter()
+        .filter(|command| !command_exists(command))
+        .map(String::from)
+        .collect();
+    for command in &missing {
+        eprintln!(
+            "Warning: Script '{}' runs '{}', which was not found in PATH.",
+            script_name, command
+        );
+    }
+    missing
+}
+
+/// Orders `targets` and everything they run `after` so that every script
+/// comes after its prerequisites. Fails on unknown scripts and on cycles.
+fn script_order<'a>(
+    scripts: &'a ScriptsSection,
+    targets: &[&'a str],
+) -> Result<Vec<&'a str>, AppError> {
+    fn visit<'a>(
+        scripts: &'a ScriptsSection,
+        name: &'a str,
+        path: &mut Vec<&'a str>,
+        order: &mut Vec<&'a str>,
+    ) -> Result<(), AppError> {
+        if order.contains(&name) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|visiting| *visiting == name) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(name);
+            return Err(AppError::Other(
+                format!(
+                    "Scripts depend on each other in a cycle: {}",
+                    cycle.join(" -> ")
+                )
+                .into(),
+            ));
+        }
+        let entry = scripts
+            .commands
+            .get(name)
+            .ok_or_else(|| match path.last() {
+                Some(dependent) => AppError::Other(
+                    format!(
+                        "Script '{}' runs after unknown script '{}'.",
+                        dependent, name
+                    )
+                    .into(),
+                ),
+                None => AppError::Other(format!("Script '{}' not found.", name).into()),
+            })?;
+        path.push(name);
+        for prerequisite in entry.after() {
+            visit(scripts, prerequisite, path, order)?;
+        }
+        path.pop();
+        order.push(name);
+        Ok(())
+    }
+
+    let mut order = Vec::new();
+    for target in targets {
+        visit(scripts, target, &mut Vec::new(), &mut order)?;
+    }
+    Ok(order)
+}
+
+/// Runs `script_name` after its prerequisites, or every script when it is
+/// `None`, in dependency order.
+pub fn run_scripts(
+    config: &Config,
+    script_name: Option<&str>,
+    is_remote_source: bool,
+    check: bool,
+    dry_run: bool,
+    down: bool,
+    no_deps: bool,
+) -> Result<(), AppError> {
+    let Some(scripts) = &config.scripts else {
+        eprintln!("No [scripts] section found in the TOML configuration.");
+        return Err(AppError::Other("No [scripts] section found.".into()));
+    };
+    // (name, shell, command) of every script to run, in order.
+    let commands: Vec<(&str, &str, &str)> = match script_name {
+        Some(name) if down || no_deps => {
+            let entry = scripts.commands.get(name).ok_or_else(|| {
+                AppError::Other(format!("Script '{}' not found in [scripts].", name).into())
+            })?;
+            // --no-deps runs the script alone; a teardown never runs its dependencies'.
+            let command = if down {
+                entry.down().ok_or_else(|| {
+                    AppError::Other(
+                        format!(
+                            "Script '{}' has no teardown; add `down = \"...\"` to its entry.",
+                            name
+                        )
+                        .into(),
+                    )
+                })?
+            } else {
+                entry.command()
+            };
+            vec![(name, entry.shell(), command)]
+        }
+        _ => {
+            let targets = match script_name {
+                Some(name) => vec![name],
+                None => {
+                    // Sorted so that independent scripts run in a stable order.
+                    let mut names: Vec<&str> =
+                        scripts.commands.keys().map(String::as_str).collect();
+                    names.sort();
+                    names
+                }
+            };
+            script_order(scripts, &targets)
+                .inspect_err(|e| eprintln!("{}", e))?
+                .into_iter()
+                .map(|name| {
+                    let entry = &scripts.commands[name];
+                    (name, entry.shell(), entry.command())
+                })
+                .collect()
+        }
+    };
+
+    if !dry_run && is_remote_source {
+        println!("WARNING: Executing script from a remote source.");
+        if !confirm_installation("Do you want to proceed?")? {
+            println!("Script execution aborted by user.");
+            return Ok(());
+        }
+    }
+
+    for (name, shell, command_to_run) in commands {
+        if check && check_script_commands(name, command_to_run).is_empty() {
+            println!("All commands of script '{}' were found.", name);
+        }
+        if dry_run {
+            preview_command(shell, ["-c", command_to_run]);
+            continue;
+        }
+        if down {
+            println!("Running teardown of script '{}': {}", name, command_to_run);
+        } else {
+            println!("Running script '{}': {}", name, command_to_run);
+        }
+        run_command_streamed(shell, ["-c", command_to_run])?;
+    }
+    Ok(())
+}
+
+/// Asks which script to run, from a numbered list of the `[scripts]` section,
+/// for `railtube run` without a script name. Without a terminal to ask on, it
+/// fails with the names of the scripts instead.
+pub fn pick_script(config: &Config) -> Result<String, AppError> {
+    let scripts = config
+        .scripts
+        .as_ref()
+        .filter(|scripts| !scripts.commands.is_empty())
+        .ok_or_else(|| AppError::Other("No [scripts] section found.".into()))?;
+    let mut names: Vec<&str> = scripts.commands.keys().map(String::as_str).collect();
+    names.sort();
+    if !io::stdin().is_terminal() {
+        return Err(AppError::Other(
+            format!(
+                "No script name given. Available scripts: {}",
+                names.join(", ")
+            )
+            .into(),
+        ));
+    }
+
+    for (number, name) in names.iter().enumerate() {
+        match scripts.commands[*name].description() {
+            Some(description) => println!("{:>3}) {}: {}", number + 1, name, description),
+            None => println!("{:>3}) {}", number + 1, name),
+        }
+    }
+    print!("Script to run (1-{}): ", names.len());
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    script_choice(input.trim(), &names)
+        .map(str::to_string)
+        .ok_or_else(|| AppError::Other(format!("No script '{}'.", input.trim()).into()))
+}
+
+/// The script picked by `choice`, a number from `pick_script`'s list or a name.
+fn script_choice<'a>(choice: &str, names: &[&'a str]) -> Option<&'a str> {
+    match choice.parse::<usize>() {
+        Ok(number) => number
+            .checked_sub(1)
+            .and_then(|index| names.get(index))
+            .copied(),
+        Err(_) => names.iter().find(|name| **name == choice).copied(),
+    }
+}
+
+/// Prints the name of every script with its description, or its command if
+/// it has none, sorted by name.
+pub fn list_scripts(config: &Config) {
+    let Some(scripts) = config.scripts.as_ref().filter(|s| !s.commands.is_empty()) else {
+        println!("No scripts in the manifest.");
+        return;
+    };
+    let mut names: Vec<&String> = scripts.commands.keys().collect();
+    names.sort();
+    let width = names
+        .iter()
+        .map(|name| name.len())
+        .max()
+        .unwrap_or_default();
+    for name in names {
+        let entry = &scripts.commands[name];
+        match entry.description() {
+            Some(description) => println!("{:<width$}  {}", name, description),
+            None => println!("{:<width$}  {}", name, paint(Level::Skip, entry.command())),
+        }
+    }
+}
+
+pub fn export_current_environment() -> Result<Config, AppError> {
+    let config = Config {
+        system: Some(SystemSection::default()),
+        apt: Some(AptSection {
+            list: into_entries(get_installed_apt_packages()?),
+            ..Default::default()
+        }),
+        snap: Some(Section {
+            list: into_entries(get_installed_snap_specs()?),
+        }),
+        flatpak: Some(Section {
+            list: into_entries(get_installed_flatpak_packages()?),
+        }),
+        cargo: Some(Section {
+            list: into_entries(get_installed_cargo_packages()?),
+        }),
+        // pipx, npm, go, gem, mise, xbps, dnf, nix and brew may be missing; leave their
+        // sections out then.
+        pipx: get_installed_pipx_packages().ok().map(|list| Section {
+            list: into_entries(list),
+        }),
+        npm: get_installed_npm_packages().ok().map(|list| Section {
+            list: into_entries(list),
+        }),
+        go: get_installed_go_packages().ok().map(|list| Section {
+            list: into_entries(list),
+        }),
+        gem: get_installed_gem_packages().ok().map(|list| Section {
+            list: into_entries(list),
+        }),
+        mise: get_installed_mise_specs().ok().map(|list| Section {
+            list: into_entries(list),
+        }),
+        xbps: get_installed_xbps_packages().ok().map(|list| Section {
+            list: into_entries(list),
+        }),
+        dnf: get_installed_dnf_packages().ok().map(|list| Section {
+            list: into_entries(list),
+        }),
+        nix: get_installed_nix_packages().ok().map(|list| Section {
+            list: into_entries(list),
+        }),
+        brew: get_installed_brew_packages().ok().map(|list| Section {
+            list: into_entries(list),
+        }),
+        brew_cask: get_installed_brew_casks().ok().map(|list| Section {
+            list: into_entries(list),
+        }),
+        brew_tap: get_installed_brew_taps().ok().map(|list| Section {
+            list: into_entries(list),
+        }),
+        ..Default::default()
+    };
+
+    Ok(config)
+}
+
+/// Collects the installed Homebrew taps, formulae and casks.
+pub fn export_brew_environment() -> Result<Config, AppError> {
+    Ok(Config {
+        brew: Some(Section {
+            list: into_entries(get_installed_brew_packages()?),
+        }),
+        brew_cask: Some(Section {
+            list: into_entries(get_installed_brew_casks()?),
+        }),
+        brew_tap: Some(Section {
+            list: into_entries(get_installed_brew_taps()?),
+        }),
+        ..Default::default()
+    })
+}
+
+fn into_entries(packages: Vec<String>) -> Vec<PackageEntry> {
+    packages.into_iter().map(PackageEntry::from).collect()
+}
+
+pub fn check_package_discrepancies<W: std::io::Write + ?Sized>(
+    writer: &mut W,
+    package_manager_name: &str,
+    toml_packages: &HashSet<&str>,
+    installed_packages: &HashSet<&str>,
+) -> std::io::Result<()> {
+    let missing: Vec<_> = toml_packages
+        .difference(installed_packages)
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        writeln!(
+            writer,
+            "\n{} packages listed in TOML but not installed:",
+            package_manager_name
+        )?;
+        for pkg in missing {
+            writeln!(writer, "- {}", paint(Level::Error, pkg))?;
+        }
+    }
+
+    let extra: Vec<_> = installed_packages
+        .difference(toml_packages)
+        .copied()
+        .collect();
+    if !extra.is_empty() {
+        writeln!(
+            writer,
+            "\n{} packages installed but not listed in TOML:",
+            package_manager_name
+        )?;
+        for pkg in extra {
+            writeln!(writer, "- {}", paint(Level::Warn, pkg))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn check_section_discrepancies<F, P>(
+    out: &mut dyn Write,
+    list: Option<&[PackageEntry]>,
+    manager_name: &str,
+    get_installed: F,
+    parse_pkg: P,
+) -> Option<SectionDiff>
+where
+    F: FnOnce() -> Result<Vec<String>, AppError>,
+    P: Fn(&str) -> &str,
+{
+    let toml_packages = list?
+        .iter()
+        .map(|entry| parse_pkg(entry.name()))
+        .collect::<HashSet<_>>();
+    match get_installed() {
+        Ok(installed_packages) => {
+            let installed_packages_set = installed_packages
+                .iter()
+                .map(String::as_str)
+                .collect::<HashSet<_>>();
+            if let Err(e) = check_package_discrepancies(
+                out,
+                manager_name,
+                &toml_packages,
+                &installed_packages_set,
+            ) {
+                warning!("Warning: Failed to write to stdout: {}", e);
+            }
+            Some(SectionDiff::new(&toml_packages, &installed_packages_set))
+        }
+        Err(e) => {
+            warning!(
+                "Warning: Failed to list installed {} packages: {}",
+                manager_name,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Rewrites `[cargo]` entries that name an installed binary rather than its
+/// crate (`rg` instead of `ripgrep`) to the crate name.
+fn resolve_cargo_binaries(list: &[PackageEntry]) -> Vec<PackageEntry> {
+    let crates = get_installed_cargo_crates().unwrap_or_default();
+    list.iter()
+        .cloned()
+        .map(|mut entry| {
+            let (name, version) = Cargo.split_spec(entry.name());
+            if let Some(installed) = find_cargo_crate(&crates, name)
+                && installed.name != name
+            {
+                let resolved = match version {
+                    Some(version) => Cargo.pin_spec(&installed.name, version),
+                    None => installed.name.clone(),
+                };
+                entry.set_name(resolved);
+            }
+            entry
+        })
+        .collect()
+}
+
+/// Prints and returns the pinned packages of `list` whose installed version
+/// differs from the pin.
+fn check_version_drift(
+    out: &mut dyn Write,
+    manager: &dyn PackageManager,
+    list: &[PackageEntry],
+) -> io::Result<Vec<VersionDrift>> {
+    let installed = match manager.installed_versions() {
+        Ok(installed) => installed,
+        Err(e) => {
+            warning!(
+                "Warning: Failed to list installed {} package versions: {}",
+                manager.display_name(),
+                e
+            );
+            return Ok(Vec::new());
+        }
+    };
+    let drift = find_version_drift(
+        manager,
+        list.iter().map(PackageEntry::name),
+        &installed,
+        VersionMatch::Exact,
+    );
+    if !drift.is_empty() {
+        writeln!(
+            out,
+            "\n{} packages installed with a different version than pinned in TOML:",
+            manager.display_name()
+        )?;
+        for item in &drift {
+            writeln!(
+                out,
+                "- {}: {} installed, {} pinned",
+                paint(Level::Warn, &item.name),
+                item.installed,
+                item.desired
+            )?;
+        }
+    }
+    Ok(drift)
+}
+
+/// Warns about manifest packages whose installed version differs from the lock file.
+fn check_lock_drift(
+    out: &mut dyn Write,
+    lock: &LockFile,
+    managers: &[(&dyn PackageManager, Option<Vec<PackageEntry>>)],
+) -> io::Result<Vec<String>> {
+    let mut drift = Vec::new();
+    for (manager, list) in managers {
+        let Some(list) = list else { continue };
+        if !lock.sections.contains_key(manager.section()) {
+            continue;
+        }
+        match manager.installed_versions() {
+            Ok(installed) => drift.extend(lock.verify(*manager, list, &installed)),
+            Err(e) => warning!(
+                "Warning: Failed to list installed {} package versions: {}",
+                manager.display_name(),
+                e
+            ),
+        }
+    }
+    if !drift.is_empty() {
+        writeln!(
+            out,
+            "\nWarning: installed versions differ from {}:",
+            LOCK_FILE
+        )?;
+        for item in &drift {
+            writeln!(out, "- {}", paint(Level::Warn, item))?;
+        }
+    }
+    Ok(drift)
+}
+
+fn check_flatpak_overrides(out: &mut dyn Write, list: &[PackageEntry]) -> io::Result<Vec<String>> {
+    let mut not_applied = Vec::new();
+    for entry in list.iter().filter(|entry| !entry.overrides().is_empty()) {
+        let permissions = match get_flatpak_permissions(entry.name()) {
+            Ok(permissions) => permissions,
+            Err(e) => {
+                warning!("Warning: {}", e);
+                continue;
+            }
+        };
+        for override_arg in entry.overrides() {
+            if is_flatpak_override_applied(&permissions, override_arg) == Some(false) {
+                not_applied.push(format!("{}: {}", entry.name(), override_arg));
+            }
+        }
+    }
+
+    if !not_applied.is_empty() {
+        writeln!(
+            out,
+            "\nFlatpak permission overrides listed in TOML but not applied:"
+        )?;
+        for item in &not_applied {
+            writeln!(out, "- {}", paint(Level::Warn, item))?;
+        }
+    }
+    Ok(not_applied)
+}
+
+/// Checks the manifest without touching the system and prints the problems
+/// found, or "manifest OK". `manifests` are the raw tables of the files it was
+/// loaded from. Returns whether the manifest is clean.
+pub fn validate_command(config: &Config, manifests: &[(String, toml::Table)]) -> bool {
+    let problems = manifest_problems(config, manifests);
+    if problems.is_empty() {
+        println!("manifest OK");
+        return true;
+    }
+    println!("Found {} problem(s) in the manifest:", problems.len());
+    for problem in &problems {
+        println!("- {}", problem);
+    }
+    false
+}
+
+/// Unknown top-level keys of the manifest files, empty sections, packages
+/// listed twice in a section and malformed `name=version` specs.
+fn manifest_problems(config: &Config, manifests: &[(String, toml::Table)]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (source, table) in manifests {
+        for key in table.keys() {
+            if !MANIFEST_KEYS.contains(&key.as_str()) {
+                problems.push(format!("{}: unknown top-level key '{}'", source, key));
+            }
+        }
+    }
+
+    let list = |section: &Option<Section>| section.as_ref().map(|s| s.list.clone());
+    let sections = [
+        ("apt", config.apt.as_ref().map(AptSection::entries)),
+        ("snap", list(&config.snap)),
+        ("flatpak", list(&config.flatpak)),
+        ("cargo", list(&config.cargo)),
+        ("pipx", list(&config.pipx)),
+        ("npm", list(&config.npm)),
+        ("go", list(&config.go)),
+        ("gem", list(&config.gem)),
+        ("mise", list(&config.mise)),
+        ("xbps", list(&config.xbps)),
+        ("dnf", list(&config.dnf)),
+        ("nix", list(&config.nix)),
+        ("brew", list(&config.brew)),
+        ("brew_cask", list(&config.brew_cask)),
+        ("brew_tap", list(&config.brew_tap)),
+    ];
+    for (section, entries) in &sections {
+        let Some(entries) = entries else {
+            continue;
+        };
+        if entries.is_empty() {
+            problems.push(format!("[{}] is empty", section));
+        }
+        let mut seen = HashSet::new();
+        let mut reported = HashSet::new();
+        for entry in entries {
+            let name = base_package_name(section, entry.name());
+            if !seen.insert(name) && reported.insert(name) {
+                problems.push(format!("[{}] lists '{}' more than once", section, name));
+            }
+        }
+    }
+
+    let managers: [&dyn PackageManager; 4] = [&Apt, &Cargo, &Pipx, &Npm];
+    for manager in managers {
+        let entries = sections
+            .iter()
+            .find(|(section, _)| *section == manager.section())
+            .and_then(|(_, entries)| entries.as_ref());
+        for entry in entries.into_iter().flatten() {
+            if is_malformed_spec(manager, entry.name()) {
+                problems.push(format!(
+                    "[{}] malformed spec '{}'",
+                    manager.section(),
+                    entry.name()
+                ));
+            }
+        }
+    }
+    // Go versions follow `@`; `path=version` would be taken as a package path.
+    for entry in config.go.iter().flat_map(|go| &go.list) {
+        if entry.name().contains(['=', ' ']) {
+            problems.push(format!("[go] malformed spec '{}'", entry.name()));
+        }
+    }
+    // Like Go, mise pins versions with `@`.
+    for entry in config.mise.iter().flat_map(|mise| &mise.list) {
+        if entry.name().contains(['=', ' ']) || entry.name().ends_with('@') {
+            problems.push(format!("[mise] malformed spec '{}'", entry.name()));
+        }
+    }
+    for (index, repo) in config.apt.iter().flat_map(|apt| &apt.repos).enumerate() {
+        if repo.source().is_none() {
+            problems.push(format!(
+                "[[apt.repos]] entry {} needs exactly one of sources_file, ppa and line",
+                index + 1
+            ));
+        }
+    }
+
+    if config.deb.as_ref().is_some_and(|deb| deb.urls.is_empty()) {
+        problems.push("[deb] is empty".to_string());
+    }
+    if config.appimage.as_ref().is_some_and(Vec::is_empty) {
+        problems.push("[[appimage]] is empty".to_string());
+    }
+    if config
+        .scripts
+        .as_ref()
+        .is_some_and(|scripts| scripts.commands.is_empty())
+    {
+        problems.push("[scripts] is empty".to_string());
+    }
+    problems
+}
+
+/// Whether `spec` has an empty name or version, or a stray `=` or space in either.
+fn is_malformed_spec(manager: &dyn PackageManager, spec: &str) -> bool {
+    let (name, version) = manager.split_spec(spec);
+    let malformed =
+        |part: &str| part.is_empty() || part.contains('=') || part.contains(char::is_whitespace);
+    malformed(name) || version.is_some_and(malformed)
+}
+
+/// Checks the manifest against the system. When `output` is given, the result
+/// is also appended to it as one JSON line for `doctor --trend`. Formats other
+/// than text suppress the human-readable report and print the document instead.
+pub fn doctor_command(
+    config: &Config,
+    source: &str,
+    output: Option<&str>,
+    format: DoctorFormat,
+) -> Result<DoctorReport, AppError> {
+    let mut stdout = io::stdout();
+    let mut sink = io::sink();
+    let out: &mut dyn Write = match format {
+        DoctorFormat::Text => &mut stdout,
+        DoctorFormat::Json | DoctorFormat::Sarif => &mut sink,
+    };
+    // With --quiet, the text report is only the discrepancies themselves.
+    let quiet = verbosity() == Verbosity::Quiet;
+    if !quiet {
+        writeln!(out, "Running railtube doctor for: {}", source)?;
+    }
+
+    let mut report = DoctorReport {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        source: source.to_string(),
+        ..Default::default()
+    };
+    let mut record = |section: &str, diff: Option<SectionDiff>| {
+        if let Some(diff) = diff {
+            report.sections.insert(section.to_string(), diff);
+        }
+    };
+
+    record(
+        "apt",
+        check_section_discrepancies(
+            out,
+            config.apt.as_ref().map(AptSection::entries).as_deref(),
+            "APT",
+            get_installed_apt_packages,
+            |pkg_spec| pkg_spec.split('=').next().unwrap_or(pkg_spec),
+        ),
+    );
+
+    record(
+        "snap",
+        check_section_discrepancies(
+            out,
+            config.snap.as_ref().map(|s| s.list.as_slice()),
+            "Snap",
+            get_installed_snap_packages,
+            |pkg| split_snap_spec(pkg).0,
+        ),
+    );
+
+    record(
+        "flatpak",
+        check_section_discrepancies(
+            out,
+            config.flatpak.as_ref().map(|s| s.list.as_slice()),
+            "Flatpak",
+            get_installed_flatpak_packages,
+            |pkg| parse_flatpak_spec(pkg).id,
+        ),
+    );
+
+    let cargo_list = config
+        .cargo
+        .as_ref()
+        .map(|s| resolve_cargo_binaries(&s.list));
+    record(
+        "cargo",
+        check_section_discrepancies(
+            out,
+            cargo_list.as_deref(),
+            "Cargo",
+            get_installed_cargo_packages,
+            |pkg| pkg.split('=').next().unwrap_or(pkg),
+        ),
+    );
+
+    record(
+        "pipx",
+        check_section_discrepancies(
+            out,
+            config.pipx.as_ref().map(|s| s.list.as_slice()),
+            "pipx",
+            get_installed_pipx_packages,
+            |pkg| base_package_name("pipx", pkg),
+        ),
+    );
+
+    record(
+        "npm",
+        check_section_discrepancies(
+            out,
+            config.npm.as_ref().map(|s| s.list.as_slice()),
+            "npm",
+            get_installed_npm_packages,
+            |pkg| base_package_name("npm", pkg),
+        ),
+    );
+
+    record(
+        "go",
+        check_section_discrepancies(
+            out,
+            config.go.as_ref().map(|s| s.list.as_slice()),
+            "Go",
+            get_installed_go_packages,
+            |pkg| base_package_name("go", pkg),
+        ),
+    );
+
+    record(
+        "gem",
+        check_section_discrepancies(
+            out,
+            config.gem.as_ref().map(|s| s.list.as_slice()),
+            "Gem",
+            get_installed_gem_packages,
+            |pkg| base_package_name("gem", pkg),
+        ),
+    );
+
+    record(
+        "mise",
+        check_section_discrepancies(
+            out,
+            config.mise.as_ref().map(|s| s.list.as_slice()),
+            "mise",
+            get_installed_mise_tools,
+            |pkg| base_package_name("mise", pkg),
+        ),
+    );
+
+    record(
+        "xbps",
+        check_section_discrepancies(
+            out,
+            config.xbps.as_ref().map(|s| s.list.as_slice()),
+            "XBPS",
+            get_installed_xbps_packages,
+            |pkg| base_package_name("xbps", pkg),
+        ),
+    );
+
+    // Only missing toolchains and components are reported: rustup installs
+    // several components by default that a manifest has no reason to list.
+    let rustup_entries = config.rustup.as_ref().map(RustupSection::entries);
+    record(
+        "rustup",
+        check_section_discrepancies(
+            out,
+            rustup_entries.as_deref(),
+            "rustup",
+            || {
+                let listed = rustup_entries.as_deref().unwrap_or_default();
+                let mut installed = get_installed_rustup_packages()?;
+                installed.retain(|name| listed.iter().any(|entry| entry.name() == name));
+                Ok(installed)
+            },
+            |pkg| pkg,
+        ),
+    );
+
+    record(
+        "dnf",
+        check_section_discrepancies(
+            out,
+            config.dnf.as_ref().map(|s| s.list.as_slice()),
+            "DNF",
+            get_installed_dnf_packages,
+            |pkg| base_package_name("dnf", pkg),
+        ),
+    );
+
+    record(
+        "nix",
+        check_section_discrepancies(
+            out,
+            config.nix.as_ref().map(|s| s.list.as_slice()),
+            "nix",
+            get_installed_nix_packages,
+            |pkg| base_package_name("nix", pkg),
+        ),
+    );
+
+    record(
+        "brew_tap",
+        check_section_discrepancies(
+            out,
+            config.brew_tap.as_ref().map(|s| s.list.as_slice()),
+            "Homebrew tap",
+            get_installed_brew_taps,
+            |pkg| pkg,
+        ),
+    );
+
+    record(
+        "brew",
+        check_section_discrepancies(
+            out,
+            config.brew.as_ref().map(|s| s.list.as_slice()),
+            "Homebrew",
+            get_installed_brew_packages,
+            |pkg| base_package_name("brew", pkg),
+        ),
+    );
+
+    record(
+        "brew_cask",
+        check_section_discrepancies(
+            out,
+            config.brew_cask.as_ref().map(|s| s.list.as_slice()),
+            "Homebrew cask",
+            get_installed_brew_casks,
+            |pkg| base_package_name("brew_cask", pkg),
+        ),
+    );
+
+    let version_managers: [(&dyn PackageManager, Option<Vec<PackageEntry>>); 7] = [
+        (&Apt, config.apt.as_ref().map(AptSection::entries)),
+        (&Cargo, config.cargo.as_ref().map(cargo_entries)),
+        (&Pipx, config.pipx.as_ref().map(|s| s.list.clone())),
+        (&Npm, config.npm.as_ref().map(|s| s.list.clone())),
+        (&Go, config.go.as_ref().map(|s| s.list.clone())),
+        (&Dnf, config.dnf.as_ref().map(|s| s.list.clone())),
+        (&Xbps, config.xbps.as_ref().map(|s| s.list.clone())),
+    ];
+    for (manager, list) in &version_managers {
+        if let Some(list) = list
+            && let Some(diff) = report.sections.get_mut(manager.section())
+        {
+            diff.drift = check_version_drift(out, *manager, list)?;
+        }
+    }
+
+    let lock_file = lock_file_path(source);
+    if lock_file.exists() {
+        match LockFile::load(&lock_file) {
+            Ok(lock) => report.lock_drift = check_lock_drift(out, &lock, &version_managers)?,
+            Err(e) => warning!("Warning: {}", e),
+        }
+    }
+
+    if let Some(flatpak) = &config.flatpak {
+        report.unapplied_overrides = check_flatpak_overrides(out, &flatpak.list)?;
+    }
+
+    if let Some(output) = output {
+        report.append_jsonl(Path::new(output))?;
+        if !quiet {
+            writeln!(out, "\nDoctor record appended to {}", output)?;
+        }
+    }
+
+    let document = match format {
+        DoctorFormat::Text => None,
+        DoctorFormat::Json => Some(serde_json::to_string_pretty(&report)),
+        DoctorFormat::Sarif => Some(serde_json::to_string_pretty(&doctor_sarif(&report))),
+    };
+    if let Some(document) = document {
+        println!("{}", document.map_err(|e| AppError::Other(Box::new(e)))?);
+    }
+
+    Ok(report)
+}
+
+/// Installs the packages `doctor` reported as missing through the regular
+/// apply path, restricted to exactly those packages. Extras are never removed.
+pub fn fix_missing_packages(
+    config: &Config,
+    doctor: &DoctorReport,
+    dry_run: bool,
+    yes: bool,
+) -> Result<(), AppError> {
+    let only: Vec<String> = doctor
+        .sections
+        .iter()
+        .flat_map(|(section, diff)| {
+            diff.missing
+                .iter()
+                .map(move |pkg| format!("{}:{}", section, pkg))
+        })
+        .collect();
+    if only.is_empty() {
+        println!("\nNo missing packages to install.");
+        return Ok(());
+    }
+
+    println!("\nInstalling {} missing package(s)...", only.len());
+    let options = ApplyOptions {
+        dry_run,
+        yes,
+        only: Some(only),
+        retries: CARGO_RETRIES,
+        ..Default::default()
+    };
+    apply_config(config, &options, &ApplyReport::new())
+}
+
+/// Prints min/max/avg discrepancy counts per manager from the doctor records
+/// in `path`, optionally limited to records newer than `since`.
+pub fn doctor_trend(path: &str, since: Option<Duration>) -> Result<(), AppError> {
+    let mut reports = DoctorReport::read_jsonl(Path::new(path))?;
+    if let Some(since) = since {
+        let cutoff = SystemTime::now()
+            .checked_sub(since)
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        reports.retain(|report| report.timestamp >= cutoff);
+    }
+
+    if reports.is_empty() {
+        println!(
+            "No doctor records found in {} for the selected period.",
+            path
+        );
+        return Ok(());
+    }
+
+    println!(
+        "Drift across {} doctor run(s) from {} to {}:",
+        reports.len(),
+        format_utc_timestamp(
+            reports
+                .iter()
+                .map(|r| r.timestamp)
+                .min()
+                .unwrap_or_default()
+        ),
+        format_utc_timestamp(
+            reports
+                .iter()
+                .map(|r| r.timestamp)
+                .max()
+                .unwrap_or_default()
+        )
+    );
+    println!(
+        "{:<10} {:>5} {:>5} {:>7} {:>5}",
+        "Manager", "Min", "Max", "Avg", "Runs"
+    );
+    for (manager, stats) in summarize_drift(&reports) {
+        println!(
+            "{:<10} {:>5} {:>5} {:>7.1} {:>5}",
+            manager, stats.min, stats.max, stats.avg, stats.runs
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn sample_config() -> Config {
+        toml::from_str(
+            r#"
+[apt]
+list = ["git", "curl=8.5.0"]
+
+[cargo]
+list = ["ripgrep", "bat"]
+"#,
+        )
+        .unwrap()
+    }
+
+    fn only(values: &[&str]) -> Option<Vec<String>> {
+        Some(values.iter().map(|v| v.to_string()).collect())
+    }
+
+    #[test]
+    fn test_only_filter_package_qualified() {
+        let config = sample_config();
+        let filter = OnlyFilter::parse(&only(&["cargo:ripgrep", "apt:curl"]), &config).unwrap();
+
+        assert!(filter.includes_section("cargo"));
+        assert!(filter.includes_section("apt"));
+        assert!(!filter.includes_section("snap"));
+        assert!(filter.includes_package("cargo", "ripgrep"));
+        assert!(!filter.includes_package("cargo", "bat"));
+        assert!(filter.includes_package("apt", "curl=8.5.0"));
+        assert!(!filter.includes_package("apt", "git"));
+    }
+
+    #[test]
+    fn test_only_filter_accepts_apt_repo_packages() {
+        let config: Config = toml::from_str(
+            r#"
+[apt]
+list = ["git"]
+
+[[apt.repos]]
+sources_file = "https://example.com/vendor.sources"
+key = "https://example.com/vendor.gpg"
+packages = ["vendor-tool"]
+"#,
+        )
+        .unwrap();
+        let filter = OnlyFilter::parse(&only(&["apt:vendor-tool"]), &config).unwrap();
+        assert!(filter.includes_package("apt", "vendor-tool"));
+        assert!(!filter.includes_package("apt", "git"));
+    }
+
+    #[test]
+    fn test_apt_repositories_accept_ppas_and_lines() {
+        let config: Config = toml::from_str(
+            r#"
+[apt]
+repositories = [
+    "ppa:neovim-ppa/stable",
+    "deb https://example.com/apt stable main",
+    { line = "deb [arch=amd64] https://download.docker.com/linux/ubuntu noble stable", key = "https://download.docker.com/linux/ubuntu/gpg" },
+]
+"#,
+        )
+        .unwrap();
+        let repos = &config.apt.as_ref().unwrap().repos;
+        assert_eq!(
+            repos[0].source(),
+            Some(AptRepoSource::Ppa("ppa:neovim-ppa/stable"))
+        );
+        assert_eq!(
+            repos[1].source(),
+            Some(AptRepoSource::Line(
+                "deb https://example.com/apt stable main"
+            ))
+        );
+        let Some(AptRepoSource::Line(line)) = repos[2].source() else {
+            panic!("expected a line: {:?}", repos[2]);
+        };
+
+        let file_name = apt_line_file_name(line).unwrap();
+        assert_eq!(file_name, "download-docker-com-linux-ubuntu.list");
+        let key_path = default_apt_key_path(&file_name, repos[2].key.as_ref().unwrap());
+        assert_eq!(
+            apt_line_signed_by(line, &key_path),
+            "deb [signed-by=/etc/apt/keyrings/download-docker-com-linux-ubuntu.asc arch=amd64] https://download.docker.com/linux/ubuntu noble stable"
+        );
+        assert_eq!(
+            apt_line_signed_by("deb  https://example.com/apt stable main", "/k.gpg"),
+            "deb [signed-by=/k.gpg] https://example.com/apt stable main"
+        );
+        assert_eq!(apt_line_file_name("ppa:neovim-ppa/stable"), None);
+
+        let sources = tempfile::tempdir().unwrap();
+        std::fs::write(
+            sources
+                .path()
+                .join("neovim-ppa-ubuntu-stable-noble.sources"),
+            "Types: deb\nURIs: https://ppa.launchpadcontent.net/neovim-ppa/stable/ubuntu/\n",
+        )
+        .unwrap();
+        assert!(!add_apt_ppa("ppa:neovim-ppa/stable", sources.path(), true).unwrap());
+        assert!(add_apt_ppa("ppa:neovim-ppa/unstable", sources.path(), true).unwrap());
+        assert!(add_apt_ppa("neovim", sources.path(), true).is_err());
+    }
+
+    #[test]
+    fn test_confirm_section_skips_prompt_without_flag() {
+        let report = ApplyReport::new();
+        let per_package = ApplyOptions::default();
+        assert!(confirm_section("cargo", &["bat"], &per_package, &report).unwrap());
+
+        let per_section = ApplyOptions {
+            confirm_sections: true,
+            ..Default::default()
+        };
+        assert!(confirm_section("cargo", &[], &per_section, &report).unwrap());
+        assert!(!per_section.prompts_per_package());
+        assert!(report.results().is_empty());
+    }
+
+    #[test]
+    fn test_starter_manifest_is_valid_with_and_without_examples() {
+        let uncommented: String = STARTER_MANIFEST
+            .lines()
+            .map(|line| match line.strip_prefix('#') {
+                Some(example) if !example.is_empty() && !example.starts_with(' ') => example,
+                _ => line,
+            })
+            .map(|line| format!("{}\n", line))
+            .collect();
+        for (content, sections) in [
+            (STARTER_MANIFEST, vec!["system", "apt", "scripts"]),
+            (
+                uncommented.as_str(),
+                vec![
+                    "system", "apt", "snap", "flatpak", "cargo", "deb", "scripts",
+                ],
+            ),
+        ] {
+            let config: Config = toml::from_str(content).unwrap();
+            let table: toml::Table = toml::from_str(content).unwrap();
+            assert_eq!(config.section_names(), sections);
+            assert!(manifest_problems(&config, &[("railtube.toml".to_string(), table)]).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_section_chains_cover_every_section_in_order() {
+        let chained: Vec<&str> = SECTION_CHAINS
+            .iter()
+            .flat_map(|chain| chain.iter().copied())
+            .collect();
+        let mut sorted = chained.clone();
+        sorted.sort();
+        let mut expected: Vec<&str> = SECTION_NAMES
+            .iter()
+            .copied()
+            .filter(|&name| name != "scripts")
+            .collect();
+        expected.sort();
+        assert_eq!(sorted, expected);
+
+        let position = |name| SECTION_NAMES.iter().position(|s| *s == name).unwrap();
+        for chain in SECTION_CHAINS {
+            assert!(
+                chain
+                    .windows(2)
+                    .all(|pair| position(pair[0]) < position(pair[1]))
+            );
+        }
+    }
+
+    #[test]
+    fn test_needs_sudo_follows_selected_sections() {
+        let config = sample_config();
+        assert!(needs_sudo(&config, &OnlyFilter::default()));
+        let cargo_only = OnlyFilter::parse(&only(&["cargo"]), &config).unwrap();
+        assert!(!needs_sudo(&config, &cargo_only));
+    }
+
+    #[test]
+    fn test_is_cargo_network_error() {
+        assert!(is_cargo_network_error(
+            "warning: spurious network error (2 tries remaining): [28] Timeout was reached"
+        ));
+        assert!(is_cargo_network_error(
+            "error: failed to download from `https://static.crates.io/crates/bat/0.24.0/download`"
+        ));
+        assert!(is_cargo_network_error(
+            "error: failed to query replaced source registry `crates-io`\n\
+             Caused by: [6] Could not resolve host: proxy.internal"
+        ));
+        assert!(!is_cargo_network_error(
+            "error[E0425]: cannot find value `x` in this scope\n\
+             error: could not compile `bat` (bin \"bat\") due to 1 previous error"
+        ));
+        // A compile error wins even if a build script also failed to fetch something.
+        assert!(!is_cargo_network_error(
+            "failed to fetch submodule\nerror: could not compile `sys-crate`"
+        ));
+    }
+
+    #[test]
+    fn test_script_commands() {
+        let script = "FOO=1 jq . a.json | sort -u && echo done\n\
+                      cd /tmp; sudo -E apt-get update # refresh\n\
+                      jq -r .name b.json 2>&1 || exit 1";
+        assert_eq!(script_commands(script), vec!["jq", "sort", "apt-get"]);
+    }
+
+    #[test]
+    fn test_script_order_runs_prerequisites_first() {
+        let scripts: ScriptsSection = toml::from_str(
+            r#"
+configure = { cmd = "make configure", after = ["install"] }
+install = { cmd = "make install", after = ["build"] }
+build = "make"
+lint = "make lint"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            script_order(&scripts, &["configure"]).unwrap(),
+            vec!["build", "install", "configure"]
+        );
+        assert_eq!(
+            script_order(&scripts, &["build", "configure", "install", "lint"]).unwrap(),
+            vec!["build", "install", "configure", "lint"]
+        );
+    }
+
+    #[test]
+    fn test_script_order_rejects_cycles_and_unknown_scripts() {
+        let scripts: ScriptsSection = toml::from_str(
+            r#"
+a = { cmd = "true", after = ["b"] }
+b = { cmd = "true", after = ["a"] }
+c = { cmd = "true", after = ["missing"] }
+"#,
+        )
+        .unwrap();
+        let err = script_order(&scripts, &["a"]).unwrap_err().to_string();
+        assert!(err.contains("a -> b -> a"), "{}", err);
+        let err = script_order(&scripts, &["c"]).unwrap_err().to_string();
+        assert!(
+            err.contains("'c' runs after unknown script 'missing'"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_manifest_problems() {
+        let content = r#"
+[atp]
+list = ["git"]
+
+[apt]
+list = ["git", "curl=", "git=1:2.43.0"]
+
+[cargo]
+list = ["ripgrep==14.0.0", "bat=0.24.0"]
+
+[pipx]
+list = ["black=24.1.0", "ruff==0.3.0"]
+
+[go]
+list = ["golang.org/x/tools/gopls=v0.15.0", "mvdan.cc/gofumpt@v0.6.0"]
+
+[flatpak]
+list = []
+"#;
+        let config: Config = toml::from_str(content).unwrap();
+        let table: toml::Table = toml::from_str(content).unwrap();
+        assert_eq!(
+            manifest_problems(&config, &[("railtube.toml".to_string(), table)]),
+            vec![
+                "railtube.toml: unknown top-level key 'atp'",
+                "[apt] lists 'git' more than once",
+                "[flatpak] is empty",
+                "[apt] malformed spec 'curl='",
+                "[cargo] malformed spec 'ripgrep==14.0.0'",
+                "[pipx] malformed spec 'black=24.1.0'",
+                "[go] malformed spec 'golang.org/x/tools/gopls=v0.15.0'",
+            ]
+        );
+
+        let clean = "[apt]\nlist = [\"git=1:2.43.0\"]\n\n[npm]\nlist = [\"@types/node@20.11.0\"]\n";
+        let config: Config = toml::from_str(clean).unwrap();
+        let table: toml::Table = toml::from_str(clean).unwrap();
+        assert!(manifest_problems(&config, &[("railtube.toml".to_string(), table)]).is_empty());
+    }
+
+    #[test]
+    fn test_dedup_specs_drops_identical_entries() {
+        let list: Vec<PackageEntry> = ["git", "curl", "git", "jq=1.7.1", "jq=1.7.1"]
+            .map(|spec| PackageEntry::from(spec.to_string()))
+            .to_vec();
+        let deduped = dedup_specs(&Apt, list).unwrap();
+        let names: Vec<&str> = deduped.iter().map(PackageEntry::name).collect();
+        assert_eq!(names, ["git", "curl", "jq=1.7.1"]);
+    }
+
+    #[test]
+    fn test_dedup_specs_rejects_conflicting_pins() {
+        let list: Vec<PackageEntry> = ["nodejs", "git", "nodejs=18.0.0", "nodejs"]
+            .map(|spec| PackageEntry::from(spec.to_string()))
+            .to_vec();
+        let err = dedup_specs(&Apt, list).unwrap_err().to_string();
+        assert!(
+            err.contains(
+                "[apt] lists 'nodejs' with conflicting versions: 'nodejs', 'nodejs=18.0.0'"
+            ),
+            "{}",
+            err
+        );
+
+        let config: Config = toml::from_str(
+            "[cargo]\nlist = [\"bat=0.24.0\", { name = \"bat\", version = \"0.23.0\" }]\n",
+        )
+        .unwrap();
+        let options = ApplyOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let err = apply_config(&config, &options, &ApplyReport::new())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("'bat=0.24.0', 'bat=0.23.0'"), "{}", err);
+    }
+
+    #[test]
+    fn test_script_choice() {
+        let names = ["build", "deploy", "test"];
+        assert_eq!(script_choice("1", &names), Some("build"));
+        assert_eq!(script_choice("3", &names), Some("test"));
+        assert_eq!(script_choice("deploy", &names), Some("deploy"));
+        for invalid in ["0", "4", "", "lint"] {
+            assert_eq!(script_choice(invalid, &names), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_run_scripts_down_requires_a_teardown() {
+        let config: Config = toml::from_str(
+            r#"
+[scripts]
+docker = { cmd = "make up", down = "make down" }
+build = "make"
+"#,
+        )
+        .unwrap();
+        assert!(run_scripts(&config, Some("docker"), false, false, true, true, false).is_ok());
+        let err = run_scripts(&config, Some("build"), false, false, true, true, false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("'build' has no teardown"), "{}", err);
+    }
+
+    #[test]
+    fn test_scripts_accept_command_description_and_shell() {
+        let config: Config = toml::from_str(
+            r#"
+[scripts]
+build = "make"
+lint = { command = "[[ -f Makefile ]] && make lint", description = "Run the linters", shell = "bash" }
+"#,
+        )
+        .unwrap();
+        let scripts = &config.scripts.unwrap().commands;
+        assert_eq!(scripts["build"].shell(), "sh");
+        assert_eq!(scripts["build"].description(), None);
+        assert_eq!(scripts["lint"].command(), "[[ -f Makefile ]] && make lint");
+        assert_eq!(scripts["lint"].description(), Some("Run the linters"));
+        assert_eq!(scripts["lint"].shell(), "bash");
+    }
+
+    #[test]
+    fn test_local_deb_path() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let deb = temp_dir.path().join("tool_1.0_amd64.deb");
+        std::fs::write(&deb, "").unwrap();
+        let dir = Some(temp_dir.path());
+        assert_eq!(
+            local_deb_path(deb.to_str().unwrap(), Some(Path::new("/elsewhere"))).unwrap(),
+            deb.canonicalize().unwrap()
+        );
+        assert_eq!(
+            local_deb_path("tool_1.0_amd64.deb", dir).unwrap(),
+            deb.canonicalize().unwrap()
+        );
+
+        let err = local_deb_path("missing.deb", dir).unwrap_err();
+        assert!(err.to_string().contains("does not exist"), "{}", err);
+        let err = local_deb_path("tool_1.0_amd64.deb", None).unwrap_err();
+        assert!(err.to_string().contains("fetched from a URL"), "{}", err);
+        let err = local_deb_path("vendor/tool.tar.gz", dir).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("neither an http(s) URL nor a path to a .deb file")
+        );
+    }
+
+    #[test]
+    fn test_appimage_desktop_entry() {
+        let entry = appimage_desktop_entry("tool", Path::new("/home/me/.local/bin/tool"));
+        assert!(entry.starts_with("[Desktop Entry]\n"));
+        assert!(entry.contains("Name=tool\n"));
+        assert!(entry.contains("Exec=\"/home/me/.local/bin/tool\" %U\n"));
+    }
+
+    #[test]
+    fn test_default_apt_key_path() {
+        assert_eq!(
+            default_apt_key_path("vendor.sources", "https://example.com/key.gpg"),
+            "/etc/apt/keyrings/vendor.gpg"
+        );
+        assert_eq!(
+            default_apt_key_path("vendor.list", "https://example.com/key.asc"),
+            "/etc/apt/keyrings/vendor.asc"
+        );
+    }
+
+    #[test]
+    fn test_only_filter_whole_sections_for_prune() {
+        let config = sample_config();
+        assert!(OnlyFilter::default().includes_whole_section("apt"));
+        let filter = OnlyFilter::parse(&only(&["cargo", "apt:curl"]), &config).unwrap();
+        assert!(filter.includes_whole_section("cargo"));
+        assert!(!filter.includes_whole_section("apt"));
+        assert!(!filter.includes_whole_section("snap"));
+    }
+
+    #[test]
+    fn test_only_filter_bare_section_wins() {
+        let config = sample_config();
+        let filter = OnlyFilter::parse(&only(&["cargo:ripgrep", "cargo"]), &config).unwrap();
+
+        assert!(filter.includes_package("cargo", "bat"));
+        assert!(filter.includes_package("cargo", "ripgrep"));
+    }
+
+    #[test]
+    fn test_only_filter_rejects_unlisted_package() {
+        let config = sample_config();
+        assert!(OnlyFilter::parse(&only(&["cargo:fd-find"]), &config).is_err());
+        assert!(OnlyFilter::parse(&only(&["deb:foo"]), &config).is_err());
+    }
+
+    #[test]
+    fn test_only_filter_rejects_unknown_sections() {
+        let config = sample_config();
+        let err = OnlyFilter::parse(&only(&["crago", "APT", "snapp:code", "crago"]), &config)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("unknown sections 'crago', 'snapp'."),
+            "{}",
+            err
+        );
+        assert!(OnlyFilter::parse(&only(&["Cargo", "system", "deb"]), &config).is_ok());
+    }
+
+    #[test]
+    fn test_new_installs_leave_out_preexisting_packages() {
+        let report = ApplyReport::new();
+        report.record("apt", "curl=8.5.0", PackageAction::Installed);
+        report.record("apt", "git", PackageAction::Installed);
+        report.record("apt", "htop", PackageAction::Skipped);
+        report.record("snap", "code --classic", PackageAction::Installed);
+        report.record("pipx", "black", PackageAction::Installed);
+        report.record(
+            "deb",
+            "https://example.com/tool.deb",
+            PackageAction::Installed,
+        );
+        let snapshot = |sections: &[(&str, &[&str])]| -> HashMap<String, HashSet<String>> {
+            sections
+                .iter()
+                .map(|(section, names)| {
+                    let names = names.iter().map(|name| name.to_string()).collect();
+                    (section.to_string(), names)
+                })
+                .collect()
+        };
+        // curl was upgraded, not installed; pipx could not be listed before;
+        // [deb] is never listed, so its installs are all kept.
+        let before = snapshot(&[("apt", &["curl", "htop"]), ("snap", &[])]);
+        let after = snapshot(&[
+            ("apt", &["curl", "git", "htop"]),
+            ("snap", &["code"]),
+            ("pipx", &["black"]),
+        ]);
+
+        assert_eq!(
+            new_installs(&report, &before, &after),
+            BTreeMap::from([
+                ("apt".to_string(), vec!["git".to_string()]),
+                (
+                    "deb".to_string(),
+                    vec!["https://example.com/tool.deb".to_string()]
+                ),
+                ("snap".to_string(), vec!["code".to_string()]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_exclude_is_subtracted_from_only() {
+        let config = sample_config();
+        let filter = OnlyFilter::parse(&only(&["cargo", "apt"]), &config)
+            .unwrap()
+            .with_exclude(&["APT".to_string()])
+            .unwrap();
+        assert!(filter.includes_section("cargo"));
+        assert!(filter.includes_package("cargo", "ripgrep"));
+        assert!(!filter.includes_section("apt"));
+        assert!(!filter.includes_package("apt", "git"));
+        assert!(!filter.includes_whole_section("apt"));
+        assert!(!filter.includes_section("snap"));
+
+        let filter = OnlyFilter::parse(&None, &config)
+            .unwrap()
+            .with_exclude(&["deb".to_string()])
+            .unwrap();
+        assert!(filter.includes_section("cargo"));
+        assert!(!filter.includes_section("deb"));
+
+        let err = OnlyFilter::parse(&None, &config)
+            .unwrap()
+            .with_exclude(&["dbe".to_string()])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("--exclude: unknown section 'dbe'."), "{}", err);
+    }
+
+    #[test]
+    fn test_cargo_install_args() {
+        let config: Config = toml::from_str(
+            r#"
+[cargo]
+list = [
+    "ripgrep",
+    "bat=0.24.0",
+    { name = "just", version = "1.25.0", features = ["cli", "color"] },
+    { name = "mytool", git = "https://example.com/mytool.git", branch = "main" },
+]
+"#,
+        )
+        .unwrap();
+        let entries = section_entries(&config, "cargo");
+        let args: Vec<String> = entries
+            .iter()
+            .map(|entry| cargo_install_args(entry, false).join(" "))
+            .collect();
+        assert_eq!(
+            args,
+            vec![
+                "install --locked --force ripgrep",
+                "install --locked --force --version 0.24.0 bat",
+                "install --locked --force --version 1.25.0 --features cli,color just",
+                "install --locked --force --git https://example.com/mytool.git --branch main mytool",
+            ]
+        );
+        // The table's version pins the spec like `name=version` does.
+        assert_eq!(entries[2].name(), "just=1.25.0");
+        assert_eq!(base_package_name("cargo", entries[2].name()), "just");
+    }
+
+    #[test]
+    fn test_only_filter_group() {
+        let config: Config = toml::from_str(
+            r#"
+[apt]
+list = ["git", { name = "steam", groups = ["gaming"] }, { name = "slack", groups = ["work"] }]
+
+[cargo]
+list = [{ name = "ripgrep", groups = ["work", "gaming"] }]
+"#,
+        )
+        .unwrap();
+        let names = |filter: &OnlyFilter, section: &str| -> Vec<String> {
+            filter
+                .select(section, &section_entries(&config, section))
+                .iter()
+                .map(|entry| entry.name().to_string())
+                .collect()
+        };
+
+        let gaming = OnlyFilter::default()
+            .with_group(Some("gaming"), &config)
+            .unwrap();
+        assert_eq!(names(&gaming, "apt"), vec!["git", "steam"]);
+        assert_eq!(names(&gaming, "cargo"), vec!["ripgrep"]);
+
+        let all = OnlyFilter::default().with_group(None, &config).unwrap();
+        assert_eq!(names(&all, "apt"), vec!["git", "steam", "slack"]);
+
+        assert!(
+            OnlyFilter::default()
+                .with_group(Some("gamng"), &config)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_only_filter_absent_selects_everything() {
+        let filter = OnlyFilter::parse(&None, &sample_config()).unwrap();
+        assert!(filter.includes_section("snap"));
+        assert!(filter.includes_package("cargo", "anything"));
+    }
+
+    fn installed(section: &str, name: &str) -> PackageResult {
+        PackageResult {
+            section: section.to_string(),
+            name: name.to_string(),
+            action: PackageAction::Installed,
+            duration_ms: None,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_relevant_refresh_hooks() {
+        let hooks = [
+            RefreshHook::Fonts,
+            RefreshHook::Ldconfig,
+            RefreshHook::Desktop,
+            RefreshHook::Ldconfig,
+        ];
+
+        let cargo_only = [installed("cargo", "ripgrep")];
+        assert!(relevant_refresh_hooks(&hooks, &cargo_only).is_empty());
+
+        let apt = [installed("apt", "libssl-dev")];
+        assert_eq!(
+            relevant_refresh_hooks(&hooks, &apt),
+            vec![RefreshHook::Ldconfig, RefreshHook::Desktop]
+        );
+
+        let font = [installed("apt", "fonts-firacode")];
+        assert_eq!(
+            relevant_refresh_hooks(&hooks, &font),
+            vec![
+                RefreshHook::Fonts,
+                RefreshHook::Ldconfig,
+                RefreshHook::Desktop
+            ]
+        );
+    }
 
     #[test]
     fn test_check_package_discrepancies_missing_and_extra() {
@@ -0,0 +1,137 @@
+use crate::cli::Args;
+use crate::config::{Config, SECTION_NAMES};
+use crate::template::render_manifest;
+use crate::utils::decode_manifest;
+use clap::{CommandFactory, ValueEnum};
+use clap_complete::Shell;
+use clap_complete::engine::CompletionCandidate;
+use std::io::Write;
+
+/// Environment variable pointing at the manifest used for completions.
+pub const MANIFEST_ENV: &str = "RAILTUBE_MANIFEST";
+/// Manifest used for completions when `RAILTUBE_MANIFEST` is not set.
+pub const DEFAULT_MANIFEST: &str = "railtube.toml";
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum CompletionKind {
+    /// Script names from the [scripts] section
+    Scripts,
+    /// Section names usable with --only
+    Sections,
+}
+
+fn default_manifest() -> String {
+    std::env::var(MANIFEST_ENV).unwrap_or_else(|_| DEFAULT_MANIFEST.to_string())
+}
+
+// Completions must stay fast and silent, so only local manifests are read and
+// any error simply yields no manifest.
+fn load_local_manifest(source: &str) -> Option<Config> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        return None;
+    }
+    let content = decode_manifest(&std::fs::read(source).ok()?).ok()?;
+    toml::from_str(&render_manifest(source, content).ok()?).ok()
+}
+
+/// Returns the completion candidates of `kind` starting with `prefix`, read
+/// from `source` or the default manifest.
+pub fn manifest_candidates(
+    kind: CompletionKind,
+    prefix: &str,
+    source: Option<&str>,
+) -> Vec<String> {
+    let source = source.map(String::from).unwrap_or_else(default_manifest);
+    let config = load_local_manifest(&source);
+
+    let mut candidates: Vec<String> = match (kind, &config) {
+        (CompletionKind::Scripts, Some(config)) => config
+            .scripts
+            .as_ref()
+            .map(|scripts| scripts.commands.keys().cloned().collect())
+            .unwrap_or_default(),
+        (CompletionKind::Scripts, None) => Vec::new(),
+        (CompletionKind::Sections, Some(config)) => config
+            .section_names()
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        (CompletionKind::Sections, None) => SECTION_NAMES.iter().map(|s| s.to_string()).collect(),
+    };
+    candidates.retain(|candidate| candidate.starts_with(prefix));
+    candidates.sort();
+    candidates
+}
+
+pub fn complete_script_names() -> Vec<CompletionCandidate> {
+    manifest_candidates(CompletionKind::Scripts, "", None)
+        .into_iter()
+        .map(CompletionCandidate::new)
+        .collect()
+}
+
+pub fn complete_section_names() -> Vec<CompletionCandidate> {
+    manifest_candidates(CompletionKind::Sections, "", None)
+        .into_iter()
+        .map(CompletionCandidate::new)
+        .collect()
+}
+
+/// Writes a static completion script for `shell` (`railtube completions`),
+/// for packaging or shells that cannot source the dynamic `COMPLETE=<shell>`
+/// registration. It completes subcommands and flags, but not manifest
+/// script or section names.
+pub fn write_completion_script(shell: Shell, out: &mut dyn Write) -> std::io::Result<()> {
+    // Generators panic on write errors, so a closed pipe (`| head`) would
+    // panic; render the script first and report write errors as usual.
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut Args::command(), "railtube", &mut script);
+    out.write_all(&script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_candidates_filters_by_prefix() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "[apt]\nlist = []\n\n[scripts]\nsetup = \"true\"\nsync = \"true\"\nbuild = \"true\"\n"
+        )
+        .unwrap();
+        let source = file.path().to_str().unwrap();
+
+        assert_eq!(
+            manifest_candidates(CompletionKind::Scripts, "s", Some(source)),
+            vec!["setup", "sync"]
+        );
+        assert_eq!(
+            manifest_candidates(CompletionKind::Sections, "", Some(source)),
+            vec!["apt", "scripts"]
+        );
+    }
+
+    #[test]
+    fn test_manifest_candidates_without_manifest() {
+        assert!(
+            manifest_candidates(CompletionKind::Scripts, "", Some("/nonexistent.toml")).is_empty()
+        );
+        assert_eq!(
+            manifest_candidates(CompletionKind::Sections, "fl", Some("/nonexistent.toml")),
+            vec!["flatpak"]
+        );
+    }
+
+    #[test]
+    fn test_write_completion_script_for_every_shell() {
+        for shell in Shell::value_variants() {
+            let mut script = Vec::new();
+            write_completion_script(*shell, &mut script).unwrap();
+            let script = String::from_utf8(script).unwrap();
+            assert!(script.contains("railtube"), "{} script: {}", shell, script);
+            assert!(script.contains("doctor"), "{} script: {}", shell, script);
+        }
+    }
+}
@@ -1,37 +1,1121 @@
-use serde::{Deserialize, Serialize};
+use crate::package::base_package_name;
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Top-level manifest sections, in the order they are applied.
+pub const SECTION_NAMES: &[&str] = &[
+    "system",
+    "apt",
+    "snap",
+    "flatpak",
+    "rustup",
+    "cargo",
+    "pipx",
+    "npm",
+    "go",
+    "gem",
+    "mise",
+    "xbps",
+    "dnf",
+    "nix",
+    "brew_tap",
+    "brew",
+    "brew_cask",
+    "deb",
+    "appimage",
+    "scripts",
+];
+
+/// Every top-level key a manifest may contain.
+pub const MANIFEST_KEYS: &[&str] = &[
+    "include",
+    "settings",
+    "defaults",
+    "system",
+    "apt",
+    "snap",
+    "flatpak",
+    "rustup",
+    "cargo",
+    "pipx",
+    "npm",
+    "go",
+    "gem",
+    "mise",
+    "xbps",
+    "dnf",
+    "nix",
+    "brew",
+    "brew_cask",
+    "brew_tap",
+    "deb",
+    "appimage",
+    "scripts",
+    "post",
+    "hooks",
+];
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Config {
+    /// Manifests (paths relative to this one, or URLs) merged under this one, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// `[settings]`, also accepted as `[defaults]`.
+    #[serde(alias = "defaults")]
+    pub settings: Option<Settings>,
     pub system: Option<SystemSection>,
-    pub apt: Option<Section>,
+    pub apt: Option<AptSection>,
     pub snap: Option<Section>,
     pub flatpak: Option<Section>,
+    /// Rust toolchains and components, installed before [cargo] crates.
+    pub rustup: Option<RustupSection>,
     pub cargo: Option<Section>,
+    /// Python applications installed with pipx.
+    pub pipx: Option<Section>,
+    /// Global npm packages.
+    pub npm: Option<Section>,
+    /// Go tools installed with `go install`, as `path[@version]`.
+    pub go: Option<Section>,
+    /// Ruby gems installed with `gem install`, as `name[=version]`.
+    pub gem: Option<Section>,
+    /// Language runtimes and tools installed with mise, as `tool[@version]`.
+    pub mise: Option<Section>,
+    /// Void Linux packages.
+    pub xbps: Option<Section>,
+    /// Fedora/RHEL packages installed with `dnf`.
+    pub dnf: Option<Section>,
+    /// Packages installed with `nix profile install`.
+    pub nix: Option<Section>,
+    /// Homebrew formulae, casks and taps. Taps are added before formulae and
+    /// casks are installed.
+    pub brew: Option<Section>,
+    pub brew_cask: Option<Section>,
+    pub brew_tap: Option<Section>,
     pub deb: Option<DebSection>,
+    /// AppImages downloaded to `~/.local/bin`, written as `[[appimage]]` tables.
+    pub appimage: Option<Vec<AppImageEntry>>,
     pub scripts: Option<ScriptsSection>,
+    pub post: Option<PostSection>,
+    pub hooks: Option<HooksSection>,
+}
+
+impl Config {
+    /// Names of the sections present in this manifest.
+    pub fn section_names(&self) -> Vec<&'static str> {
+        let present = [
+            self.system.is_some(),
+            self.apt.is_some(),
+            self.snap.is_some(),
+            self.flatpak.is_some(),
+            self.rustup.is_some(),
+            self.cargo.is_some(),
+            self.pipx.is_some(),
+            self.npm.is_some(),
+            self.go.is_some(),
+            self.gem.is_some(),
+            self.mise.is_some(),
+            self.xbps.is_some(),
+            self.dnf.is_some(),
+            self.nix.is_some(),
+            self.brew_tap.is_some(),
+            self.brew.is_some(),
+            self.brew_cask.is_some(),
+            self.deb.is_some(),
+            self.appimage.is_some(),
+            self.scripts.is_some(),
+        ];
+        SECTION_NAMES
+            .iter()
+            .zip(present)
+            .filter_map(|(name, present)| present.then_some(*name))
+            .collect()
+    }
+
+    /// Merges `other` over `self`. Package entries and AppImages from `other`
+    /// replace those of `self` with the same name and are appended otherwise; deb
+    /// URLs, rustup toolchains and components, and refresh hooks are unioned;
+    /// scripts and `[system]` settings from `other` take precedence.
+    pub fn merge(&mut self, other: Config) {
+        if let Some(other_settings) = other.settings {
+            let settings = self.settings.get_or_insert_with(Settings::default);
+            *settings = settings.with_overrides(&other_settings);
+        }
+        if other.system.is_some() {
+            self.system = other.system;
+        }
+        if let Some(other_apt) = other.apt {
+            let apt = self.apt.get_or_insert_with(AptSection::default);
+            merge_entries(&mut apt.list, other_apt.list, "apt");
+            if other_apt.default_release.is_some() {
+                apt.default_release = other_apt.default_release;
+            }
+            if other_apt.frontend.is_some() {
+                apt.frontend = other_apt.frontend;
+            }
+            for repo in other_apt.repos {
+                match apt
+                    .repos
+                    .iter_mut()
+                    .find(|existing| repo.source().is_some() && existing.source() == repo.source())
+                {
+                    Some(existing) => *existing = repo,
+                    None => apt.repos.push(repo),
+                }
+            }
+        }
+        merge_section(&mut self.snap, other.snap, "snap");
+        merge_section(&mut self.flatpak, other.flatpak, "flatpak");
+        if let Some(other_rustup) = other.rustup {
+            let rustup = self.rustup.get_or_insert_with(RustupSection::default);
+            for (list, other_list) in [
+                (&mut rustup.toolchains, other_rustup.toolchains),
+                (&mut rustup.components, other_rustup.components),
+            ] {
+                for name in other_list {
+                    if !list.contains(&name) {
+                        list.push(name);
+                    }
+                }
+            }
+        }
+        merge_section(&mut self.cargo, other.cargo, "cargo");
+        merge_section(&mut self.pipx, other.pipx, "pipx");
+        merge_section(&mut self.npm, other.npm, "npm");
+        merge_section(&mut self.go, other.go, "go");
+        merge_section(&mut self.gem, other.gem, "gem");
+        merge_section(&mut self.mise, other.mise, "mise");
+        merge_section(&mut self.xbps, other.xbps, "xbps");
+        merge_section(&mut self.dnf, other.dnf, "dnf");
+        merge_section(&mut self.nix, other.nix, "nix");
+        merge_section(&mut self.brew, other.brew, "brew");
+        merge_section(&mut self.brew_cask, other.brew_cask, "brew_cask");
+        merge_section(&mut self.brew_tap, other.brew_tap, "brew_tap");
+        if let Some(other_deb) = other.deb {
+            let deb = self.deb.get_or_insert_with(DebSection::default);
+            for entry in other_deb.urls {
+                match deb
+                    .urls
+                    .iter_mut()
+                    .find(|existing| existing.url() == entry.url())
+                {
+                    Some(existing) => *existing = entry,
+                    None => deb.urls.push(entry),
+                }
+            }
+        }
+        if let Some(other_appimages) = other.appimage {
+            let appimages = self.appimage.get_or_insert_with(Vec::new);
+            for entry in other_appimages {
+                match appimages
+                    .iter_mut()
+                    .find(|existing| existing.name == entry.name)
+                {
+                    Some(existing) => *existing = entry,
+                    None => appimages.push(entry),
+                }
+            }
+        }
+        if let Some(other_scripts) = other.scripts {
+            self.scripts
+                .get_or_insert_with(ScriptsSection::default)
+                .commands
+                .extend(other_scripts.commands);
+        }
+        if let Some(other_post) = other.post {
+            let post = self.post.get_or_insert_with(PostSection::default);
+            for hook in other_post.refresh {
+                if !post.refresh.contains(&hook) {
+                    post.refresh.push(hook);
+                }
+            }
+        }
+        if let Some(other_hooks) = other.hooks {
+            self.hooks
+                .get_or_insert_with(HooksSection::default)
+                .merge(other_hooks);
+        }
+    }
+
+    /// The package lists of every section, keyed by section name.
+    fn package_lists_mut(&mut self) -> Vec<(&'static str, &mut Vec<PackageEntry>)> {
+        let sections = [
+            ("snap", &mut self.snap),
+            ("flatpak", &mut self.flatpak),
+            ("cargo", &mut self.cargo),
+            ("pipx", &mut self.pipx),
+            ("npm", &mut self.npm),
+            ("go", &mut self.go),
+            ("gem", &mut self.gem),
+            ("mise", &mut self.mise),
+            ("xbps", &mut self.xbps),
+            ("dnf", &mut self.dnf),
+            ("nix", &mut self.nix),
+            ("brew", &mut self.brew),
+            ("brew_cask", &mut self.brew_cask),
+            ("brew_tap", &mut self.brew_tap),
+        ];
+        self.apt
+            .as_mut()
+            .map(|apt| ("apt", &mut apt.list))
+            .into_iter()
+            .chain(
+                sections
+                    .into_iter()
+                    .filter_map(|(name, section)| Some((name, &mut section.as_mut()?.list))),
+            )
+            .collect()
+    }
+
+    /// Copies the package descriptions of `previous` onto the entries of
+    /// `self` with the same section and package name, so regenerating a
+    /// manifest keeps its hand-written notes.
+    pub fn keep_descriptions(&mut self, mut previous: Config) {
+        let described: HashMap<(&str, String), String> = previous
+            .package_lists_mut()
+            .into_iter()
+            .flat_map(|(section, list)| {
+                list.iter().filter_map(move |entry| {
+                    let name = base_package_name(section, entry.name()).to_string();
+                    Some(((section, name), entry.description()?.to_string()))
+                })
+            })
+            .collect();
+        for (section, list) in self.package_lists_mut() {
+            for entry in list {
+                let name = base_package_name(section, entry.name()).to_string();
+                if let Some(description) = described.get(&(section, name)) {
+                    entry.set_description(description.clone());
+                }
+            }
+        }
+    }
+}
+
+fn merge_section(base: &mut Option<Section>, other: Option<Section>, section_name: &str) {
+    if let Some(other) = other {
+        let base = base.get_or_insert_with(Section::default);
+        merge_entries(&mut base.list, other.list, section_name);
+    }
+}
+
+fn merge_entries(base: &mut Vec<PackageEntry>, other: Vec<PackageEntry>, section_name: &str) {
+    for entry in other {
+        let name = base_package_name(section_name, entry.name());
+        match base
+            .iter_mut()
+            .find(|existing| base_package_name(section_name, existing.name()) == name)
+        {
+            Some(existing) => *existing = entry,
+            None => base.push(entry),
+        }
+    }
+}
+
+/// Operational defaults for `apply`. Explicit CLI flags take precedence.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Settings {
+    pub yes: Option<bool>,
+    /// Apply independent sections, and install Snap and Flatpak packages, in
+    /// parallel. `false` forces one job.
+    pub parallel: Option<bool>,
+    pub dry_run: Option<bool>,
+    /// Number of parallel install workers (default: one per CPU).
+    pub jobs: Option<usize>,
+    /// Colored output of the package managers railtube runs.
+    pub color: Option<ColorChoice>,
+}
+
+impl Settings {
+    /// Returns these settings with every value set in `overrides` replaced.
+    pub fn with_overrides(&self, overrides: &Settings) -> Settings {
+        Settings {
+            yes: overrides.yes.or(self.yes),
+            parallel: overrides.parallel.or(self.parallel),
+            dry_run: overrides.dry_run.or(self.dry_run),
+            jobs: overrides.jobs.or(self.jobs),
+            color: overrides.color.or(self.color),
+        }
+    }
+
+    /// Worker count for parallel installs: `Some(1)` when `parallel = false`,
+    /// `None` for the default.
+    pub fn jobs(&self) -> Option<usize> {
+        if self.parallel == Some(false) {
+            Some(1)
+        } else {
+            self.jobs
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct SystemSection {
     #[serde(default)]
     pub update: bool,
+    /// `sudo apt upgrade -y` after the update.
+    #[serde(default)]
+    pub upgrade: bool,
+    /// `sudo apt full-upgrade -y` after the update, instead of `upgrade`.
+    #[serde(default)]
+    pub dist_upgrade: bool,
+    /// `sudo apt autoremove -y` after the upgrade.
+    #[serde(default)]
+    pub autoremove: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl SystemSection {
+    /// The APT commands run after `update`, in order: `full-upgrade` (or
+    /// `upgrade`), then `autoremove`.
+    pub fn apt_commands(&self) -> Vec<&'static str> {
+        let upgrade = if self.dist_upgrade {
+            Some("full-upgrade")
+        } else {
+            self.upgrade.then_some("upgrade")
+        };
+        upgrade
+            .into_iter()
+            .chain(self.autoremove.then_some("autoremove"))
+            .collect()
+    }
+
+    /// Whether `[system]` runs anything at all.
+    pub fn is_active(&self) -> bool {
+        self.update || !self.apt_commands().is_empty()
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Section {
     #[serde(default)]
-    pub list: Vec<String>,
+    pub list: Vec<PackageEntry>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct AptSection {
+    #[serde(default)]
+    pub list: Vec<PackageEntry>,
+    /// Third-party repositories to set up before installing packages, also
+    /// accepted as `repositories`.
+    #[serde(
+        default,
+        alias = "repositories",
+        deserialize_with = "deserialize_apt_repos",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub repos: Vec<AptRepo>,
+    /// Suite or codename passed as `apt install -t <release>` for every
+    /// package without its own `release`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_release: Option<String>,
+    /// Program run instead of `apt` to install packages and update the index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frontend: Option<AptFrontend>,
+}
+
+/// APT front-end of `[apt] frontend`. Installed packages are still read with dpkg.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AptFrontend {
+    #[default]
+    Apt,
+    Aptitude,
+    Nala,
+}
+
+impl AptFrontend {
+    pub fn program(self) -> &'static str {
+        match self {
+            AptFrontend::Apt => "apt",
+            AptFrontend::Aptitude => "aptitude",
+            AptFrontend::Nala => "nala",
+        }
+    }
+}
+
+impl AptSection {
+    /// The packages of `list` followed by the packages of every repository.
+    pub fn entries(&self) -> Vec<PackageEntry> {
+        self.list
+            .iter()
+            .cloned()
+            .chain(
+                self.repos
+                    .iter()
+                    .flat_map(|repo| repo.packages.iter().cloned().map(PackageEntry::Name)),
+            )
+            .collect()
+    }
+}
+
+/// A third-party APT repository: a sources snippet downloaded from a URL, a
+/// Launchpad PPA or a one-line sources entry, plus an optional signing key.
+/// Exactly one of `sources_file`, `ppa` and `line` must be set.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct AptRepo {
+    /// URL of a `.list` or `.sources` file installed into `/etc/apt/sources.list.d/`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sources_file: Option<String>,
+    /// A PPA, `ppa:owner/name`, added with `add-apt-repository`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ppa: Option<String>,
+    /// A one-line sources entry (`deb https://example.com/apt stable main`),
+    /// written to `/etc/apt/sources.list.d/<host and path>.list`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<String>,
+    /// URL of the repository signing key.
+    pub key: Option<String>,
+    /// Where to install the key. Defaults to `/etc/apt/keyrings/<sources file name>.asc`
+    /// (`.gpg` for binary keys), the path the snippet's `signed-by` usually expects.
+    pub key_path: Option<String>,
+    /// Packages installed from this repository.
+    #[serde(default)]
+    pub packages: Vec<String>,
+}
+
+/// Where an `AptRepo` comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AptRepoSource<'a> {
+    SourcesFile(&'a str),
+    Ppa(&'a str),
+    Line(&'a str),
+}
+
+impl AptRepo {
+    /// The repository of a plain string entry: a PPA if it starts with `ppa:`,
+    /// else a one-line sources entry.
+    pub fn from_spec(spec: String) -> Self {
+        if spec.starts_with("ppa:") {
+            AptRepo {
+                ppa: Some(spec),
+                ..Default::default()
+            }
+        } else {
+            AptRepo {
+                line: Some(spec),
+                ..Default::default()
+            }
+        }
+    }
+
+    /// The source of the repository, or `None` unless exactly one of
+    /// `sources_file`, `ppa` and `line` is set.
+    pub fn source(&self) -> Option<AptRepoSource<'_>> {
+        match (&self.sources_file, &self.ppa, &self.line) {
+            (Some(url), None, None) => Some(AptRepoSource::SourcesFile(url)),
+            (None, Some(ppa), None) => Some(AptRepoSource::Ppa(ppa)),
+            (None, None, Some(line)) => Some(AptRepoSource::Line(line)),
+            _ => None,
+        }
+    }
+}
+
+/// Reads `[apt] repos`, whose entries are tables or plain strings (see
+/// `AptRepo::from_spec`).
+fn deserialize_apt_repos<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<AptRepo>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Entry {
+        Spec(String),
+        Detailed(AptRepo),
+    }
+    Ok(Vec::<Entry>::deserialize(deserializer)?
+        .into_iter()
+        .map(|entry| match entry {
+            Entry::Spec(spec) => AptRepo::from_spec(spec),
+            Entry::Detailed(repo) => repo,
+        })
+        .collect())
+}
+
+/// A package entry, either a plain spec string (`"git"`, `"ripgrep=14.0.0"`)
+/// or a table carrying extra per-package settings.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum PackageEntry {
+    Name(String),
+    Detailed(PackageDetails),
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PackageDetails {
+    pub name: String,
+    /// Why the package is in the manifest. Kept when `export` rewrites the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Flatpak permission overrides, passed to `flatpak override --user`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overrides: Vec<String>,
+    /// APT target release (`-t`), overriding `[apt] default_release`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release: Option<String>,
+    /// Groups selectable with `apply --group`. Without any, the package
+    /// belongs to every group.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// Cargo crate version, the same as writing `name=version`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Cargo features to enable (`--features`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    /// Git repository to install the crate from (`cargo install --git`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git: Option<String>,
+    /// Branch of `git` to install from (`--branch`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+}
+
+impl PackageEntry {
+    pub fn name(&self) -> &str {
+        match self {
+            PackageEntry::Name(name) => name,
+            PackageEntry::Detailed(details) => &details.name,
+        }
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        match self {
+            PackageEntry::Name(existing) => *existing = name,
+            PackageEntry::Detailed(details) => details.name = name,
+        }
+    }
+
+    pub fn overrides(&self) -> &[String] {
+        match self {
+            PackageEntry::Name(_) => &[],
+            PackageEntry::Detailed(details) => &details.overrides,
+        }
+    }
+
+    pub fn release(&self) -> Option<&str> {
+        match self {
+            PackageEntry::Name(_) => None,
+            PackageEntry::Detailed(details) => details.release.as_deref(),
+        }
+    }
+
+    pub fn groups(&self) -> &[String] {
+        match self {
+            PackageEntry::Name(_) => &[],
+            PackageEntry::Detailed(details) => &details.groups,
+        }
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        match self {
+            PackageEntry::Name(_) => None,
+            PackageEntry::Detailed(details) => details.version.as_deref(),
+        }
+    }
+
+    pub fn features(&self) -> &[String] {
+        match self {
+            PackageEntry::Name(_) => &[],
+            PackageEntry::Detailed(details) => &details.features,
+        }
+    }
+
+    /// The git repository and branch, if any, to install from.
+    pub fn git(&self) -> Option<(&str, Option<&str>)> {
+        match self {
+            PackageEntry::Name(_) => None,
+            PackageEntry::Detailed(details) => details
+                .git
+                .as_deref()
+                .map(|git| (git, details.branch.as_deref())),
+        }
+    }
+
+    /// Whether the package belongs to `group`: untagged packages belong to all groups.
+    pub fn in_group(&self, group: &str) -> bool {
+        self.groups().is_empty() || self.groups().iter().any(|g| g == group)
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        match self {
+            PackageEntry::Name(_) => None,
+            PackageEntry::Detailed(details) => details.description.as_deref(),
+        }
+    }
+
+    /// Sets the description, turning a plain spec into the table form.
+    pub fn set_description(&mut self, description: String) {
+        match self {
+            PackageEntry::Name(name) => {
+                *self = PackageEntry::Detailed(PackageDetails {
+                    name: std::mem::take(name),
+                    description: Some(description),
+                    ..Default::default()
+                })
+            }
+            PackageEntry::Detailed(details) => details.description = Some(description),
+        }
+    }
+}
+
+impl From<String> for PackageEntry {
+    fn from(name: String) -> Self {
+        PackageEntry::Name(name)
+    }
+}
+
+/// Rust toolchains and components managed with rustup.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct RustupSection {
+    /// Toolchains such as `stable` or `1.79.0`, installed with `rustup toolchain install`.
+    #[serde(default)]
+    pub toolchains: Vec<String>,
+    /// Components such as `clippy`, added to the default toolchain with `rustup component add`.
+    #[serde(default)]
+    pub components: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl RustupSection {
+    /// The toolchains followed by the components, as package entries.
+    pub fn entries(&self) -> Vec<PackageEntry> {
+        self.toolchains
+            .iter()
+            .chain(&self.components)
+            .cloned()
+            .map(PackageEntry::Name)
+            .collect()
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct DebSection {
     #[serde(default)]
-    pub urls: Vec<String>,
+    pub urls: Vec<DebEntry>,
+}
+
+/// A `.deb` URL or local path, optionally with the SHA-256 the file must match.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum DebEntry {
+    Url(String),
+    Detailed {
+        #[serde(alias = "path")]
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sha256: Option<String>,
+    },
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl DebEntry {
+    pub fn url(&self) -> &str {
+        match self {
+            DebEntry::Url(url) | DebEntry::Detailed { url, .. } => url,
+        }
+    }
+
+    pub fn sha256(&self) -> Option<&str> {
+        match self {
+            DebEntry::Url(_) => None,
+            DebEntry::Detailed { sha256, .. } => sha256.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct ScriptsSection {
     #[serde(flatten)]
-    pub commands: HashMap<String, String>,
+    pub commands: HashMap<String, ScriptEntry>,
+}
+
+/// An AppImage installed as `~/.local/bin/<name>`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AppImageEntry {
+    pub url: String,
+    pub name: String,
+    /// Also write a `.desktop` entry so the app shows up in application menus.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desktop: Option<bool>,
+}
+
+impl AppImageEntry {
+    pub fn wants_desktop_entry(&self) -> bool {
+        self.desktop.unwrap_or(false)
+    }
+}
+
+/// A script, either a plain shell command or a table naming the scripts that
+/// must run before it, the command undoing it, a description and the shell.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ScriptEntry {
+    Command(String),
+    Detailed {
+        #[serde(alias = "command")]
+        cmd: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        after: Vec<String>,
+        /// Teardown command, run by `railtube run --down`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        down: Option<String>,
+        /// Shown by `railtube list-scripts`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        description: Option<String>,
+        /// Shell the commands run with, as `<shell> -c` (default: `sh`).
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        shell: Option<String>,
+    },
+}
+
+impl ScriptEntry {
+    pub fn command(&self) -> &str {
+        match self {
+            ScriptEntry::Command(cmd) | ScriptEntry::Detailed { cmd, .. } => cmd,
+        }
+    }
+
+    /// Scripts that run before this one.
+    pub fn after(&self) -> &[String] {
+        match self {
+            ScriptEntry::Command(_) => &[],
+            ScriptEntry::Detailed { after, .. } => after,
+        }
+    }
+
+    /// The command undoing this script, if any.
+    pub fn down(&self) -> Option<&str> {
+        match self {
+            ScriptEntry::Command(_) => None,
+            ScriptEntry::Detailed { down, .. } => down.as_deref(),
+        }
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        match self {
+            ScriptEntry::Command(_) => None,
+            ScriptEntry::Detailed { description, .. } => description.as_deref(),
+        }
+    }
+
+    /// The shell the command and teardown run with.
+    pub fn shell(&self) -> &str {
+        match self {
+            ScriptEntry::Detailed {
+                shell: Some(shell), ..
+            } => shell,
+            _ => "sh",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PostSection {
+    /// Caches to refresh after an apply that installed something.
+    #[serde(default)]
+    pub refresh: Vec<RefreshHook>,
+}
+
+/// Shell commands run with `sh -c` before (`pre_<section>`) and after
+/// (`post_<section>`) a section is applied.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HooksSection {
+    pub pre_apt: Option<String>,
+    pub post_apt: Option<String>,
+    pub pre_snap: Option<String>,
+    pub post_snap: Option<String>,
+    pub pre_flatpak: Option<String>,
+    pub post_flatpak: Option<String>,
+    pub pre_rustup: Option<String>,
+    pub post_rustup: Option<String>,
+    pub pre_cargo: Option<String>,
+    pub post_cargo: Option<String>,
+    pub pre_pipx: Option<String>,
+    pub post_pipx: Option<String>,
+    pub pre_npm: Option<String>,
+    pub post_npm: Option<String>,
+    pub pre_go: Option<String>,
+    pub post_go: Option<String>,
+    pub pre_gem: Option<String>,
+    pub post_gem: Option<String>,
+    pub pre_mise: Option<String>,
+    pub post_mise: Option<String>,
+    pub pre_xbps: Option<String>,
+    pub post_xbps: Option<String>,
+    pub pre_dnf: Option<String>,
+    pub post_dnf: Option<String>,
+    pub pre_nix: Option<String>,
+    pub post_nix: Option<String>,
+    pub pre_brew_tap: Option<String>,
+    pub post_brew_tap: Option<String>,
+    pub pre_brew: Option<String>,
+    pub post_brew: Option<String>,
+    pub pre_brew_cask: Option<String>,
+    pub post_brew_cask: Option<String>,
+    pub pre_deb: Option<String>,
+    pub post_deb: Option<String>,
+    pub pre_appimage: Option<String>,
+    pub post_appimage: Option<String>,
+}
+
+impl HooksSection {
+    fn hooks_mut(&mut self) -> [&mut Option<String>; 36] {
+        [
+            &mut self.pre_apt,
+            &mut self.post_apt,
+            &mut self.pre_snap,
+            &mut self.post_snap,
+            &mut self.pre_flatpak,
+            &mut self.post_flatpak,
+            &mut self.pre_rustup,
+            &mut self.post_rustup,
+            &mut self.pre_cargo,
+            &mut self.post_cargo,
+            &mut self.pre_pipx,
+            &mut self.post_pipx,
+            &mut self.pre_npm,
+            &mut self.post_npm,
+            &mut self.pre_go,
+            &mut self.post_go,
+            &mut self.pre_gem,
+            &mut self.post_gem,
+            &mut self.pre_mise,
+            &mut self.post_mise,
+            &mut self.pre_xbps,
+            &mut self.post_xbps,
+            &mut self.pre_dnf,
+            &mut self.post_dnf,
+            &mut self.pre_nix,
+            &mut self.post_nix,
+            &mut self.pre_brew_tap,
+            &mut self.post_brew_tap,
+            &mut self.pre_brew,
+            &mut self.post_brew,
+            &mut self.pre_brew_cask,
+            &mut self.post_brew_cask,
+            &mut self.pre_deb,
+            &mut self.post_deb,
+            &mut self.pre_appimage,
+            &mut self.post_appimage,
+        ]
+    }
+
+    /// Hooks set in `other` replace those of `self`.
+    fn merge(&mut self, mut other: HooksSection) {
+        for (hook, other_hook) in self.hooks_mut().into_iter().zip(other.hooks_mut()) {
+            if other_hook.is_some() {
+                *hook = other_hook.take();
+            }
+        }
+    }
+
+    /// The hook run before `section` is applied.
+    pub fn pre(&self, section: &str) -> Option<&str> {
+        match section {
+            "apt" => &self.pre_apt,
+            "snap" => &self.pre_snap,
+            "flatpak" => &self.pre_flatpak,
+            "rustup" => &self.pre_rustup,
+            "cargo" => &self.pre_cargo,
+            "pipx" => &self.pre_pipx,
+            "npm" => &self.pre_npm,
+            "go" => &self.pre_go,
+            "gem" => &self.pre_gem,
+            "mise" => &self.pre_mise,
+            "xbps" => &self.pre_xbps,
+            "dnf" => &self.pre_dnf,
+            "nix" => &self.pre_nix,
+            "brew_tap" => &self.pre_brew_tap,
+            "brew" => &self.pre_brew,
+            "brew_cask" => &self.pre_brew_cask,
+            "deb" => &self.pre_deb,
+            "appimage" => &self.pre_appimage,
+            _ => &None,
+        }
+        .as_deref()
+    }
+
+    /// The hook run after `section` was applied.
+    pub fn post(&self, section: &str) -> Option<&str> {
+        match section {
+            "apt" => &self.post_apt,
+            "snap" => &self.post_snap,
+            "flatpak" => &self.post_flatpak,
+            "rustup" => &self.post_rustup,
+            "cargo" => &self.post_cargo,
+            "pipx" => &self.post_pipx,
+            "npm" => &self.post_npm,
+            "go" => &self.post_go,
+            "gem" => &self.post_gem,
+            "mise" => &self.post_mise,
+            "xbps" => &self.post_xbps,
+            "dnf" => &self.post_dnf,
+            "nix" => &self.post_nix,
+            "brew_tap" => &self.post_brew_tap,
+            "brew" => &self.post_brew,
+            "brew_cask" => &self.post_brew_cask,
+            "deb" => &self.post_deb,
+            "appimage" => &self.post_appimage,
+            _ => &None,
+        }
+        .as_deref()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RefreshHook {
+    /// `sudo ldconfig`, after APT or deb installs.
+    Ldconfig,
+    /// `sudo update-desktop-database`, after APT, deb or Snap installs.
+    Desktop,
+    /// `fc-cache -f`, after a font package was installed.
+    Fonts,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_system_apt_commands_order() {
+        let system = |toml: &str| toml::from_str::<SystemSection>(toml).unwrap();
+        assert!(!system("update = false").is_active());
+        assert_eq!(
+            system("autoremove = true\nupgrade = true").apt_commands(),
+            ["upgrade", "autoremove"]
+        );
+        // dist_upgrade supersedes upgrade.
+        let both = system("upgrade = true\ndist_upgrade = true\nautoremove = true");
+        assert_eq!(both.apt_commands(), ["full-upgrade", "autoremove"]);
+        assert!(both.is_active());
+    }
+
+    #[test]
+    fn test_settings_cli_overrides_manifest() {
+        let manifest: Config = toml::from_str(
+            r#"
+[settings]
+yes = true
+parallel = false
+color = "never"
+"#,
+        )
+        .unwrap();
+        let settings = manifest.settings.unwrap();
+        assert_eq!(settings.jobs(), Some(1));
+
+        let resolved = settings.with_overrides(&Settings {
+            yes: Some(false),
+            parallel: Some(true),
+            jobs: Some(8),
+            ..Default::default()
+        });
+        assert_eq!(resolved.yes, Some(false));
+        assert_eq!(resolved.jobs(), Some(8));
+        assert_eq!(resolved.color, Some(ColorChoice::Never));
+        assert_eq!(resolved.dry_run, None);
+
+        let defaults: Config = toml::from_str("[defaults]\nyes = true\njobs = 4\n").unwrap();
+        let settings = defaults.settings.unwrap();
+        assert_eq!((settings.yes, settings.jobs()), (Some(true), Some(4)));
+        assert!(toml::from_str::<Config>("[settings]\n[defaults]\n").is_err());
+    }
+
+    #[test]
+    fn test_keep_descriptions_across_export() {
+        let previous: Config = toml::from_str(
+            r#"
+[apt]
+list = [{ name = "git=1:2.39.2-1", description = "needed by the deploy scripts" }]
+
+[cargo]
+list = ["bat", { name = "ripgrep", description = "fast grep" }]
+"#,
+        )
+        .unwrap();
+        let mut exported: Config = toml::from_str(
+            r#"
+[apt]
+list = ["git", "curl"]
+
+[cargo]
+list = ["ripgrep", "bat"]
+"#,
+        )
+        .unwrap();
+        exported.keep_descriptions(previous);
+
+        let apt = &exported.apt.as_ref().unwrap().list;
+        assert_eq!(apt[0].description(), Some("needed by the deploy scripts"));
+        assert_eq!(apt[0].name(), "git");
+        assert_eq!(apt[1].description(), None);
+        let cargo = &exported.cargo.as_ref().unwrap().list;
+        assert_eq!(cargo[0].description(), Some("fast grep"));
+
+        let toml = toml::to_string(&exported).unwrap();
+        assert!(toml.contains("description = \"fast grep\""));
+        assert!(toml.contains("\"bat\""));
+    }
+
+    #[test]
+    fn test_deb_entries_accept_urls_and_checksums() {
+        let config: Config = toml::from_str(
+            r#"
+[deb]
+urls = [
+    "https://example.com/a.deb",
+    { url = "https://example.com/b.deb", sha256 = "abc123" },
+]
+"#,
+        )
+        .unwrap();
+        let urls = &config.deb.unwrap().urls;
+        assert_eq!(urls[0].url(), "https://example.com/a.deb");
+        assert_eq!(urls[0].sha256(), None);
+        assert_eq!(urls[1].url(), "https://example.com/b.deb");
+        assert_eq!(urls[1].sha256(), Some("abc123"));
+    }
+
+    #[test]
+    fn test_hooks_merge_and_lookup() {
+        let mut base: Config = toml::from_str(
+            r#"
+[hooks]
+pre_apt = "echo base"
+post_deb = "echo deb"
+post_appimage = "update-desktop-database ~/.local/share/applications"
+"#,
+        )
+        .unwrap();
+        let host: Config = toml::from_str(
+            r#"
+[hooks]
+pre_apt = "echo host"
+pre_brew = "brew update"
+post_rustup = "rustup default stable"
+"#,
+        )
+        .unwrap();
+        base.merge(host);
+
+        let hooks = base.hooks.unwrap();
+        assert_eq!(hooks.pre("apt"), Some("echo host"));
+        assert_eq!(hooks.post("deb"), Some("echo deb"));
+        assert_eq!(hooks.post("apt"), None);
+        assert_eq!(hooks.pre("brew"), Some("brew update"));
+        assert_eq!(hooks.post("rustup"), Some("rustup default stable"));
+        assert_eq!(
+            hooks.post("appimage"),
+            Some("update-desktop-database ~/.local/share/applications")
+        );
+        assert!(toml::from_str::<Config>("[hooks]\npre_ap = \"typo\"").is_err());
+    }
 }
@@ -0,0 +1,155 @@
+use anstream::ColorChoice;
+use anstyle::{AnsiColor, Style};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+static QUIET: AtomicBool = AtomicBool::new(false);
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+/// How much railtube prints on the console (`-q`, `-v`, `-vv`). The log file
+/// always gets the full detail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Only warnings and errors.
+    Quiet,
+    Normal,
+    /// Also every command railtube runs.
+    Verbose,
+    /// Also the output of those commands, as it arrives.
+    Debug,
+}
+
+impl Verbosity {
+    /// The level for `-q` and the number of `-v` flags.
+    pub fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::Debug,
+        }
+    }
+}
+
+/// How a console message is styled. Warnings and errors go to stderr, the rest to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    /// Nothing to do, e.g. a package that is already installed (dim).
+    Skip,
+    /// A command or install being run (cyan).
+    Step,
+    Success,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn style(self) -> Style {
+        match self {
+            Level::Info => Style::new(),
+            Level::Skip => Style::new().dimmed(),
+            Level::Step => AnsiColor::Cyan.on_default(),
+            Level::Success => AnsiColor::Green.on_default(),
+            Level::Warn => AnsiColor::Yellow.on_default(),
+            Level::Error => AnsiColor::Red.on_default().bold(),
+        }
+    }
+}
+
+/// Turns colors off for the rest of the run (`--no-color`). Without it, colors
+/// are shown unless `NO_COLOR` is set or the stream is not a terminal.
+pub fn disable_color() {
+    ColorChoice::Never.write_global();
+}
+
+/// Sends all messages, and the output of the commands railtube runs, to stderr
+/// so that stdout only carries machine-readable output (`apply --format json`).
+pub fn reserve_stdout() {
+    STDOUT_RESERVED.store(true, Ordering::Relaxed);
+}
+
+pub fn stdout_reserved() -> bool {
+    STDOUT_RESERVED.load(Ordering::Relaxed)
+}
+
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        2 => Verbosity::Verbose,
+        _ => Verbosity::Debug,
+    }
+}
+
+/// Hides all but warnings and errors while set, e.g. while `apply --plan`
+/// works out its plan.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Prints one line at `level`. Escape codes are stripped when the stream does
+/// not show colors; the log file is written separately and stays plain.
+pub fn print(level: Level, args: fmt::Arguments) {
+    let style = level.style();
+    match level {
+        Level::Warn | Level::Error => anstream::eprintln!("{style}{args}{style:#}"),
+        _ if QUIET.load(Ordering::Relaxed) || verbosity() == Verbosity::Quiet => {}
+        _ if stdout_reserved() => anstream::eprintln!("{style}{args}{style:#}"),
+        _ => anstream::println!("{style}{args}{style:#}"),
+    }
+}
+
+/// `text` styled for `level` if stdout shows colors, for reports written
+/// through a plain `Write` such as doctor's.
+pub fn paint(level: Level, text: impl fmt::Display) -> String {
+    if anstream::AutoStream::choice(&io::stdout()) == ColorChoice::Never {
+        return text.to_string();
+    }
+    let style = level.style();
+    format!("{style}{text}{style:#}")
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::console::print($crate::console::Level::Info, format_args!($($arg)*))
+    };
+}
+
+macro_rules! skip {
+    ($($arg:tt)*) => {
+        $crate::console::print($crate::console::Level::Skip, format_args!($($arg)*))
+    };
+}
+
+macro_rules! step {
+    ($($arg:tt)*) => {
+        $crate::console::print($crate::console::Level::Step, format_args!($($arg)*))
+    };
+}
+
+macro_rules! success {
+    ($($arg:tt)*) => {
+        $crate::console::print($crate::console::Level::Success, format_args!($($arg)*))
+    };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::console::print($crate::console::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::console::print($crate::console::Level::Error, format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {error, info, skip, step, success, warning};
@@ -53,3 +53,32 @@ pub enum AppError {
     #[error("Other Error: {0}")]
     Other(#[from] Box<dyn std::error::Error + Send + Sync>),
 }
+
+/// Exit status of an error that has no more specific one.
+pub const EXIT_ERROR: u8 = 1;
+/// Exit status of a `--keep-going` apply in which some packages failed.
+pub const EXIT_PARTIAL_FAILURE: u8 = 2;
+/// Exit status when a manifest is not valid TOML or does not match the format.
+pub const EXIT_MANIFEST_ERROR: u8 = 3;
+
+impl AppError {
+    /// The process exit status this error ends railtube with.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            AppError::TomlDe(_) => EXIT_MANIFEST_ERROR,
+            _ => EXIT_ERROR,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        let parse_error = toml::from_str::<toml::Table>("[apt").unwrap_err();
+        assert_eq!(AppError::from(parse_error).exit_code(), EXIT_MANIFEST_ERROR);
+        assert_eq!(AppError::Other("failed".into()).exit_code(), EXIT_ERROR);
+    }
+}
@@ -0,0 +1,224 @@
+use crate::console::{Level, paint, warning};
+use crate::errors::AppError;
+use crate::report::{ApplyReport, PackageAction, PackageResult};
+use crate::utils::{format_utc_timestamp, log_file};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// `history.jsonl`, in the directory of the log file (`--log-file`).
+pub fn history_file() -> PathBuf {
+    log_file().with_file_name("history.jsonl")
+}
+
+/// One `apply` run, stored as one line of the history file.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ApplyRun {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub duration_ms: u128,
+    /// The sections the run applied, in manifest order.
+    pub sections: Vec<String>,
+    /// What happened to each package the run installed, removed, declined or
+    /// failed on. Packages that were already installed are only counted.
+    pub packages: Vec<PackageResult>,
+    pub skipped: usize,
+    /// The packages this run installed that were not installed before it,
+    /// per section: what `rollback` removes.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub new_installs: BTreeMap<String, Vec<String>>,
+    /// The error that stopped the run, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ApplyRun {
+    pub fn new(
+        sections: &[&str],
+        report: &ApplyReport,
+        new_installs: BTreeMap<String, Vec<String>>,
+        outcome: &Result<(), AppError>,
+    ) -> Self {
+        let (skipped, packages): (Vec<_>, Vec<_>) = report
+            .results()
+            .into_iter()
+            .partition(|result| result.action == PackageAction::Skipped);
+        ApplyRun {
+            timestamp: report.started_at_secs(),
+            duration_ms: report.elapsed().as_millis(),
+            sections: sections.iter().map(|s| s.to_string()).collect(),
+            packages,
+            skipped: skipped.len(),
+            new_installs,
+            error: outcome.as_ref().err().map(ToString::to_string),
+        }
+    }
+
+    /// Appends the run to the history at `path`, creating its directory.
+    pub fn append(&self, path: &Path) -> Result<(), AppError> {
+        let line = serde_json::to_string(self).map_err(|e| AppError::Other(Box::new(e)))?;
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+
+    fn count(&self, action: PackageAction) -> usize {
+        self.packages.iter().filter(|p| p.action == action).count()
+    }
+}
+
+/// The runs recorded at `path`, oldest first. A missing file is an empty
+/// history; unreadable lines, e.g. from a run killed mid-write, are skipped.
+pub fn read_history(path: &Path) -> Result<Vec<ApplyRun>, AppError> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(AppError::Other(
+                format!("Failed to read history {}: {}", path.display(), e).into(),
+            ));
+        }
+    };
+    let mut runs = Vec::new();
+    for (index, line) in io::BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(run) => runs.push(run),
+            Err(e) => warning!(
+                "Warning: {}:{}: skipping invalid history record: {}",
+                path.display(),
+                index + 1,
+                e
+            ),
+        }
+    }
+    Ok(runs)
+}
+
+/// Prints `runs`, numbered from `first_id`, one heading per run followed by
+/// its package outcomes.
+pub fn print_runs(runs: &[ApplyRun], first_id: usize, out: &mut impl Write) -> io::Result<()> {
+    for (index, run) in runs.iter().enumerate() {
+        if index > 0 {
+            writeln!(out)?;
+        }
+        let sections = if run.sections.is_empty() {
+            "no sections".to_string()
+        } else {
+            run.sections.join(", ")
+        };
+        let status = match &run.error {
+            Some(_) => paint(Level::Error, "failed"),
+            None if run.count(PackageAction::Failed) > 0 => {
+                paint(Level::Warn, "finished with failures")
+            }
+            None => paint(Level::Success, "ok"),
+        };
+        writeln!(
+            out,
+            "#{}  {}  {}  [{}] in {:.1}s",
+            first_id + index,
+            format_utc_timestamp(run.timestamp),
+            status,
+            sections,
+            run.duration_ms as f64 / 1000.0
+        )?;
+        for package in &run.packages {
+            let level = match package.action {
+                PackageAction::Installed | PackageAction::Removed => Level::Success,
+                PackageAction::Failed => Level::Error,
+                _ => Level::Info,
+            };
+            write!(
+                out,
+                "  {} [{}] {}",
+                paint(level, format!("{:<13}", package.action.as_str())),
+                package.section,
+                package.name
+            )?;
+            if let Some(duration_ms) = package.duration_ms {
+                write!(out, " ({:.1}s)", duration_ms as f64 / 1000.0)?;
+            }
+            writeln!(out)?;
+        }
+        writeln!(
+            out,
+            "  {} installed, {} removed, {} failed, {} already installed",
+            run.count(PackageAction::Installed),
+            run.count(PackageAction::Removed),
+            run.count(PackageAction::Failed),
+            run.skipped
+        )?;
+        if let Some(error) = &run.error {
+            writeln!(out, "  {}", paint(Level::Error, error.trim_end()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_round_trip_skips_invalid_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("history.jsonl");
+        assert!(read_history(&path).unwrap().is_empty());
+
+        let report = ApplyReport::new();
+        report.record("apt", "git", PackageAction::Skipped);
+        report.record("cargo", "ripgrep", PackageAction::Installed);
+        let new_installs = BTreeMap::from([("cargo".to_string(), vec!["ripgrep".to_string()])]);
+        let ok = ApplyRun::new(&["apt", "cargo"], &report, new_installs, &Ok(()));
+        ok.append(&path).unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"timestamp\":\n")
+            .unwrap();
+        let failed = ApplyRun::new(
+            &["snap"],
+            &ApplyReport::new(),
+            BTreeMap::new(),
+            &Err(AppError::Other("snap install failed".into())),
+        );
+        failed.append(&path).unwrap();
+
+        let runs = read_history(&path).unwrap();
+        assert_eq!(runs, vec![ok, failed]);
+        assert_eq!(runs[0].skipped, 1);
+        assert_eq!(runs[0].packages.len(), 1);
+        assert_eq!(runs[0].packages[0].name, "ripgrep");
+        assert!(
+            runs[1]
+                .error
+                .as_deref()
+                .unwrap()
+                .contains("snap install failed")
+        );
+
+        let mut out = Vec::new();
+        print_runs(&runs, 1, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("#1  "), "{}", out);
+        assert!(out.contains("\n#2  "), "{}", out);
+        assert!(out.contains("[apt, cargo]"), "{}", out);
+        assert!(out.contains("[cargo] ripgrep"), "{}", out);
+        assert!(out.contains("1 installed, 0 removed, 0 failed, 1 already installed"));
+        assert!(out.contains("snap install failed"), "{}", out);
+    }
+}
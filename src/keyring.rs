@@ -0,0 +1,250 @@
+use crate::errors::AppError;
+use crate::utils::create_temp_dir;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A trusted public key, as read by `gpg --show-keys`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustedKey {
+    pub fingerprint: String,
+    pub user_ids: Vec<String>,
+}
+
+/// `$XDG_CONFIG_HOME/railtube/keyring`, holding one `<fingerprint>.asc` per
+/// trusted key.
+pub fn keyring_dir() -> Result<PathBuf, AppError> {
+    dirs::config_dir()
+        .map(|dir| dir.join("railtube").join("keyring"))
+        .ok_or_else(|| AppError::Other("Could not determine the config directory.".into()))
+}
+
+/// Reads the keys in `path` without importing them into any GnuPG keyring.
+fn show_keys(path: &Path) -> Result<Vec<TrustedKey>, AppError> {
+    let output = Command::new("gpg")
+        .args(["--show-keys", "--with-colons"])
+        .arg(path)
+        .output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::Other(
+            format!("Failed to read public key {}: {}", path.display(), stderr).into(),
+        ));
+    }
+
+    Ok(parse_gpg_colons(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parses `gpg --with-colons` output: a `pub` record starts a key, the first
+/// `fpr` record after it is the primary key's fingerprint, and `uid` records
+/// carry the user IDs in field 10.
+fn parse_gpg_colons(stdout: &str) -> Vec<TrustedKey> {
+    let mut keys: Vec<TrustedKey> = Vec::new();
+    let mut awaiting_fingerprint = false;
+    for line in stdout.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.first() {
+            Some(&"pub") => awaiting_fingerprint = true,
+            Some(&"fpr") if awaiting_fingerprint => {
+                if let Some(fingerprint) = fields.get(9) {
+                    keys.push(TrustedKey {
+                        fingerprint: fingerprint.to_string(),
+                        user_ids: Vec::new(),
+                    });
+                }
+                awaiting_fingerprint = false;
+            }
+            Some(&"uid") => {
+                if let (Some(key), Some(uid)) = (keys.last_mut(), fields.get(9)) {
+                    key.user_ids.push(uid.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    keys
+}
+
+/// Copies the public key in `file` into the keyring.
+pub fn import_key(file: &Path) -> Result<TrustedKey, AppError> {
+    let mut keys = show_keys(file)?;
+    if keys.len() != 1 {
+        return Err(AppError::Other(
+            format!(
+                "{} contains {} keys; import one public key at a time.",
+                file.display(),
+                keys.len()
+            )
+            .into(),
+        ));
+    }
+    let key = keys.remove(0);
+    let dir = keyring_dir()?;
+    std::fs::create_dir_all(&dir)?;
+    std::fs::copy(file, dir.join(format!("{}.asc", key.fingerprint)))?;
+    Ok(key)
+}
+
+/// The `.asc` files in the keyring, one per trusted key.
+fn key_files() -> Result<Vec<PathBuf>, AppError> {
+    let dir = keyring_dir()?;
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "asc") {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Lists the trusted keys, sorted by fingerprint.
+pub fn list_keys() -> Result<Vec<TrustedKey>, AppError> {
+    let mut keys = Vec::new();
+    for path in key_files()? {
+        keys.extend(show_keys(&path)?);
+    }
+    keys.sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+    Ok(keys)
+}
+
+/// Checks that `signature` is a detached signature of `content` by one of the
+/// trusted keys and returns that key's fingerprint. The keys are imported
+/// into a throwaway GnuPG home, so the user's own keyring plays no part.
+pub fn verify_signature(content: &[u8], signature: &[u8]) -> Result<String, AppError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let files = key_files()?;
+    if files.is_empty() {
+        return Err(AppError::Other(
+            format!(
+                "No trusted keys in {}; add the signer's key with `railtube keyring import`.",
+                keyring_dir()?.display()
+            )
+            .into(),
+        ));
+    }
+    let home = create_temp_dir()?;
+    // gpg warns about a home directory others can read.
+    std::fs::set_permissions(home.path(), std::fs::Permissions::from_mode(0o700))?;
+    let gpg = |args: &[&OsStr]| {
+        Command::new("gpg")
+            .arg("--homedir")
+            .arg(home.path())
+            .args(["--batch", "--no-tty", "--status-fd", "1"])
+            .args(args)
+            .output()
+    };
+
+    let mut import: Vec<&OsStr> = vec!["--import".as_ref()];
+    import.extend(files.iter().map(|path| path.as_os_str()));
+    let output = gpg(&import)?;
+    if !output.status.success() {
+        return Err(AppError::Other(
+            format!(
+                "Failed to import the trusted keys: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .into(),
+        ));
+    }
+
+    let data = home.path().join("manifest");
+    let sig = home.path().join("manifest.sig");
+    std::fs::write(&data, content)?;
+    std::fs::write(&sig, signature)?;
+    let output = gpg(&["--verify".as_ref(), sig.as_os_str(), data.as_os_str()])?;
+    match parse_validsig(&String::from_utf8_lossy(&output.stdout)) {
+        Some(fingerprint) if output.status.success() => Ok(fingerprint),
+        _ => Err(AppError::Other(
+            format!(
+                "The manifest signature does not verify against a trusted key: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into(),
+        )),
+    }
+}
+
+/// The primary key fingerprint of the `VALIDSIG` status line `gpg
+/// --status-fd` prints for a good signature. It is the line's last field,
+/// after the fingerprint of the (sub)key that made the signature.
+fn parse_validsig(status: &str) -> Option<String> {
+    status.lines().find_map(|line| {
+        let fields: Vec<&str> = line
+            .strip_prefix("[GNUPG:] VALIDSIG ")?
+            .split_whitespace()
+            .collect();
+        fields.get(9).or(fields.first()).map(|fpr| fpr.to_string())
+    })
+}
+
+/// Removes the key whose fingerprint is, or ends with, `fingerprint` (so a
+/// long key ID works too). Fails unless exactly one key matches.
+pub fn remove_key(fingerprint: &str) -> Result<String, AppError> {
+    let wanted = fingerprint.replace(' ', "").to_ascii_uppercase();
+    let dir = keyring_dir()?;
+    let matches: Vec<String> = list_keys()?
+        .into_iter()
+        .map(|key| key.fingerprint)
+        .filter(|fpr| fpr.ends_with(&wanted))
+        .collect();
+    match matches.as_slice() {
+        [fpr] => {
+            std::fs::remove_file(dir.join(format!("{}.asc", fpr)))?;
+            Ok(fpr.clone())
+        }
+        [] => Err(AppError::Other(
+            format!("No trusted key matches {}.", fingerprint).into(),
+        )),
+        _ => Err(AppError::Other(
+            format!(
+                "{} matches {} keys; give the full fingerprint.",
+                fingerprint,
+                matches.len()
+            )
+            .into(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gpg_colons() {
+        let stdout = "\
+pub:-:4096:1:0123456789ABCDEF:1700000000:::-:::scESC::::::23::0:
+fpr:::::::::AAAABBBBCCCCDDDDEEEEFFFF0123456789ABCDEF:
+uid:-::::1700000000::HASH::Jane Doe <jane@example.com>::::::::::0:
+sub:-:4096:1:FEDCBA9876543210:1700000000::::::e::::::23:
+fpr:::::::::1111222233334444555566667777FEDCBA987654:
+";
+        assert_eq!(
+            parse_gpg_colons(stdout),
+            vec![TrustedKey {
+                fingerprint: "AAAABBBBCCCCDDDDEEEEFFFF0123456789ABCDEF".to_string(),
+                user_ids: vec!["Jane Doe <jane@example.com>".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_validsig() {
+        let status = "\
+[GNUPG:] NEWSIG
+[GNUPG:] GOODSIG FEDCBA9876543210 Jane Doe <jane@example.com>
+[GNUPG:] VALIDSIG 1111222233334444555566667777FEDCBA987654 2024-01-01 1700000000 0 4 0 1 10 00 AAAABBBBCCCCDDDDEEEEFFFF0123456789ABCDEF
+";
+        assert_eq!(
+            parse_validsig(status).as_deref(),
+            Some("AAAABBBBCCCCDDDDEEEEFFFF0123456789ABCDEF")
+        );
+        assert_eq!(parse_validsig("[GNUPG:] BADSIG FEDCBA9876543210\n"), None);
+    }
+}
@@ -0,0 +1,405 @@
+use crate::config::Config;
+use crate::console::info;
+use crate::errors::AppError;
+use crate::keyring::verify_signature;
+use crate::template::{TEMPLATE_SUFFIX, render_manifest};
+use crate::utils::{
+    fetch_bytes, fetch_optional_toml_content, fetch_toml_content, is_url, system_hostname,
+};
+use std::path::{Path, PathBuf};
+
+/// Shared manifest merged under every host manifest of a manifest directory.
+pub const BASE_MANIFEST: &str = "base.toml";
+/// Directory holding per-host manifests (`hosts/<host>.toml`).
+pub const HOSTS_DIR: &str = "hosts";
+
+/// Loads the manifest at `source`.
+///
+/// When `source` is a directory or a URL base (not ending in `.toml`),
+/// `<source>/hosts/<host>.toml` is merged over `<source>/base.toml`, with
+/// `host` defaulting to the system hostname.
+pub fn load_config(source: &str, host: Option<&str>) -> Result<Config, AppError> {
+    if !is_manifest_base(source) {
+        return load_config_recursive(source, &mut Vec::new());
+    }
+
+    let host = resolve_host(host)?;
+    let base_source = join_source(source, BASE_MANIFEST);
+    let host_source = join_source(source, &format!("{}/{}.toml", HOSTS_DIR, host));
+
+    let base = read_optional_manifest(&base_source)?;
+    let host_manifest = read_optional_manifest(&host_source)?;
+    if base.is_none() && host_manifest.is_none() {
+        return Err(AppError::Other(
+            format!("Neither '{}' nor '{}' exists.", base_source, host_source).into(),
+        ));
+    }
+
+    let mut config = match base {
+        Some(content) => resolve_includes(&base_source, &content, &mut Vec::new())?,
+        None => Config::default(),
+    };
+    match host_manifest {
+        Some(content) => {
+            info!("Using host manifest {}", host_source);
+            config.merge(resolve_includes(&host_source, &content, &mut Vec::new())?);
+        }
+        None => eprintln!(
+            "Warning: No manifest for host '{}' at {}, using {} only.",
+            host, host_source, base_source
+        ),
+    }
+    Ok(config)
+}
+
+/// Loads the manifest file at `source` once `signature`, a detached signature
+/// of its text, verifies against a trusted key. Manifest directories and
+/// includes are refused: the signature covers a single file.
+pub fn load_signed_config(source: &str, signature: &str) -> Result<Config, AppError> {
+    if is_manifest_base(source) {
+        return Err(AppError::Other(
+            format!(
+                "--signature needs a single manifest file, not the manifest directory '{}'.",
+                source
+            )
+            .into(),
+        ));
+    }
+    let content = fetch_toml_content(source)?;
+    let fingerprint = verify_signature(content.as_bytes(), &fetch_bytes(signature)?)?;
+    info!(
+        "Manifest {} is signed by trusted key {}",
+        source, fingerprint
+    );
+
+    let config: Config = toml::from_str(&render_manifest(source, content)?)?;
+    if !config.include.is_empty() {
+        return Err(AppError::Other(
+            format!(
+                "{} includes {}, which its signature does not cover.",
+                source,
+                config.include.join(", ")
+            )
+            .into(),
+        ));
+    }
+    Ok(config)
+}
+
+/// The local directory of the manifest at `source`: the parent of a manifest
+/// file, or a manifest directory itself. `None` for a URL.
+pub fn manifest_dir(source: &str) -> Option<PathBuf> {
+    if is_url(source) {
+        return None;
+    }
+    let path = Path::new(source);
+    if path.is_dir() {
+        Some(path.to_path_buf())
+    } else {
+        Some(path.parent().unwrap_or(Path::new("")).to_path_buf())
+    }
+}
+
+/// Every manifest file `load_config` reads for `source` and `host`, including
+/// its includes, parsed as plain TOML tables for checks the typed [`Config`]
+/// does not do, such as spotting unknown keys.
+pub fn load_manifest_tables(
+    source: &str,
+    host: Option<&str>,
+) -> Result<Vec<(String, toml::Table)>, AppError> {
+    let roots = if is_manifest_base(source) {
+        let host = resolve_host(host)?;
+        vec![
+            join_source(source, BASE_MANIFEST),
+            join_source(source, &format!("{}/{}.toml", HOSTS_DIR, host)),
+        ]
+    } else {
+        vec![source.to_string()]
+    };
+
+    let mut tables = Vec::new();
+    let mut visited = Vec::new();
+    for root in &roots {
+        collect_manifest_tables(root, &mut tables, &mut visited)?;
+    }
+    Ok(tables)
+}
+
+fn collect_manifest_tables(
+    source: &str,
+    tables: &mut Vec<(String, toml::Table)>,
+    visited: &mut Vec<String>,
+) -> Result<(), AppError> {
+    let key = source_key(source);
+    if visited.contains(&key) {
+        return Ok(());
+    }
+    visited.push(key);
+
+    let Some(content) = read_optional_manifest(source)? else {
+        return Ok(());
+    };
+    let table: toml::Table = toml::from_str(&content)?;
+    let includes = table
+        .get("include")
+        .and_then(toml::Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(toml::Value::as_str);
+    for include in includes {
+        collect_manifest_tables(&resolve_include(source, include), tables, visited)?;
+    }
+    tables.push((source.to_string(), table));
+    Ok(())
+}
+
+/// `host`, defaulting to the system hostname.
+fn resolve_host(host: Option<&str>) -> Result<String, AppError> {
+    match host {
+        Some(host) => Ok(host.to_string()),
+        None => system_hostname().ok_or_else(|| {
+            AppError::Other("Could not determine the hostname; pass --host <name>.".into())
+        }),
+    }
+}
+
+/// Loads the manifest file at `source` with its `include`s merged under it.
+/// `chain` holds the manifests currently being loaded, to detect include cycles.
+fn load_config_recursive(source: &str, chain: &mut Vec<String>) -> Result<Config, AppError> {
+    let content = render_manifest(source, fetch_toml_content(source)?)?;
+    resolve_includes(source, &content, chain)
+}
+
+/// The manifest at `source`, rendered if it is a template, or `None` if it
+/// does not exist.
+fn read_optional_manifest(source: &str) -> Result<Option<String>, AppError> {
+    fetch_optional_toml_content(source)?
+        .map(|content| render_manifest(source, content))
+        .transpose()
+}
+
+/// Parses `content`, the manifest at `source`, and merges its includes in order
+/// under it: later includes are merged over earlier ones, and the manifest itself
+/// over all of them.
+fn resolve_includes(
+    source: &str,
+    content: &str,
+    chain: &mut Vec<String>,
+) -> Result<Config, AppError> {
+    let key = source_key(source);
+    if let Some(start) = chain.iter().position(|visited| *visited == key) {
+        let mut cycle = chain[start..].to_vec();
+        cycle.push(key);
+        return Err(AppError::Other(
+            format!("Manifest include cycle: {}", cycle.join(" -> ")).into(),
+        ));
+    }
+
+    let mut manifest: Config = toml::from_str(content)?;
+    let includes = std::mem::take(&mut manifest.include);
+    if includes.is_empty() {
+        return Ok(manifest);
+    }
+
+    chain.push(key);
+    let mut config = Config::default();
+    for include in &includes {
+        config.merge(load_config_recursive(
+            &resolve_include(source, include),
+            chain,
+        )?);
+    }
+    chain.pop();
+    config.merge(manifest);
+    Ok(config)
+}
+
+/// Resolves `include` relative to the directory of the manifest at `source`.
+fn resolve_include(source: &str, include: &str) -> String {
+    if is_url(include) || Path::new(include).is_absolute() {
+        return include.to_string();
+    }
+    if is_url(source) {
+        let dir = source.rsplit_once('/').map_or(source, |(dir, _)| dir);
+        return format!("{}/{}", dir, include);
+    }
+    Path::new(source)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(include)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Identifies a manifest for cycle detection, resolving `..` and symlinks of local paths.
+fn source_key(source: &str) -> String {
+    if is_url(source) {
+        return source.to_string();
+    }
+    std::fs::canonicalize(source)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| source.to_string())
+}
+
+fn is_manifest_base(source: &str) -> bool {
+    if is_url(source) {
+        let source = source.trim_end_matches('/');
+        !source.ends_with(".toml") && !source.ends_with(TEMPLATE_SUFFIX)
+    } else {
+        Path::new(source).is_dir()
+    }
+}
+
+fn join_source(base: &str, relative: &str) -> String {
+    if is_url(base) {
+        format!("{}/{}", base.trim_end_matches('/'), relative)
+    } else {
+        Path::new(base)
+            .join(relative)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, relative: &str, content: &str) {
+        let path = dir.join(relative);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn test_load_config_merges_host_over_base() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "base.toml",
+            "[apt]\nlist = [\"git\", \"curl\"]\n\n[scripts]\nhello = \"echo base\"\n",
+        );
+        write(
+            dir.path(),
+            "hosts/laptop.toml",
+            "[apt]\nlist = [\"curl=8.5.0\", \"tlp\"]\n\n[scripts]\nhello = \"echo laptop\"\n",
+        );
+
+        let config = load_config(dir.path().to_str().unwrap(), Some("laptop")).unwrap();
+        let apt: Vec<&str> = config
+            .apt
+            .as_ref()
+            .unwrap()
+            .list
+            .iter()
+            .map(|e| e.name())
+            .collect();
+        assert_eq!(apt, vec!["git", "curl=8.5.0", "tlp"]);
+        assert_eq!(
+            config.scripts.unwrap().commands["hello"].command(),
+            "echo laptop"
+        );
+    }
+
+    #[test]
+    fn test_load_config_falls_back_to_base() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "base.toml", "[cargo]\nlist = [\"ripgrep\"]\n");
+
+        let config = load_config(dir.path().to_str().unwrap(), Some("unknown")).unwrap();
+        assert_eq!(config.cargo.unwrap().list.len(), 1);
+    }
+
+    #[test]
+    fn test_load_config_requires_a_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load_config(dir.path().to_str().unwrap(), Some("laptop")).is_err());
+    }
+
+    #[test]
+    fn test_load_config_merges_includes_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "shared/base.toml",
+            "[apt]\nlist = [\"git\"]\n\n[scripts]\nhello = \"echo base\"\n",
+        );
+        write(
+            dir.path(),
+            "shared/team.toml",
+            "[apt]\nlist = [\"curl\"]\n\n[scripts]\nhello = \"echo team\"\n",
+        );
+        write(
+            dir.path(),
+            "me.toml",
+            "include = [\"shared/base.toml\", \"shared/team.toml\"]\n\n[apt]\nlist = [\"tlp\"]\n",
+        );
+
+        let config = load_config(dir.path().join("me.toml").to_str().unwrap(), None).unwrap();
+        let apt: Vec<&str> = config
+            .apt
+            .as_ref()
+            .unwrap()
+            .list
+            .iter()
+            .map(|e| e.name())
+            .collect();
+        assert_eq!(apt, vec!["git", "curl", "tlp"]);
+        assert_eq!(
+            config.scripts.unwrap().commands["hello"].command(),
+            "echo team"
+        );
+        assert!(config.include.is_empty());
+    }
+
+    #[test]
+    fn test_load_config_rejects_include_cycles() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a.toml", "include = [\"b.toml\"]\n");
+        write(dir.path(), "b.toml", "include = [\"./a.toml\"]\n");
+
+        let err = load_config(dir.path().join("a.toml").to_str().unwrap(), None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("include cycle"), "{}", err);
+    }
+
+    #[test]
+    fn test_load_manifest_tables_follows_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "base.toml", "[atp]\nlist = [\"git\"]\n");
+        write(
+            dir.path(),
+            "hosts/laptop.toml",
+            "include = [\"../extra.toml\"]\n",
+        );
+        write(dir.path(), "extra.toml", "[cargo]\nlist = []\n");
+
+        let tables = load_manifest_tables(dir.path().to_str().unwrap(), Some("laptop")).unwrap();
+        let keys: Vec<Vec<&String>> = tables.iter().map(|(_, t)| t.keys().collect()).collect();
+        assert_eq!(keys, vec![vec!["atp"], vec!["cargo"], vec!["include"]]);
+    }
+
+    #[test]
+    fn test_resolve_include() {
+        assert_eq!(
+            resolve_include("https://example.com/m/me.toml", "base.toml"),
+            "https://example.com/m/base.toml"
+        );
+        assert_eq!(
+            resolve_include("/etc/railtube/me.toml", "https://example.com/team.toml"),
+            "https://example.com/team.toml"
+        );
+        assert_eq!(
+            resolve_include("conf/me.toml", "base.toml"),
+            "conf/base.toml"
+        );
+    }
+
+    #[test]
+    fn test_is_manifest_base() {
+        assert!(is_manifest_base("https://example.com/fleet"));
+        assert!(!is_manifest_base("https://example.com/fleet/base.toml"));
+        assert!(!is_manifest_base("/nonexistent/railtube.toml"));
+    }
+}
@@ -0,0 +1,232 @@
+use crate::config::PackageEntry;
+use crate::errors::AppError;
+use crate::loader::manifest_dir;
+use crate::package::PackageManager;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+pub const LOCK_FILE: &str = "railtube.lock";
+
+/// Where the lock file of the manifest at `source` lives: next to the
+/// manifest, or inside a manifest directory. Manifests fetched from a URL
+/// keep theirs in the current directory.
+pub fn lock_file_path(source: &str) -> PathBuf {
+    manifest_dir(source).unwrap_or_default().join(LOCK_FILE)
+}
+
+/// Resolved package versions per section, e.g.
+///
+/// ```toml
+/// [cargo]
+/// ripgrep = "14.1.0"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LockFile {
+    #[serde(flatten)]
+    pub sections: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl LockFile {
+    pub fn load(path: &Path) -> Result<Self, AppError> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            AppError::Other(format!("Failed to read lock file {}: {}", path.display(), e).into())
+        })?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), AppError> {
+        std::fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn version(&self, section: &str, name: &str) -> Option<&str> {
+        self.sections.get(section)?.get(name).map(String::as_str)
+    }
+
+    /// Rewrites every entry of `list` to the version locked for it. Fails if a
+    /// package is not in the lock, or if the manifest pins a different version.
+    pub fn pin(
+        &self,
+        manager: &dyn PackageManager,
+        list: &[PackageEntry],
+    ) -> Result<Vec<PackageEntry>, AppError> {
+        let mut problems = Vec::new();
+        let mut pinned = Vec::new();
+        for entry in list {
+            let (name, declared) = manager.split_spec(entry.name());
+            match self.version(manager.section(), name) {
+                None => problems.push(format!(
+                    "{} '{}' is not in the lock file",
+                    manager.section(),
+                    name
+                )),
+                Some(locked) if declared.is_some_and(|declared| declared != locked) => problems
+                    .push(format!(
+                        "{} '{}' is pinned to {} in the manifest but locked at {}",
+                        manager.section(),
+                        name,
+                        declared.unwrap_or_default(),
+                        locked
+                    )),
+                Some(locked) => {
+                    let mut entry = entry.clone();
+                    entry.set_name(manager.pin_spec(name, locked));
+                    pinned.push(entry);
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(pinned)
+        } else {
+            Err(AppError::Other(
+                format!(
+                    "Manifest does not match {}:\n- {}",
+                    LOCK_FILE,
+                    problems.join("\n- ")
+                )
+                .into(),
+            ))
+        }
+    }
+
+    /// Rewrites the entries of `list` that are in the lock to their locked
+    /// version, leaving packages missing from the lock, and versions pinned in
+    /// the manifest, as they are.
+    pub fn pin_locked(
+        &self,
+        manager: &dyn PackageManager,
+        list: &[PackageEntry],
+    ) -> Vec<PackageEntry> {
+        list.iter()
+            .map(|entry| {
+                let mut entry = entry.clone();
+                let (name, declared) = manager.split_spec(entry.name());
+                if declared.is_none()
+                    && let Some(locked) = self.version(manager.section(), name)
+                {
+                    entry.set_name(manager.pin_spec(name, locked));
+                }
+                entry
+            })
+            .collect()
+    }
+
+    /// Packages of `list` whose installed version differs from the lock.
+    pub fn verify(
+        &self,
+        manager: &dyn PackageManager,
+        list: &[PackageEntry],
+        installed: &HashMap<String, String>,
+    ) -> Vec<String> {
+        list.iter()
+            .filter_map(|entry| {
+                let (name, _) = manager.split_spec(entry.name());
+                let locked = self.version(manager.section(), name)?;
+                match installed.get(name) {
+                    Some(version) if version == locked => None,
+                    Some(version) => Some(format!(
+                        "{} '{}': {} installed, {} locked",
+                        manager.section(),
+                        name,
+                        version,
+                        locked
+                    )),
+                    None => Some(format!(
+                        "{} '{}': not installed, {} locked",
+                        manager.section(),
+                        name,
+                        locked
+                    )),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::{Apt, Cargo};
+
+    fn lock() -> LockFile {
+        toml::from_str(
+            r#"
+[apt]
+curl = "8.5.0-2ubuntu10"
+
+[cargo]
+ripgrep = "14.1.0"
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pin_uses_locked_versions() {
+        let list = vec![PackageEntry::from("ripgrep".to_string())];
+        let pinned = lock().pin(&Cargo, &list).unwrap();
+        assert_eq!(pinned[0].name(), "ripgrep=14.1.0");
+    }
+
+    #[test]
+    fn test_pin_rejects_unlocked_and_conflicting_packages() {
+        let list = vec![
+            PackageEntry::from("curl=8.4.0".to_string()),
+            PackageEntry::from("git".to_string()),
+        ];
+        let err = lock().pin(&Apt, &list).unwrap_err().to_string();
+        assert!(err.contains(
+            "apt 'curl' is pinned to 8.4.0 in the manifest but locked at 8.5.0-2ubuntu10"
+        ));
+        assert!(err.contains("apt 'git' is not in the lock file"));
+    }
+
+    #[test]
+    fn test_pin_locked_keeps_unlocked_and_pinned_packages() {
+        let list = vec![
+            PackageEntry::from("curl".to_string()),
+            PackageEntry::from("git".to_string()),
+        ];
+        let pinned: Vec<String> = lock()
+            .pin_locked(&Apt, &list)
+            .iter()
+            .map(|entry| entry.name().to_string())
+            .collect();
+        assert_eq!(pinned, vec!["curl=8.5.0-2ubuntu10", "git"]);
+
+        let list = vec![PackageEntry::from("ripgrep=14.0.0".to_string())];
+        assert_eq!(lock().pin_locked(&Cargo, &list)[0].name(), "ripgrep=14.0.0");
+    }
+
+    #[test]
+    fn test_verify_reports_mismatches() {
+        let list = vec![PackageEntry::from("ripgrep".to_string())];
+        let installed = HashMap::from([("ripgrep".to_string(), "14.0.0".to_string())]);
+        assert_eq!(
+            lock().verify(&Cargo, &list, &installed),
+            vec!["cargo 'ripgrep': 14.0.0 installed, 14.1.0 locked"]
+        );
+        let installed = HashMap::from([("ripgrep".to_string(), "14.1.0".to_string())]);
+        assert!(lock().verify(&Cargo, &list, &installed).is_empty());
+    }
+
+    #[test]
+    fn test_lock_file_sits_next_to_the_manifest() {
+        assert_eq!(
+            lock_file_path("config/dev.toml"),
+            PathBuf::from("config/railtube.lock")
+        );
+        assert_eq!(lock_file_path("dev.toml"), PathBuf::from("railtube.lock"));
+        assert_eq!(
+            lock_file_path("https://example.com/dev.toml"),
+            PathBuf::from("railtube.lock")
+        );
+        let dir = tempfile::TempDir::new().unwrap();
+        assert_eq!(
+            lock_file_path(dir.path().to_str().unwrap()),
+            dir.path().join("railtube.lock")
+        );
+    }
+}
@@ -1,31 +1,142 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use clap_complete::CompleteEnv;
+use std::path::Path;
+use std::process::ExitCode;
 use std::{fs, io::Write};
 
+mod brewfile;
+mod build_info;
 mod commands;
 mod errors;
-use crate::errors::AppError;
+mod history;
+use crate::errors::{AppError, EXIT_PARTIAL_FAILURE};
+use crate::history::{history_file, print_runs, read_history};
 
 mod config;
-use crate::config::Config;
+use crate::config::{Config, Settings};
 mod cli;
-use crate::cli::{Args, Commands};
+use crate::cli::{Args, Commands, ExportFormat, ImportFormat, KeyringAction};
+mod completion;
+mod console;
+mod keyring;
+use crate::completion::{manifest_candidates, write_completion_script};
 
+mod loader;
+mod lock;
+mod manifest_cache;
+use crate::loader::{load_config, load_manifest_tables, load_signed_config, manifest_dir};
+use crate::lock::{LockFile, lock_file_path};
+use crate::manifest_cache::CacheMode;
 mod package;
+mod report;
+mod sarif;
+mod template;
+use crate::console::Verbosity;
+use crate::report::{
+    ApplyFormat, ApplyReport, EventStream, write_command_script, write_output_bundle,
+};
+use crate::template::set_template_context;
 mod utils;
-use crate::commands::{apply_config, doctor_command, export_current_environment, run_scripts};
-use crate::utils::fetch_toml_content;
-fn main() -> Result<(), AppError> {
+use crate::commands::{
+    ApplyOptions, apply_config, benchmark_cargo_installs, confirm_plan, doctor_command,
+    doctor_trend, export_brew_environment, export_current_environment, fix_missing_packages,
+    init_command, list_scripts, pick_script, rollback_command, run_scripts, validate_command,
+    which_command,
+};
+use crate::utils::{
+    default_log_file, fetch_toml_content, is_url, log_file, log_or_eprint, rotate_log,
+    set_child_color, set_command_timeout, set_log_file, set_offline, set_sudo_command,
+    set_temp_dir,
+};
+/// With `--locked`, the lock file at `path` if there is one yet.
+fn load_existing_lock_file(locked: bool, path: &Path) -> Result<Option<LockFile>, AppError> {
+    if !locked {
+        return Ok(None);
+    }
+    if !path.exists() {
+        console::info!("No {} yet; installing the latest versions.", path.display());
+        return Ok(None);
+    }
+    LockFile::load(path).map(Some)
+}
+
+fn main() -> ExitCode {
+    // Answers dynamic shell completion requests (COMPLETE=<shell>) and exits.
+    CompleteEnv::with_factory(Args::command).complete();
+
     let args = Args::parse();
+    if args.no_color {
+        console::disable_color();
+    }
+    console::set_verbosity(Verbosity::from_flags(args.quiet, args.verbose));
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            console::error!("Error: {:?}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+fn run(args: Args) -> Result<(), AppError> {
+    set_log_file(args.log_file.clone().unwrap_or_else(default_log_file))?;
+    if let Err(e) = rotate_log(log_file(), args.log_max_size, args.log_keep) {
+        eprintln!("Warning: Failed to rotate log file: {}", e);
+    }
+    if let Some(temp_dir) = args.temp_dir.clone() {
+        set_temp_dir(temp_dir)?;
+    }
+    if args.sudo_command != "sudo" {
+        let escalation = match args.sudo_command.as_str() {
+            "" => "none (running privileged commands directly)",
+            other => other,
+        };
+        log_or_eprint(
+            &format!("Privilege escalation: {}", escalation),
+            "Failed to log message",
+        );
+    }
+    set_sudo_command(args.sudo_command.clone());
+    set_template_context(args.host.clone(), args.template_vars.clone());
+    manifest_cache::set_cache_mode(if args.no_cache {
+        CacheMode::Off
+    } else if args.refresh {
+        CacheMode::Refresh
+    } else {
+        CacheMode::Revalidate
+    });
+
+    if let Commands::Export {
+        ref output,
+        format: ExportFormat::Brewfile,
+    } = args.command
+    {
+        let output = output.as_deref().unwrap_or("Brewfile");
+        fs::write(output, brewfile::render(&export_brew_environment()?))?;
+        println!("Homebrew environment exported to {}", output);
+        return Ok(());
+    }
 
     // Handle the Export command separately as it exits early
-    if let Commands::Export { ref output } = args.command {
-        let exported_config = export_current_environment()?;
+    if let Commands::Export { ref output, .. } = args.command {
+        let output = output.as_deref().unwrap_or("exported-env.toml");
+        let mut exported_config = export_current_environment()?;
+        // Re-exporting over a curated manifest keeps its package descriptions.
+        if let Ok(previous) = fs::read_to_string(output) {
+            match toml::from_str::<Config>(&previous) {
+                Ok(previous) => exported_config.keep_descriptions(previous),
+                Err(e) => eprintln!(
+                    "Warning: Could not read descriptions from existing {}: {}",
+                    output, e
+                ),
+            }
+        }
         let toml_string =
             toml::to_string_pretty(&exported_config).map_err(|e| AppError::Other(Box::new(e)))?;
 
         // Add comment for unexported sections
         let mut final_toml_string = String::new();
-        final_toml_string.push_str("# NOTE: scripts and deb sections are not exported as they are defined, not installed.\n");
+        final_toml_string.push_str("# NOTE: scripts, deb and appimage sections are not exported as they are defined, not installed.\n");
         final_toml_string.push_str(&toml_string);
 
         let mut file = fs::File::create(output)?;
@@ -34,49 +145,348 @@ fn main() -> Result<(), AppError> {
         return Ok(()); // Exit after export
     }
 
+    if let Commands::Import {
+        from: ImportFormat::Brewfile,
+        ref input,
+        ref output,
+    } = args.command
+    {
+        let config = brewfile::parse(&fetch_toml_content(input)?)?;
+        fs::write(output, toml::to_string_pretty(&config)?)?;
+        println!("Brewfile {} imported to {}", input, output);
+        return Ok(());
+    }
+
+    if let Commands::Version { json } = args.command {
+        let info = build_info::build_info();
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&info).map_err(|e| AppError::Other(Box::new(e)))?
+            );
+        } else {
+            println!(
+                "railtube {} ({} {})",
+                info.version, info.git_hash, info.build_date
+            );
+            println!("{}", info.rustc);
+        }
+        return Ok(());
+    }
+
+    if let Commands::Init { ref output, force } = args.command {
+        init_command(output, force)?;
+        return Ok(());
+    }
+
+    if let Commands::Keyring { ref action } = args.command {
+        match action {
+            KeyringAction::Import { file } => {
+                let key = keyring::import_key(file)?;
+                println!("Imported {} {}", key.fingerprint, key.user_ids.join(", "));
+            }
+            KeyringAction::List => {
+                for key in keyring::list_keys()? {
+                    println!("{} {}", key.fingerprint, key.user_ids.join(", "));
+                }
+            }
+            KeyringAction::Rm { fingerprint } => {
+                println!("Removed {}", keyring::remove_key(fingerprint)?);
+            }
+        }
+        return Ok(());
+    }
+
+    if let Commands::Log { last } = args.command {
+        let runs = read_history(&history_file())?;
+        if runs.is_empty() {
+            println!("No apply runs recorded in {}", history_file().display());
+        } else {
+            let first = runs.len().saturating_sub(last);
+            print_runs(&runs[first..], first + 1, &mut std::io::stdout())?;
+        }
+        return Ok(());
+    }
+
+    if let Commands::Which { ref name } = args.command {
+        if !which_command(name)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Commands::Rollback { run, dry_run, yes } = args.command {
+        rollback_command(run, dry_run, yes)?;
+        return Ok(());
+    }
+
+    if let Commands::Completions { shell } = args.command {
+        write_completion_script(shell, &mut std::io::stdout())?;
+        return Ok(());
+    }
+
+    if let Commands::Complete {
+        kind,
+        ref prefix,
+        ref source,
+    } = args.command
+    {
+        for candidate in manifest_candidates(kind, prefix, source.as_deref()) {
+            println!("{}", candidate);
+        }
+        return Ok(());
+    }
+
+    if let Commands::Doctor {
+        trend: true,
+        output: Some(ref output),
+        since,
+        ..
+    } = args.command
+    {
+        doctor_trend(output, since)?;
+        return Ok(());
+    }
+
+    // Set before loading so a URL manifest fails before anything is fetched.
+    if let Commands::Apply { offline: true, .. } = args.command {
+        set_offline();
+        log_or_eprint(
+            "Offline mode: skipping network operations.",
+            "Failed to log message",
+        );
+    }
+
+    if let Commands::Config { .. } = args.command {
+        // stdout carries nothing but the TOML.
+        console::reserve_stdout();
+    }
+
     // For other commands, fetch and parse the TOML configuration
     let config: Config = match &args.command {
+        Commands::Apply {
+            source,
+            signature: Some(signature),
+            ..
+        } => load_signed_config(source, signature)?,
         Commands::Apply { source, .. }
         | Commands::Run { source, .. }
-        | Commands::Doctor { source } => {
-            let toml_str = fetch_toml_content(source)?;
-            toml::from_str(&toml_str).map_err(AppError::TomlDe)?
-        }
+        | Commands::ListScripts { source }
+        | Commands::Config { source }
+        | Commands::Validate { source }
+        | Commands::Doctor {
+            source: Some(source),
+            ..
+        } => load_config(source, args.host.as_deref())?,
         // Export command is handled above, so this arm should not be reached.
         // If it were, it would indicate a logic error.
-        Commands::Export { .. } => unreachable!("Export command handled separately"),
+        Commands::Export { .. }
+        | Commands::Import { .. }
+        | Commands::Init { .. }
+        | Commands::Keyring { .. }
+        | Commands::Log { .. }
+        | Commands::Rollback { .. }
+        | Commands::Which { .. }
+        | Commands::Version { .. }
+        | Commands::Completions { .. }
+        | Commands::Complete { .. }
+        | Commands::Doctor { .. } => {
+            unreachable!("Command handled separately")
+        }
     };
 
     // Determine if the source was a URL for logging purposes before args.command is moved
     let is_remote_source = if let Commands::Run { source, .. } = &args.command {
-        source.starts_with("http://") || source.starts_with("https://")
+        is_url(source)
     } else {
         false // Should not happen in this arm
     };
 
+    if let Commands::Apply { timeout, .. } | Commands::Run { timeout, .. } = args.command {
+        set_command_timeout(timeout);
+    }
+
     // Execute the appropriate command logic
     match args.command {
+        Commands::Apply {
+            benchmark: true,
+            only,
+            ..
+        } => {
+            benchmark_cargo_installs(&config, &only)?;
+        }
         Commands::Apply {
             dry_run,
             yes,
+            parallel,
+            jobs,
+            color,
+            confirm_sections,
+            plan,
+            no_update,
+            offline,
+            frozen,
+            locked,
             only: args_only,
-            .. // Ignore source as it's already used to load config
+            exclude,
+            group,
+            version_match,
+            output_dir,
+            wait_for_lock,
+            emit_script,
+            events,
+            prune,
+            verify,
+            assume_installed,
+            retries,
+            deb_match,
+            format,
+            keep_going,
+            ref source,
+            ..
         } => {
-            apply_config(&config, dry_run, yes, args_only)?;
+            // Manifest [settings] are defaults; flags given on the command line win.
+            let settings = config.settings.clone().unwrap_or_default().with_overrides(&Settings {
+                yes,
+                parallel,
+                dry_run,
+                jobs,
+                color,
+            });
+            set_child_color(settings.color.unwrap_or_default());
+            let lock_file = lock_file_path(source);
+            let mut options = ApplyOptions {
+                dry_run: emit_script.is_some() || settings.dry_run.unwrap_or(false),
+                yes: settings.yes.unwrap_or(false),
+                jobs: settings.jobs(),
+                confirm_sections,
+                deb_match,
+                no_update,
+                offline,
+                frozen: frozen.then(|| LockFile::load(&lock_file)).transpose()?,
+                locked: load_existing_lock_file(locked, &lock_file)?,
+                lock_file: Some(lock_file),
+                manifest_dir: manifest_dir(source),
+                only: args_only,
+                exclude,
+                group,
+                version_match,
+                wait_for_lock,
+                prune,
+                verify,
+                assume_installed,
+                retries,
+            };
+            let mut report = match &events {
+                Some(target) => ApplyReport::new().with_events(EventStream::open(target)?),
+                None => ApplyReport::new(),
+            };
+            if format == ApplyFormat::Json {
+                console::reserve_stdout();
+            }
+            let keep_going = keep_going || format == ApplyFormat::Json;
+            if keep_going {
+                report = report.keep_going();
+            }
+            if plan && !options.dry_run {
+                if !confirm_plan(&config, &options)? {
+                    console::info!("Apply aborted; nothing was installed.");
+                    return Ok(());
+                }
+                // The plan was confirmed as a whole.
+                options.yes = true;
+            }
+            let log_offset = fs::metadata(log_file()).map(|m| m.len()).unwrap_or(0);
+            let outcome = apply_config(&config, &options, &report);
+            if let Some(path) = &emit_script
+                && outcome.is_ok()
+            {
+                write_command_script(path.as_ref(), source, &report)?;
+                console::info!("Commands written to {}", path);
+            }
+            if let Some(dir) = output_dir {
+                let bundle_dir =
+                    write_output_bundle(dir.as_ref(), &config, &report, &outcome, log_offset)?;
+                console::info!("Run artifacts written to {}", bundle_dir.display());
+            }
+            if format == ApplyFormat::Json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&report.results())
+                        .map_err(|e| AppError::Other(Box::new(e)))?
+                );
+            }
+            if keep_going {
+                report.print_failures();
+            }
+            outcome?;
+            if report.has_failures() {
+                std::process::exit(EXIT_PARTIAL_FAILURE.into());
+            }
         }
-        Commands::Doctor { ref source } => {
+        Commands::Doctor {
+            ref source,
+            ref output,
+            exit_code,
+            fix_missing,
+            dry_run,
+            yes,
+            format,
+            ..
+        } => {
             // The config is already loaded above.
-            doctor_command(&config, source)?;
+            let source = source.as_deref().unwrap_or_default();
+            let report = doctor_command(&config, source, output.as_deref(), format)?;
+            if fix_missing {
+                fix_missing_packages(&config, &report, dry_run, yes)?;
+            }
+            if exit_code && report.has_drift() {
+                std::process::exit(1);
+            }
         }
         Commands::Run {
             ref script_name,
+            all,
+            check,
+            dry_run,
+            down,
+            no_deps,
             .. // Ignore source as it's already used to load config
         } => {
-            run_scripts(&config, script_name, is_remote_source)?;
+            let script_name = match script_name {
+                None if !all => Some(pick_script(&config)?),
+                name => name.clone(),
+            };
+            run_scripts(
+                &config,
+                script_name.as_deref(),
+                is_remote_source,
+                check,
+                dry_run,
+                down,
+                no_deps,
+            )?;
+        }
+        Commands::ListScripts { .. } => list_scripts(&config),
+        Commands::Config { .. } => print!("{}", toml::to_string_pretty(&config)?),
+        Commands::Validate { ref source } => {
+            let manifests = load_manifest_tables(source, args.host.as_deref())?;
+            if !validate_command(&config, &manifests) {
+                std::process::exit(1);
+            }
         }
-        Commands::Export { .. } => {
-            // This case is handled before the match, so it should be unreachable.
-            unreachable!("Export command handled separately");
+        Commands::Export { .. }
+        | Commands::Import { .. }
+        | Commands::Init { .. }
+        | Commands::Keyring { .. }
+        | Commands::Log { .. }
+        | Commands::Rollback { .. }
+        | Commands::Which { .. }
+        | Commands::Version { .. }
+        | Commands::Completions { .. }
+        | Commands::Complete { .. } => {
+            // These cases are handled before the match, so they should be unreachable.
+            unreachable!("Command handled separately");
         }
     };
 
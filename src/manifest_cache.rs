@@ -0,0 +1,191 @@
+use crate::utils::log_or_eprint;
+use reqwest::header::{
+    ETAG, HeaderMap, HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How remote manifests use the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Revalidate the cached copy with `If-None-Match`/`If-Modified-Since`.
+    Revalidate,
+    /// Download again and replace the cached copy (`--refresh`).
+    Refresh,
+    /// Neither read nor write the cache (`--no-cache`).
+    Off,
+}
+
+static CACHE_MODE: OnceLock<CacheMode> = OnceLock::new();
+
+pub fn set_cache_mode(mode: CacheMode) {
+    let _ = CACHE_MODE.set(mode);
+}
+
+pub fn cache_mode() -> CacheMode {
+    CACHE_MODE.get().copied().unwrap_or(CacheMode::Revalidate)
+}
+
+/// Cached copies older than this are downloaded again without validators, so
+/// a server that keeps answering 304 cannot pin a stale copy forever.
+const MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// `$XDG_CACHE_HOME/railtube/manifests`, falling back to `~/.cache/railtube/manifests`.
+pub fn cache_dir() -> Option<PathBuf> {
+    dirs::cache_dir()
+        .or_else(|| dirs::home_dir().map(|home| home.join(".cache")))
+        .map(|dir| dir.join("railtube").join("manifests"))
+}
+
+/// What is recorded next to a cached manifest, in `<hash>.json`.
+#[derive(Debug, Deserialize, Serialize)]
+struct CacheMeta {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// Seconds since the Unix epoch.
+    fetched_at: u64,
+    /// SHA-256 of the cached manifest, to spot truncated or edited copies.
+    sha256: String,
+}
+
+/// A manifest read back from the cache.
+#[derive(Debug)]
+pub struct CachedManifest {
+    pub content: String,
+    meta: CacheMeta,
+}
+
+impl CachedManifest {
+    /// The conditional request headers that let the server answer 304.
+    pub fn validators(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let mut insert = |name, value: &Option<String>| {
+            if let Some(value) = value
+                && let Ok(value) = HeaderValue::from_str(value)
+            {
+                headers.insert(name, value);
+            }
+        };
+        insert(IF_NONE_MATCH, &self.meta.etag);
+        insert(IF_MODIFIED_SINCE, &self.meta.last_modified);
+        headers
+    }
+}
+
+fn sha256_hex(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// The manifest and metadata files of `url`'s cache entry in `dir`.
+fn entry_paths(dir: &Path, url: &str) -> (PathBuf, PathBuf) {
+    let key = sha256_hex(url);
+    (
+        dir.join(format!("{}.toml", key)),
+        dir.join(format!("{}.json", key)),
+    )
+}
+
+/// The cached copy of `url`, or `None` if there is none or it is unusable
+/// (unreadable, corrupt or older than `MAX_AGE`), in which case the caller
+/// downloads it again.
+pub fn load(dir: &Path, url: &str) -> Option<CachedManifest> {
+    let (content_path, meta_path) = entry_paths(dir, url);
+    let meta: CacheMeta = serde_json::from_slice(&std::fs::read(meta_path).ok()?).ok()?;
+    let content = std::fs::read_to_string(content_path).ok()?;
+    if meta.url != url || meta.sha256 != sha256_hex(&content) {
+        log_or_eprint(
+            &format!("Ignoring corrupt cache entry for {}", url),
+            "Failed to log message",
+        );
+        return None;
+    }
+    if now_secs().saturating_sub(meta.fetched_at) > MAX_AGE.as_secs() {
+        return None;
+    }
+    Some(CachedManifest { content, meta })
+}
+
+/// Caches `content`, just downloaded from `url`, with the validators of the
+/// response `headers`. Failing to write the cache only logs a warning.
+pub fn store(dir: &Path, url: &str, content: &str, headers: &HeaderMap) {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let meta = CacheMeta {
+        url: url.to_string(),
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+        fetched_at: now_secs(),
+        sha256: sha256_hex(content),
+    };
+    let (content_path, meta_path) = entry_paths(dir, url);
+    let written = std::fs::create_dir_all(dir)
+        .and_then(|()| std::fs::write(&content_path, content))
+        .and_then(|()| std::fs::write(&meta_path, serde_json::to_vec_pretty(&meta)?));
+    if let Err(e) = written {
+        log_or_eprint(
+            &format!("Warning: Failed to cache manifest {}: {}", url, e),
+            "Failed to log message",
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://example.com/railtube.toml";
+
+    fn headers(etag: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_str(etag).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_store_and_load_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        store(dir.path(), URL, "[apt]\nlist = []\n", &headers("\"v1\""));
+
+        let cached = load(dir.path(), URL).unwrap();
+        assert_eq!(cached.content, "[apt]\nlist = []\n");
+        let validators = cached.validators();
+        assert_eq!(validators.get(IF_NONE_MATCH).unwrap(), "\"v1\"");
+        assert!(validators.get(IF_MODIFIED_SINCE).is_none());
+        assert!(load(dir.path(), "https://example.com/other.toml").is_none());
+    }
+
+    #[test]
+    fn test_load_ignores_corrupt_and_expired_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let (content_path, meta_path) = entry_paths(dir.path(), URL);
+
+        store(dir.path(), URL, "[apt]\n", &headers("\"v1\""));
+        std::fs::write(&content_path, "[ap").unwrap();
+        assert!(load(dir.path(), URL).is_none());
+
+        store(dir.path(), URL, "[apt]\n", &headers("\"v1\""));
+        std::fs::write(&meta_path, "{not json").unwrap();
+        assert!(load(dir.path(), URL).is_none());
+
+        store(dir.path(), URL, "[apt]\n", &headers("\"v1\""));
+        let mut meta: CacheMeta =
+            serde_json::from_slice(&std::fs::read(&meta_path).unwrap()).unwrap();
+        meta.fetched_at -= MAX_AGE.as_secs() + 1;
+        std::fs::write(&meta_path, serde_json::to_vec(&meta).unwrap()).unwrap();
+        assert!(load(dir.path(), URL).is_none());
+    }
+}
@@ -1,9 +1,212 @@
+use crate::console::{info, skip};
 use crate::errors::AppError;
-use std::collections::HashMap;
+use crate::report::VersionDrift;
+use clap::ValueEnum;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::process::Command;
 
+/// How a pinned version is compared against the installed one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum VersionMatch {
+    /// The full version string must match.
+    #[default]
+    Exact,
+    /// Only the upstream part must match, ignoring a Debian epoch (`2:`) and revision (`-1`).
+    Upstream,
+}
+
+/// `apply --assume-installed`: answers every presence check the same way
+/// instead of querying the package managers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AssumeInstalled {
+    /// Nothing is installed: install every package.
+    None,
+    /// Everything is installed: skip every package.
+    All,
+}
+
+/// Extracts the upstream version from a Debian version string
+/// (`[epoch:]upstream_version[-debian_revision]`).
+pub fn debian_upstream_version(version: &str) -> &str {
+    let without_epoch = version.split_once(':').map_or(version, |(_, rest)| rest);
+    without_epoch
+        .rsplit_once('-')
+        .map_or(without_epoch, |(upstream, _)| upstream)
+}
+
+pub fn versions_match(installed: &str, desired: &str, version_match: VersionMatch) -> bool {
+    match version_match {
+        VersionMatch::Exact => installed == desired,
+        VersionMatch::Upstream => {
+            debian_upstream_version(installed) == debian_upstream_version(desired)
+        }
+    }
+}
+
+/// Returns the bare package name of a manifest spec in `section`, stripping
+/// version pins (`ripgrep=14.0.0`) and install flags (`code --classic`).
+pub fn base_package_name<'a>(section: &str, spec: &'a str) -> &'a str {
+    match section {
+        "apt" | "cargo" | "gem" | "dnf" => spec.split('=').next().unwrap_or(spec),
+        "snap" => split_snap_spec(spec).0,
+        "flatpak" => parse_flatpak_spec(spec).id,
+        "pipx" => split_pinned_spec(spec, "==").0,
+        "npm" => split_npm_spec(spec).0,
+        "xbps" => split_xbps_pkgver(spec).0,
+        "nix" => nix_package_name(spec),
+        // `go install` takes `path@version`, not `name=version`.
+        "go" => go_package_path(spec),
+        "mise" => split_pinned_spec(spec, "@").0,
+        // Formulae and casks of a tap may be written `user/tap/name`.
+        "brew" | "brew_cask" => spec.rsplit('/').next().unwrap_or(spec),
+        _ => spec,
+    }
+}
+
+/// Heuristically detects font packages (`fonts-noto`, `ttf-mscorefonts-installer`, ...).
+pub fn is_font_package(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.contains("font") || name.starts_with("ttf-") || name.starts_with("otf-")
+}
+
+/// Package index refresh run for `[system] update`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemUpdate {
+    /// `sudo apt update`
+    Apt,
+    /// `sudo xbps-install -S` on Void Linux.
+    Xbps,
+    /// `sudo dnf makecache` on Fedora and RHEL-like distributions.
+    Dnf,
+}
+
+impl SystemUpdate {
+    /// Picks the refresh for the running distribution from `/etc/os-release`,
+    /// falling back to apt.
+    pub fn detect() -> Self {
+        let os_release = std::fs::read_to_string("/etc/os-release").unwrap_or_default();
+        Self::from_os_release(&os_release)
+    }
+
+    fn from_os_release(content: &str) -> Self {
+        let id = content
+            .lines()
+            .find_map(|line| line.strip_prefix("ID="))
+            .map(|id| id.trim().trim_matches('"'));
+        let id_like = content
+            .lines()
+            .find_map(|line| line.strip_prefix("ID_LIKE="))
+            .map(|id_like| id_like.trim().trim_matches('"'))
+            .unwrap_or_default();
+        match id {
+            Some("void") => SystemUpdate::Xbps,
+            Some("fedora" | "rhel" | "centos") => SystemUpdate::Dnf,
+            _ if id_like
+                .split_whitespace()
+                .any(|like| matches!(like, "fedora" | "rhel")) =>
+            {
+                SystemUpdate::Dnf
+            }
+            _ => SystemUpdate::Apt,
+        }
+    }
+
+    /// Arguments passed to `sudo`.
+    pub fn args(self) -> &'static [&'static str] {
+        match self {
+            SystemUpdate::Apt => &["apt", "update"],
+            SystemUpdate::Xbps => &["xbps-install", "-S"],
+            SystemUpdate::Dnf => &["dnf", "makecache"],
+        }
+    }
+}
+
+/// Lock files held by dpkg/apt while they modify the system.
+pub const DPKG_LOCK_FILES: &[&str] = &[
+    "/var/lib/dpkg/lock-frontend",
+    "/var/lib/dpkg/lock",
+    "/var/lib/apt/lists/lock",
+];
+
+/// A process currently holding one of the dpkg/apt locks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockHolder {
+    pub pid: u32,
+    pub name: Option<String>,
+    pub path: String,
+}
+
+/// Returns the process holding a dpkg/apt lock, if any. Reads `/proc/locks`,
+/// so it works without root privileges.
+pub fn find_dpkg_lock_holder() -> Option<LockHolder> {
+    use std::os::unix::fs::MetadataExt;
+
+    let locks = std::fs::read_to_string("/proc/locks").ok()?;
+    DPKG_LOCK_FILES.iter().find_map(|path| {
+        let meta = std::fs::metadata(path).ok()?;
+        let dev = meta.dev();
+        let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+        let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+        let pid = find_lock_pid(&locks, major, minor, meta.ino())?;
+        let name = std::fs::read_to_string(format!("/proc/{}/comm", pid))
+            .ok()
+            .map(|comm| comm.trim().to_string());
+        Some(LockHolder {
+            pid,
+            name,
+            path: path.to_string(),
+        })
+    })
+}
+
+/// Finds the PID holding a lock on the given device/inode in `/proc/locks` content.
+fn find_lock_pid(locks: &str, major: u64, minor: u64, inode: u64) -> Option<u32> {
+    let target = format!("{:02x}:{:02x}:{}", major, minor, inode);
+    locks.lines().find_map(|line| {
+        // Lines prefixed with "->" are waiters, not holders.
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) == Some(&"->") {
+            return None;
+        }
+        match fields.as_slice() {
+            [_, _, _, _, pid, file, ..] if *file == target => pid.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// Splits a `[snap]` spec such as `code --classic` into the snap name and the
+/// flags passed to `snap install`. A channel pinned with `name=channel` is
+/// left out of both; see `snap_install_args`.
+pub fn split_snap_spec(spec: &str) -> (&str, Vec<&str>) {
+    let mut words = spec.split_whitespace();
+    let name = words.next().unwrap_or(spec);
+    let name = name.split_once('=').map_or(name, |(name, _)| name);
+    (name, words.collect())
+}
+
+/// The `snap install` arguments of a `[snap]` spec, turning a pinned
+/// `name=channel` into `name --channel=channel`.
+pub fn snap_install_args(spec: &str) -> Vec<String> {
+    let (name, flags) = split_snap_spec(spec);
+    let mut args = vec![name.to_string()];
+    args.extend(flags.into_iter().map(str::to_string));
+    if let Some((_, channel)) = spec
+        .split_whitespace()
+        .next()
+        .and_then(|w| w.split_once('='))
+        && !channel.is_empty()
+    {
+        args.push(format!("--channel={}", channel));
+    }
+    args
+}
+
+/// Checks whether the snap of a `[snap]` spec is installed. A pinned channel
+/// is not compared: an installed snap is not moved to another channel.
 pub fn is_snap_package_installed(pkg_name: &str) -> bool {
-    let base_pkg_name = pkg_name.split_whitespace().next().unwrap_or(pkg_name);
+    let base_pkg_name = split_snap_spec(pkg_name).0;
 
     let output = Command::new("snap").arg("info").arg(base_pkg_name).output();
 
@@ -19,8 +222,83 @@ pub fn is_snap_package_installed(pkg_name: &str) -> bool {
     }
 }
 
+/// A `[flatpak]` spec split into the parts of a Flatpak ref. Specs are an
+/// application ID (`org.gimp.GIMP`), an ID pinned to a branch
+/// (`org.gimp.GIMP=beta`), or a ref, full (`app/org.gimp.GIMP/x86_64/stable`)
+/// or partial (`org.gimp.GIMP//beta`).
+#[derive(Debug, PartialEq, Eq)]
+pub struct FlatpakRef<'a> {
+    /// `app` or `runtime`, if the ref gives it.
+    pub kind: Option<&'a str>,
+    pub id: &'a str,
+    pub arch: Option<&'a str>,
+    pub branch: Option<&'a str>,
+}
+
+pub fn parse_flatpak_spec(spec: &str) -> FlatpakRef<'_> {
+    let spec = spec.trim();
+    fn non_empty(part: Option<&str>) -> Option<&str> {
+        part.filter(|part| !part.is_empty())
+    }
+    if let Some((id, branch)) = spec.split_once('=') {
+        return FlatpakRef {
+            kind: None,
+            id,
+            arch: None,
+            branch: non_empty(Some(branch)),
+        };
+    }
+    let mut parts = spec.split('/').peekable();
+    let kind = parts
+        .next_if(|part| matches!(*part, "app" | "runtime"))
+        .filter(|_| spec.contains('/'));
+    FlatpakRef {
+        kind,
+        id: parts.next().unwrap_or(spec),
+        arch: non_empty(parts.next()),
+        branch: non_empty(parts.next()),
+    }
+}
+
+impl FlatpakRef<'_> {
+    /// The ref passed to `flatpak install`, e.g. `org.gimp.GIMP//beta` for
+    /// `org.gimp.GIMP=beta`.
+    pub fn install_ref(&self) -> String {
+        let mut install_ref = match self.kind {
+            Some(kind) => format!("{}/{}", kind, self.id),
+            None => self.id.to_string(),
+        };
+        if self.arch.is_some() || self.branch.is_some() {
+            install_ref.push_str(&format!(
+                "/{}/{}",
+                self.arch.unwrap_or_default(),
+                self.branch.unwrap_or_default()
+            ));
+        }
+        install_ref
+    }
+
+    /// The `flatpak info` arguments that find this ref, and only this branch
+    /// and arch of it if they are given.
+    fn info_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = self
+            .arch
+            .map(|arch| format!("--arch={}", arch))
+            .into_iter()
+            .collect();
+        args.push(self.id.to_string());
+        args.extend(self.branch.map(str::to_string));
+        args
+    }
+}
+
+/// Checks whether the app or runtime of a `[flatpak]` spec is installed, on
+/// the pinned branch and arch if the spec gives them.
 pub fn is_flatpak_package_installed(pkg_name: &str) -> bool {
-    let output = Command::new("flatpak").arg("info").arg(pkg_name).output();
+    let output = Command::new("flatpak")
+        .arg("info")
+        .args(parse_flatpak_spec(pkg_name).info_args())
+        .output();
 
     match output {
         Ok(output) => output.status.success(),
@@ -34,6 +312,49 @@ pub fn is_flatpak_package_installed(pkg_name: &str) -> bool {
     }
 }
 
+/// The argument `dnf install` takes for a `[dnf]` spec: `name`, or
+/// `name-version` for `name=version`.
+pub fn dnf_install_arg(spec: &str) -> String {
+    match split_pinned_spec(spec, "=") {
+        (name, Some(version)) => format!("{}-{}", name, version),
+        (name, None) => name.to_string(),
+    }
+}
+
+pub fn get_installed_dnf_packages() -> Result<Vec<String>, AppError> {
+    let mut packages: Vec<String> = get_installed_dnf_packages_map()?.into_keys().collect();
+    packages.sort();
+    Ok(packages)
+}
+
+/// Installed RPM packages mapped to their `version-release`.
+pub fn get_installed_dnf_packages_map() -> Result<HashMap<String, String>, AppError> {
+    let output = Command::new("rpm")
+        .args(["-qa", "--queryformat", "%{NAME} %{VERSION}-%{RELEASE}\n"])
+        .output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::Other(
+            format!("Failed to list installed RPM packages: {}", stderr).into(),
+        ));
+    }
+
+    Ok(parse_rpm_query(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parses `rpm -qa` lines of `name version-release`. Multilib and installonly
+/// packages (kernel) are listed once per instance; the last one is kept.
+fn parse_rpm_query(stdout: &str) -> HashMap<String, String> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let (name, version) = line.split_once(' ')?;
+            Some((name.to_string(), version.trim().to_string()))
+        })
+        .collect()
+}
+
 pub fn get_installed_apt_packages() -> Result<Vec<String>, AppError> {
     let output = Command::new("dpkg-query")
         .arg("-W")
@@ -60,20 +381,24 @@ pub fn get_installed_apt_packages() -> Result<Vec<String>, AppError> {
 }
 
 pub fn get_installed_cargo_packages() -> Result<Vec<String>, AppError> {
+    Ok(get_installed_cargo_crates()?
+        .into_iter()
+        .map(|installed| installed.name)
+        .collect())
+}
+
+pub fn get_installed_snap_packages() -> Result<Vec<String>, AppError> {
     let mut packages = Vec::new();
-    let output = Command::new("cargo")
-        .arg("install")
-        .arg("--list")
-        .output()?;
+    let output = Command::new("snap").arg("list").output()?;
 
     if !output.status.success() {
         return Err(AppError::Other(
-            "Failed to list installed Cargo packages.".into(),
+            "Failed to list installed Snap packages.".into(),
         ));
     }
 
     let stdout = String::from_utf8_lossy(&output.stdout);
-    for line in stdout.lines() {
+    for line in stdout.lines().skip(1) {
         if let Some(pkg_name) = line.split_whitespace().next() {
             packages.push(pkg_name.to_string());
         }
@@ -81,8 +406,10 @@ pub fn get_installed_cargo_packages() -> Result<Vec<String>, AppError> {
     Ok(packages)
 }
 
-pub fn get_installed_snap_packages() -> Result<Vec<String>, AppError> {
-    let mut packages = Vec::new();
+/// Installed snaps as `[snap]` specs, with `--classic` for classic snaps and
+/// `--channel=<tracking>` for snaps not following `latest/stable`, so that an
+/// exported manifest reinstalls them the same way.
+pub fn get_installed_snap_specs() -> Result<Vec<String>, AppError> {
     let output = Command::new("snap").arg("list").output()?;
 
     if !output.status.success() {
@@ -91,13 +418,32 @@ pub fn get_installed_snap_packages() -> Result<Vec<String>, AppError> {
         ));
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    for line in stdout.lines().skip(1) {
-        if let Some(pkg_name) = line.split_whitespace().next() {
-            packages.push(pkg_name.to_string());
-        }
-    }
-    Ok(packages)
+    Ok(parse_snap_specs(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parses `snap list` output (`Name Version Rev Tracking Publisher Notes`)
+/// into `[snap]` specs.
+fn parse_snap_specs(stdout: &str) -> Vec<String> {
+    stdout
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let mut spec = fields.first()?.to_string();
+            if let Some(tracking) = fields.get(3)
+                && !matches!(*tracking, "-" | "latest/stable" | "stable")
+            {
+                spec.push_str(&format!(" --channel={}", tracking));
+            }
+            if fields
+                .get(5)
+                .is_some_and(|notes| notes.split(',').any(|note| note == "classic"))
+            {
+                spec.push_str(" --classic");
+            }
+            Some(spec)
+        })
+        .collect()
 }
 
 pub fn get_installed_flatpak_packages() -> Result<Vec<String>, AppError> {
@@ -122,139 +468,1779 @@ pub fn get_installed_flatpak_packages() -> Result<Vec<String>, AppError> {
         .collect())
 }
 
-pub fn get_installed_apt_packages_map() -> Result<HashMap<String, String>, AppError> {
-    let output = Command::new("dpkg-query")
-        .arg("-W")
-        .arg("-f=${Package} ${Version}\\n")
-        .output()?;
+pub fn get_installed_xbps_packages() -> Result<Vec<String>, AppError> {
+    Ok(get_installed_xbps_packages_map()?.into_keys().collect())
+}
+
+pub fn get_installed_xbps_packages_map() -> Result<HashMap<String, String>, AppError> {
+    let output = Command::new("xbps-query").arg("-l").output()?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
         return Err(AppError::Other(
-            format!(
-                "Failed to list installed APT packages with versions: {}",
-                stderr
-            )
-            .into(),
+            format!("Failed to list installed XBPS packages: {}", stderr).into(),
         ));
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let mut map = HashMap::new();
-    for line in stdout.lines() {
-        if let Some((pkg, ver)) = line.split_once(' ') {
-            map.insert(pkg.to_string(), ver.to_string());
-        }
+    Ok(parse_xbps_query_list(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Parses `xbps-query -l` output (`ii bash-5.2.21_1  GNU Bourne Again Shell`)
+/// into a package → version map, keeping only fully installed packages.
+fn parse_xbps_query_list(stdout: &str) -> HashMap<String, String> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let status = fields.next()?;
+            let (name, version) = split_xbps_pkgver(fields.next()?);
+            let version = version.filter(|_| status == "ii")?;
+            Some((name.to_string(), version.to_string()))
+        })
+        .collect()
+}
+
+/// Splits an xbps `pkgver` such as `python3-pip-24.0_1` into name and version.
+/// The version is the part after the last `-` and always carries a `_revision`,
+/// which tells it apart from a dash inside the name.
+fn split_xbps_pkgver(spec: &str) -> (&str, Option<&str>) {
+    match spec.rsplit_once('-') {
+        Some((name, version)) if !name.is_empty() && version.contains('_') => (name, Some(version)),
+        _ => (spec, None),
     }
-    Ok(map)
 }
 
-pub fn get_installed_cargo_packages_map() -> Result<HashMap<String, String>, AppError> {
-    let output = Command::new("cargo")
-        .arg("install")
-        .arg("--list")
-        .output()?;
+/// Suites and codenames (`a=` and `n=` of the `release` lines) of the
+/// repositories APT knows about, from `apt-cache policy`.
+pub fn get_apt_release_names() -> Result<Vec<String>, AppError> {
+    let output = Command::new("apt-cache").arg("policy").output()?;
 
     if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
         return Err(AppError::Other(
-            "Failed to list installed Cargo packages.".into(),
+            format!("Failed to run apt-cache policy: {}", stderr).into(),
         ));
     }
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let mut map = HashMap::new();
-    for line in stdout.lines() {
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() >= 2 {
-            let pkg_name = parts[0].trim_end_matches(':');
-            let version = parts[1].trim_start_matches('v');
-            map.insert(pkg_name.to_string(), version.to_string());
-        }
+    Ok(parse_apt_policy_releases(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+fn parse_apt_policy_releases(stdout: &str) -> Vec<String> {
+    let mut releases: Vec<String> = stdout
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("release "))
+        .flat_map(|fields| fields.split(','))
+        .filter_map(|field| {
+            field
+                .strip_prefix("a=")
+                .or_else(|| field.strip_prefix("n="))
+        })
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect();
+    releases.sort();
+    releases.dedup();
+    releases
+}
+
+/// A package `railtube which` found: the name to list in the manifest and
+/// the version that would be installed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub name: String,
+    pub version: String,
+}
+
+/// Runs a package search, treating a failure to run it as an error but
+/// leaving its exit status to `parse`, since some searches fail when nothing
+/// matches.
+fn search_output(program: &str, args: &[&str]) -> Result<String, AppError> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| AppError::Other(format!("Failed to run {}: {}", program, e).into()))?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The candidate version of APT package `name`, from `apt-cache policy`.
+pub fn find_apt_candidate(name: &str) -> Result<Option<Candidate>, AppError> {
+    let stdout = search_output("apt-cache", &["policy", name])?;
+    Ok(
+        parse_apt_policy_candidate(&stdout).map(|version| Candidate {
+            name: name.to_string(),
+            version,
+        }),
+    )
+}
+
+/// The `Candidate:` of `apt-cache policy <name>`, unless it is `(none)`.
+fn parse_apt_policy_candidate(stdout: &str) -> Option<String> {
+    stdout
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Candidate:"))
+        .map(str::trim)
+        .filter(|version| !version.is_empty() && *version != "(none)")
+        .map(String::from)
+}
+
+/// The snap called exactly `name` in the store, from `snap find`.
+pub fn find_snap_candidate(name: &str) -> Result<Option<Candidate>, AppError> {
+    let stdout = search_output("snap", &["find", name])?;
+    Ok(parse_snap_find(&stdout, name))
+}
+
+/// Finds `name` in `snap find` output (`Name Version Publisher Notes Summary`).
+fn parse_snap_find(stdout: &str, name: &str) -> Option<Candidate> {
+    stdout.lines().skip(1).find_map(|line| {
+        let mut columns = line.split_whitespace();
+        let (found, version) = (columns.next()?, columns.next()?);
+        (found == name).then(|| Candidate {
+            name: found.to_string(),
+            version: version.to_string(),
+        })
+    })
+}
+
+/// The Flatpak app whose ID is `name` or ends in `.name`, ignoring case
+/// (`gimp` finds `org.gimp.GIMP`), from `flatpak search`.
+pub fn find_flatpak_candidate(name: &str) -> Result<Option<Candidate>, AppError> {
+    let stdout = search_output(
+        "flatpak",
+        &["search", "--columns=application,version", name],
+    )?;
+    Ok(parse_flatpak_search(&stdout, name))
+}
+
+/// Finds `name` in `flatpak search --columns=application,version` output,
+/// one tab-separated app per line.
+fn parse_flatpak_search(stdout: &str, name: &str) -> Option<Candidate> {
+    stdout.lines().find_map(|line| {
+        let mut columns = line.split('\t');
+        let id = columns.next()?.trim();
+        let version = columns.next().map(str::trim).unwrap_or_default();
+        let last = id.rsplit('.').next().unwrap_or(id);
+        (id.eq_ignore_ascii_case(name) || last.eq_ignore_ascii_case(name)).then(|| Candidate {
+            name: id.to_string(),
+            version: if version.is_empty() { "?" } else { version }.to_string(),
+        })
+    })
+}
+
+/// The latest stable version of crate `name` on crates.io.
+pub fn find_crates_io_candidate(name: &str) -> Result<Option<Candidate>, AppError> {
+    // crates.io rejects requests without a User-Agent.
+    let client = reqwest::blocking::Client::builder()
+        .user_agent(concat!("railtube/", env!("CARGO_PKG_VERSION")))
+        .timeout(std::time::Duration::from_secs(30))
+        .build()?;
+    let url = format!("https://crates.io/api/v1/crates/{}", name);
+    let response = crate::utils::fetch_with_retry(&client, &url, crate::utils::NETWORK_RETRIES)?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
     }
-    Ok(map)
+    let body: serde_json::Value = serde_json::from_slice(&response.error_for_status()?.bytes()?)
+        .map_err(|e| AppError::Other(format!("Failed to parse {}: {}", url, e).into()))?;
+    Ok(parse_crates_io_crate(&body))
 }
 
-pub fn determine_package_installation(
-    pkg_name: &str,
-    desired_version: &Option<String>,
-    installed_version: Option<&String>,
-    package_type: &str,
-) -> bool {
-    if let Some(installed_version) = installed_version {
-        if let Some(version_to_match) = desired_version {
-            if installed_version != version_to_match {
-                println!(
-                    "{} package '{}' installed with version '{}', but '{}' is requested. Reinstalling.",
-                    package_type, pkg_name, installed_version, version_to_match
-                );
-                true
-            } else {
-                println!(
-                    "{} package '{}' version '{}' already installed, skipping.",
-                    package_type, pkg_name, installed_version
-                );
-                false
-            }
-        } else {
-            println!(
-                "{} package '{}' already installed, skipping.",
-                package_type, pkg_name
-            );
-            false
-        }
-    } else {
-        if let Some(version) = desired_version {
-            println!(
-                "{} package '{}' version '{}' not installed. Installing.",
-                package_type, pkg_name, version
-            );
+/// The name and `max_stable_version` (or, for crates without a stable
+/// release, `newest_version`) of a crates.io `/api/v1/crates/<name>` response.
+fn parse_crates_io_crate(body: &serde_json::Value) -> Option<Candidate> {
+    let krate = &body["crate"];
+    let version = krate["max_stable_version"]
+        .as_str()
+        .or_else(|| krate["newest_version"].as_str())?;
+    Some(Candidate {
+        name: krate["name"].as_str()?.to_string(),
+        version: version.to_string(),
+    })
+}
+
+pub fn get_installed_pipx_packages() -> Result<Vec<String>, AppError> {
+    let mut packages: Vec<String> = get_installed_pipx_packages_map()?.into_keys().collect();
+    packages.sort();
+    Ok(packages)
+}
+
+pub fn get_installed_pipx_packages_map() -> Result<HashMap<String, String>, AppError> {
+    let output = Command::new("pipx").arg("list").arg("--json").output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::Other(
+            format!("Failed to list installed pipx packages: {}", stderr).into(),
+        ));
+    }
+
+    parse_pipx_list_json(&output.stdout)
+}
+
+/// Parses `pipx list --json`, mapping each venv's main package to its version.
+fn parse_pipx_list_json(stdout: &[u8]) -> Result<HashMap<String, String>, AppError> {
+    let list: serde_json::Value = serde_json::from_slice(stdout)
+        .map_err(|e| AppError::Other(format!("Failed to parse pipx list --json: {}", e).into()))?;
+    Ok(list["venvs"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(venv, info)| {
+            let main = &info["metadata"]["main_package"];
+            let name = main["package"].as_str().unwrap_or(venv);
+            let version = main["package_version"].as_str()?;
+            Some((name.to_string(), version.to_string()))
+        })
+        .collect())
+}
+
+pub fn get_installed_npm_packages() -> Result<Vec<String>, AppError> {
+    let mut packages: Vec<String> = get_installed_npm_packages_map()?.into_keys().collect();
+    packages.sort();
+    Ok(packages)
+}
+
+pub fn get_installed_npm_packages_map() -> Result<HashMap<String, String>, AppError> {
+    let output = Command::new("npm")
+        .args(["ls", "-g", "--json", "--depth=0"])
+        .output()?;
+
+    // `npm ls` also exits non-zero for problems such as extraneous packages,
+    // while still listing what is installed.
+    parse_npm_ls_json(&output.stdout).map_err(|e| {
+        if output.status.success() {
+            e
         } else {
-            println!(
-                "{} package '{}' not installed. Installing.",
-                package_type, pkg_name
-            );
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            AppError::Other(format!("Failed to list global npm packages: {}", stderr).into())
         }
-        true
+    })
+}
+
+/// Parses `npm ls -g --json --depth=0`, mapping each global package to its version.
+fn parse_npm_ls_json(stdout: &[u8]) -> Result<HashMap<String, String>, AppError> {
+    let list: serde_json::Value = serde_json::from_slice(stdout)
+        .map_err(|e| AppError::Other(format!("Failed to parse npm ls --json: {}", e).into()))?;
+    Ok(list["dependencies"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(name, info)| Some((name.clone(), info["version"].as_str()?.to_string())))
+        .collect())
+}
+
+/// Splits an npm spec `name@version` at its last `@`, so that scoped packages
+/// (`@scope/name@1.0.0`) keep their leading `@`.
+pub fn split_npm_spec(spec: &str) -> (&str, Option<&str>) {
+    match spec.rfind('@') {
+        Some(at) if at > 0 => (&spec[..at], Some(&spec[at + 1..])),
+        _ => (spec, None),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+pub fn get_installed_nix_packages() -> Result<Vec<String>, AppError> {
+    let output = Command::new("nix").args(["profile", "list"]).output()?;
 
-    #[test]
-    fn test_determine_install_not_installed_no_version() {
-        let result = determine_package_installation("testpkg", &None, None, "Test");
-        assert!(result);
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::Other(
+            format!("Failed to list nix profile packages: {}", stderr).into(),
+        ));
     }
 
-    #[test]
-    fn test_determine_install_not_installed_with_version() {
-        let result =
-            determine_package_installation("testpkg", &Some("1.0".to_string()), None, "Test");
-        assert!(result);
+    Ok(parse_nix_profile_list(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Parses `nix profile list` output: the `Name:` lines of Nix 2.20 and later,
+/// or the `<index> <flake>#<attr> <locked flake> <store paths>` lines of
+/// earlier versions.
+fn parse_nix_profile_list(stdout: &str) -> Vec<String> {
+    let mut packages: Vec<String> = stdout
+        .lines()
+        .filter_map(|line| {
+            if let Some(name) = line.strip_prefix("Name:") {
+                return Some(name.trim().to_string());
+            }
+            let mut fields = line.split_whitespace();
+            fields.next()?.parse::<usize>().ok()?;
+            Some(nix_package_name(fields.next()?).to_string())
+        })
+        .collect();
+    packages.sort();
+    packages
+}
+
+/// The package name of a `[nix]` spec: the last component of the attribute
+/// path after `#` (`nixpkgs#python3Packages.black` is `black`), of a bare
+/// nixpkgs attribute (`ripgrep`), or the last path segment of a flake URL
+/// without one (`github:nix-community/home-manager`). Unlike other sections,
+/// nix specs have no `=version` part; flake URLs may contain `=` in queries.
+pub fn nix_package_name(spec: &str) -> &str {
+    match spec.split_once('#') {
+        Some((_, attr)) => attr.rsplit('.').next().unwrap_or(attr),
+        None if spec.contains(':') => {
+            let url = spec.split('?').next().unwrap_or(spec);
+            url.trim_end_matches('/').rsplit('/').next().unwrap_or(url)
+        }
+        None => spec.rsplit('.').next().unwrap_or(spec),
     }
+}
 
-    #[test]
-    fn test_determine_skip_installed_no_desired() {
-        let installed = "1.0".to_string();
-        let result = determine_package_installation("testpkg", &None, Some(&installed), "Test");
-        assert!(!result);
+/// The installable passed to `nix profile install`: flake references are
+/// used as they are, bare attributes are looked up in `nixpkgs`.
+pub fn nix_installable(spec: &str) -> String {
+    if spec.contains('#') || spec.contains(':') {
+        spec.to_string()
+    } else {
+        format!("nixpkgs#{}", spec)
     }
+}
 
-    #[test]
-    fn test_determine_skip_installed_matching_version() {
-        let installed = "1.0".to_string();
-        let result = determine_package_installation(
-            "testpkg",
-            &Some("1.0".to_string()),
-            Some(&installed),
-            "Test",
-        );
-        assert!(!result);
+/// The package path of a `[go]` spec, without its `@version` suffix.
+pub fn go_package_path(spec: &str) -> &str {
+    split_go_spec(spec).0
+}
+
+/// Splits `path@version`. `@latest` is not a pin: any installed version of the
+/// package satisfies it.
+pub fn split_go_spec(spec: &str) -> (&str, Option<&str>) {
+    match spec.split_once('@') {
+        Some((path, "latest")) => (path, None),
+        Some((path, version)) => (path, Some(version)),
+        None => (spec, None),
     }
+}
 
-    #[test]
+/// The argument passed to `go install`: outside a module it needs a
+/// version, so a spec without one installs `@latest`.
+pub fn go_install_target(spec: &str) -> String {
+    if spec.contains('@') {
+        spec.to_string()
+    } else {
+        format!("{}@latest", spec)
+    }
+}
+
+/// Where `go install` puts binaries: `$GOBIN`, else the `bin` directory of
+/// the first `$GOPATH` entry, else `~/go/bin`.
+pub fn go_bin_dir() -> Option<PathBuf> {
+    let non_empty = |name| std::env::var_os(name).filter(|value| !value.is_empty());
+    if let Some(gobin) = non_empty("GOBIN") {
+        return Some(PathBuf::from(gobin));
+    }
+    match non_empty("GOPATH") {
+        Some(gopath) => std::env::split_paths(&gopath)
+            .next()
+            .map(|path| path.join("bin")),
+        None => dirs::home_dir().map(|home| home.join("go").join("bin")),
+    }
+}
+
+/// Package paths of the binaries in the Go bin directory.
+pub fn get_installed_go_packages() -> Result<Vec<String>, AppError> {
+    let mut packages: Vec<String> = get_installed_go_packages_map()?.into_keys().collect();
+    packages.sort();
+    Ok(packages)
+}
+
+/// Package paths of the binaries in the Go bin directory mapped to their
+/// module versions, read from the build information `go version -m` prints
+/// for each of them.
+pub fn get_installed_go_packages_map() -> Result<HashMap<String, String>, AppError> {
+    let Some(dir) = go_bin_dir().filter(|dir| dir.is_dir()) else {
+        return Ok(HashMap::new());
+    };
+    let output = Command::new("go")
+        .arg("version")
+        .arg("-m")
+        .arg(&dir)
+        .output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::Other(
+            format!("Failed to list installed Go binaries: {}", stderr).into(),
+        ));
+    }
+
+    Ok(parse_go_version_m(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parses `go version -m`: each binary's `path` line names its package and
+/// the `mod` line after it the version of its main module.
+fn parse_go_version_m(stdout: &str) -> HashMap<String, String> {
+    let mut packages = HashMap::new();
+    let mut path: Option<&str> = None;
+    for line in stdout.lines() {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some("path"), Some(package), _) => {
+                packages.entry(package.to_string()).or_default();
+                path = Some(package);
+            }
+            (Some("mod"), Some(_), Some(version)) => {
+                if let Some(package) = path.take() {
+                    packages.insert(package.to_string(), version.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    packages
+}
+
+/// `gem install` arguments for a `[gem]` spec: `name`, or `name -v version`
+/// for `name=version`.
+pub fn gem_install_args(spec: &str) -> Vec<&str> {
+    match split_pinned_spec(spec, "=") {
+        (name, Some(version)) => vec!["install", name, "-v", version],
+        (name, None) => vec!["install", name],
+    }
+}
+
+/// Whether the gem of `spec` is in `installed`, at the pinned version if it
+/// has one.
+pub fn is_gem_package_installed(installed: &HashMap<String, Vec<String>>, spec: &str) -> bool {
+    let (name, version) = split_pinned_spec(spec, "=");
+    installed
+        .get(name)
+        .is_some_and(|versions| version.is_none_or(|v| versions.iter().any(|i| i == v)))
+}
+
+pub fn get_installed_gem_packages() -> Result<Vec<String>, AppError> {
+    let mut gems: Vec<String> = get_installed_gem_packages_map()?.into_keys().collect();
+    gems.sort();
+    Ok(gems)
+}
+
+/// Installed gems mapped to their installed versions.
+pub fn get_installed_gem_packages_map() -> Result<HashMap<String, Vec<String>>, AppError> {
+    let output = Command::new("gem").args(["list", "--local"]).output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::Other(
+            format!("Failed to list installed gems: {}", stderr).into(),
+        ));
+    }
+
+    Ok(parse_gem_list(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parses `gem list --local`: one `name (version, ...)` line per gem, with
+/// `default:` before the versions of gems that ship with Ruby and the
+/// platform after those of native gems (`1.16.0 x86_64-linux`).
+fn parse_gem_list(stdout: &str) -> HashMap<String, Vec<String>> {
+    stdout
+        .lines()
+        .filter_map(|line| line.split_once(" ("))
+        .map(|(name, versions)| {
+            let versions = versions
+                .trim_end_matches(')')
+                .split(", ")
+                .filter_map(|version| {
+                    let version = version.trim_start_matches("default: ");
+                    version.split_whitespace().next().map(String::from)
+                })
+                .collect();
+            (name.trim().to_string(), versions)
+        })
+        .collect()
+}
+
+/// Whether the tool of a `[mise]` spec, `tool` or `tool@version`, is in
+/// `installed`, at that version if it has one. Like mise, a version prefix
+/// matches (`node@20` is satisfied by 20.11.0), and `latest` by any version.
+pub fn is_mise_tool_installed(installed: &HashMap<String, Vec<String>>, spec: &str) -> bool {
+    let (tool, version) = split_pinned_spec(spec, "@");
+    installed.get(tool).is_some_and(|versions| {
+        versions
+            .iter()
+            .any(|installed| version.is_none_or(|v| mise_version_matches(installed, v)))
+    })
+}
+
+pub fn get_installed_mise_tools() -> Result<Vec<String>, AppError> {
+    let mut tools: Vec<String> = get_installed_mise_tools_map()?.into_keys().collect();
+    tools.sort();
+    Ok(tools)
+}
+
+fn mise_version_matches(installed: &str, requested: &str) -> bool {
+    requested == "latest"
+        || installed == requested
+        || installed
+            .strip_prefix(requested)
+            .is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// The installed mise tools as `tool@version` specs, with the active version
+/// of tools that have several.
+pub fn get_installed_mise_specs() -> Result<Vec<String>, AppError> {
+    let mut specs: Vec<String> = get_installed_mise_tools_map()?
+        .into_iter()
+        .map(|(tool, versions)| format!("{}@{}", tool, versions[0]))
+        .collect();
+    specs.sort();
+    Ok(specs)
+}
+
+/// Installed mise tools mapped to their installed versions, the active one first.
+pub fn get_installed_mise_tools_map() -> Result<HashMap<String, Vec<String>>, AppError> {
+    let output = Command::new("mise").args(["ls", "--json"]).output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::Other(
+            format!("Failed to list installed mise tools: {}", stderr).into(),
+        ));
+    }
+
+    parse_mise_ls_json(&output.stdout)
+}
+
+/// Parses `mise ls --json`: each tool maps to a list of versions, flagged
+/// `installed` and `active`. Versions a config requests but that are not
+/// installed are left out.
+fn parse_mise_ls_json(stdout: &[u8]) -> Result<HashMap<String, Vec<String>>, AppError> {
+    let list: serde_json::Value = serde_json::from_slice(stdout)
+        .map_err(|e| AppError::Other(format!("Failed to parse mise ls --json: {}", e).into()))?;
+    Ok(list
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(tool, versions)| {
+            let mut installed: Vec<&serde_json::Value> = versions
+                .as_array()?
+                .iter()
+                .filter(|v| v["installed"].as_bool().unwrap_or(false))
+                .collect();
+            installed.sort_by_key(|v| !v["active"].as_bool().unwrap_or(false));
+            let installed: Vec<String> = installed
+                .into_iter()
+                .filter_map(|v| Some(v["version"].as_str()?.to_string()))
+                .collect();
+            (!installed.is_empty()).then(|| (tool.clone(), installed))
+        })
+        .collect())
+}
+
+/// Manually installed APT packages that `apply --prune` may remove: those
+/// marked manual, minus essential packages and those of `required` or
+/// `important` priority, which make up the base system.
+pub fn get_prunable_apt_packages() -> Result<Vec<String>, AppError> {
+    let manual = Command::new("apt-mark").arg("showmanual").output()?;
+    if !manual.status.success() {
+        let stderr = String::from_utf8_lossy(&manual.stderr);
+        return Err(AppError::Other(
+            format!("Failed to list manually installed APT packages: {}", stderr).into(),
+        ));
+    }
+
+    let priorities = Command::new("dpkg-query")
+        .arg("-W")
+        .arg("-f=${Package} ${Essential} ${Priority}\n")
+        .output()?;
+    if !priorities.status.success() {
+        let stderr = String::from_utf8_lossy(&priorities.stderr);
+        return Err(AppError::Other(
+            format!("Failed to read APT package priorities: {}", stderr).into(),
+        ));
+    }
+    let protected = parse_protected_dpkg_packages(&String::from_utf8_lossy(&priorities.stdout));
+
+    Ok(String::from_utf8_lossy(&manual.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|pkg| !pkg.is_empty() && !protected.contains(*pkg))
+        .map(String::from)
+        .collect())
+}
+
+/// Packages of `dpkg-query -W -f='${Package} ${Essential} ${Priority}\n'`
+/// output that are essential or of `required`/`important` priority.
+fn parse_protected_dpkg_packages(stdout: &str) -> HashSet<String> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let pkg = fields.next()?;
+            let rest: Vec<&str> = fields.collect();
+            rest.iter()
+                .any(|field| matches!(*field, "yes" | "required" | "important"))
+                .then(|| pkg.to_string())
+        })
+        .collect()
+}
+
+/// Installed snaps that `apply --prune` may remove, leaving out the base,
+/// core and snapd snaps that others depend on.
+pub fn get_prunable_snap_packages() -> Result<Vec<String>, AppError> {
+    let output = Command::new("snap").arg("list").output()?;
+
+    if !output.status.success() {
+        return Err(AppError::Other(
+            "Failed to list installed Snap packages.".into(),
+        ));
+    }
+
+    Ok(parse_prunable_snaps(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Parses `snap list` output (`Name Version Rev Tracking Publisher Notes`),
+/// skipping snaps whose notes mark them as `base`, `core` or `snapd`.
+fn parse_prunable_snaps(stdout: &str) -> Vec<String> {
+    stdout
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let name = fields.first()?;
+            let notes = fields.last()?;
+            let system = notes
+                .split(',')
+                .any(|note| matches!(note, "base" | "core" | "snapd"));
+            (!system && *name != "snapd").then(|| name.to_string())
+        })
+        .collect()
+}
+
+/// First components of the target triples rustup appends to toolchain and
+/// component names (`stable-x86_64-unknown-linux-gnu`, `clippy-aarch64-apple-darwin`).
+const TARGET_ARCHES: &[&str] = &[
+    "x86_64",
+    "i686",
+    "i586",
+    "aarch64",
+    "arm",
+    "thumb",
+    "riscv",
+    "wasm32",
+    "powerpc",
+    "s390x",
+    "mips",
+    "loongarch64",
+    "sparc",
+];
+
+/// `name` without the target triple rustup appended to it, if any.
+fn strip_target_triple(name: &str) -> &str {
+    name.match_indices('-')
+        .find(|(i, _)| {
+            TARGET_ARCHES
+                .iter()
+                .any(|arch| name[i + 1..].starts_with(arch))
+        })
+        .map_or(name, |(i, _)| &name[..i])
+}
+
+/// Parses `rustup toolchain list` or `rustup component list --installed`
+/// output into names without target triples or `(default)` markers.
+pub fn parse_rustup_list(stdout: &str) -> Vec<String> {
+    let mut names: Vec<String> = stdout
+        .lines()
+        .filter(|line| !line.starts_with("no installed"))
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|name| strip_target_triple(name).to_string())
+        .collect();
+    names.dedup();
+    names
+}
+
+fn rustup_list(args: &[&str]) -> Result<Vec<String>, AppError> {
+    let output = Command::new("rustup").args(args).output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::Other(
+            format!("Failed to run rustup {}: {}", args.join(" "), stderr).into(),
+        ));
+    }
+    Ok(parse_rustup_list(&String::from_utf8_lossy(&output.stdout)))
+}
+
+pub fn get_installed_rustup_toolchains() -> Result<Vec<String>, AppError> {
+    rustup_list(&["toolchain", "list"])
+}
+
+/// Components installed for the default toolchain.
+pub fn get_installed_rustup_components() -> Result<Vec<String>, AppError> {
+    rustup_list(&["component", "list", "--installed"])
+}
+/// Installed toolchains followed by installed components.
+pub fn get_installed_rustup_packages() -> Result<Vec<String>, AppError> {
+    let mut installed = get_installed_rustup_toolchains()?;
+    installed.extend(get_installed_rustup_components()?);
+    Ok(installed)
+}
+
+/// Runs `brew` with `args` and returns the non-empty output lines.
+fn brew_list(args: &[&str]) -> Result<Vec<String>, AppError> {
+    let output = Command::new("brew").args(args).output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::Other(
+            format!("Failed to run brew {}: {}", args.join(" "), stderr).into(),
+        ));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Whether `brew list <kind> <name>` succeeds, `kind` being `--formula` or `--cask`.
+fn is_brew_installed(kind: &str, name: &str) -> bool {
+    let output = Command::new("brew").args(["list", kind, name]).output();
+
+    match output {
+        Ok(output) => output.status.success(),
+        Err(e) => {
+            eprintln!(
+                "Warning: Error executing 'brew list': {}. Assuming '{}' is not installed.",
+                e, name
+            );
+            false
+        }
+    }
+}
+
+pub fn is_brew_package_installed(pkg_name: &str) -> bool {
+    is_brew_installed("--formula", pkg_name)
+}
+
+pub fn is_brew_cask_installed(cask: &str) -> bool {
+    is_brew_installed("--cask", cask)
+}
+
+pub fn get_installed_brew_packages() -> Result<Vec<String>, AppError> {
+    brew_list(&["list", "--formula", "-1"])
+}
+
+pub fn get_installed_brew_casks() -> Result<Vec<String>, AppError> {
+    brew_list(&["list", "--cask", "-1"])
+}
+
+pub fn get_installed_brew_taps() -> Result<Vec<String>, AppError> {
+    brew_list(&["tap"])
+}
+
+pub fn get_flatpak_permissions(app_id: &str) -> Result<String, AppError> {
+    let output = Command::new("flatpak")
+        .arg("info")
+        .arg("--show-permissions")
+        .arg(app_id)
+        .output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::Other(
+            format!(
+                "Failed to read Flatpak permissions for '{}': {}",
+                app_id, stderr
+            )
+            .into(),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Checks whether a `flatpak override` argument (e.g. `--filesystem=home`) is
+/// reflected in the output of `flatpak info --show-permissions`.
+///
+/// Returns `None` for override kinds that cannot be verified this way.
+pub fn is_flatpak_override_applied(permissions: &str, override_arg: &str) -> Option<bool> {
+    let (flag, value) = override_arg.trim_start_matches("--").split_once('=')?;
+    let (group, key, expected) = match flag {
+        "filesystem" => ("Context", "filesystems", value.to_string()),
+        "nofilesystem" => ("Context", "filesystems", format!("!{}", value)),
+        "share" => ("Context", "shared", value.to_string()),
+        "unshare" => ("Context", "shared", format!("!{}", value)),
+        "socket" => ("Context", "sockets", value.to_string()),
+        "nosocket" => ("Context", "sockets", format!("!{}", value)),
+        "device" => ("Context", "devices", value.to_string()),
+        "nodevice" => ("Context", "devices", format!("!{}", value)),
+        "env" => {
+            let (var, val) = value.split_once('=')?;
+            return Some(keyfile_value(permissions, "Environment", var).is_some_and(|v| v == val));
+        }
+        _ => return None,
+    };
+
+    Some(
+        keyfile_value(permissions, group, key)
+            .is_some_and(|v| v.split(';').any(|item| item == expected)),
+    )
+}
+
+fn keyfile_value<'a>(content: &'a str, group: &str, key: &str) -> Option<&'a str> {
+    let mut in_group = false;
+    for line in content.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_group = name == group;
+        } else if in_group
+            && let Some((k, v)) = line.split_once('=')
+            && k == key
+        {
+            return Some(v);
+        }
+    }
+    None
+}
+
+pub fn get_installed_apt_packages_map() -> Result<HashMap<String, String>, AppError> {
+    let output = Command::new("dpkg-query")
+        .arg("-W")
+        .arg("-f=${db:Status-Abbrev} ${Package} ${Version}\\n")
+        .output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::Other(
+            format!(
+                "Failed to list installed APT packages with versions: {}",
+                stderr
+            )
+            .into(),
+        ));
+    }
+
+    Ok(parse_dpkg_query_map(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Parses `dpkg-query -W -f='${db:Status-Abbrev} ${Package} ${Version}\n'`
+/// output into a package → version map, keeping only packages that are
+/// actually installed (not e.g. removed with leftover config files).
+fn parse_dpkg_query_map(stdout: &str) -> HashMap<String, String> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let status = fields.next()?;
+            let pkg = fields.next()?;
+            let ver = fields.next()?;
+            (status.chars().nth(1) == Some('i')).then(|| (pkg.to_string(), ver.to_string()))
+        })
+        .collect()
+}
+
+/// Returns whether `cargo binstall` is available.
+pub fn is_cargo_binstall_available() -> bool {
+    Command::new("cargo")
+        .arg("binstall")
+        .arg("-V")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+pub fn get_installed_cargo_packages_map() -> Result<HashMap<String, String>, AppError> {
+    Ok(get_installed_cargo_crates()?
+        .into_iter()
+        .map(|installed| (installed.name, installed.version))
+        .collect())
+}
+
+/// A crate installed with `cargo install`, with the binaries it provides.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CargoCrate {
+    pub name: String,
+    pub version: String,
+    pub binaries: Vec<String>,
+}
+
+pub fn get_installed_cargo_crates() -> Result<Vec<CargoCrate>, AppError> {
+    let output = Command::new("cargo")
+        .arg("install")
+        .arg("--list")
+        .output()?;
+
+    if !output.status.success() {
+        return Err(AppError::Other(
+            "Failed to list installed Cargo packages.".into(),
+        ));
+    }
+
+    Ok(parse_cargo_install_list(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Parses `cargo install --list` output: a `<crate> v<version>[ (<source>)]:`
+/// line per crate, followed by its binaries indented on their own lines.
+fn parse_cargo_install_list(stdout: &str) -> Vec<CargoCrate> {
+    let mut crates: Vec<CargoCrate> = Vec::new();
+    for line in stdout.lines() {
+        if line.starts_with(char::is_whitespace) {
+            if let (Some(installed), binary) = (crates.last_mut(), line.trim())
+                && !binary.is_empty()
+            {
+                installed.binaries.push(binary.to_string());
+            }
+            continue;
+        }
+        let mut parts = line.trim_end_matches(':').split_whitespace();
+        if let (Some(name), Some(version)) = (parts.next(), parts.next()) {
+            crates.push(CargoCrate {
+                name: name.to_string(),
+                version: version
+                    .trim_start_matches('v')
+                    .trim_end_matches(':')
+                    .to_string(),
+                binaries: Vec::new(),
+            });
+        }
+    }
+    crates
+}
+
+/// Finds the installed crate called `name`, or else the one providing a
+/// binary called `name` (e.g. `rg` for `ripgrep`), since `cargo uninstall`
+/// needs the crate name.
+pub fn find_cargo_crate<'a>(crates: &'a [CargoCrate], name: &str) -> Option<&'a CargoCrate> {
+    crates
+        .iter()
+        .find(|installed| installed.name == name)
+        .or_else(|| {
+            crates
+                .iter()
+                .find(|installed| installed.binaries.iter().any(|binary| binary == name))
+        })
+}
+
+/// A package manager that reports installed versions, so pinned manifest
+/// entries can be compared against what is installed. Apply and doctor share
+/// this path for every version-capable section.
+pub trait PackageManager {
+    /// Manifest section name, e.g. `apt`.
+    fn section(&self) -> &'static str;
+
+    /// Name used in messages, e.g. `APT`.
+    fn display_name(&self) -> &'static str;
+
+    /// Splits a manifest spec into the package name and its pinned version, if any.
+    fn split_spec<'a>(&self, spec: &'a str) -> (&'a str, Option<&'a str>);
+
+    /// Builds a spec pinning `name` to `version`, the inverse of `split_spec`.
+    fn pin_spec(&self, name: &str, version: &str) -> String {
+        format!("{}={}", name, version)
+    }
+
+    /// Installed packages mapped to their versions.
+    fn installed_versions(&self) -> Result<HashMap<String, String>, AppError>;
+}
+
+pub struct Apt;
+
+impl PackageManager for Apt {
+    fn section(&self) -> &'static str {
+        "apt"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "APT"
+    }
+
+    fn split_spec<'a>(&self, spec: &'a str) -> (&'a str, Option<&'a str>) {
+        split_pinned_spec(spec, "=")
+    }
+
+    fn installed_versions(&self) -> Result<HashMap<String, String>, AppError> {
+        get_installed_apt_packages_map()
+    }
+}
+
+pub struct Cargo;
+
+impl PackageManager for Cargo {
+    fn section(&self) -> &'static str {
+        "cargo"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "Cargo"
+    }
+
+    fn split_spec<'a>(&self, spec: &'a str) -> (&'a str, Option<&'a str>) {
+        split_pinned_spec(spec, "=")
+    }
+
+    fn installed_versions(&self) -> Result<HashMap<String, String>, AppError> {
+        get_installed_cargo_packages_map()
+    }
+}
+
+pub struct Pipx;
+
+impl PackageManager for Pipx {
+    fn section(&self) -> &'static str {
+        "pipx"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "pipx"
+    }
+
+    fn split_spec<'a>(&self, spec: &'a str) -> (&'a str, Option<&'a str>) {
+        split_pinned_spec(spec, "==")
+    }
+
+    fn pin_spec(&self, name: &str, version: &str) -> String {
+        format!("{}=={}", name, version)
+    }
+
+    fn installed_versions(&self) -> Result<HashMap<String, String>, AppError> {
+        get_installed_pipx_packages_map()
+    }
+}
+
+pub struct Npm;
+
+impl PackageManager for Npm {
+    fn section(&self) -> &'static str {
+        "npm"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "npm"
+    }
+
+    fn split_spec<'a>(&self, spec: &'a str) -> (&'a str, Option<&'a str>) {
+        split_npm_spec(spec)
+    }
+
+    fn pin_spec(&self, name: &str, version: &str) -> String {
+        format!("{}@{}", name, version)
+    }
+
+    fn installed_versions(&self) -> Result<HashMap<String, String>, AppError> {
+        get_installed_npm_packages_map()
+    }
+}
+
+pub struct Go;
+
+impl PackageManager for Go {
+    fn section(&self) -> &'static str {
+        "go"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "Go"
+    }
+
+    fn split_spec<'a>(&self, spec: &'a str) -> (&'a str, Option<&'a str>) {
+        split_go_spec(spec)
+    }
+
+    fn pin_spec(&self, name: &str, version: &str) -> String {
+        format!("{}@{}", name, version)
+    }
+
+    fn installed_versions(&self) -> Result<HashMap<String, String>, AppError> {
+        get_installed_go_packages_map()
+    }
+}
+
+pub struct Dnf;
+
+impl PackageManager for Dnf {
+    fn section(&self) -> &'static str {
+        "dnf"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "DNF"
+    }
+
+    fn split_spec<'a>(&self, spec: &'a str) -> (&'a str, Option<&'a str>) {
+        split_pinned_spec(spec, "=")
+    }
+
+    fn installed_versions(&self) -> Result<HashMap<String, String>, AppError> {
+        get_installed_dnf_packages_map()
+    }
+}
+
+pub struct Xbps;
+
+impl PackageManager for Xbps {
+    fn section(&self) -> &'static str {
+        "xbps"
+    }
+
+    fn display_name(&self) -> &'static str {
+        "XBPS"
+    }
+
+    fn split_spec<'a>(&self, spec: &'a str) -> (&'a str, Option<&'a str>) {
+        split_xbps_pkgver(spec)
+    }
+
+    fn pin_spec(&self, name: &str, version: &str) -> String {
+        format!("{}-{}", name, version)
+    }
+
+    fn installed_versions(&self) -> Result<HashMap<String, String>, AppError> {
+        get_installed_xbps_packages_map()
+    }
+}
+
+/// Splits `name<separator>version`, e.g. `ripgrep=14.0.0` or `black==24.1.0`.
+pub fn split_pinned_spec<'a>(spec: &'a str, separator: &str) -> (&'a str, Option<&'a str>) {
+    match spec.split_once(separator) {
+        Some((name, version)) => (name, Some(version)),
+        None => (spec, None),
+    }
+}
+
+/// Pinned entries of `specs` whose installed version does not match the pin.
+/// Unpinned and missing packages are not drift.
+pub fn find_version_drift<'a>(
+    manager: &dyn PackageManager,
+    specs: impl IntoIterator<Item = &'a str>,
+    installed: &HashMap<String, String>,
+    version_match: VersionMatch,
+) -> Vec<VersionDrift> {
+    let mut drift: Vec<VersionDrift> = specs
+        .into_iter()
+        .filter_map(|spec| {
+            let (name, desired) = manager.split_spec(spec);
+            let desired = desired?;
+            let installed = installed.get(name)?;
+            (!versions_match(installed, desired, version_match)).then(|| VersionDrift {
+                name: name.to_string(),
+                installed: installed.clone(),
+                desired: desired.to_string(),
+            })
+        })
+        .collect();
+    drift.sort_by(|a, b| a.name.cmp(&b.name));
+    drift
+}
+
+pub fn determine_package_installation(
+    pkg_name: &str,
+    desired_version: &Option<String>,
+    installed_version: Option<&String>,
+    package_type: &str,
+    version_match: VersionMatch,
+) -> bool {
+    if let Some(installed_version) = installed_version {
+        if let Some(version_to_match) = desired_version {
+            if !versions_match(installed_version, version_to_match, version_match) {
+                info!(
+                    "{} package '{}' installed with version '{}', but '{}' is requested. Reinstalling.",
+                    package_type, pkg_name, installed_version, version_to_match
+                );
+                true
+            } else {
+                skip!(
+                    "{} package '{}' version '{}' already installed, skipping.",
+                    package_type,
+                    pkg_name,
+                    installed_version
+                );
+                false
+            }
+        } else {
+            skip!(
+                "{} package '{}' already installed, skipping.",
+                package_type,
+                pkg_name
+            );
+            false
+        }
+    } else {
+        if let Some(version) = desired_version {
+            info!(
+                "{} package '{}' version '{}' not installed. Installing.",
+                package_type, pkg_name, version
+            );
+        } else {
+            info!(
+                "{} package '{}' not installed. Installing.",
+                package_type, pkg_name
+            );
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CARGO_INSTALL_LIST: &str = "\
+cargo-edit v0.12.2:
+    cargo-add
+    cargo-rm
+    cargo-set-version
+ripgrep v14.1.0:
+    rg
+tool v0.1.0 (https://github.com/example/tool#0123abcd):
+    tool
+";
+
+    #[test]
+    fn test_parse_cargo_install_list() {
+        let crates = parse_cargo_install_list(CARGO_INSTALL_LIST);
+        assert_eq!(crates.len(), 3);
+        assert_eq!(
+            crates[0],
+            CargoCrate {
+                name: "cargo-edit".to_string(),
+                version: "0.12.2".to_string(),
+                binaries: vec![
+                    "cargo-add".to_string(),
+                    "cargo-rm".to_string(),
+                    "cargo-set-version".to_string()
+                ],
+            }
+        );
+        assert_eq!(crates[1].version, "14.1.0");
+        assert_eq!(crates[2].name, "tool");
+        assert_eq!(crates[2].version, "0.1.0");
+    }
+
+    #[test]
+    fn test_find_cargo_crate_by_binary() {
+        let crates = parse_cargo_install_list(CARGO_INSTALL_LIST);
+        assert_eq!(
+            find_cargo_crate(&crates, "ripgrep").unwrap().name,
+            "ripgrep"
+        );
+        assert_eq!(find_cargo_crate(&crates, "rg").unwrap().name, "ripgrep");
+        assert_eq!(
+            find_cargo_crate(&crates, "cargo-rm").unwrap().name,
+            "cargo-edit"
+        );
+        assert!(find_cargo_crate(&crates, "fd").is_none());
+    }
+
+    #[test]
+    fn test_parse_xbps_query_list() {
+        let installed = parse_xbps_query_list(
+            "ii bash-5.2.21_1                 GNU Bourne Again Shell\n\
+             ii python3-pip-24.0_1            PyPA recommended tool\n\
+             uu ripgrep-14.1.0_1              Fast grep\n",
+        );
+        assert_eq!(installed.len(), 2);
+        assert_eq!(installed["bash"], "5.2.21_1");
+        assert_eq!(installed["python3-pip"], "24.0_1");
+        assert_eq!(Xbps.split_spec("python3-pip"), ("python3-pip", None));
+        assert_eq!(Xbps.pin_spec("bash", "5.2.21_1"), "bash-5.2.21_1");
+    }
+
+    #[test]
+    fn test_parse_which_candidates() {
+        let policy = "ripgrep:\n  Installed: (none)\n  Candidate: 14.1.0-1\n  Version table:\n";
+        assert_eq!(
+            parse_apt_policy_candidate(policy).as_deref(),
+            Some("14.1.0-1")
+        );
+        assert_eq!(
+            parse_apt_policy_candidate("foo:\n  Installed: (none)\n  Candidate: (none)\n"),
+            None
+        );
+        assert_eq!(parse_apt_policy_candidate(""), None);
+
+        let find = "\
+Name            Version  Publisher   Notes    Summary
+ripgrep-all     0.10.6   phiresky    -        rga
+ripgrep         14.1.0   mmstick     classic  Fast grep
+";
+        let candidate = |name: &str, version: &str| Candidate {
+            name: name.to_string(),
+            version: version.to_string(),
+        };
+        assert_eq!(
+            parse_snap_find(find, "ripgrep"),
+            Some(candidate("ripgrep", "14.1.0"))
+        );
+        assert_eq!(parse_snap_find(find, "rg"), None);
+
+        let search = "org.gimp.GIMP\t2.10.38\norg.gimp.GIMP.Plugin.Resynthesizer\t\n";
+        assert_eq!(
+            parse_flatpak_search(search, "gimp"),
+            Some(candidate("org.gimp.GIMP", "2.10.38"))
+        );
+        assert_eq!(
+            parse_flatpak_search(search, "resynthesizer"),
+            Some(candidate("org.gimp.GIMP.Plugin.Resynthesizer", "?"))
+        );
+        assert_eq!(parse_flatpak_search("No matches found\n", "gimp"), None);
+
+        let body = serde_json::json!({
+            "crate": { "name": "ripgrep", "max_stable_version": "14.1.1", "newest_version": "15.0.0-rc.1" }
+        });
+        assert_eq!(
+            parse_crates_io_crate(&body),
+            Some(candidate("ripgrep", "14.1.1"))
+        );
+        let body = serde_json::json!({
+            "crate": { "name": "new-crate", "max_stable_version": null, "newest_version": "0.1.0-alpha" }
+        });
+        assert_eq!(
+            parse_crates_io_crate(&body),
+            Some(candidate("new-crate", "0.1.0-alpha"))
+        );
+    }
+
+    #[test]
+    fn test_parse_apt_policy_releases() {
+        let stdout = "\
+Package files:
+ 100 /var/lib/dpkg/status
+     release a=now
+ 500 http://deb.debian.org/debian bookworm/main amd64 Packages
+     release v=12.5,o=Debian,a=stable,n=bookworm,l=Debian,c=main,b=amd64
+     origin deb.debian.org
+ 100 http://deb.debian.org/debian trixie/main amd64 Packages
+     release o=Debian,a=testing,n=trixie,l=Debian,c=main,b=amd64
+Pinned packages:
+";
+        assert_eq!(
+            parse_apt_policy_releases(stdout),
+            vec!["bookworm", "now", "stable", "testing", "trixie"]
+        );
+    }
+
+    #[test]
+    fn test_parse_pipx_list_json() {
+        let stdout = br#"{
+  "pipx_spec_version": "0.1",
+  "venvs": {
+    "black": {
+      "metadata": {
+        "main_package": { "package": "black", "package_version": "24.1.0" }
+      }
+    },
+    "httpie": {
+      "metadata": {
+        "main_package": { "package": "httpie", "package_version": "3.2.2" }
+      }
+    }
+  }
+}"#;
+        let installed = parse_pipx_list_json(stdout).unwrap();
+        assert_eq!(installed.len(), 2);
+        assert_eq!(installed["black"], "24.1.0");
+        assert_eq!(Pipx.split_spec("black==24.1.0"), ("black", Some("24.1.0")));
+    }
+
+    #[test]
+    fn test_parse_npm_ls_json() {
+        let stdout = br#"{
+  "name": "lib",
+  "dependencies": {
+    "typescript": { "version": "5.3.3", "overridden": false },
+    "@biomejs/biome": { "version": "1.5.3", "overridden": false }
+  }
+}"#;
+        let installed = parse_npm_ls_json(stdout).unwrap();
+        assert_eq!(installed.len(), 2);
+        assert_eq!(installed["@biomejs/biome"], "1.5.3");
+        assert_eq!(parse_npm_ls_json(b"{}").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_split_npm_spec() {
+        assert_eq!(split_npm_spec("prettier"), ("prettier", None));
+        assert_eq!(
+            split_npm_spec("typescript@5.3.3"),
+            ("typescript", Some("5.3.3"))
+        );
+        assert_eq!(split_npm_spec("@biomejs/biome"), ("@biomejs/biome", None));
+        assert_eq!(
+            split_npm_spec("@biomejs/biome@1.5.3"),
+            ("@biomejs/biome", Some("1.5.3"))
+        );
+    }
+
+    #[test]
+    fn test_parse_protected_dpkg_packages() {
+        let protected = parse_protected_dpkg_packages(
+            "bash yes required\ncoreutils yes required\napt no important\nripgrep no optional\nvim no optional\n",
+        );
+        assert!(protected.contains("bash"));
+        assert!(protected.contains("apt"));
+        assert!(!protected.contains("ripgrep"));
+    }
+
+    #[test]
+    fn test_parse_nix_profile_list() {
+        let current = "\
+Name:               ripgrep
+Flake attribute:    legacyPackages.x86_64-linux.ripgrep
+Original flake URL: flake:nixpkgs
+Locked flake URL:   github:NixOS/nixpkgs/b06025f1533a1e07b6db3e75151caa155d1c7eb3
+Store paths:        /nix/store/3x4b7ns5yi2v2ymnyk6jykl5aq2pqzs0-ripgrep-14.1.0
+
+Name:               black
+Flake attribute:    legacyPackages.x86_64-linux.python3Packages.black
+Original flake URL: flake:nixpkgs
+Locked flake URL:   github:NixOS/nixpkgs/b06025f1533a1e07b6db3e75151caa155d1c7eb3
+Store paths:        /nix/store/9pd4ck2a0pc2y9lnvwsy5mm0kmx4aqlx-black-24.2.0
+";
+        assert_eq!(parse_nix_profile_list(current), vec!["black", "ripgrep"]);
+
+        let legacy = "0 flake:nixpkgs#legacyPackages.x86_64-linux.ripgrep github:NixOS/nixpkgs/b06025f1533a1e07b6db3e75151caa155d1c7eb3#legacyPackages.x86_64-linux.ripgrep /nix/store/3x4b7ns5yi2v2ymnyk6jykl5aq2pqzs0-ripgrep-14.1.0\n";
+        assert_eq!(parse_nix_profile_list(legacy), vec!["ripgrep"]);
+    }
+
+    #[test]
+    fn test_nix_specs() {
+        assert_eq!(nix_package_name("ripgrep"), "ripgrep");
+        assert_eq!(nix_package_name("nixpkgs#python3Packages.black"), "black");
+        assert_eq!(
+            nix_package_name("github:nix-community/home-manager?ref=release-24.05"),
+            "home-manager"
+        );
+        assert_eq!(base_package_name("nix", "nixpkgs#hello"), "hello");
+        assert_eq!(nix_installable("ripgrep"), "nixpkgs#ripgrep");
+        assert_eq!(
+            nix_installable("github:NixOS/nixpkgs/nixos-24.05#hello"),
+            "github:NixOS/nixpkgs/nixos-24.05#hello"
+        );
+    }
+
+    #[test]
+    fn test_go_specs() {
+        let gopls = "golang.org/x/tools/gopls@v0.15.0";
+        assert_eq!(base_package_name("go", gopls), "golang.org/x/tools/gopls");
+        assert_eq!(go_install_target(gopls), gopls);
+        assert_eq!(
+            go_install_target("mvdan.cc/gofumpt"),
+            "mvdan.cc/gofumpt@latest"
+        );
+        assert_eq!(
+            split_go_spec(gopls),
+            ("golang.org/x/tools/gopls", Some("v0.15.0"))
+        );
+        assert_eq!(
+            split_go_spec("mvdan.cc/gofumpt@latest"),
+            ("mvdan.cc/gofumpt", None)
+        );
+    }
+
+    #[test]
+    fn test_parse_go_version_m() {
+        let stdout = "\
+/home/user/go/bin/gopls: go1.22.1
+\tpath\tgolang.org/x/tools/gopls
+\tmod\tgolang.org/x/tools/gopls\tv0.15.0\th1:abc=
+\tdep\tgolang.org/x/mod\tv0.15.0\th1:def=
+/home/user/go/bin/yq: go1.22.1
+\tpath\tgithub.com/mikefarah/yq/v4
+\tmod\tgithub.com/mikefarah/yq/v4\tv4.42.1\th1:ghi=
+";
+        let packages = parse_go_version_m(stdout);
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages["golang.org/x/tools/gopls"], "v0.15.0");
+        assert_eq!(packages["github.com/mikefarah/yq/v4"], "v4.42.1");
+    }
+
+    #[test]
+    fn test_parse_gem_list_and_install_args() {
+        let stdout = "\
+*** LOCAL GEMS ***
+
+rubocop (1.60.2, 1.59.0)
+bundler (default: 2.5.6)
+nokogiri (1.16.0 x86_64-linux)
+jekyll (4.3.3)
+";
+        let gems = parse_gem_list(stdout);
+        assert_eq!(gems.len(), 4);
+        assert_eq!(gems["rubocop"], vec!["1.60.2", "1.59.0"]);
+        assert_eq!(gems["bundler"], vec!["2.5.6"]);
+        assert_eq!(gems["nokogiri"], vec!["1.16.0"]);
+        assert!(is_gem_package_installed(&gems, "rubocop=1.59.0"));
+        assert!(is_gem_package_installed(&gems, "jekyll"));
+        assert!(!is_gem_package_installed(&gems, "jekyll=4.3.2"));
+        assert_eq!(gem_install_args("rubocop"), vec!["install", "rubocop"]);
+        assert_eq!(
+            gem_install_args("jekyll=4.3.3"),
+            vec!["install", "jekyll", "-v", "4.3.3"]
+        );
+        assert_eq!(base_package_name("gem", "jekyll=4.3.3"), "jekyll");
+    }
+
+    #[test]
+    fn test_parse_rpm_query_and_dnf_specs() {
+        let stdout = "neovim 0.9.5-1.fc39\nkernel 6.5.6-300.fc39\nkernel 6.6.8-200.fc39\n";
+        let packages = parse_rpm_query(stdout);
+        assert_eq!(packages.len(), 2);
+        assert_eq!(packages["neovim"], "0.9.5-1.fc39");
+        assert_eq!(packages["kernel"], "6.6.8-200.fc39");
+        assert_eq!(dnf_install_arg("neovim=0.9.5"), "neovim-0.9.5");
+        assert_eq!(dnf_install_arg("java-17-openjdk"), "java-17-openjdk");
+        assert_eq!(base_package_name("dnf", "neovim=0.9.5"), "neovim");
+    }
+
+    #[test]
+    fn test_parse_mise_ls_json() {
+        let stdout = br#"{
+  "node": [
+    {"version": "18.19.0", "install_path": "/m/node/18.19.0", "installed": true, "active": false},
+    {"version": "20.11.0", "requested_version": "20", "installed": true, "active": true}
+  ],
+  "python": [
+    {"version": "3.12.1", "requested_version": "3.12", "installed": false, "active": true}
+  ]
+}"#;
+        let installed = parse_mise_ls_json(stdout).unwrap();
+        assert_eq!(
+            installed,
+            HashMap::from([(
+                "node".to_string(),
+                vec!["20.11.0".to_string(), "18.19.0".to_string()]
+            )])
+        );
+        assert_eq!(base_package_name("mise", "node@20.11.0"), "node");
+        assert!(mise_version_matches("20.11.0", "20"));
+        assert!(mise_version_matches("20.11.0", "20.11.0"));
+        assert!(mise_version_matches("20.11.0", "latest"));
+        assert!(!mise_version_matches("20.11.0", "20.1"));
+        assert!(parse_mise_ls_json(b"not json").is_err());
+    }
+
+    #[test]
+    fn test_parse_rustup_list() {
+        let toolchains = "\
+stable-x86_64-unknown-linux-gnu (default)
+nightly-2024-05-01-x86_64-unknown-linux-gnu
+1.79.0-aarch64-apple-darwin
+";
+        assert_eq!(
+            parse_rustup_list(toolchains),
+            vec!["stable", "nightly-2024-05-01", "1.79.0"]
+        );
+        let components = "\
+cargo-x86_64-unknown-linux-gnu
+clippy-x86_64-unknown-linux-gnu
+rust-analyzer-x86_64-unknown-linux-gnu
+rust-src
+rust-std-wasm32-unknown-unknown
+";
+        assert_eq!(
+            parse_rustup_list(components),
+            vec!["cargo", "clippy", "rust-analyzer", "rust-src", "rust-std"]
+        );
+        assert!(parse_rustup_list("no installed toolchains\n").is_empty());
+    }
+
+    #[test]
+    fn test_split_snap_spec() {
+        assert_eq!(split_snap_spec("spotify"), ("spotify", vec![]));
+        assert_eq!(
+            split_snap_spec("code  --classic --channel=insiders/stable"),
+            ("code", vec!["--classic", "--channel=insiders/stable"])
+        );
+        assert_eq!(base_package_name("snap", "code --classic"), "code");
+        assert_eq!(
+            base_package_name("snap", "helm=3.14/stable --classic"),
+            "helm"
+        );
+    }
+
+    #[test]
+    fn test_snap_install_args() {
+        assert_eq!(snap_install_args("spotify"), vec!["spotify"]);
+        assert_eq!(
+            snap_install_args("helm=3.14/stable  --classic"),
+            vec!["helm", "--classic", "--channel=3.14/stable"]
+        );
+        assert_eq!(
+            snap_install_args("code --classic --channel=insiders"),
+            vec!["code", "--classic", "--channel=insiders"]
+        );
+        assert_eq!(snap_install_args("code="), vec!["code"]);
+    }
+
+    #[test]
+    fn test_parse_flatpak_spec() {
+        let gimp = parse_flatpak_spec("org.gimp.GIMP");
+        assert_eq!(
+            gimp,
+            FlatpakRef {
+                kind: None,
+                id: "org.gimp.GIMP",
+                arch: None,
+                branch: None,
+            }
+        );
+        assert_eq!(gimp.install_ref(), "org.gimp.GIMP");
+        assert_eq!(gimp.info_args(), vec!["org.gimp.GIMP"]);
+
+        let beta = parse_flatpak_spec("org.gimp.GIMP=beta");
+        assert_eq!(beta.branch, Some("beta"));
+        assert_eq!(beta.install_ref(), "org.gimp.GIMP//beta");
+        assert_eq!(beta.info_args(), vec!["org.gimp.GIMP", "beta"]);
+        assert_eq!(parse_flatpak_spec("org.gimp.GIMP//beta"), beta);
+
+        let runtime = parse_flatpak_spec("runtime/org.freedesktop.Platform/x86_64/23.08");
+        assert_eq!(
+            runtime,
+            FlatpakRef {
+                kind: Some("runtime"),
+                id: "org.freedesktop.Platform",
+                arch: Some("x86_64"),
+                branch: Some("23.08"),
+            }
+        );
+        assert_eq!(
+            runtime.install_ref(),
+            "runtime/org.freedesktop.Platform/x86_64/23.08"
+        );
+        assert_eq!(
+            runtime.info_args(),
+            vec!["--arch=x86_64", "org.freedesktop.Platform", "23.08"]
+        );
+        assert_eq!(
+            base_package_name("flatpak", "app/org.gimp.GIMP/x86_64/stable"),
+            "org.gimp.GIMP"
+        );
+    }
+
+    #[test]
+    fn test_parse_snap_specs() {
+        let stdout = "\
+Name      Version   Rev    Tracking       Publisher   Notes
+core22    20240111  1122   latest/stable  canonical✓  base
+code      1.86.2    152    latest/stable  vscode✓     classic
+helm      3.14.0    402    3.14/stable    snapcrafters✪  classic
+spotify   1.2.31    74     latest/edge    spotify✓    -
+hello     2.10      x1     -              -           -
+";
+        assert_eq!(
+            parse_snap_specs(stdout),
+            vec![
+                "core22",
+                "code --classic",
+                "helm --channel=3.14/stable --classic",
+                "spotify --channel=latest/edge",
+                "hello",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_prunable_snaps() {
+        let stdout = "\
+Name      Version   Rev    Tracking       Publisher   Notes
+core22    20240111  1122   latest/stable  canonical✓  base
+snapd     2.61.3    21184  latest/stable  canonical✓  snapd
+code      1.86.2    152    latest/stable  vscode✓     classic
+spotify   1.2.31    74     latest/stable  spotify✓    -
+";
+        assert_eq!(parse_prunable_snaps(stdout), vec!["code", "spotify"]);
+    }
+
+    #[test]
+    fn test_system_update_from_os_release() {
+        assert_eq!(
+            SystemUpdate::from_os_release("NAME=\"Void\"\nID=\"void\"\n"),
+            SystemUpdate::Xbps
+        );
+        assert_eq!(
+            SystemUpdate::from_os_release("ID=ubuntu\nID_LIKE=debian\n"),
+            SystemUpdate::Apt
+        );
+        assert_eq!(SystemUpdate::from_os_release(""), SystemUpdate::Apt);
+        assert_eq!(
+            SystemUpdate::from_os_release("ID=fedora\nVERSION_ID=40\n"),
+            SystemUpdate::Dnf
+        );
+        assert_eq!(
+            SystemUpdate::from_os_release("ID=\"rocky\"\nID_LIKE=\"rhel centos fedora\"\n"),
+            SystemUpdate::Dnf
+        );
+    }
+
+    #[test]
+    fn test_find_version_drift() {
+        let installed = HashMap::from([
+            ("ripgrep".to_string(), "13.0.0".to_string()),
+            ("bat".to_string(), "0.24.0".to_string()),
+            ("fd-find".to_string(), "9.0.0".to_string()),
+        ]);
+        let drift = find_version_drift(
+            &Cargo,
+            ["ripgrep=14.0.0", "bat=0.24.0", "fd-find", "eza=0.18.0"],
+            &installed,
+            VersionMatch::Exact,
+        );
+        assert_eq!(
+            drift,
+            vec![VersionDrift {
+                name: "ripgrep".to_string(),
+                installed: "13.0.0".to_string(),
+                desired: "14.0.0".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_determine_install_not_installed_no_version() {
+        let result =
+            determine_package_installation("testpkg", &None, None, "Test", VersionMatch::Exact);
+        assert!(result);
+    }
+
+    #[test]
+    fn test_determine_install_not_installed_with_version() {
+        let result = determine_package_installation(
+            "testpkg",
+            &Some("1.0".to_string()),
+            None,
+            "Test",
+            VersionMatch::Exact,
+        );
+        assert!(result);
+    }
+
+    #[test]
+    fn test_determine_skip_installed_no_desired() {
+        let installed = "1.0".to_string();
+        let result = determine_package_installation(
+            "testpkg",
+            &None,
+            Some(&installed),
+            "Test",
+            VersionMatch::Exact,
+        );
+        assert!(!result);
+    }
+
+    #[test]
+    fn test_determine_skip_installed_matching_version() {
+        let installed = "1.0".to_string();
+        let result = determine_package_installation(
+            "testpkg",
+            &Some("1.0".to_string()),
+            Some(&installed),
+            "Test",
+            VersionMatch::Exact,
+        );
+        assert!(!result);
+    }
+
+    #[test]
     fn test_determine_install_installed_mismatching_version() {
         let installed = "1.0".to_string();
         let result = determine_package_installation(
@@ -262,7 +2248,81 @@ mod tests {
             &Some("2.0".to_string()),
             Some(&installed),
             "Test",
+            VersionMatch::Exact,
         );
         assert!(result);
     }
+
+    #[test]
+    fn test_determine_skip_installed_upstream_version_match() {
+        let installed = "2:1.2.3-1ubuntu1".to_string();
+        let desired = Some("1.2.3".to_string());
+        assert!(determine_package_installation(
+            "testpkg",
+            &desired,
+            Some(&installed),
+            "Test",
+            VersionMatch::Exact,
+        ));
+        assert!(!determine_package_installation(
+            "testpkg",
+            &desired,
+            Some(&installed),
+            "Test",
+            VersionMatch::Upstream,
+        ));
+    }
+
+    #[test]
+    fn test_debian_upstream_version() {
+        assert_eq!(debian_upstream_version("1.2.3"), "1.2.3");
+        assert_eq!(debian_upstream_version("2:1.2.3-1"), "1.2.3");
+        assert_eq!(debian_upstream_version("1:2.39.2-1.1"), "2.39.2");
+        assert_eq!(debian_upstream_version("1.0-beta-2"), "1.0-beta");
+    }
+
+    #[test]
+    fn test_parse_dpkg_query_map_skips_removed_packages() {
+        let stdout = "ii  git 1:2.39.2-1.1\nrc  oldpkg 1.0-1\nhi  held 2.0\nun  never\n";
+        let map = parse_dpkg_query_map(stdout);
+        assert_eq!(map.get("git").map(String::as_str), Some("1:2.39.2-1.1"));
+        assert_eq!(map.get("held").map(String::as_str), Some("2.0"));
+        assert!(!map.contains_key("oldpkg"));
+        assert!(!map.contains_key("never"));
+    }
+
+    #[test]
+    fn test_find_lock_pid() {
+        let locks = "1: POSIX  ADVISORY  WRITE 4242 08:02:131090 0 EOF\n\
+                     1: -> POSIX  ADVISORY  WRITE 5151 08:02:131090 0 EOF\n\
+                     2: FLOCK  ADVISORY  WRITE 777 00:1a:99 0 EOF\n";
+        assert_eq!(find_lock_pid(locks, 8, 2, 131090), Some(4242));
+        assert_eq!(find_lock_pid(locks, 0, 0x1a, 99), Some(777));
+        assert_eq!(find_lock_pid(locks, 8, 2, 1), None);
+    }
+
+    #[test]
+    fn test_flatpak_override_applied() {
+        let permissions = "[Context]\nshared=network;ipc;\nfilesystems=home;!host;\n\n[Environment]\nGTK_THEME=Adwaita\n";
+        assert_eq!(
+            is_flatpak_override_applied(permissions, "--filesystem=home"),
+            Some(true)
+        );
+        assert_eq!(
+            is_flatpak_override_applied(permissions, "--nofilesystem=host"),
+            Some(true)
+        );
+        assert_eq!(
+            is_flatpak_override_applied(permissions, "--socket=x11"),
+            Some(false)
+        );
+        assert_eq!(
+            is_flatpak_override_applied(permissions, "--env=GTK_THEME=Adwaita"),
+            Some(true)
+        );
+        assert_eq!(
+            is_flatpak_override_applied(permissions, "--talk-name=org.foo"),
+            None
+        );
+    }
 }
@@ -0,0 +1,750 @@
+use crate::build_info::{BuildInfo, build_info};
+use crate::config::Config;
+use crate::console::{error, step, warning};
+use crate::errors::{AppError, CommandError};
+use crate::utils::{format_utc_timestamp, log_file, previewed_commands};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PackageAction {
+    Installed,
+    Skipped,
+    WouldInstall,
+    Declined,
+    Failed,
+    Removed,
+    WouldRemove,
+}
+
+impl PackageAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PackageAction::Installed => "installed",
+            PackageAction::Skipped => "skipped",
+            PackageAction::WouldInstall => "would_install",
+            PackageAction::Declined => "declined",
+            PackageAction::Failed => "failed",
+            PackageAction::Removed => "removed",
+            PackageAction::WouldRemove => "would_remove",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PackageResult {
+    pub section: String,
+    pub name: String,
+    pub action: PackageAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// One line of the `--events` NDJSON stream.
+#[derive(Debug, Serialize)]
+struct PackageEvent<'a> {
+    event: &'static str,
+    timestamp_ms: u128,
+    manager: &'a str,
+    package: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Destination of the per-package NDJSON events written during `apply`.
+/// Each event is written and flushed as one line under a lock, so lines from
+/// parallel installs never interleave.
+pub struct EventStream(Mutex<Box<dyn Write + Send>>);
+
+impl std::fmt::Debug for EventStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EventStream")
+    }
+}
+
+impl EventStream {
+    /// Opens `target`, a file path (truncated) or an inherited file descriptor
+    /// number such as `3`.
+    pub fn open(target: &str) -> Result<Self, AppError> {
+        let path = if !target.is_empty() && target.bytes().all(|b| b.is_ascii_digit()) {
+            PathBuf::from(format!("/dev/fd/{}", target))
+        } else {
+            PathBuf::from(target)
+        };
+        let file = std::fs::File::create(&path).map_err(|e| {
+            AppError::Other(format!("Failed to open event stream {}: {}", target, e).into())
+        })?;
+        Ok(Self::new(Box::new(file)))
+    }
+
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        EventStream(Mutex::new(writer))
+    }
+
+    fn emit(&self, event: &PackageEvent) {
+        let Ok(mut line) = serde_json::to_string(event) else {
+            return;
+        };
+        line.push('\n');
+        let mut writer = self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(e) = writer
+            .write_all(line.as_bytes())
+            .and_then(|_| writer.flush())
+        {
+            eprintln!("Warning: Failed to write event: {}", e);
+        }
+    }
+}
+
+/// Collects per-package outcomes during `apply`. Safe to share across the
+/// rayon workers used for parallel installs.
+#[derive(Debug)]
+pub struct ApplyReport {
+    started_at: SystemTime,
+    started: Instant,
+    results: Mutex<Vec<PackageResult>>,
+    events: Option<EventStream>,
+    /// Installs announced with `expect_installs` so far, across sections.
+    installs_expected: AtomicUsize,
+    /// Installs started so far, numbering the `[n/total]` progress prefix.
+    installs_started: AtomicUsize,
+    /// Record failed installs and move on instead of stopping the apply.
+    keep_going: bool,
+}
+
+impl Default for ApplyReport {
+    fn default() -> Self {
+        ApplyReport {
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+            results: Mutex::new(Vec::new()),
+            events: None,
+            installs_expected: AtomicUsize::new(0),
+            installs_started: AtomicUsize::new(0),
+            keep_going: false,
+        }
+    }
+}
+
+impl ApplyReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Streams every recorded outcome to `events` as it happens.
+    pub fn with_events(mut self, events: EventStream) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Makes `time_install` return `Ok` for a failed install once it is
+    /// recorded, so the apply goes on with the next package.
+    pub fn keep_going(mut self) -> Self {
+        self.keep_going = true;
+        self
+    }
+
+    fn emit(
+        &self,
+        event: &'static str,
+        section: &str,
+        name: &str,
+        reason: Option<&'static str>,
+        duration_ms: Option<u128>,
+        error: Option<String>,
+    ) {
+        if let Some(events) = &self.events {
+            events.emit(&PackageEvent {
+                event,
+                timestamp_ms: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis())
+                    .unwrap_or_default(),
+                manager: section,
+                package: name,
+                reason,
+                duration_ms,
+                error,
+            });
+        }
+    }
+
+    pub fn record(&self, section: &str, name: &str, action: PackageAction) {
+        let (event, reason) = match action {
+            PackageAction::Skipped => ("skip", Some("already_installed")),
+            PackageAction::Declined => ("skip", Some("declined")),
+            PackageAction::WouldInstall => ("would_install", None),
+            PackageAction::Installed => ("install_done", None),
+            PackageAction::Failed => ("error", None),
+            PackageAction::Removed => ("remove_done", None),
+            PackageAction::WouldRemove => ("would_remove", None),
+        };
+        self.emit(event, section, name, reason, None, None);
+        self.push(PackageResult {
+            section: section.to_string(),
+            name: name.to_string(),
+            action,
+            duration_ms: None,
+            error: None,
+        });
+    }
+
+    /// Adds `count` packages that are about to be installed (already installed
+    /// and declined ones left out) to the total of the progress prefix.
+    pub fn expect_installs(&self, count: usize) {
+        self.installs_expected.fetch_add(count, Ordering::SeqCst);
+    }
+
+    /// Runs an install step, recording it as installed or failed along with its duration.
+    pub fn time_install<F>(&self, section: &str, name: &str, install: F) -> Result<(), CommandError>
+    where
+        F: FnOnce() -> Result<(), CommandError>,
+    {
+        self.time_batch_install(section, &[name], install)
+    }
+
+    /// Like `time_install`, for one step installing several packages at once:
+    /// each package is recorded with the outcome and duration of the whole step.
+    pub fn time_batch_install<F>(
+        &self,
+        section: &str,
+        names: &[&str],
+        install: F,
+    ) -> Result<(), CommandError>
+    where
+        F: FnOnce() -> Result<(), CommandError>,
+    {
+        for name in names {
+            self.emit("install_start", section, name, None, None, None);
+        }
+        // Numbers are claimed atomically so that parallel installs never share
+        // one, and each progress line is printed whole.
+        let last = self
+            .installs_started
+            .fetch_add(names.len(), Ordering::SeqCst)
+            + names.len();
+        let total = self.installs_expected.load(Ordering::SeqCst).max(last);
+        step!(
+            "{} Installing [{}] {}",
+            progress_label(last + 1 - names.len(), last, total),
+            section,
+            names.join(" ")
+        );
+        let start = Instant::now();
+        let result = install();
+        let duration_ms = start.elapsed().as_millis();
+        for name in names {
+            match &result {
+                Ok(()) => self.emit("install_done", section, name, None, Some(duration_ms), None),
+                Err(e) => self.emit(
+                    "error",
+                    section,
+                    name,
+                    None,
+                    Some(duration_ms),
+                    Some(e.to_string()),
+                ),
+            }
+            self.push(PackageResult {
+                section: section.to_string(),
+                name: name.to_string(),
+                action: if result.is_ok() {
+                    PackageAction::Installed
+                } else {
+                    PackageAction::Failed
+                },
+                duration_ms: Some(duration_ms),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+        }
+        match result {
+            Err(e) if self.keep_going => {
+                warning!(
+                    "Warning: Installing [{}] {} failed, continuing:\n{}",
+                    section,
+                    names.join(" "),
+                    e.to_string().trim_end()
+                );
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    pub fn results(&self) -> Vec<PackageResult> {
+        self.results
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Packages whose install failed.
+    pub fn failures(&self) -> Vec<PackageResult> {
+        self.results()
+            .into_iter()
+            .filter(|r| r.action == PackageAction::Failed)
+            .collect()
+    }
+
+    pub fn has_failures(&self) -> bool {
+        !self.failures().is_empty()
+    }
+
+    /// Lists the failed installs of a `--keep-going` apply, if any.
+    pub fn print_failures(&self) {
+        let failures = self.failures();
+        if failures.is_empty() {
+            return;
+        }
+        error!("{} package(s) failed to install:", failures.len());
+        for failure in failures {
+            error!("  [{}] {}", failure.section, failure.name);
+        }
+    }
+
+    /// Packages that were installed, or would have been in a dry run.
+    pub fn changed(&self) -> Vec<PackageResult> {
+        self.results()
+            .into_iter()
+            .filter(|r| {
+                matches!(
+                    r.action,
+                    PackageAction::Installed | PackageAction::WouldInstall
+                )
+            })
+            .collect()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn started_at_secs(&self) -> u64 {
+        self.started_at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+
+    fn push(&self, result: PackageResult) {
+        self.results
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(result);
+    }
+}
+
+#[derive(Serialize)]
+struct ReportDocument<'a> {
+    railtube: BuildInfo,
+    started_at: String,
+    duration_ms: u128,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    packages: &'a [PackageResult],
+}
+
+/// Writes the artifacts of an apply run into a timestamped subdirectory of `dir`:
+/// `report.json`, `config.toml`, `timings.csv` and `commands.log` (the part of
+/// the log written since `log_offset`).
+pub fn write_output_bundle(
+    dir: &Path,
+    config: &Config,
+    report: &ApplyReport,
+    outcome: &Result<(), AppError>,
+    log_offset: u64,
+) -> Result<PathBuf, AppError> {
+    let started_at = format_utc_timestamp(report.started_at_secs());
+    let bundle_dir = dir.join(format!("apply-{}", started_at.replace(['-', ':'], "")));
+    std::fs::create_dir_all(&bundle_dir)?;
+
+    let results = report.results();
+    let document = ReportDocument {
+        railtube: build_info(),
+        started_at,
+        duration_ms: report.elapsed().as_millis(),
+        success: outcome.is_ok(),
+        error: outcome.as_ref().err().map(|e| e.to_string()),
+        packages: &results,
+    };
+    let json = serde_json::to_string_pretty(&document).map_err(|e| AppError::Other(Box::new(e)))?;
+    std::fs::write(bundle_dir.join("report.json"), json)?;
+
+    std::fs::write(
+        bundle_dir.join("config.toml"),
+        toml::to_string_pretty(config)?,
+    )?;
+
+    let mut timings = String::from("section,name,action,duration_ms\n");
+    for result in &results {
+        if let Some(duration_ms) = result.duration_ms {
+            timings.push_str(&format!(
+                "{},{},{},{}\n",
+                result.section,
+                result.name,
+                result.action.as_str(),
+                duration_ms
+            ));
+        }
+    }
+    std::fs::write(bundle_dir.join("timings.csv"), timings)?;
+
+    let mut log = String::new();
+    if let Ok(mut file) = std::fs::File::open(log_file()) {
+        file.seek(SeekFrom::Start(log_offset))?;
+        file.read_to_string(&mut log)?;
+    }
+    std::fs::write(bundle_dir.join("commands.log"), log)?;
+
+    Ok(bundle_dir)
+}
+
+/// A pinned package installed with a different version than the manifest asks for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionDrift {
+    pub name: String,
+    pub installed: String,
+    pub desired: String,
+}
+
+/// Writes the commands previewed by a dry run as a runnable shell script, with
+/// the packages that were skipped listed as comments.
+pub fn write_command_script(
+    path: &Path,
+    source: &str,
+    report: &ApplyReport,
+) -> Result<(), AppError> {
+    let mut script = format!(
+        "#!/bin/sh\n# Generated by `railtube apply --emit-script` from {} at {}.\nset -e\n\n",
+        source,
+        format_utc_timestamp(report.started_at_secs())
+    );
+    let skipped: Vec<PackageResult> = report
+        .results()
+        .into_iter()
+        .filter(|r| r.action == PackageAction::Skipped)
+        .collect();
+    if !skipped.is_empty() {
+        script.push_str("# Skipped, already installed:\n");
+        for result in skipped {
+            script.push_str(&format!("#   [{}] {}\n", result.section, result.name));
+        }
+        script.push('\n');
+    }
+    for line in previewed_commands() {
+        script.push_str(&line);
+        script.push('\n');
+    }
+
+    std::fs::write(path, script)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// Packages of one section that are missing from, or extra on, the system.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SectionDiff {
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub drift: Vec<VersionDrift>,
+}
+
+impl SectionDiff {
+    pub fn new(toml_packages: &HashSet<&str>, installed_packages: &HashSet<&str>) -> Self {
+        let sorted = |set: HashSet<&&str>| {
+            let mut packages: Vec<String> = set.into_iter().map(|p| p.to_string()).collect();
+            packages.sort();
+            packages
+        };
+        SectionDiff {
+            missing: sorted(toml_packages.difference(installed_packages).collect()),
+            extra: sorted(installed_packages.difference(toml_packages).collect()),
+            drift: Vec::new(),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.missing.len() + self.extra.len() + self.drift.len()
+    }
+}
+
+/// Output format of `apply`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ApplyFormat {
+    /// Human-readable progress only.
+    #[default]
+    Text,
+    /// Also print the per-package results as a JSON array on stdout, with all
+    /// other output moved to stderr.
+    Json,
+}
+
+/// Output format of `doctor`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DoctorFormat {
+    /// Human-readable report.
+    #[default]
+    Text,
+    /// The `DoctorReport` as pretty-printed JSON, for monitoring dashboards.
+    Json,
+    /// SARIF 2.1.0, for code scanning dashboards.
+    Sarif,
+}
+
+/// Result of a `doctor` run, serialized as one JSON line per run for trend tracking.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DoctorReport {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub source: String,
+    pub sections: BTreeMap<String, SectionDiff>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unapplied_overrides: Vec<String>,
+    /// Packages installed at a different version than `railtube.lock` records.
+    /// Only a warning: not counted by `has_drift`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lock_drift: Vec<String>,
+}
+
+impl DoctorReport {
+    /// Whether any section differs from the manifest or an override is unapplied.
+    pub fn has_drift(&self) -> bool {
+        self.sections.values().any(|diff| diff.count() > 0) || !self.unapplied_overrides.is_empty()
+    }
+
+    pub fn append_jsonl(&self, path: &Path) -> Result<(), AppError> {
+        let line = serde_json::to_string(self).map_err(|e| AppError::Other(Box::new(e)))?;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+
+    pub fn read_jsonl(path: &Path) -> Result<Vec<DoctorReport>, AppError> {
+        let file = std::fs::File::open(path)?;
+        let mut reports = Vec::new();
+        for (index, line) in std::io::BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let report = serde_json::from_str(&line).map_err(|e| {
+                AppError::Other(
+                    format!(
+                        "{}:{}: invalid doctor record: {}",
+                        path.display(),
+                        index + 1,
+                        e
+                    )
+                    .into(),
+                )
+            })?;
+            reports.push(report);
+        }
+        Ok(reports)
+    }
+}
+
+/// Minimum, maximum and average discrepancy counts of one manager across runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DriftStats {
+    pub min: usize,
+    pub max: usize,
+    pub avg: f64,
+    pub runs: usize,
+}
+
+/// Summarizes discrepancy counts per manager over `reports`.
+pub fn summarize_drift(reports: &[DoctorReport]) -> BTreeMap<String, DriftStats> {
+    let mut counts: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for report in reports {
+        for (manager, diff) in &report.sections {
+            counts
+                .entry(manager.clone())
+                .or_default()
+                .push(diff.count());
+        }
+    }
+    counts
+        .into_iter()
+        .map(|(manager, counts)| {
+            let stats = DriftStats {
+                min: counts.iter().copied().min().unwrap_or(0),
+                max: counts.iter().copied().max().unwrap_or(0),
+                avg: counts.iter().sum::<usize>() as f64 / counts.len() as f64,
+                runs: counts.len(),
+            };
+            (manager, stats)
+        })
+        .collect()
+}
+
+/// `[3/12]`, or `[3-5/12]` for a step installing packages 3 to 5 at once.
+fn progress_label(first: usize, last: usize, total: usize) -> String {
+    if first >= last {
+        format!("[{}/{}]", last, total)
+    } else {
+        format!("[{}-{}/{}]", first, last, total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_label() {
+        assert_eq!(progress_label(3, 3, 12), "[3/12]");
+        assert_eq!(progress_label(3, 5, 12), "[3-5/12]");
+
+        let report = ApplyReport::new();
+        report.expect_installs(3);
+        report
+            .time_batch_install("apt", &["git", "curl"], || Ok(()))
+            .unwrap();
+        report.time_install("cargo", "ripgrep", || Ok(())).unwrap();
+        assert_eq!(report.installs_started.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_apply_report_streams_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.ndjson");
+        let report =
+            ApplyReport::new().with_events(EventStream::open(path.to_str().unwrap()).unwrap());
+        report.record("apt", "git", PackageAction::Skipped);
+        report.time_install("cargo", "ripgrep", || Ok(())).unwrap();
+
+        let events: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let kinds: Vec<&str> = events
+            .iter()
+            .map(|e| e["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(kinds, vec!["skip", "install_start", "install_done"]);
+        assert_eq!(events[0]["reason"], "already_installed");
+        assert_eq!(events[1]["manager"], "cargo");
+        assert_eq!(events[2]["package"], "ripgrep");
+        assert!(events[2]["duration_ms"].is_number());
+    }
+
+    #[test]
+    fn test_keep_going_records_failures() {
+        let failure = || {
+            Err(CommandError {
+                command: "false".into(),
+                args: Vec::new(),
+                exit_code: Some(1),
+                stdout: String::new(),
+                stderr: String::new(),
+            })
+        };
+
+        let report = ApplyReport::new();
+        assert!(report.time_install("cargo", "ripgrep", failure).is_err());
+
+        let report = ApplyReport::new().keep_going();
+        assert!(report.time_install("cargo", "ripgrep", failure).is_ok());
+        report.time_install("cargo", "bat", || Ok(())).unwrap();
+        assert!(report.has_failures());
+        let results = report.results();
+        assert_eq!(results[0].action, PackageAction::Failed);
+        assert!(results[0].error.is_some());
+        assert_eq!(results[1].action, PackageAction::Installed);
+    }
+
+    fn record(timestamp: u64, apt: usize, cargo: usize) -> DoctorReport {
+        let diff = |n: usize| SectionDiff {
+            missing: (0..n).map(|i| format!("pkg{}", i)).collect(),
+            extra: Vec::new(),
+            drift: Vec::new(),
+        };
+        DoctorReport {
+            timestamp,
+            source: "test.toml".to_string(),
+            sections: BTreeMap::from([
+                ("apt".to_string(), diff(apt)),
+                ("cargo".to_string(), diff(cargo)),
+            ]),
+            unapplied_overrides: Vec::new(),
+            lock_drift: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_section_diff_sorted() {
+        let toml = HashSet::from(["b", "a", "common"]);
+        let installed = HashSet::from(["common", "z", "y"]);
+        let diff = SectionDiff::new(&toml, &installed);
+        assert_eq!(diff.missing, vec!["a", "b"]);
+        assert_eq!(diff.extra, vec!["y", "z"]);
+        assert_eq!(diff.count(), 4);
+    }
+
+    #[test]
+    fn test_summarize_drift() {
+        let stats = summarize_drift(&[record(1, 0, 3), record(2, 4, 3), record(3, 2, 3)]);
+        assert_eq!(
+            stats["apt"],
+            DriftStats {
+                min: 0,
+                max: 4,
+                avg: 2.0,
+                runs: 3
+            }
+        );
+        assert_eq!(stats["cargo"].min, 3);
+        assert_eq!(stats["cargo"].max, 3);
+    }
+
+    #[test]
+    fn test_doctor_report_has_drift() {
+        assert!(!record(1, 0, 0).has_drift());
+        assert!(record(1, 0, 2).has_drift());
+
+        let mut overrides_only = record(1, 0, 0);
+        overrides_only.unapplied_overrides =
+            vec!["org.example.App: --nofilesystem=home".to_string()];
+        assert!(overrides_only.has_drift());
+    }
+
+    #[test]
+    fn test_doctor_report_jsonl_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("drift.jsonl");
+        record(10, 1, 0).append_jsonl(&path).unwrap();
+        record(20, 2, 1).append_jsonl(&path).unwrap();
+
+        let reports = DoctorReport::read_jsonl(&path).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[1].timestamp, 20);
+        assert_eq!(reports[1].sections["cargo"].missing, vec!["pkg0"]);
+    }
+}
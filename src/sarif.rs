@@ -0,0 +1,240 @@
+use crate::report::DoctorReport;
+use serde::Serialize;
+
+const SARIF_VERSION: &str = "2.1.0";
+const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+const MISSING_PACKAGE: &str = "missing-package";
+const EXTRA_PACKAGE: &str = "extra-package";
+const VERSION_DRIFT: &str = "version-drift";
+const UNAPPLIED_OVERRIDE: &str = "unapplied-override";
+
+/// A minimal SARIF 2.1.0 log: one run with railtube as the tool.
+#[derive(Debug, Serialize)]
+pub struct SarifLog {
+    version: &'static str,
+    #[serde(rename = "$schema")]
+    schema: &'static str,
+    runs: Vec<SarifRun>,
+}
+
+#[derive(Debug, Serialize)]
+struct SarifRun {
+    tool: SarifTool,
+    results: Vec<SarifResult>,
+}
+
+#[derive(Debug, Serialize)]
+struct SarifTool {
+    driver: SarifDriver,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifDriver {
+    name: &'static str,
+    version: &'static str,
+    information_uri: &'static str,
+    rules: Vec<SarifRule>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifRule {
+    id: &'static str,
+    short_description: SarifMessage,
+    default_configuration: SarifConfiguration,
+}
+
+#[derive(Debug, Serialize)]
+struct SarifConfiguration {
+    level: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifResult {
+    rule_id: &'static str,
+    level: &'static str,
+    message: SarifMessage,
+    locations: Vec<SarifLocation>,
+}
+
+#[derive(Debug, Serialize)]
+struct SarifMessage {
+    text: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifLocation {
+    physical_location: SarifPhysicalLocation,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SarifPhysicalLocation {
+    artifact_location: SarifArtifactLocation,
+}
+
+#[derive(Debug, Serialize)]
+struct SarifArtifactLocation {
+    uri: String,
+}
+
+/// `(rule id, level, description)` of every finding doctor reports.
+const RULES: &[(&str, &str, &str)] = &[
+    (
+        MISSING_PACKAGE,
+        "error",
+        "Package listed in the manifest is not installed.",
+    ),
+    (
+        EXTRA_PACKAGE,
+        "note",
+        "Installed package is not listed in the manifest.",
+    ),
+    (
+        VERSION_DRIFT,
+        "warning",
+        "Pinned package is installed with a different version.",
+    ),
+    (
+        UNAPPLIED_OVERRIDE,
+        "warning",
+        "Flatpak permission override is not applied.",
+    ),
+];
+
+fn level(rule_id: &str) -> &'static str {
+    RULES
+        .iter()
+        .find(|(id, _, _)| *id == rule_id)
+        .map_or("warning", |(_, level, _)| level)
+}
+
+/// Maps every discrepancy of `report` to a SARIF result located at the manifest.
+pub fn doctor_sarif(report: &DoctorReport) -> SarifLog {
+    let mut results = Vec::new();
+    let mut push = |rule_id: &'static str, text: String| {
+        results.push(SarifResult {
+            rule_id,
+            level: level(rule_id),
+            message: SarifMessage { text },
+            locations: vec![SarifLocation {
+                physical_location: SarifPhysicalLocation {
+                    artifact_location: SarifArtifactLocation {
+                        uri: report.source.clone(),
+                    },
+                },
+            }],
+        });
+    };
+
+    for (section, diff) in &report.sections {
+        for name in &diff.missing {
+            push(
+                MISSING_PACKAGE,
+                format!("[{}] package '{}' is not installed.", section, name),
+            );
+        }
+        for name in &diff.extra {
+            push(
+                EXTRA_PACKAGE,
+                format!(
+                    "[{}] package '{}' is installed but not listed.",
+                    section, name
+                ),
+            );
+        }
+        for drift in &diff.drift {
+            push(
+                VERSION_DRIFT,
+                format!(
+                    "[{}] package '{}' is pinned to {} but {} is installed.",
+                    section, drift.name, drift.desired, drift.installed
+                ),
+            );
+        }
+    }
+    for item in &report.unapplied_overrides {
+        push(
+            UNAPPLIED_OVERRIDE,
+            format!("Flatpak override not applied: {}", item),
+        );
+    }
+
+    SarifLog {
+        version: SARIF_VERSION,
+        schema: SARIF_SCHEMA,
+        runs: vec![SarifRun {
+            tool: SarifTool {
+                driver: SarifDriver {
+                    name: "railtube",
+                    version: env!("CARGO_PKG_VERSION"),
+                    information_uri: env!("CARGO_PKG_REPOSITORY"),
+                    rules: RULES
+                        .iter()
+                        .map(|(id, level, description)| SarifRule {
+                            id,
+                            short_description: SarifMessage {
+                                text: description.to_string(),
+                            },
+                            default_configuration: SarifConfiguration { level },
+                        })
+                        .collect(),
+                },
+            },
+            results,
+        }],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::{SectionDiff, VersionDrift};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_doctor_sarif_results() {
+        let report = DoctorReport {
+            timestamp: 0,
+            source: "railtube.toml".to_string(),
+            sections: BTreeMap::from([(
+                "cargo".to_string(),
+                SectionDiff {
+                    missing: vec!["bat".to_string()],
+                    extra: vec!["eza".to_string()],
+                    drift: vec![VersionDrift {
+                        name: "ripgrep".to_string(),
+                        installed: "13.0.0".to_string(),
+                        desired: "14.0.0".to_string(),
+                    }],
+                },
+            )]),
+            unapplied_overrides: Vec::new(),
+            lock_drift: Vec::new(),
+        };
+
+        let json = serde_json::to_value(doctor_sarif(&report)).unwrap();
+        assert_eq!(json["version"], "2.1.0");
+        let results = json["runs"][0]["results"].as_array().unwrap();
+        let rules: Vec<(&str, &str)> = results
+            .iter()
+            .map(|r| (r["ruleId"].as_str().unwrap(), r["level"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                (MISSING_PACKAGE, "error"),
+                (EXTRA_PACKAGE, "note"),
+                (VERSION_DRIFT, "warning")
+            ]
+        );
+        assert_eq!(
+            results[0]["locations"][0]["physicalLocation"]["artifactLocation"]["uri"],
+            "railtube.toml"
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use crate::errors::AppError;
+use crate::utils::system_hostname;
+use minijinja::{Environment, UndefinedBehavior, Value};
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/// Manifests whose source ends in this are always rendered as templates.
+pub const TEMPLATE_SUFFIX: &str = ".toml.j2";
+
+/// What templates are rendered with: `--host` and the `--template-vars`.
+#[derive(Debug, Default)]
+struct TemplateContext {
+    host: Option<String>,
+    vars: Vec<(String, String)>,
+}
+
+static CONTEXT: OnceLock<TemplateContext> = OnceLock::new();
+
+/// Sets the `hostname` templates see (`--host`, defaulting to the system
+/// hostname) and the `--template-vars`. Passing any variable makes every
+/// manifest a template, not only `.toml.j2` ones.
+pub fn set_template_context(host: Option<String>, vars: Vec<(String, String)>) {
+    let _ = CONTEXT.set(TemplateContext { host, vars });
+}
+
+/// Parses a `--template-vars` value, `key=value`.
+pub fn parse_template_var(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected KEY=VALUE, got '{}'", arg)),
+    }
+}
+
+/// `content`, the manifest at `source`, rendered with minijinja if it is a
+/// template (see `set_template_context`). Other manifests are returned as is,
+/// so a literal `{{` in them is never interpreted.
+pub fn render_manifest(source: &str, content: String) -> Result<String, AppError> {
+    let context = CONTEXT.get_or_init(TemplateContext::default);
+    if !source.ends_with(TEMPLATE_SUFFIX) && context.vars.is_empty() {
+        return Ok(content);
+    }
+    render(source, &content, context)
+}
+
+fn render(source: &str, content: &str, context: &TemplateContext) -> Result<String, AppError> {
+    let mut vars: BTreeMap<String, Value> = BTreeMap::new();
+    let hostname = context.host.clone().or_else(system_hostname);
+    vars.insert(
+        "hostname".to_string(),
+        Value::from(hostname.unwrap_or_default()),
+    );
+    vars.insert("os".to_string(), Value::from(std::env::consts::OS));
+    vars.insert("arch".to_string(), Value::from(std::env::consts::ARCH));
+    for (key, value) in &context.vars {
+        vars.insert(key.clone(), Value::from(value.as_str()));
+    }
+
+    let mut env = Environment::new();
+    // A misspelled variable fails the load instead of rendering as nothing.
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.render_str(content, Value::from(vars)).map_err(|e| {
+        AppError::Other(format!("Failed to render manifest template {}: {}", source, e).into())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(vars: &[(&str, &str)]) -> TemplateContext {
+        TemplateContext {
+            host: Some("laptop".to_string()),
+            vars: vars
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_render_builtin_and_custom_vars() {
+        let template = r#"
+[apt]
+list = [
+    "git",
+{% if hostname == "laptop" %}    "tlp",
+{% endif %}    "{{ editor }}",
+]
+# {{ os }}/{{ arch }}
+"#;
+        let rendered = render("m.toml.j2", template, &context(&[("editor", "neovim")])).unwrap();
+        assert_eq!(
+            rendered,
+            format!(
+                "\n[apt]\nlist = [\n    \"git\",\n    \"tlp\",\n    \"neovim\",\n]\n# {}/{}",
+                std::env::consts::OS,
+                std::env::consts::ARCH
+            )
+        );
+
+        let err = render("m.toml.j2", "x = \"{{ editr }}\"", &context(&[]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("m.toml.j2"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_template_var() {
+        assert_eq!(
+            parse_template_var("role=web=1"),
+            Ok(("role".to_string(), "web=1".to_string()))
+        );
+        assert_eq!(
+            parse_template_var("empty="),
+            Ok(("empty".to_string(), String::new()))
+        );
+        assert!(parse_template_var("novalue").is_err());
+        assert!(parse_template_var("=x").is_err());
+    }
+}
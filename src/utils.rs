@@ -1,18 +1,58 @@
+use crate::config::ColorChoice;
+use crate::console::{Verbosity, step, verbosity, warning};
 use crate::errors::{AppError, CommandError};
-use reqwest::blocking::Client;
+use crate::manifest_cache::{self, CacheMode, CachedManifest};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{CONTENT_TYPE, HeaderMap};
 use std::fs::OpenOptions;
 use std::io::{Read, Write};
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 use std::ffi::{OsStr, OsString};
 
+static LOG_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// `$XDG_STATE_HOME/railtube/railtube.log`, falling back to
+/// `~/.local/state/railtube/railtube.log` and then the current directory.
+pub fn default_log_file() -> PathBuf {
+    dirs::state_dir()
+        .or_else(|| dirs::home_dir().map(|home| home.join(".local").join("state")))
+        .map(|dir| dir.join("railtube").join("railtube.log"))
+        .unwrap_or_else(|| PathBuf::from("railtube.log"))
+}
+
+/// Sets the file `log_message` appends to, creating its parent directory.
+pub fn set_log_file(path: PathBuf) -> Result<(), AppError> {
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(parent).map_err(|e| {
+            AppError::Other(
+                format!("Failed to create log directory {}: {}", parent.display(), e).into(),
+            )
+        })?;
+    }
+    let _ = LOG_FILE.set(path);
+    Ok(())
+}
+
+/// The file set by `--log-file`, or `default_log_file()`.
+pub fn log_file() -> &'static Path {
+    LOG_FILE.get_or_init(default_log_file)
+}
+
 // Function to log messages to a file
 pub fn log_message(message: &str) -> Result<(), std::io::Error> {
-    const LOG_FILE: &str = "railtube.log";
     let mut file = OpenOptions::new()
         .create(true)
         .append(true)
-        .open(LOG_FILE)?;
+        .open(log_file())?;
     writeln!(file, "{}", message)?;
     Ok(())
 }
@@ -24,93 +64,1183 @@ pub fn log_or_eprint(message: &str, error_message: &str) {
     }
 }
 
-pub fn run_command<C, A, I>(cmd: C, args: A) -> Result<(), CommandError>
+/// Rotates `path` to `path.1` (shifting older generations up to `path.<keep>`)
+/// when it has grown beyond `max_size` bytes. A `max_size` of 0 disables rotation.
+pub fn rotate_log(path: &Path, max_size: u64, keep: usize) -> Result<(), std::io::Error> {
+    if max_size == 0 {
+        return Ok(());
+    }
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.len() > max_size => {}
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    }
+
+    if keep == 0 {
+        return std::fs::remove_file(path);
+    }
+
+    let generation = |n: usize| -> PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    };
+
+    let oldest = generation(keep);
+    if oldest.exists() {
+        std::fs::remove_file(&oldest)?;
+    }
+    for n in (1..keep).rev() {
+        let from = generation(n);
+        if from.exists() {
+            std::fs::rename(&from, generation(n + 1))?;
+        }
+    }
+    std::fs::rename(path, generation(1))
+}
+
+/// Parses a byte size such as `10485760`, `512K`, `10MB` or `1G`.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let upper = value.to_ascii_uppercase();
+    let digits_end = upper
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(upper.len());
+    let (number, unit) = upper.split_at(digits_end);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", value))?;
+    let multiplier = match unit.trim_end_matches('B').trim_end_matches('I') {
+        "" => 1,
+        "K" => 1024,
+        "M" => 1024 * 1024,
+        "G" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid size unit in '{}'", value)),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{}' is too large", value))
+}
+
+/// Parses a duration such as `90s`, `30m`, `24h`, `7d` or `2w`.
+pub fn parse_duration(value: &str) -> Result<std::time::Duration, String> {
+    let value = value.trim();
+    let unit_start = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("missing unit in duration '{}' (use s, m, h, d or w)", value))?;
+    let (number, unit) = value.split_at(unit_start);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", value))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid duration unit in '{}' (use s, m, h, d or w)",
+                value
+            ));
+        }
+    };
+    number
+        .checked_mul(seconds)
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(|| format!("duration '{}' is too large", value))
+}
+
+static CHILD_COLOR: OnceLock<ColorChoice> = OnceLock::new();
+
+/// Command lines announced by `preview_command`, in order, for `--emit-script`.
+static PREVIEWED_COMMANDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Quotes `arg` for a POSIX shell if it contains anything but safe characters.
+pub fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+fn shell_command_line<C, A, I>(cmd: C, args: A) -> String
 where
     C: AsRef<OsStr>,
     A: IntoIterator<Item = I>,
     I: AsRef<OsStr>,
 {
-    let cmd_os = cmd.as_ref();
-    let arg_os: Vec<OsString> = args
-        .into_iter()
-        .map(|a| a.as_ref().to_os_string())
-        .collect();
-    let arg_strs: Vec<String> = arg_os
-        .iter()
-        .map(|a| a.to_string_lossy().into_owned())
-        .collect();
-    let command_str = format!("{} {}", cmd_os.to_string_lossy(), arg_strs.join(" "));
-    log_or_eprint(
-        &format!("Executing: {}", command_str),
-        "Failed to log message",
-    );
-    println!("Executing: {}", command_str);
-
-    let mut command = Command::new(cmd_os);
-    command.args(&arg_os);
-
-    let output = command.output().map_err(|e| {
-        let stderr_msg = format!("Error executing command '{}': {}", command_str, e);
-        log_or_eprint(&stderr_msg, "Failed to log error message");
-        CommandError {
-            command: cmd_os.to_os_string(),
-            args: arg_os.to_vec(),
-            exit_code: None,
-            stdout: String::new(),
-            stderr: stderr_msg,
+    // An empty program is what `sudo()` returns for `--sudo-command ''`.
+    std::iter::once(cmd.as_ref().to_string_lossy().into_owned())
+        .filter(|word| !word.is_empty())
+        .chain(
+            args.into_iter()
+                .map(|arg| arg.as_ref().to_string_lossy().into_owned()),
+        )
+        .map(|word| shell_quote(&word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Announces a command a dry run does not execute, and records it for `--emit-script`.
+pub fn preview_command<C, A, I>(cmd: C, args: A)
+where
+    C: AsRef<OsStr>,
+    A: IntoIterator<Item = I>,
+    I: AsRef<OsStr>,
+{
+    let line = shell_command_line(cmd, args);
+    step!("Would run: {}", line);
+    PREVIEWED_COMMANDS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .push(line);
+}
+
+/// Announces a download a dry run does not perform, recorded as a `curl`
+/// invocation for `--emit-script`.
+pub fn preview_download(url: &str, dest: &str, privileged: bool) {
+    step!("Would download {} to {}", url, dest);
+    let curl = ["curl", "-fsSL", "--create-dirs", "-o", dest, url];
+    let line = if privileged {
+        shell_command_line(sudo(), curl)
+    } else {
+        shell_command_line(curl[0], &curl[1..])
+    };
+    PREVIEWED_COMMANDS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .push(line);
+}
+
+pub fn previewed_commands() -> Vec<String> {
+    PREVIEWED_COMMANDS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+}
+
+/// Runs the command, or only previews it in a dry run.
+pub fn run_or_preview<C, A, I>(dry_run: bool, cmd: C, args: A) -> Result<(), CommandError>
+where
+    C: AsRef<OsStr>,
+    A: IntoIterator<Item = I>,
+    I: AsRef<OsStr>,
+{
+    if dry_run {
+        preview_command(cmd, args);
+        Ok(())
+    } else {
+        run_command(cmd, args)
+    }
+}
+
+static SUDO_COMMAND: OnceLock<String> = OnceLock::new();
+
+/// Sets the program privileged commands are run through (`--sudo-command`),
+/// e.g. `doas` or `run0`. An empty string runs them directly.
+pub fn set_sudo_command(command: String) {
+    let _ = SUDO_COMMAND.set(command);
+}
+
+/// The privilege escalation program, `sudo` unless `--sudo-command` says
+/// otherwise. Empty when privileged commands run directly; `run_command` and
+/// `preview_command` then run their first argument as the program.
+pub fn sudo() -> &'static str {
+    SUDO_COMMAND.get().map_or("sudo", String::as_str)
+}
+
+/// How often `SudoKeepalive` refreshes the sudo timestamp; well below sudo's
+/// default 5 minute timeout.
+const SUDO_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keeps sudo's cached credentials fresh in a background thread so a long
+/// apply never stops at a password prompt between packages, which parallel
+/// installs cannot show. The thread stops when the value is dropped.
+pub struct SudoKeepalive {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SudoKeepalive {
+    /// Asks for the sudo password once up front, then refreshes the timestamp
+    /// with `sudo -n -v` every minute. Returns `None` when running as root or
+    /// when sudo could not be validated; commands then prompt as before.
+    pub fn start() -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+
+        if std::fs::metadata("/proc/self").is_ok_and(|meta| meta.uid() == 0) {
+            return None;
+        }
+        match Command::new("sudo").arg("-v").status() {
+            Ok(status) if status.success() => {}
+            Ok(_) => return None,
+            Err(e) => {
+                warning!("Warning: Failed to run sudo -v: {}", e);
+                return None;
+            }
         }
-    })?;
 
-    let exit_code = output.status.code();
-    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(SUDO_KEEPALIVE_INTERVAL)
+            {
+                let refreshed = Command::new("sudo")
+                    .args(["-n", "-v"])
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status();
+                if !refreshed.is_ok_and(|status| status.success()) {
+                    log_or_eprint(
+                        "Failed to refresh sudo credentials; later commands may prompt again.",
+                        "Failed to log message",
+                    );
+                    break;
+                }
+            }
+        });
+        Some(SudoKeepalive {
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+}
 
-    // Log stdout and stderr regardless of success
-    if !stdout.is_empty() {
-        log_or_eprint(&format!("Stdout:\n{}", stdout), "Failed to log stdout");
+impl Drop for SudoKeepalive {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread with `Disconnected`.
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
     }
-    if !stderr.is_empty() {
-        log_or_eprint(&format!("Stderr:\n{}", stderr), "Failed to log stderr");
+}
+
+static TEMP_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets the directory `create_temp_dir` creates its directories in.
+pub fn set_temp_dir(path: PathBuf) -> Result<(), AppError> {
+    if !path.is_dir() {
+        return Err(AppError::Other(
+            format!("Temp directory {} does not exist.", path.display()).into(),
+        ));
     }
+    let _ = TEMP_DIR.set(path);
+    Ok(())
+}
+
+/// Creates a temporary directory in `--temp-dir`/`RAILTUBE_TMPDIR`, or the
+/// system temp directory if neither is set.
+pub fn create_temp_dir() -> Result<tempfile::TempDir, AppError> {
+    let base = TEMP_DIR.get().cloned().unwrap_or_else(std::env::temp_dir);
+    tempfile::Builder::new()
+        .prefix("railtube-")
+        .tempdir_in(&base)
+        .map_err(|e| {
+            AppError::Other(
+                format!("Temp directory {} is not writable: {}", base.display(), e).into(),
+            )
+        })
+}
 
+/// Free bytes on the filesystem holding `path`, from `df`.
+fn available_space(path: &Path) -> Option<u64> {
+    let output = Command::new("df").arg("-Pk").arg(path).output().ok()?;
     if !output.status.success() {
-        let error_msg = format!(
-            "Command failed with exit code {:?}: {}",
-            exit_code, command_str
+        return None;
+    }
+    parse_df_available(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parses the available space out of POSIX `df -Pk` output.
+fn parse_df_available(stdout: &str) -> Option<u64> {
+    let kib: u64 = stdout
+        .lines()
+        .nth(1)?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+/// Sets the color preference passed to commands started by `run_command`.
+pub fn set_child_color(choice: ColorChoice) {
+    let _ = CHILD_COLOR.set(choice);
+}
+
+/// A command about to be run by `run_command` or `run_command_streamed`.
+struct PreparedCommand {
+    command: Command,
+    cmd_os: OsString,
+    arg_os: Vec<OsString>,
+    command_str: String,
+}
+
+impl PreparedCommand {
+    /// Builds the command and announces it on the console and in the log.
+    fn new<C, A, I>(cmd: C, args: A) -> Self
+    where
+        C: AsRef<OsStr>,
+        A: IntoIterator<Item = I>,
+        I: AsRef<OsStr>,
+    {
+        let mut cmd_os = cmd.as_ref().to_os_string();
+        let mut arg_os: Vec<OsString> = args
+            .into_iter()
+            .map(|a| a.as_ref().to_os_string())
+            .collect();
+        if cmd_os.is_empty() && !arg_os.is_empty() {
+            cmd_os = arg_os.remove(0);
+        }
+        let arg_strs: Vec<String> = arg_os
+            .iter()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+        let command_str = format!("{} {}", cmd_os.to_string_lossy(), arg_strs.join(" "));
+        log_or_eprint(
+            &format!("Executing: {}", command_str),
+            "Failed to log message",
         );
-        log_or_eprint(&error_msg, "Failed to log error message");
-        return Err(CommandError {
-            command: cmd_os.to_os_string(),
-            args: arg_os.to_vec(),
+        if verbosity() >= Verbosity::Verbose {
+            step!("Executing: {}", command_str);
+        }
+
+        let mut command = Command::new(&cmd_os);
+        command.args(&arg_os);
+        match CHILD_COLOR.get() {
+            Some(ColorChoice::Always) => {
+                command.env("CARGO_TERM_COLOR", "always");
+            }
+            Some(ColorChoice::Never) => {
+                command
+                    .env("CARGO_TERM_COLOR", "never")
+                    .env("NO_COLOR", "1");
+            }
+            Some(ColorChoice::Auto) | None => {}
+        }
+        PreparedCommand {
+            command,
+            cmd_os,
+            arg_os,
+            command_str,
+        }
+    }
+
+    fn error(&self, exit_code: Option<i32>, stdout: String, stderr: String) -> CommandError {
+        CommandError {
+            command: self.cmd_os.clone(),
+            args: self.arg_os.clone(),
             exit_code,
             stdout,
             stderr,
+        }
+    }
+
+    fn spawn_error(&self, e: std::io::Error) -> CommandError {
+        let stderr_msg = format!("Error executing command '{}': {}", self.command_str, e);
+        log_or_eprint(&stderr_msg, "Failed to log error message");
+        self.error(None, String::new(), stderr_msg)
+    }
+
+    /// Logs the captured output and turns a failed exit status into an error.
+    fn finish(
+        &self,
+        status: std::process::ExitStatus,
+        stdout: String,
+        stderr: String,
+    ) -> Result<(), CommandError> {
+        // Log stdout and stderr regardless of success
+        if !stdout.is_empty() {
+            log_or_eprint(&format!("Stdout:\n{}", stdout), "Failed to log stdout");
+        }
+        if !stderr.is_empty() {
+            log_or_eprint(&format!("Stderr:\n{}", stderr), "Failed to log stderr");
+        }
+
+        if !status.success() {
+            let exit_code = status.code();
+            let error_msg = format!(
+                "Command failed with exit code {:?}: {}",
+                exit_code, self.command_str
+            );
+            log_or_eprint(&error_msg, "Failed to log error message");
+            return Err(self.error(exit_code, stdout, stderr));
+        }
+        Ok(())
+    }
+}
+
+pub fn run_command<C, A, I>(cmd: C, args: A) -> Result<(), CommandError>
+where
+    C: AsRef<OsStr>,
+    A: IntoIterator<Item = I>,
+    I: AsRef<OsStr>,
+{
+    let mut prepared = PreparedCommand::new(cmd, args);
+    prepared.command.stdin(Stdio::null());
+    run_prepared(prepared, false)
+}
+
+/// Like `run_command`, but echoes the command's output line by line while it
+/// runs instead of only logging it at the end, for long-running scripts.
+/// The output is still captured for the log and for the error on failure.
+pub fn run_command_streamed<C, A, I>(cmd: C, args: A) -> Result<(), CommandError>
+where
+    C: AsRef<OsStr>,
+    A: IntoIterator<Item = I>,
+    I: AsRef<OsStr>,
+{
+    run_prepared(PreparedCommand::new(cmd, args), true)
+}
+
+static COMMAND_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+/// How long a command that ignores SIGTERM gets before it is killed.
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Limits how long each command started by `run_command` may run. 0 means no limit.
+pub fn set_command_timeout(seconds: u64) {
+    if seconds > 0 {
+        let _ = COMMAND_TIMEOUT.set(Duration::from_secs(seconds));
+    }
+}
+
+fn run_prepared(mut prepared: PreparedCommand, echo: bool) -> Result<(), CommandError> {
+    use std::os::unix::process::CommandExt;
+    use wait_timeout::ChildExt;
+
+    // -vv shows every command's output inline; it is logged either way.
+    let echo = echo || verbosity() == Verbosity::Debug;
+    let timeout = COMMAND_TIMEOUT.get().copied();
+    if timeout.is_some() {
+        // Its own process group lets a timeout stop everything the command started.
+        prepared.command.process_group(0);
+    }
+    let mut child = prepared
+        .command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| prepared.spawn_error(e))?;
+
+    let capture = |pipe: Box<dyn Read + Send>, console: Box<dyn Write + Send>| {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let captured = if echo {
+                tee_lines(pipe, console)
+            } else {
+                let mut bytes = Vec::new();
+                let _ = { pipe }.read_to_end(&mut bytes);
+                String::from_utf8_lossy(&bytes).into_owned()
+            };
+            let _ = sender.send(captured);
         });
+        receiver
+    };
+    let stdout = child.stdout.take().map(|pipe| {
+        let console: Box<dyn Write + Send> = if crate::console::stdout_reserved() {
+            Box::new(std::io::stderr())
+        } else {
+            Box::new(std::io::stdout())
+        };
+        capture(Box::new(pipe), console)
+    });
+    let stderr = child
+        .stderr
+        .take()
+        .map(|pipe| capture(Box::new(pipe), Box::new(std::io::stderr())));
+
+    let mut timed_out = None;
+    let status = match timeout {
+        None => child.wait(),
+        Some(limit) => match child.wait_timeout(limit) {
+            Ok(Some(status)) => Ok(status),
+            Ok(None) => {
+                timed_out = Some(limit);
+                terminate_process_group(&mut child);
+                child.wait()
+            }
+            Err(e) => Err(e),
+        },
+    };
+    // Output a timed-out command's leftover children still hold open is not waited for.
+    let collect = |receiver: Option<mpsc::Receiver<String>>| {
+        receiver
+            .and_then(|receiver| match timed_out {
+                None => receiver.recv().ok(),
+                Some(_) => receiver.recv_timeout(Duration::from_secs(1)).ok(),
+            })
+            .unwrap_or_default()
+    };
+    let (stdout, mut stderr) = (collect(stdout), collect(stderr));
+    let status = status.map_err(|e| prepared.spawn_error(e))?;
+
+    if let Some(limit) = timed_out {
+        let error_msg = format!(
+            "Command timed out after {}s and was killed: {}",
+            limit.as_secs(),
+            prepared.command_str
+        );
+        log_or_eprint(&error_msg, "Failed to log error message");
+        if !stderr.is_empty() && !stderr.ends_with('\n') {
+            stderr.push('\n');
+        }
+        stderr.push_str(&error_msg);
+        return Err(prepared.error(None, stdout, stderr));
+    }
+    prepared.finish(status, stdout, stderr)
+}
+
+/// Asks the process group of `child` to terminate (sudo relays SIGTERM to the
+/// command it runs), then kills it if it is still running after a grace period.
+/// The caller reaps `child` with `wait`.
+fn terminate_process_group(child: &mut std::process::Child) {
+    use wait_timeout::ChildExt;
+
+    let group = format!("-{}", child.id());
+    let signal_group = |signal: &str| {
+        let _ = Command::new("kill")
+            .args([signal, "--", &group])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    };
+    signal_group("-TERM");
+    if !matches!(child.wait_timeout(KILL_GRACE_PERIOD), Ok(Some(_))) {
+        signal_group("-KILL");
+        let _ = child.kill();
+    }
+}
+
+/// Copies `reader` to `console` line by line as it arrives, returning everything read.
+fn tee_lines(reader: impl Read, mut console: impl Write) -> String {
+    use std::io::BufRead;
+
+    let mut reader = std::io::BufReader::new(reader);
+    let mut captured = Vec::new();
+    let mut line = Vec::new();
+    while let Ok(n) = reader.read_until(b'\n', &mut line) {
+        if n == 0 {
+            break;
+        }
+        let _ = console.write_all(&line);
+        let _ = console.flush();
+        captured.append(&mut line);
+    }
+    String::from_utf8_lossy(&captured).into_owned()
+}
+
+/// Redirects followed when fetching a remote manifest.
+const MAX_MANIFEST_REDIRECTS: usize = 5;
+
+fn manifest_client() -> Result<Client, AppError> {
+    Ok(Client::builder()
+        .redirect(reqwest::redirect::Policy::limited(MAX_MANIFEST_REDIRECTS))
+        .build()?)
+}
+
+/// How often a request failing with a timeout or connection error is retried.
+pub const NETWORK_RETRIES: u32 = 3;
+
+/// Delay before the first retry; doubled for each further one.
+pub const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Sends a GET request to `url`, retrying up to `retries` times with exponential
+/// backoff when it times out or cannot connect. Other errors, and any HTTP
+/// response including error statuses, are returned without retrying.
+pub fn fetch_with_retry(client: &Client, url: &str, retries: u32) -> Result<Response, AppError> {
+    fetch_with_headers(client, url, &HeaderMap::new(), retries)
+}
+
+/// `fetch_with_retry` sending extra request `headers`, such as cache validators.
+fn fetch_with_headers(
+    client: &Client,
+    url: &str,
+    headers: &HeaderMap,
+    retries: u32,
+) -> Result<Response, AppError> {
+    let mut attempt = 0;
+    loop {
+        match client.get(url).headers(headers.clone()).send() {
+            Err(e) if attempt < retries && (e.is_timeout() || e.is_connect()) => {
+                let delay = RETRY_BASE_DELAY * 2u32.pow(attempt);
+                attempt += 1;
+                log_or_eprint(
+                    &format!(
+                        "Request to {} failed ({}); retrying in {}s (attempt {}/{})",
+                        url,
+                        e,
+                        delay.as_secs(),
+                        attempt,
+                        retries
+                    ),
+                    "Failed to log retry",
+                );
+                std::thread::sleep(delay);
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// Reads and decodes a manifest response, rejecting HTML bodies up front so an
+/// expired login redirect does not surface as a confusing TOML parse error.
+fn read_manifest_response(source: &str, mut response: Response) -> Result<String, AppError> {
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let final_url = response.url().to_string();
+    let mut content = Vec::new();
+    response.read_to_end(&mut content)?;
+    check_manifest_body(source, &final_url, &content_type, &content)?;
+    decode_manifest(&content)
+}
+
+fn check_manifest_body(
+    source: &str,
+    final_url: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), AppError> {
+    let head = String::from_utf8_lossy(&body[..body.len().min(256)]).to_ascii_lowercase();
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    let is_html = content_type.to_ascii_lowercase().contains("text/html")
+        || head.starts_with("<!doctype html")
+        || head.starts_with("<html");
+    if !is_html {
+        return Ok(());
+    }
+
+    let redirect_note = if final_url != source {
+        format!(" (redirected to {})", final_url)
+    } else {
+        String::new()
+    };
+    Err(AppError::Other(
+        format!(
+            "{} returned an HTML page instead of a TOML manifest{}. \
+             The URL may require authentication or have redirected to a login page.",
+            source, redirect_note
+        )
+        .into(),
+    ))
+}
+
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Makes manifest fetches from URLs fail for the rest of the run (`apply --offline`).
+pub fn set_offline() {
+    OFFLINE.store(true, Ordering::Relaxed);
+}
+
+fn ensure_online(source: &str) -> Result<(), AppError> {
+    if OFFLINE.load(Ordering::Relaxed) {
+        return Err(AppError::Other(
+            format!(
+                "Cannot fetch {} with --offline; download the manifest and pass its local path instead.",
+                source
+            )
+            .into(),
+        ));
     }
     Ok(())
 }
 
 pub fn fetch_toml_content(source: &str) -> Result<String, AppError> {
-    if source.starts_with("http://") || source.starts_with("https://") {
-        let client = Client::new();
-        let mut response = client.get(source).send()?;
-        if !response.status().is_success() {
-            return Err(AppError::Other(
-                format!("Failed to fetch URL: {}", response.status()).into(),
-            ));
+    if is_url(source) {
+        fetch_remote_manifest(source)?.ok_or_else(|| {
+            AppError::Other(
+                format!("Failed to fetch URL: {}", reqwest::StatusCode::NOT_FOUND).into(),
+            )
+        })
+    } else {
+        decode_manifest(&std::fs::read(source)?)
+    }
+}
+
+/// Reads the local file at `source`, or downloads it if it is a URL.
+pub fn fetch_bytes(source: &str) -> Result<Vec<u8>, AppError> {
+    if !is_url(source) {
+        return Ok(std::fs::read(source)?);
+    }
+    ensure_online(source)?;
+    let response = fetch_with_retry(&Client::new(), source, NETWORK_RETRIES)?;
+    if !response.status().is_success() {
+        return Err(AppError::Other(
+            format!("Failed to fetch {}: {}", source, response.status()).into(),
+        ));
+    }
+    Ok(response.bytes()?.to_vec())
+}
+
+/// Decodes manifest bytes as UTF-8, stripping a leading byte order mark and
+/// transcoding UTF-16 (detected by its BOM) as saved by some Windows editors.
+pub fn decode_manifest(bytes: &[u8]) -> Result<String, AppError> {
+    let utf16 = |bytes: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = bytes
+            .chunks(2)
+            .map(|pair| from_bytes([pair[0], *pair.get(1).unwrap_or(&0)]))
+            .collect();
+        String::from_utf16(&units)
+            .map_err(|e| AppError::Other(format!("Invalid UTF-16 manifest: {}", e).into()))
+    };
+
+    match bytes {
+        [0xEF, 0xBB, 0xBF, rest @ ..] => String::from_utf8(rest.to_vec())
+            .map_err(|e| AppError::Other(format!("Invalid UTF-8 manifest: {}", e).into())),
+        [0xFF, 0xFE, rest @ ..] => utf16(rest, u16::from_le_bytes),
+        [0xFE, 0xFF, rest @ ..] => utf16(rest, u16::from_be_bytes),
+        _ => String::from_utf8(bytes.to_vec())
+            .map_err(|e| AppError::Other(format!("Invalid UTF-8 manifest: {}", e).into())),
+    }
+}
+
+/// Formats seconds since the Unix epoch as an RFC 3339 UTC timestamp.
+pub fn format_utc_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // Civil-from-days conversion (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+/// Downloads `url` into the file at `path`.
+pub fn download_file(client: &Client, url: &str, path: &Path) -> Result<(), AppError> {
+    let mut response = fetch_with_retry(client, url, NETWORK_RETRIES)?;
+    if !response.status().is_success() {
+        return Err(AppError::Other(
+            format!("Failed to download {}: {}", url, response.status()).into(),
+        ));
+    }
+    if let Some(size) = response.content_length()
+        && let Some(dir) = path.parent()
+        && let Some(available) = available_space(dir)
+        && available < size
+    {
+        return Err(AppError::Other(
+            format!(
+                "Not enough space in {} to download {} ({} bytes needed, {} available). \
+                 Use --temp-dir or RAILTUBE_TMPDIR to download elsewhere.",
+                dir.display(),
+                url,
+                size,
+                available
+            )
+            .into(),
+        ));
+    }
+    let mut file = std::fs::File::create(path)?;
+    response.copy_to(&mut file)?;
+    Ok(())
+}
+
+/// Like `fetch_toml_content`, but returns `None` when the local file or remote
+/// URL does not exist (404) instead of failing.
+pub fn fetch_optional_toml_content(source: &str) -> Result<Option<String>, AppError> {
+    if is_url(source) {
+        fetch_remote_manifest(source)
+    } else {
+        match std::fs::read(source) {
+            Ok(content) => decode_manifest(&content).map(Some),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(AppError::Io(e)),
         }
-        let mut content = String::new();
-        response.read_to_string(&mut content)?;
-        Ok(content)
+    }
+}
+
+/// Fetches the manifest at the URL `source`, or `None` on a 404. Unless
+/// `--no-cache` is given, the manifest is cached, and a cached copy is
+/// revalidated with its ETag/Last-Modified and reused on a 304.
+fn fetch_remote_manifest(source: &str) -> Result<Option<String>, AppError> {
+    ensure_online(source)?;
+    let mode = manifest_cache::cache_mode();
+    let cache_dir = (mode != CacheMode::Off)
+        .then(manifest_cache::cache_dir)
+        .flatten();
+    let cached = cache_dir
+        .as_deref()
+        .filter(|_| mode == CacheMode::Revalidate)
+        .and_then(|dir| manifest_cache::load(dir, source));
+    let validators = cached
+        .as_ref()
+        .map(CachedManifest::validators)
+        .unwrap_or_default();
+
+    let response = fetch_with_headers(&manifest_client()?, source, &validators, NETWORK_RETRIES)?;
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED
+        && let Some(cached) = cached
+    {
+        log_or_eprint(
+            &format!("Using cached manifest for {} (not modified)", source),
+            "Failed to log message",
+        );
+        return Ok(Some(cached.content));
+    }
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        return Err(AppError::Other(
+            format!("Failed to fetch URL: {}", response.status()).into(),
+        ));
+    }
+    let headers = response.headers().clone();
+    let content = read_manifest_response(source, response)?;
+    if let Some(dir) = cache_dir {
+        manifest_cache::store(&dir, source, &content, &headers);
+    }
+    Ok(Some(content))
+}
+
+/// Checks that the SHA-256 of the file at `path` is `expected` (hex, case-insensitive).
+pub fn verify_sha256(path: &Path, expected: &str) -> Result<(), AppError> {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    let actual = format!("{:x}", hasher.finalize());
+    if actual.eq_ignore_ascii_case(expected.trim()) {
+        Ok(())
     } else {
-        std::fs::read_to_string(source).map_err(AppError::Io)
+        Err(AppError::Other(
+            format!(
+                "SHA-256 mismatch for {}: expected {}, got {}",
+                path.display(),
+                expected.trim(),
+                actual
+            )
+            .into(),
+        ))
     }
 }
 
+/// Matches `text` against a glob `pattern` (`*` any run of characters, `?` one
+/// character). A pattern without wildcards matches as a substring.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    if !pattern.contains(['*', '?']) {
+        return text.contains(pattern);
+    }
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Whether `name` resolves to an executable file, either as a path or through `PATH`.
+pub fn command_exists(name: &str) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    let is_executable = |path: &Path| {
+        std::fs::metadata(path)
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    };
+    if name.contains('/') {
+        return is_executable(Path::new(name));
+    }
+    std::env::var_os("PATH").is_some_and(|paths| {
+        std::env::split_paths(&paths).any(|dir| is_executable(&dir.join(name)))
+    })
+}
+
+pub fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Returns the system hostname.
+pub fn system_hostname() -> Option<String> {
+    if let Ok(name) = std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        return Some(name.trim().to_string()).filter(|name| !name.is_empty());
+    }
+    let output = Command::new("hostname").output().ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .filter(|name| output.status.success() && !name.is_empty())
+}
+
 pub fn confirm_installation(prompt: &str) -> Result<bool, AppError> {
-    print!("{} (y/N): ", prompt);
-    std::io::Write::flush(&mut std::io::stdout())?;
+    confirm_while_locked(prompt, &lock_prompts())
+}
+
+static PROMPTS: Mutex<()> = Mutex::new(());
+
+/// Serializes questions to the user: sections applied in parallel must not
+/// ask at the same time. Hold the guard while printing what a question is
+/// about, then ask with `confirm_while_locked`.
+pub fn lock_prompts() -> MutexGuard<'static, ()> {
+    PROMPTS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// `confirm_installation` for a caller already holding `lock_prompts()`.
+pub fn confirm_while_locked(prompt: &str, _prompts: &MutexGuard<()>) -> Result<bool, AppError> {
+    if crate::console::stdout_reserved() {
+        eprint!("{} (y/N): ", prompt);
+    } else {
+        print!("{} (y/N): ", prompt);
+        std::io::Write::flush(&mut std::io::stdout())?;
+    }
     let mut input = String::new();
     std::io::stdin().read_line(&mut input)?;
     Ok(input.trim().eq_ignore_ascii_case("y"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_manifest_body_rejects_html() {
+        let url = "https://example.com/railtube.toml";
+        assert!(check_manifest_body(url, url, "text/plain", b"[apt]\nlist = []\n").is_ok());
+
+        let err = check_manifest_body(
+            url,
+            "https://login.example.com/",
+            "text/html; charset=utf-8",
+            b"<form>",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("redirected to https://login.example.com/"));
+
+        assert!(
+            check_manifest_body(url, url, "application/octet-stream", b"\n  <!DOCTYPE html>")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let url = "https://example.com/releases/tool_1.2.3_amd64.deb";
+        assert!(glob_match("tool_", url));
+        assert!(glob_match("*/tool_*_amd64.deb", url));
+        assert!(glob_match("https://example.com/*/tool_?.?.?_*", url));
+        assert!(!glob_match("*_arm64.deb", url));
+        assert!(!glob_match("other", url));
+    }
+
+    #[test]
+    fn test_parse_df_available() {
+        let stdout = "Filesystem     1024-blocks    Used Available Capacity Mounted on\n\
+                      tmpfs              2097152  524288   1572864      25% /tmp\n";
+        assert_eq!(parse_df_available(stdout), Some(1572864 * 1024));
+        assert_eq!(parse_df_available(""), None);
+    }
+
+    #[test]
+    fn test_fetch_with_retry_does_not_retry_http_errors() {
+        use std::io::{BufRead, BufReader};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/manifest.toml", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            reader
+                .get_mut()
+                .write_all(
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+                .unwrap();
+            listener.set_nonblocking(true).unwrap();
+            listener.accept().is_err()
+        });
+
+        let response = fetch_with_retry(&Client::new(), &url, NETWORK_RETRIES).unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        assert!(server.join().unwrap(), "a 404 must not be retried");
+    }
+
+    #[test]
+    fn test_fetch_with_retry_returns_connect_errors() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = format!("http://127.0.0.1:{}/", port);
+        assert!(fetch_with_retry(&Client::new(), &url, 0).is_err());
+    }
+
+    #[test]
+    fn test_tee_lines_echoes_and_captures() {
+        let mut console = Vec::new();
+        let captured = tee_lines(&b"first\nsecond"[..], &mut console);
+        assert_eq!(captured, "first\nsecond");
+        assert_eq!(console, b"first\nsecond");
+    }
+
+    #[test]
+    fn test_run_command_streamed_keeps_stderr_on_failure() {
+        let err =
+            run_command_streamed("sh", ["-c", "echo out; echo oops >&2; exit 3"]).unwrap_err();
+        assert_eq!(err.exit_code, Some(3));
+        assert_eq!(err.stdout, "out\n");
+        assert_eq!(err.stderr, "oops\n");
+    }
+
+    #[test]
+    fn test_verify_sha256() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hello.deb");
+        std::fs::write(&path, "hello\n").unwrap();
+        let sha256 = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+        assert!(verify_sha256(&path, sha256).is_ok());
+        assert!(verify_sha256(&path, &sha256.to_uppercase()).is_ok());
+        let err = verify_sha256(&path, &"0".repeat(64))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("SHA-256 mismatch"));
+    }
+
+    #[test]
+    fn test_command_exists() {
+        assert!(command_exists("sh"));
+        assert!(command_exists("/bin/sh"));
+        assert!(!command_exists("railtube-no-such-command"));
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("ripgrep=14.0.0"), "ripgrep=14.0.0");
+        assert_eq!(shell_quote("code --classic"), "'code --classic'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("10MB"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("1GiB"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("ten").is_err());
+        assert!(parse_size("10TB").is_err());
+        assert_eq!(
+            parse_size("99999999999G"),
+            Err("size '99999999999G' is too large".to_string())
+        );
+    }
+
+    #[test]
+    fn test_decode_manifest_strips_utf8_bom() {
+        let bytes = b"\xEF\xBB\xBF[apt]\nlist = []\n";
+        assert_eq!(decode_manifest(bytes).unwrap(), "[apt]\nlist = []\n");
+    }
+
+    #[test]
+    fn test_decode_manifest_transcodes_utf16() {
+        let text = "[apt]\nlist = [\"git\"]\n";
+        let mut le = vec![0xFF, 0xFE];
+        le.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        let mut be = vec![0xFE, 0xFF];
+        be.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+
+        assert_eq!(decode_manifest(&le).unwrap(), text);
+        assert_eq!(decode_manifest(&be).unwrap(), text);
+    }
+
+    #[test]
+    fn test_decode_manifest_plain_utf8() {
+        assert_eq!(decode_manifest(b"[cargo]").unwrap(), "[cargo]");
+        assert!(decode_manifest(b"\xFF\x00\x80").is_err());
+    }
+
+    #[test]
+    fn test_format_utc_timestamp() {
+        assert_eq!(format_utc_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_utc_timestamp(1_760_523_330), "2025-10-15T10:15:30Z");
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        use std::time::Duration;
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("24h"), Ok(Duration::from_secs(86_400)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(604_800)));
+        assert!(parse_duration("7").is_err());
+        assert!(parse_duration("7y").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("99999999999999999w").is_err());
+    }
+
+    #[test]
+    fn test_rotate_log_shifts_generations() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("railtube.log");
+        std::fs::write(&log, "current").unwrap();
+        std::fs::write(dir.path().join("railtube.log.1"), "first").unwrap();
+        std::fs::write(dir.path().join("railtube.log.2"), "second").unwrap();
+
+        rotate_log(&log, 1, 2).unwrap();
+
+        assert!(!log.exists());
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("railtube.log.1"), "current");
+        assert_eq!(read("railtube.log.2"), "first");
+    }
+
+    #[test]
+    fn test_rotate_log_below_limit_is_noop() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("railtube.log");
+        std::fs::write(&log, "small").unwrap();
+
+        rotate_log(&log, 1024, 3).unwrap();
+
+        assert!(log.exists());
+        assert!(!dir.path().join("railtube.log.1").exists());
+    }
+}
@@ -1,6 +1,7 @@
 use std::fs::File;
 use std::io::Write;
-use std::process::Command;
+use std::os::unix::fs::PermissionsExt;
+use std::process::{Command, Stdio};
 use tempfile::TempDir;
 
 #[test]
@@ -43,6 +44,336 @@ list = ["fake-pkg"]
     );
 }
 
+#[test]
+fn test_apply_dry_run_batches_apt_installs() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[apt]
+list = ["fake-pkg", "fake-other-pkg=1.0"]
+"#
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("apply")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--dry-run")
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Would run: sudo apt install -y fake-pkg fake-other-pkg=1.0"),
+        "Expected a single apt invocation, got: {}",
+        stdout
+    );
+}
+
+#[test]
+fn test_apply_dry_run_flatpak_overrides() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[flatpak]
+list = [{{ name = "org.example.Fake", overrides = ["--filesystem=home"] }}]
+"#
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("apply")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--dry-run")
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Would run: flatpak override --user org.example.Fake --filesystem=home"),
+        "Expected dry-run output for flatpak override"
+    );
+}
+
+#[test]
+fn test_apply_no_update_skips_system_update() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[system]
+update = true
+"#
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("apply")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--dry-run")
+        .arg("--no-update")
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Skipping apt update (--no-update)."));
+    assert!(!stdout.contains("Would run: sudo apt update"));
+}
+
+#[test]
+fn test_apply_dry_run_system_upgrade_and_autoremove() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    std::fs::write(
+        &toml_path,
+        "[system]\nautoremove = true\nupgrade = true\nupdate = true\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .args(["apply", "--source", toml_path.to_str().unwrap()])
+        .args(["--dry-run", "--only", "system"])
+        .arg("--log-file")
+        .arg(temp_dir.path().join("railtube.log"))
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let previews: Vec<&str> = stdout
+        .lines()
+        .filter_map(|line| line.split_once("Would run: ").map(|(_, command)| command))
+        .collect();
+    assert_eq!(
+        previews,
+        [
+            "sudo apt update",
+            "sudo apt upgrade -y",
+            "sudo apt autoremove -y"
+        ]
+    );
+}
+
+#[test]
+fn test_apply_dry_run_apt_target_release() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[apt]
+default_release = "stable"
+list = ["fake-pkg", {{ name = "fake-testing-pkg", release = "testing" }}]
+"#
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("apply")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--dry-run")
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Would run: sudo apt install -y -t stable fake-pkg"));
+    assert!(stdout.contains("Would run: sudo apt install -y -t testing fake-testing-pkg"));
+}
+
+#[test]
+fn test_apply_empty_sections_are_all_visited() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[apt]
+list = []
+
+[snap]
+list = []
+
+[flatpak]
+list = []
+
+[cargo]
+list = []
+
+[deb]
+urls = []
+"#
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("apply")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--dry-run")
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for section in ["apt", "snap", "flatpak", "cargo", "deb"] {
+        assert!(
+            stdout.contains(&format!("Nothing to do in [{}].", section)),
+            "Expected [{}] to be visited, got: {}",
+            section,
+            stdout
+        );
+    }
+}
+
+#[test]
+fn test_apply_dry_run_installs_local_debs() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    std::fs::create_dir(temp_dir.path().join("vendor")).unwrap();
+    std::fs::write(temp_dir.path().join("vendor/tool_1.0_amd64.deb"), "").unwrap();
+    std::fs::write(
+        &toml_path,
+        "[deb]\nurls = [\"vendor/tool_1.0_amd64.deb\"]\n",
+    )
+    .unwrap();
+    // Relative paths are resolved against the manifest, not the current directory.
+    let cwd = temp_dir.path().join("elsewhere");
+    std::fs::create_dir(&cwd).unwrap();
+    let apply = || {
+        Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .args([
+                "apply",
+                "--source",
+                toml_path.to_str().unwrap(),
+                "--dry-run",
+            ])
+            .arg("--log-file")
+            .arg(temp_dir.path().join("railtube.log"))
+            .current_dir(&cwd)
+            .output()
+            .expect("failed to execute process")
+    };
+
+    let output = apply();
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let deb = temp_dir
+        .path()
+        .canonicalize()
+        .unwrap()
+        .join("vendor/tool_1.0_amd64.deb");
+    assert!(
+        stdout.contains(&format!("Would run: sudo dpkg -i {}", deb.display())),
+        "Unexpected stdout: {}",
+        stdout
+    );
+    assert!(!stdout.contains("Would download"), "{}", stdout);
+
+    std::fs::remove_file(&deb).unwrap();
+    let output = apply();
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .contains("[deb] file 'vendor/tool_1.0_amd64.deb' does not exist.")
+    );
+}
+
+#[test]
+fn test_apply_bom_prefixed_manifest() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    file.write_all(b"\xEF\xBB\xBF[apt]\nlist = [\"fake-pkg\"]\n")
+        .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("apply")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--dry-run")
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("Would run: sudo apt install -y fake-pkg"),
+        "Expected the BOM-prefixed manifest to parse"
+    );
+}
+
 #[test]
 fn test_export_generates_toml() {
     let temp_dir = TempDir::new().unwrap();
@@ -58,7 +389,7 @@ fn test_export_generates_toml() {
 
     for (command, args, name) in &prerequisites {
         let status = std::process::Command::new(command).args(*args).status();
-        if !status.map_or(false, |s| s.success()) {
+        if !status.is_ok_and(|s| s.success()) {
             eprintln!("Prerequisite '{}' not met. Skipping export test.", name);
             return;
         }
@@ -91,3 +422,2188 @@ fn test_export_generates_toml() {
         "Exported TOML should have [cargo] section"
     );
 }
+
+#[test]
+fn test_doctor_fix_missing_dry_run() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[cargo]
+list = ["railtube-test-missing-crate"]
+"#
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("doctor")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--fix-missing")
+        .arg("--dry-run")
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Would run: cargo install --locked --force railtube-test-missing-crate"),
+        "Expected the missing crate to be installed, got: {}",
+        stdout
+    );
+}
+
+#[test]
+fn test_doctor_format_json() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[cargo]
+list = ["railtube-test-missing-crate"]
+"#
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("doctor")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--format")
+        .arg("json")
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let report: serde_json::Value =
+        serde_json::from_slice(&output.stdout).expect("doctor output should be valid JSON");
+    assert_eq!(
+        report["sections"]["cargo"]["missing"][0],
+        "railtube-test-missing-crate"
+    );
+}
+
+#[test]
+fn test_apply_emit_script() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let script_path = temp_dir.path().join("apply.sh");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[system]
+update = true
+
+[apt]
+list = ["fake-pkg"]
+"#
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("apply")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--emit-script")
+        .arg(script_path.to_str().unwrap())
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let script = std::fs::read_to_string(&script_path).unwrap();
+    assert!(script.starts_with("#!/bin/sh\n"));
+    let commands: Vec<&str> = script
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.is_empty())
+        .collect();
+    assert_eq!(
+        commands,
+        vec!["set -e", "sudo apt update", "sudo apt install -y fake-pkg"]
+    );
+}
+
+#[test]
+fn test_version_json() {
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("version")
+        .arg("--json")
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    assert!(info["git_hash"].is_string());
+    assert!(info["rustc"].as_str().unwrap().starts_with("rustc "));
+}
+
+#[test]
+fn test_log_file_flag_creates_parent_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let log_path = temp_dir.path().join("logs").join("custom.log");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[scripts]
+hello = "echo hello"
+"#
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--log-file")
+        .arg(log_path.to_str().unwrap())
+        .arg("run")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("hello")
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let log = std::fs::read_to_string(&log_path).expect("log file should be written");
+    assert!(log.contains("echo hello"), "Unexpected log: {}", log);
+}
+
+#[test]
+fn test_apply_dry_run_appimage() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[[appimage]]
+name = "fake-tool"
+url = "https://example.com/fake-tool.AppImage"
+desktop = true
+"#
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("apply")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--dry-run")
+        .env("XDG_BIN_HOME", temp_dir.path().join("bin"))
+        .env("XDG_DATA_HOME", temp_dir.path().join("share"))
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let target = temp_dir.path().join("bin").join("fake-tool");
+    assert!(
+        stdout.contains(&format!(
+            "Would download https://example.com/fake-tool.AppImage to {}",
+            target.display()
+        )),
+        "Unexpected output: {}",
+        stdout
+    );
+    assert!(stdout.contains("Would create desktop entry"));
+    assert!(!target.exists());
+}
+
+#[test]
+fn test_run_timeout_kills_hung_script() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[scripts]
+hang = "echo started; sleep 30; echo finished"
+"#
+    )
+    .unwrap();
+
+    let start = std::time::Instant::now();
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("run")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--timeout")
+        .arg("1")
+        .arg("hang")
+        .output()
+        .expect("failed to execute process");
+
+    assert!(!output.status.success());
+    assert!(start.elapsed() < std::time::Duration::from_secs(20));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Command timed out after 1s and was killed"),
+        "Unexpected stderr: {}",
+        stderr
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\nstarted\n") && !stdout.contains("\nfinished\n"));
+}
+
+#[test]
+fn test_list_scripts_and_run_with_bash() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[scripts]
+greet = "echo hello"
+check-bash = {{ command = "[[ -n $BASH_VERSION ]] && echo running-in-bash", description = "Needs bash", shell = "bash" }}
+"#
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .args(["list-scripts", "--source", toml_path.to_str().unwrap()])
+        .output()
+        .expect("failed to execute process");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines, ["check-bash  Needs bash", "greet       echo hello"]);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .args(["run", "--source", toml_path.to_str().unwrap(), "check-bash"])
+        .output()
+        .expect("failed to execute process");
+    assert!(
+        output.status.success(),
+        "stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).contains("running-in-bash"));
+}
+
+#[test]
+fn test_malformed_manifest_exits_with_code_3() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    std::fs::write(&toml_path, "[apt\nlist = [\"git\"]\n").unwrap();
+
+    for command in [&["apply", "--dry-run"][..], &["validate"]] {
+        let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .args(command)
+            .args(["--source", toml_path.to_str().unwrap()])
+            .args([
+                "--log-file",
+                temp_dir.path().join("railtube.log").to_str().unwrap(),
+            ])
+            .output()
+            .expect("failed to execute process");
+        assert_eq!(output.status.code(), Some(3), "{:?}", command);
+    }
+}
+
+#[test]
+fn test_run_without_script_name_lists_scripts_off_a_terminal() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    std::fs::write(
+        &toml_path,
+        "[scripts]\ntest = \"echo test\"\nbuild = \"echo build\"\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .args(["run", "--source", toml_path.to_str().unwrap()])
+        .arg("--log-file")
+        .arg(temp_dir.path().join("railtube.log"))
+        .stdin(std::process::Stdio::null())
+        .output()
+        .expect("failed to execute process");
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("No script name given. Available scripts: build, test"),
+        "Unexpected stderr: {}",
+        stderr
+    );
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Running script"));
+}
+
+#[test]
+fn test_config_prints_the_merged_manifest() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("main.toml.j2");
+    std::fs::write(
+        temp_dir.path().join("common.toml"),
+        "[apt]\nlist = [\"curl\"]\n\n[cargo]\nlist = [\"ripgrep\"]\n",
+    )
+    .unwrap();
+    std::fs::write(
+        &toml_path,
+        "include = [\"common.toml\"]\n\n[apt]\nlist = [\"git\"]\n\n\
+         [scripts]\ngreet = \"echo {{ hostname }}\"\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .args([
+            "--host",
+            "box",
+            "config",
+            "--source",
+            toml_path.to_str().unwrap(),
+        ])
+        .arg("--log-file")
+        .arg(temp_dir.path().join("railtube.log"))
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Test command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // stdout is nothing but the merged manifest.
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let config: toml::Table = toml::from_str(&stdout).unwrap();
+    assert_eq!(
+        config["apt"]["list"],
+        toml::Value::from(vec!["curl", "git"])
+    );
+    assert_eq!(config["cargo"]["list"], toml::Value::from(vec!["ripgrep"]));
+    assert_eq!(config["scripts"]["greet"].as_str(), Some("echo box"));
+    assert!(!config.contains_key("include"), "{}", stdout);
+}
+
+#[test]
+fn test_validate_reports_problems() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[cargo]
+list = ["ripgrep", "ripgrep=14.0.0"]
+
+[flatpack]
+list = ["org.gimp.GIMP"]
+"#
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("validate")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .output()
+        .expect("failed to execute process");
+
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Found 2 problem(s)"), "{}", stdout);
+    assert!(stdout.contains("unknown top-level key 'flatpack'"));
+    assert!(stdout.contains("[cargo] lists 'ripgrep' more than once"));
+
+    std::fs::write(&toml_path, "[cargo]\nlist = [\"ripgrep=14.0.0\"]\n").unwrap();
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("validate")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .output()
+        .expect("failed to execute process");
+
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("manifest OK"));
+}
+
+#[test]
+fn test_apply_lists_nix_profile_once() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let log_path = temp_dir.path().join("railtube.log");
+    let dir = temp_dir.path().display();
+    write_fake_command(
+        temp_dir.path(),
+        "nix",
+        &format!(
+            "echo \"$*\" >> {dir}/nix-calls\n\
+             [ \"$2\" = list ] && printf 'Name:               ripgrep\\n'\n\
+             exit 0\n"
+        ),
+    );
+    std::fs::write(
+        &toml_path,
+        "[nix]\nlist = [\"ripgrep\", \"fd\", \"nixpkgs#python3Packages.black\"]\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .arg("--log-file")
+        .arg(&log_path)
+        .args(["apply", "--source", toml_path.to_str().unwrap(), "--yes"])
+        .current_dir(temp_dir.path())
+        .env("HOME", temp_dir.path())
+        .env("XDG_STATE_HOME", temp_dir.path().join("state"))
+        .env("PATH", temp_dir.path())
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Unexpected stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).contains("'ripgrep' already installed"));
+    // The outer listings are the history snapshots taken around the run.
+    assert_eq!(
+        std::fs::read_to_string(temp_dir.path().join("nix-calls")).unwrap(),
+        "profile list\n\
+         profile list\n\
+         profile install nixpkgs#fd\n\
+         profile install nixpkgs#python3Packages.black\n\
+         profile list\n"
+    );
+}
+
+#[test]
+fn test_apply_skips_nix_without_nix_binary() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[nix]
+list = ["ripgrep"]
+"#
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .arg("apply")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--dry-run")
+        .env("PATH", temp_dir.path())
+        .output()
+        .expect("failed to execute process");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("nix is not installed or not on PATH; skipping the [nix] section"),
+        "Unexpected stderr: {}",
+        stderr
+    );
+}
+
+#[test]
+fn test_colored_output_keeps_log_plain() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let log_path = temp_dir.path().join("railtube.log");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[scripts]
+hello = "echo hello"
+"#
+    )
+    .unwrap();
+
+    let run = |extra_args: &[&str]| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--log-file")
+            .arg(log_path.to_str().unwrap())
+            .arg("-v")
+            .args(extra_args)
+            .arg("run")
+            .arg("--source")
+            .arg(toml_path.to_str().unwrap())
+            .arg("hello")
+            .env("CLICOLOR_FORCE", "1")
+            .env_remove("NO_COLOR")
+            .output()
+            .expect("failed to execute process")
+    };
+
+    let output = run(&[]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("\x1b[36mExecuting: sh -c echo hello"),
+        "Expected colored output: {:?}",
+        stdout
+    );
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    assert!(log.contains("Executing: sh -c echo hello") && !log.contains('\x1b'));
+
+    let output = run(&["--no-color"]);
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains('\x1b'));
+}
+
+#[test]
+fn test_apply_installs_gems() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let log_path = temp_dir.path().join("railtube.log");
+    let dir = temp_dir.path().display();
+    // rubocop is installed at any version; jekyll only at 4.3.2.
+    write_fake_command(
+        temp_dir.path(),
+        "gem",
+        &format!(
+            "case \"$1\" in\n\
+             list) printf 'rubocop (1.60.2)\\njekyll (4.3.2)\\n' ;;\n\
+             install) echo \"$*\" >> {dir}/installed ;;\n\
+             *) exit 1 ;;\n\
+             esac\n"
+        ),
+    );
+    std::fs::write(
+        &toml_path,
+        "[gem]\nlist = [\"rubocop\", \"jekyll=4.3.3\", \"rake\"]\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .arg("--log-file")
+        .arg(&log_path)
+        .args(["apply", "--source", toml_path.to_str().unwrap(), "--yes"])
+        .current_dir(temp_dir.path())
+        .env("HOME", temp_dir.path())
+        .env("XDG_STATE_HOME", temp_dir.path().join("state"))
+        .env("PATH", temp_dir.path())
+        .output()
+        .expect("failed to execute process");
+
+    assert!(
+        output.status.success(),
+        "Unexpected stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("Gem package 'rubocop' already installed")
+    );
+    assert_eq!(
+        std::fs::read_to_string(temp_dir.path().join("installed")).unwrap(),
+        "install jekyll -v 4.3.3\ninstall rake\n"
+    );
+}
+
+#[test]
+fn test_apply_assume_installed_skips_presence_checks() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let log_path = temp_dir.path().join("railtube.log");
+    let dir = temp_dir.path().display();
+    // Both managers record every call; `npm ls` is the installed-versions probe.
+    for manager in ["gem", "npm"] {
+        write_fake_command(
+            temp_dir.path(),
+            manager,
+            &format!("echo \"{manager} $*\" >> {dir}/calls\necho '{{}}'\n"),
+        );
+    }
+    std::fs::write(
+        &toml_path,
+        "[gem]\nlist = [\"rake\"]\n\n[npm]\nlist = [\"typescript\"]\n",
+    )
+    .unwrap();
+    let apply = |assume: &str| {
+        Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .arg("--log-file")
+            .arg(&log_path)
+            .args([
+                "apply",
+                "--source",
+                toml_path.to_str().unwrap(),
+                "--dry-run",
+            ])
+            .args(["--assume-installed", assume])
+            .current_dir(temp_dir.path())
+            .env("PATH", temp_dir.path())
+            .output()
+            .expect("failed to execute process")
+    };
+
+    let output = apply("none");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Would run: gem install rake"), "{}", stdout);
+    assert!(
+        stdout.contains("Would run: npm install -g typescript"),
+        "{}",
+        stdout
+    );
+
+    let output = apply("all");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("Would run"), "{}", stdout);
+    assert!(
+        stdout.contains("Gem package 'rake' already installed"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains("package 'typescript' assumed installed"),
+        "{}",
+        stdout
+    );
+
+    assert!(!temp_dir.path().join("calls").exists());
+}
+
+#[test]
+fn test_apply_installs_mise_tools() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let log_path = temp_dir.path().join("railtube.log");
+    let dir = temp_dir.path().display();
+    // node 20.11.0 is installed; python is only requested by a config.
+    write_fake_command(
+        temp_dir.path(),
+        "mise",
+        &format!(
+            "case \"$1\" in\n\
+             ls) echo '{{\"node\":[{{\"version\":\"20.11.0\",\"installed\":true,\"active\":true}}],\
+             \"python\":[{{\"version\":\"3.12.1\",\"installed\":false}}]}}' ;;\n\
+             use) echo \"$*\" >> {dir}/mise-calls ;;\n\
+             *) exit 1 ;;\n\
+             esac\n"
+        ),
+    );
+    std::fs::write(
+        &toml_path,
+        "[mise]\nlist = [\"node@20.11.0\", \"python@3.12.1\", \"node@18.19.0\"]\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .arg("--log-file")
+        .arg(&log_path)
+        .args(["apply", "--source", toml_path.to_str().unwrap(), "--yes"])
+        .current_dir(temp_dir.path())
+        .env("HOME", temp_dir.path())
+        .env("XDG_STATE_HOME", temp_dir.path().join("state"))
+        .env("PATH", temp_dir.path())
+        .output()
+        .expect("failed to execute process");
+    assert!(
+        output.status.success(),
+        "Unexpected stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        String::from_utf8_lossy(&output.stdout).contains("mise package 'node' already installed")
+    );
+    assert_eq!(
+        std::fs::read_to_string(temp_dir.path().join("mise-calls")).unwrap(),
+        "use -g python@3.12.1\nuse -g node@18.19.0\n"
+    );
+}
+
+#[test]
+fn test_apply_verify_reports_silent_install_failures() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let log_path = temp_dir.path().join("railtube.log");
+    let dir = temp_dir.path().display();
+    // Installing "ghost" succeeds without installing anything.
+    write_fake_command(
+        temp_dir.path(),
+        "gem",
+        &format!(
+            "case \"$1\" in\n\
+             list) for gem in {dir}/gem-*; do [ -e \"$gem\" ] && echo \"${{gem##*/gem-}} (1.0)\"; done ;;\n\
+             install) [ \"$2\" = ghost ] || : > {dir}/gem-$2 ;;\n\
+             *) exit 1 ;;\n\
+             esac\n"
+        ),
+    );
+    std::fs::write(&toml_path, "[gem]\nlist = [\"rake\", \"ghost\"]\n").unwrap();
+
+    let apply = |extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .arg("--log-file")
+            .arg(&log_path)
+            .args(["apply", "--source", toml_path.to_str().unwrap(), "--yes"])
+            .args(extra_args)
+            .current_dir(temp_dir.path())
+            .env("HOME", temp_dir.path())
+            .env("XDG_STATE_HOME", temp_dir.path().join("state"))
+            .env("PATH", temp_dir.path())
+            .output()
+            .expect("failed to execute process")
+    };
+
+    assert!(apply(&[]).status.success());
+
+    let output = apply(&["--verify"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("Verification failed: 1 of 1 installed package(s)")
+            && stderr.contains("- [gem] ghost"),
+        "Unexpected stderr: {}",
+        stderr
+    );
+
+    std::fs::write(&toml_path, "[gem]\nlist = [\"rake\", \"rspec\"]\n").unwrap();
+    let output = apply(&["--verify"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Verified 1 installed package(s)."));
+}
+
+#[test]
+fn test_verbosity_levels() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let log_path = temp_dir.path().join("railtube.log");
+    write_fake_command(
+        temp_dir.path(),
+        "npm",
+        "case \"$1\" in\n\
+         ls) echo '{}' ;;\n\
+         install) echo \"added $3\" ;;\n\
+         *) exit 1 ;;\n\
+         esac\n",
+    );
+    std::fs::write(&toml_path, "[npm]\nlist = [\"prettier\"]\n").unwrap();
+
+    let apply = |flags: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .arg("--log-file")
+            .arg(&log_path)
+            .args(flags)
+            .args(["apply", "--source", toml_path.to_str().unwrap(), "--yes"])
+            .current_dir(temp_dir.path())
+            .env("HOME", temp_dir.path())
+            .env("XDG_STATE_HOME", temp_dir.path().join("state"))
+            .env("PATH", temp_dir.path())
+            .output()
+            .expect("failed to execute process");
+        assert!(
+            output.status.success(),
+            "Unexpected stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let stdout = apply(&[]);
+    assert!(
+        stdout.contains("prettier") && !stdout.contains("Executing:"),
+        "Unexpected stdout: {}",
+        stdout
+    );
+    let stdout = apply(&["-v"]);
+    assert!(
+        stdout.contains("Executing: npm install") && !stdout.contains("added prettier"),
+        "Unexpected stdout: {}",
+        stdout
+    );
+    let stdout = apply(&["-vv"]);
+    assert!(
+        stdout.contains("added prettier"),
+        "Unexpected stdout: {}",
+        stdout
+    );
+    let stdout = apply(&["--quiet"]);
+    assert!(stdout.is_empty(), "Unexpected stdout: {}", stdout);
+
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    assert_eq!(log.matches("Executing: npm install").count(), 4);
+    assert_eq!(log.matches("added prettier").count(), 4);
+}
+
+#[test]
+fn test_apply_falls_back_to_apt_without_frontend() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[apt]
+frontend = "nala"
+list = ["fake-pkg"]
+"#
+    )
+    .unwrap();
+    // The fallback is only used if apt itself is on PATH.
+    let apt_path = temp_dir.path().join("apt");
+    std::fs::write(&apt_path, "#!/bin/sh\n").unwrap();
+    std::fs::set_permissions(&apt_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .arg("apply")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--dry-run")
+        .env("PATH", temp_dir.path())
+        .output()
+        .expect("failed to execute process");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("APT frontend 'nala' is not installed"),
+        "Unexpected stderr: {}",
+        stderr
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Would run: sudo apt install -y fake-pkg"));
+}
+
+#[test]
+fn test_apply_dry_run_previews_apt_repositories() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let log_path = temp_dir.path().join("railtube.log");
+    write_fake_command(temp_dir.path(), "apt", "");
+    std::fs::write(
+        &toml_path,
+        r#"
+[apt]
+list = ["fake-neovim"]
+repositories = [
+    "ppa:fake-owner/fake-ppa",
+    { line = "deb https://apt.example.com/fake stable main", key = "https://apt.example.com/key.gpg" },
+]
+"#,
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .arg("--log-file")
+        .arg(&log_path)
+        .args([
+            "apply",
+            "--source",
+            toml_path.to_str().unwrap(),
+            "--dry-run",
+        ])
+        .current_dir(temp_dir.path())
+        .env("PATH", temp_dir.path())
+        .output()
+        .expect("failed to execute process");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let expected = [
+        "Would run: sudo add-apt-repository -y ppa:fake-owner/fake-ppa",
+        "Would download https://apt.example.com/key.gpg to /etc/apt/keyrings/apt-example-com-fake.gpg",
+        "'deb [signed-by=/etc/apt/keyrings/apt-example-com-fake.gpg] https://apt.example.com/fake stable main' /etc/apt/sources.list.d/apt-example-com-fake.list",
+        "Would run: sudo apt update",
+        "Would run: sudo apt install -y fake-neovim",
+    ];
+    let mut rest = stdout.as_ref();
+    for line in expected {
+        let Some(index) = rest.find(line) else {
+            panic!("Expected {:?} in order in: {}", line, stdout);
+        };
+        rest = &rest[index + line.len()..];
+    }
+}
+
+#[test]
+fn test_apply_skips_apt_and_dnf_when_missing() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[apt]
+list = ["fake-pkg"]
+
+[dnf]
+list = ["fake-rpm"]
+"#
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .arg("apply")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--dry-run")
+        .env("PATH", temp_dir.path())
+        .output()
+        .expect("failed to execute process");
+
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("apt is not installed or not on PATH; skipping the [apt] section")
+            && stderr.contains("dnf is not installed or not on PATH; skipping the [dnf] section"),
+        "Unexpected stderr: {}",
+        stderr
+    );
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("Would run"));
+}
+
+#[test]
+fn test_apply_dnf_lists_installed_packages_once() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let dir = temp_dir.path().display();
+    write_fake_command(temp_dir.path(), "dnf", "exit 0\n");
+    write_fake_command(
+        temp_dir.path(),
+        "rpm",
+        &format!(
+            "echo \"$*\" >> {dir}/rpm-calls\n\
+             printf 'neovim 0.9.5-1.fc39\\ngit 2.42.0-1.fc39\\n'\n"
+        ),
+    );
+    std::fs::write(
+        &toml_path,
+        "[dnf]\nlist = [\"neovim\", \"git=2.43.0\", \"htop\"]\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .args([
+            "apply",
+            "--source",
+            toml_path.to_str().unwrap(),
+            "--dry-run",
+        ])
+        .env("PATH", temp_dir.path())
+        .output()
+        .expect("failed to execute process");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("DNF package 'neovim' already installed")
+            && stdout.contains("Would run: sudo dnf install -y git-2.43.0 htop"),
+        "Unexpected stdout: {}",
+        stdout
+    );
+    let rpm_calls = std::fs::read_to_string(temp_dir.path().join("rpm-calls")).unwrap();
+    assert_eq!(rpm_calls.matches("-qa").count(), 1);
+}
+
+#[test]
+fn test_apply_sudo_command() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let apt_path = temp_dir.path().join("apt");
+    std::fs::write(&apt_path, "#!/bin/sh\nexit 0\n").unwrap();
+    std::fs::set_permissions(&apt_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[apt]
+list = ["fake-pkg"]
+"#
+    )
+    .unwrap();
+
+    for (sudo_command, expected) in [
+        ("doas", "Would run: doas apt install -y fake-pkg"),
+        ("", "Would run: apt install -y fake-pkg"),
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .arg("apply")
+            .arg("--source")
+            .arg(toml_path.to_str().unwrap())
+            .arg("--dry-run")
+            .arg("--sudo-command")
+            .arg(sudo_command)
+            .env("PATH", temp_dir.path())
+            .output()
+            .expect("failed to execute process");
+
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains(expected), "Unexpected stdout: {}", stdout);
+    }
+}
+
+#[test]
+fn test_apply_go_skips_installed_binaries() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let gobin = temp_dir.path().join("gobin");
+    std::fs::create_dir(&gobin).unwrap();
+    // Installed: gopls, yq at an older version and golangci-lint at its pin.
+    write_fake_command(
+        temp_dir.path(),
+        "go",
+        "[ \"$1\" = version ] || exit 0\n\
+         printf '%s: go1.22.1\\n\\tpath\\t%s\\n\\tmod\\t%s\\t%s\\th1:x=\\n' \\\n\
+         gopls golang.org/x/tools/gopls golang.org/x/tools/gopls v0.15.0 \\\n\
+         yq github.com/mikefarah/yq/v4 github.com/mikefarah/yq/v4 v4.40.5 \\\n\
+         golangci-lint github.com/golangci/golangci-lint/cmd/golangci-lint \\\n\
+         github.com/golangci/golangci-lint v1.59.0\n",
+    );
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[go]
+list = ["golang.org/x/tools/gopls@latest", "mvdan.cc/gofumpt", "github.com/mikefarah/yq/v4@v4.42.1", "github.com/golangci/golangci-lint/cmd/golangci-lint@v1.59.0"]
+"#
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .arg("apply")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--dry-run")
+        .env("PATH", temp_dir.path())
+        .env("GOBIN", &gobin)
+        .output()
+        .expect("failed to execute process");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Go package 'golang.org/x/tools/gopls' already installed")
+            && stdout.contains("Would run: go install mvdan.cc/gofumpt@latest")
+            && stdout.contains("installed with version 'v4.40.5', but 'v4.42.1' is requested")
+            && stdout.contains("Would run: go install github.com/mikefarah/yq/v4@v4.42.1")
+            && stdout.contains("version 'v1.59.0' already installed")
+            && !stdout.contains("go install golang.org/x/tools/gopls")
+            && !stdout.contains("go install github.com/golangci"),
+        "Unexpected stdout: {}",
+        stdout
+    );
+}
+
+/// Writes an executable `sh` script called `name` into `dir`.
+fn write_fake_command(dir: &std::path::Path, name: &str, script: &str) {
+    let path = dir.join(name);
+    std::fs::write(&path, format!("#!/bin/sh\n{}", script)).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn test_apply_retries_cargo_network_errors_only() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let log_path = temp_dir.path().join("railtube.log");
+    let dir = temp_dir.path().display();
+    // The first download of ripgrep fails; broken never compiles.
+    write_fake_command(
+        temp_dir.path(),
+        "cargo",
+        &format!(
+            "[ \"$2\" = --list ] && exit 0\n\
+             echo \"$*\" >> {dir}/calls\n\
+             case \"$*\" in\n\
+             *broken) echo 'error: could not compile `broken`' >&2; exit 101 ;;\n\
+             esac\n\
+             if [ ! -e {dir}/downloaded ]; then\n\
+             : > {dir}/downloaded\n\
+             echo 'error: failed to download from `https://static.crates.io/crates/ripgrep`' >&2\n\
+             exit 101\n\
+             fi\n"
+        ),
+    );
+    std::fs::write(&toml_path, "[cargo]\nlist = [\"broken\", \"ripgrep\"]\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .arg("--log-file")
+        .arg(&log_path)
+        .args(["apply", "--source", toml_path.to_str().unwrap()])
+        .args(["--yes", "--keep-going", "--retries", "1"])
+        .current_dir(temp_dir.path())
+        .env("HOME", temp_dir.path())
+        .env("XDG_STATE_HOME", temp_dir.path().join("state"))
+        .env("PATH", temp_dir.path())
+        .output()
+        .expect("failed to execute process");
+
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        std::fs::read_to_string(temp_dir.path().join("calls")).unwrap(),
+        "install --locked --force broken\n\
+         install --locked --force ripgrep\n\
+         install --locked --force ripgrep\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("failed with a network error; retrying in 1s (attempt 1/1)"),
+        "{}",
+        stderr
+    );
+    assert!(
+        std::fs::read_to_string(&log_path)
+            .unwrap()
+            .contains("retrying in 1s")
+    );
+}
+
+#[test]
+fn test_apply_runs_sections_in_parallel() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let dir = temp_dir.path().display();
+    // pipx comes first in manifest order but only succeeds once npm has
+    // started, which it can only do when the sections run concurrently.
+    write_fake_command(
+        temp_dir.path(),
+        "pipx",
+        &format!(
+            "[ \"$1\" = install ] || exit 1\n\
+             for _ in 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20; do\n\
+             [ -e {dir}/npm-started ] && exit 0\n\
+             /bin/sleep 0.25\n\
+             done\n\
+             exit 1\n"
+        ),
+    );
+    write_fake_command(
+        temp_dir.path(),
+        "npm",
+        &format!("[ \"$1\" = install ] || exit 1\n: > {dir}/npm-started\n"),
+    );
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[pipx]
+list = ["black"]
+
+[npm]
+list = ["prettier"]
+"#
+    )
+    .unwrap();
+
+    let apply = |extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .arg("--log-file")
+            .arg(temp_dir.path().join("railtube.log"))
+            .arg("apply")
+            .arg("--source")
+            .arg(toml_path.to_str().unwrap())
+            .arg("--yes")
+            .args(extra_args)
+            .current_dir(temp_dir.path())
+            .env("HOME", temp_dir.path())
+            .env("XDG_STATE_HOME", temp_dir.path().join("state"))
+            .env("PATH", temp_dir.path())
+            .output()
+            .expect("failed to execute process")
+    };
+
+    let output = apply(&[]);
+    assert!(
+        output.status.success(),
+        "Unexpected stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // Both sections fail now; both errors are reported.
+    write_fake_command(temp_dir.path(), "npm", "exit 1\n");
+    std::fs::remove_file(temp_dir.path().join("npm-started")).unwrap();
+    write_fake_command(temp_dir.path(), "pipx", "exit 1\n");
+    let output = apply(&[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("2 sections failed") && stderr.contains("pipx") && stderr.contains("npm"),
+        "Unexpected stderr: {}",
+        stderr
+    );
+}
+
+#[test]
+fn test_apply_offline_skips_network() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let log_path = temp_dir.path().join("railtube.log");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[cargo]
+list = ["railtube-offline-test-crate"]
+
+[deb]
+urls = ["https://example.com/tool.deb"]
+"#
+    )
+    .unwrap();
+
+    let apply = |source: &str| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--log-file")
+            .arg(log_path.to_str().unwrap())
+            .arg("apply")
+            .arg("--source")
+            .arg(source)
+            .arg("--offline")
+            .arg("--dry-run")
+            .output()
+            .expect("failed to execute process")
+    };
+
+    let output = apply(toml_path.to_str().unwrap());
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(
+            "Would run: cargo install --locked --force --offline railtube-offline-test-crate"
+        ),
+        "Unexpected stdout: {}",
+        stdout
+    );
+    assert!(!stdout.contains("example.com/tool.deb"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Skipping the [deb] section"));
+    let log = std::fs::read_to_string(&log_path).unwrap();
+    assert!(log.contains("Offline mode"));
+
+    let output = apply("https://example.com/railtube.toml");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Cannot fetch https://example.com/railtube.toml with --offline"),
+        "Unexpected stderr: {}",
+        stderr
+    );
+}
+
+#[test]
+fn test_apply_brew_sections() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[brew]
+list = ["user/tools/fake-formula"]
+
+[brew_cask]
+list = ["fake-cask"]
+
+[brew_tap]
+list = ["user/tools"]
+"#
+    )
+    .unwrap();
+
+    let apply = || {
+        Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .arg("apply")
+            .arg("--source")
+            .arg(toml_path.to_str().unwrap())
+            .arg("--dry-run")
+            .env("PATH", temp_dir.path())
+            .output()
+            .expect("failed to execute process")
+    };
+
+    let output = apply();
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("brew is not installed or not on PATH; skipping the [brew] section"),
+        "Unexpected stderr: {}",
+        stderr
+    );
+
+    // A brew that knows no installed taps, formulae or casks.
+    let brew_path = temp_dir.path().join("brew");
+    std::fs::write(&brew_path, "#!/bin/sh\nexit 1\n").unwrap();
+    std::fs::set_permissions(&brew_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = apply();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let tap = stdout.find("Would run: brew tap user/tools");
+    let formula = stdout.find("Would run: brew install user/tools/fake-formula");
+    let cask = stdout.find("Would run: brew install --cask fake-cask");
+    assert!(
+        tap.is_some() && tap < formula && formula < cask,
+        "Unexpected stdout: {}",
+        stdout
+    );
+}
+
+#[test]
+fn test_apply_format_json_collects_failures() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[brew]
+list = ["first-formula", "second-formula"]
+"#
+    )
+    .unwrap();
+    // A brew on which every command fails.
+    let brew_path = temp_dir.path().join("brew");
+    std::fs::write(&brew_path, "#!/bin/sh\necho \"brew $*\"\nexit 1\n").unwrap();
+    std::fs::set_permissions(&brew_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .arg("--log-file")
+        .arg(temp_dir.path().join("railtube.log"))
+        .arg("apply")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--yes")
+        .arg("--format")
+        .arg("json")
+        .current_dir(temp_dir.path())
+        .env("HOME", temp_dir.path())
+        .env("XDG_STATE_HOME", temp_dir.path().join("state"))
+        .env("PATH", temp_dir.path())
+        .output()
+        .expect("failed to execute process");
+
+    assert_eq!(output.status.code(), Some(2));
+    // stdout holds nothing but the results, the brew output went to stderr.
+    let results: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let results = results.as_array().unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[1]["section"], "brew");
+    assert_eq!(results[1]["name"], "second-formula");
+    assert_eq!(results[1]["action"], "failed");
+    assert!(
+        results[1]["error"]
+            .as_str()
+            .unwrap()
+            .contains("brew install")
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("brew install first-formula"));
+}
+
+#[test]
+fn test_apply_keep_going_continues_after_failure() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[brew]
+list = ["broken-formula", "good-formula"]
+"#
+    )
+    .unwrap();
+    // Only installing broken-formula fails; nothing is installed yet.
+    let brew_path = temp_dir.path().join("brew");
+    std::fs::write(
+        &brew_path,
+        "#!/bin/sh\n[ \"$1\" = install ] || exit 1\n[ \"$2\" != broken-formula ]\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&brew_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let apply = |extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .arg("--log-file")
+            .arg(temp_dir.path().join("railtube.log"))
+            .arg("apply")
+            .arg("--source")
+            .arg(toml_path.to_str().unwrap())
+            .arg("--yes")
+            .args(extra_args)
+            .current_dir(temp_dir.path())
+            .env("HOME", temp_dir.path())
+            .env("XDG_STATE_HOME", temp_dir.path().join("state"))
+            .env("PATH", temp_dir.path())
+            .output()
+            .expect("failed to execute process")
+    };
+
+    let output = apply(&[]);
+    assert!(!output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("good-formula"));
+
+    let output = apply(&["--keep-going"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("[2/2] Installing [brew] good-formula"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("1 package(s) failed to install:\n  [brew] broken-formula"),
+        "Unexpected stderr: {}",
+        stderr
+    );
+}
+
+#[test]
+fn test_apply_rustup_installs_missing_toolchains_and_components() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[rustup]
+toolchains = ["stable", "nightly"]
+components = ["clippy", "rustfmt"]
+"#
+    )
+    .unwrap();
+    let rustup_path = temp_dir.path().join("rustup");
+    std::fs::write(
+        &rustup_path,
+        "#!/bin/sh\ncase \"$1\" in\n\
+         toolchain) echo 'stable-x86_64-unknown-linux-gnu (default)' ;;\n\
+         component) echo 'clippy-x86_64-unknown-linux-gnu' ;;\n\
+         esac\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&rustup_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .arg("apply")
+        .arg("--source")
+        .arg(toml_path.to_str().unwrap())
+        .arg("--dry-run")
+        .env("PATH", temp_dir.path())
+        .output()
+        .expect("failed to execute process");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Would run: rustup toolchain install nightly")
+            && stdout.contains("Would run: rustup component add rustfmt")
+            && stdout.contains("rustup toolchain 'stable' already installed")
+            && stdout.contains("rustup component 'clippy' already installed"),
+        "Unexpected stdout: {}",
+        stdout
+    );
+}
+
+#[test]
+fn test_apply_plan_confirms_once() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[brew]
+list = ["first-formula", "second-formula"]
+"#
+    )
+    .unwrap();
+    // Nothing is installed yet and every install succeeds.
+    let brew_path = temp_dir.path().join("brew");
+    std::fs::write(&brew_path, "#!/bin/sh\n[ \"$1\" = install ]\n").unwrap();
+    std::fs::set_permissions(&brew_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let apply = |answer: &str| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .arg("--log-file")
+            .arg(temp_dir.path().join("railtube.log"))
+            .arg("apply")
+            .arg("--source")
+            .arg(toml_path.to_str().unwrap())
+            .arg("--plan")
+            .current_dir(temp_dir.path())
+            .env("HOME", temp_dir.path())
+            .env("XDG_STATE_HOME", temp_dir.path().join("state"))
+            .env("PATH", temp_dir.path())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to execute process");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(answer.as_bytes())
+            .unwrap();
+        child.wait_with_output().unwrap()
+    };
+
+    let output = apply("n\n");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(
+            "Plan: 2 install(s), 0 removal(s).\n  [brew]\n    install first-formula\n    install second-formula"
+        ),
+        "Unexpected stdout: {}",
+        stdout
+    );
+    assert!(stdout.contains("nothing was installed") && !stdout.contains("Installing [brew]"));
+
+    // One answer covers every package.
+    let output = apply("y\n");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("[1/2] Installing [brew] first-formula"));
+    assert!(stdout.contains("[2/2] Installing [brew] second-formula"));
+}
+
+#[test]
+fn test_run_no_deps_skips_prerequisites() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+
+    let mut file = File::create(&toml_path).unwrap();
+    writeln!(
+        file,
+        r#"
+[scripts]
+build = "echo building"
+test = {{ cmd = "echo testing", after = ["build"] }}
+"#
+    )
+    .unwrap();
+
+    let run = |extra_args: &[&str]| {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("run")
+            .arg("--source")
+            .arg(toml_path.to_str().unwrap())
+            .arg("test")
+            .args(extra_args)
+            .output()
+            .expect("failed to execute process");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let stdout = run(&[]);
+    assert!(stdout.contains("\nbuilding\n") && stdout.contains("\ntesting\n"));
+    let stdout = run(&["--no-deps"]);
+    assert!(!stdout.contains("\nbuilding\n") && stdout.contains("\ntesting\n"));
+}
+
+#[test]
+fn test_remote_manifest_cache_revalidates_with_etag() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/railtube.toml", listener.local_addr().unwrap());
+    // Whether each request carried the cached ETag.
+    let revalidations = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&revalidations);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut line = String::new();
+            let mut revalidating = false;
+            while reader.read_line(&mut line).unwrap() > 2 {
+                revalidating |= line.eq_ignore_ascii_case("if-none-match: \"v1\"\r\n");
+                line.clear();
+            }
+            recorded.lock().unwrap().push(revalidating);
+            let response: &[u8] = if revalidating {
+                b"HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n"
+            } else {
+                b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 21\r\nConnection: close\r\n\r\n[cargo]\nlist = [\"a\"]\n"
+            };
+            reader.get_mut().write_all(response).unwrap();
+        }
+    });
+
+    let validate = |extra_args: &[&str]| {
+        revalidations.lock().unwrap().clear();
+        let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .args(["validate", "--source", &url])
+            .args(extra_args)
+            .env("XDG_CACHE_HOME", temp_dir.path())
+            .output()
+            .expect("failed to execute process");
+        assert!(
+            output.status.success(),
+            "Unexpected stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        revalidations.lock().unwrap().clone()
+    };
+
+    assert!(!validate(&["--no-cache"]).iter().any(|&r| r));
+    assert!(!temp_dir.path().join("railtube").exists());
+    let first = validate(&[]);
+    assert_eq!(first.first(), Some(&false));
+    assert!(validate(&[]).iter().all(|&r| r));
+    assert!(!validate(&["--refresh"]).iter().any(|&r| r));
+}
+
+#[test]
+fn test_init_refuses_to_overwrite_without_force() {
+    let temp_dir = TempDir::new().unwrap();
+    let manifest = temp_dir.path().join("starter.toml");
+    let init = |extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .args(["init", "--output", manifest.to_str().unwrap()])
+            .args(extra_args)
+            .output()
+            .expect("failed to execute process")
+    };
+
+    let output = init(&[]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("commit that file too"));
+    assert!(
+        std::fs::read_to_string(&manifest)
+            .unwrap()
+            .contains("[apt]")
+    );
+
+    std::fs::write(&manifest, "# edited\n").unwrap();
+    let output = init(&[]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("starter.toml already exists"));
+    assert!(stderr.contains("--force"));
+    assert_eq!(std::fs::read_to_string(&manifest).unwrap(), "# edited\n");
+
+    assert!(init(&["--force"]).status.success());
+    assert!(
+        std::fs::read_to_string(&manifest)
+            .unwrap()
+            .contains("[apt]")
+    );
+}
+
+#[test]
+fn test_apply_checks_the_manifest_signature_against_the_keyring() {
+    if Command::new("gpg").arg("--version").output().is_err() {
+        return;
+    }
+    let temp_dir = TempDir::new().unwrap();
+    let gnupg = temp_dir.path().join("gnupg");
+    std::fs::create_dir(&gnupg).unwrap();
+    let gpg = |args: &[&str]| {
+        let output = Command::new("gpg")
+            .args(["--batch", "--passphrase", ""])
+            .args(args)
+            .env("GNUPGHOME", &gnupg)
+            .current_dir(temp_dir.path())
+            .output()
+            .expect("failed to execute gpg");
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    };
+    gpg(&[
+        "--quick-gen-key",
+        "Signer <signer@example.com>",
+        "ed25519",
+        "sign",
+        "never",
+    ]);
+    gpg(&[
+        "--armor",
+        "--output",
+        "signer.asc",
+        "--export",
+        "signer@example.com",
+    ]);
+    std::fs::write(temp_dir.path().join("m.toml"), "[settings]\nyes = true\n").unwrap();
+    gpg(&[
+        "--armor",
+        "--detach-sign",
+        "--output",
+        "m.toml.asc",
+        "m.toml",
+    ]);
+
+    let railtube = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .arg("--log-file")
+            .arg(temp_dir.path().join("railtube.log"))
+            .args(args)
+            .current_dir(temp_dir.path())
+            .env("XDG_CONFIG_HOME", temp_dir.path().join("config"))
+            .output()
+            .expect("failed to execute process")
+    };
+    let apply = || {
+        railtube(&[
+            "apply",
+            "-s",
+            "m.toml",
+            "--signature",
+            "m.toml.asc",
+            "--dry-run",
+        ])
+    };
+
+    let output = apply();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No trusted keys"));
+
+    assert!(
+        railtube(&["keyring", "import", "signer.asc"])
+            .status
+            .success()
+    );
+    let output = apply();
+    assert!(
+        output.status.success(),
+        "Unexpected stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    std::fs::write(temp_dir.path().join("m.toml"), "[settings]\nyes = false\n").unwrap();
+    let output = apply();
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("does not verify against a trusted key")
+    );
+    let _ = Command::new("gpgconf")
+        .args(["--kill", "gpg-agent"])
+        .env("GNUPGHOME", &gnupg)
+        .status();
+}
+
+#[test]
+fn test_apply_runs_are_recorded_in_history() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let log_path = temp_dir.path().join("state").join("railtube.log");
+    write_fake_command(temp_dir.path(), "pipx", "[ \"$1\" = install ]\n");
+    write_fake_command(temp_dir.path(), "npm", "exit 1\n");
+    std::fs::write(
+        &toml_path,
+        "[pipx]\nlist = [\"black\"]\n\n[npm]\nlist = [\"prettier\"]\n",
+    )
+    .unwrap();
+
+    let railtube = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .arg("--log-file")
+            .arg(&log_path)
+            .args(args)
+            .current_dir(temp_dir.path())
+            .env("HOME", temp_dir.path())
+            .env("XDG_STATE_HOME", temp_dir.path().join("state"))
+            .env("PATH", temp_dir.path())
+            .output()
+            .expect("failed to execute process")
+    };
+    let source = toml_path.to_str().unwrap();
+
+    let output = railtube(&["log"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("No apply runs recorded"));
+
+    assert!(
+        !railtube(&["apply", "--source", source, "--yes"])
+            .status
+            .success()
+    );
+    // Dry runs install nothing and are not recorded.
+    assert!(
+        railtube(&["apply", "--source", source, "--dry-run"])
+            .status
+            .success()
+    );
+    assert!(
+        railtube(&["apply", "--source", source, "--yes", "--only", "pipx"])
+            .status
+            .success()
+    );
+    assert!(temp_dir.path().join("state").join("history.jsonl").exists());
+
+    let output = railtube(&["log", "--last", "5"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.matches("[pipx, npm]").count(), 1, "{}", stdout);
+    assert_eq!(stdout.matches("[pipx] in").count(), 1, "{}", stdout);
+    assert!(
+        stdout.contains("failed") && stdout.contains("[npm] prettier"),
+        "Unexpected stdout: {}",
+        stdout
+    );
+
+    let output = railtube(&["log", "--last", "1"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        !stdout.contains("[npm]") && stdout.contains("installed     [pipx] black"),
+        "Unexpected stdout: {}",
+        stdout
+    );
+}
+
+#[test]
+fn test_rollback_removes_only_new_installs() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    let log_path = temp_dir.path().join("railtube.log");
+    let dir = temp_dir.path().display();
+    // eslint is installed from the start; prettier only once `npm install`
+    // has run, until `npm uninstall` removes it again.
+    write_fake_command(
+        temp_dir.path(),
+        "npm",
+        &format!(
+            "case \"$1\" in\n\
+             ls) if [ -e {dir}/prettier ]; then\n\
+             echo '{{\"dependencies\":{{\"eslint\":{{\"version\":\"9.0.0\"}},\"prettier\":{{\"version\":\"3.0.0\"}}}}}}'\n\
+             else echo '{{\"dependencies\":{{\"eslint\":{{\"version\":\"9.0.0\"}}}}}}'; fi ;;\n\
+             install) : > {dir}/$3 ;;\n\
+             uninstall) echo \"uninstalled $3\" >> {dir}/uninstalled; /bin/rm {dir}/$3 ;;\n\
+             *) exit 1 ;;\n\
+             esac\n"
+        ),
+    );
+    std::fs::write(&toml_path, "[npm]\nlist = [\"eslint\", \"prettier\"]\n").unwrap();
+
+    let railtube = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .arg("--log-file")
+            .arg(&log_path)
+            .args(args)
+            .current_dir(temp_dir.path())
+            .env("HOME", temp_dir.path())
+            .env("XDG_STATE_HOME", temp_dir.path().join("state"))
+            .env("PATH", temp_dir.path())
+            .output()
+            .expect("failed to execute process")
+    };
+
+    let output = railtube(&["rollback"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No apply runs recorded"));
+
+    let output = railtube(&["apply", "--source", toml_path.to_str().unwrap(), "--yes"]);
+    assert!(
+        output.status.success(),
+        "Unexpected stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(temp_dir.path().join("prettier").exists());
+
+    let output = railtube(&["rollback", "--run", "1", "--dry-run"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success()
+            && stdout.contains("Would run: npm uninstall -g prettier")
+            && !stdout.contains("eslint"),
+        "Unexpected stdout: {}",
+        stdout
+    );
+    assert!(temp_dir.path().join("prettier").exists());
+
+    assert!(railtube(&["rollback", "--yes"]).status.success());
+    assert_eq!(
+        std::fs::read_to_string(temp_dir.path().join("uninstalled")).unwrap(),
+        "uninstalled prettier\n"
+    );
+    // Rolling back again finds nothing left to remove.
+    let output = railtube(&["rollback", "--yes"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("no longer installed"));
+
+    let output = railtube(&["rollback", "--run", "2"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No run #2"));
+}
+
+#[test]
+fn test_frozen_rejects_sections_without_lock_support() {
+    let temp_dir = TempDir::new().unwrap();
+    let toml_path = temp_dir.path().join("test.toml");
+    std::fs::write(
+        &toml_path,
+        "[apt]\nlist = [\"curl\"]\n\n[snap]\nlist = [\"code\"]\n",
+    )
+    .unwrap();
+    std::fs::write(
+        temp_dir.path().join("railtube.lock"),
+        "[apt]\ncurl = \"8.5.0\"\n",
+    )
+    .unwrap();
+
+    let railtube = |extra: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .args(["apply", "--source", toml_path.to_str().unwrap()])
+            .args(["--frozen", "--dry-run"])
+            .args(extra)
+            .current_dir(temp_dir.path())
+            .env("PATH", temp_dir.path())
+            .output()
+            .expect("failed to execute process")
+    };
+
+    let output = railtube(&[]);
+    assert!(!output.status.success());
+    assert!(
+        String::from_utf8_lossy(&output.stderr).contains("--frozen cannot pin [snap]"),
+        "Unexpected stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let output = railtube(&["--exclude", "snap"]);
+    assert!(
+        output.status.success(),
+        "Unexpected stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn test_lock_file_is_written_next_to_the_manifest_after_complete_runs() {
+    let temp_dir = TempDir::new().unwrap();
+    let manifest_dir = temp_dir.path().join("config");
+    std::fs::create_dir(&manifest_dir).unwrap();
+    let toml_path = manifest_dir.join("dev.toml");
+    let lock_path = manifest_dir.join("railtube.lock");
+    let log_path = temp_dir.path().join("railtube.log");
+    write_fake_command(
+        temp_dir.path(),
+        "npm",
+        "[ \"$1\" = ls ] && echo '{\"dependencies\":{\"eslint\":{\"version\":\"9.0.0\"},\"prettier\":{\"version\":\"3.0.0\"}}}'\n\
+         exit 0\n",
+    );
+    std::fs::write(&toml_path, "[npm]\nlist = [\"eslint\", \"prettier\"]\n").unwrap();
+
+    let railtube = |extra: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .arg("--log-file")
+            .arg(&log_path)
+            .args(["apply", "--source", toml_path.to_str().unwrap(), "--yes"])
+            .args(extra)
+            .current_dir(temp_dir.path())
+            .env("HOME", temp_dir.path())
+            .env("XDG_STATE_HOME", temp_dir.path().join("state"))
+            .env("PATH", temp_dir.path())
+            .output()
+            .expect("failed to execute process")
+    };
+
+    // A partial run would leave the other packages out of the lock.
+    assert!(railtube(&["--only", "npm:eslint"]).status.success());
+    assert!(!lock_path.exists());
+
+    let output = railtube(&[]);
+    assert!(
+        output.status.success(),
+        "Unexpected stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        std::fs::read_to_string(&lock_path).unwrap(),
+        "[npm]\neslint = \"9.0.0\"\nprettier = \"3.0.0\"\n"
+    );
+    assert!(!temp_dir.path().join("railtube.lock").exists());
+}
+
+#[test]
+fn test_rollback_names_installs_it_cannot_remove() {
+    let temp_dir = TempDir::new().unwrap();
+    let log_path = temp_dir.path().join("railtube.log");
+    std::fs::write(
+        temp_dir.path().join("history.jsonl"),
+        "{\"timestamp\":0,\"duration_ms\":0,\"sections\":[\"go\",\"deb\"],\"packages\":[],\"skipped\":0,\
+         \"new_installs\":{\"go\":[\"golang.org/x/tools/gopls\"],\"deb\":[\"./tool.deb\"]}}\n",
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_railtube"))
+        .arg("--log-file")
+        .arg(&log_path)
+        .args(["rollback", "--yes"])
+        .env("PATH", temp_dir.path())
+        .output()
+        .expect("failed to execute process");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "Unexpected stderr: {}", stderr);
+    assert!(stderr.contains("Cannot roll back [go]; remove golang.org/x/tools/gopls by hand."));
+    assert!(stderr.contains("Cannot roll back [deb]; remove ./tool.deb by hand."));
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("nothing to roll back"));
+}
+
+#[test]
+fn test_which_reports_each_manager() {
+    let temp_dir = TempDir::new().unwrap();
+    write_fake_command(
+        temp_dir.path(),
+        "apt-cache",
+        "[ \"$2\" = gimp ] || exit 0\n\
+         printf 'gimp:\\n  Installed: (none)\\n  Candidate: 2.10.36-3\\n'\n",
+    );
+    write_fake_command(
+        temp_dir.path(),
+        "flatpak",
+        "printf 'org.gimp.GIMP\\t2.10.38\\n'\n",
+    );
+
+    let which = |name: &str| {
+        Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .arg("which")
+            .arg(name)
+            .env("PATH", temp_dir.path())
+            .output()
+            .expect("failed to execute process")
+    };
+
+    let output = which("gimp");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().map(str::trim_end).collect();
+    assert_eq!(
+        lines,
+        vec![
+            "SECTION  PACKAGE        VERSION",
+            "apt      gimp           2.10.36-3",
+            "snap     -              snap is not installed",
+            "flatpak  org.gimp.GIMP  2.10.38",
+            "cargo    -              cargo is not installed",
+        ],
+        "Unexpected stdout: {}",
+        stdout
+    );
+
+    let output = which("inkscape");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("apt      -        not found"));
+}
+
+#[test]
+fn test_apply_renders_manifest_templates() {
+    let temp_dir = TempDir::new().unwrap();
+    let template_path = temp_dir.path().join("test.toml.j2");
+    let toml_path = temp_dir.path().join("test.toml");
+    std::fs::write(
+        &template_path,
+        "[apt]\nlist = [\"pkg-{{ hostname }}-{{ arch }}\", \"{{ editor }}\"]\n",
+    )
+    .unwrap();
+    std::fs::write(&toml_path, "[apt]\nlist = [\"{{ editor }}\"]\n").unwrap();
+
+    let apply = |source: &std::path::Path, extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_railtube"))
+            .arg("--host")
+            .arg("laptop")
+            .args(extra_args)
+            .arg("apply")
+            .arg("--source")
+            .arg(source)
+            .arg("--dry-run")
+            .output()
+            .expect("failed to execute process")
+    };
+
+    let output = apply(&template_path, &["--template-vars", "editor=fake-neovim"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success()
+            && stdout.contains(&format!(
+                "Would run: sudo apt install -y pkg-laptop-{} fake-neovim",
+                std::env::consts::ARCH
+            )),
+        "Unexpected stdout: {}\nstderr: {}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // An undefined variable fails instead of rendering as nothing.
+    let output = apply(&template_path, &[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Failed to render manifest template"));
+
+    // Plain manifests are not templates unless variables are passed.
+    let output = apply(&toml_path, &[]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("apt install -y '{{ editor }}'"));
+    let output = apply(&toml_path, &["--template-vars", "editor=fake-editor"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("apt install -y fake-editor"));
+}